@@ -0,0 +1,118 @@
+//! Compare the throughput of the asynchronous `Client`, with and without a dedicated writer.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::{AsyncRead, AsyncWrite};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tjiftjaf::{
+    aio::{Client, Emit},
+    publish, ConnAck, Connect, Packet,
+};
+
+const PUBLICATIONS: usize = 100;
+
+// An in-memory socket. Reading yields a CONNACK and then blocks forever.
+// Every write first yields `Poll::Pending` once, to simulate a slow socket.
+struct Socket {
+    inbound: Vec<u8>,
+    pending: bool,
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        let connack: Packet = ConnAck::builder().build().into();
+        Self {
+            inbound: connack.into_bytes(),
+            pending: true,
+        }
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.inbound.is_empty() {
+            return Poll::Pending;
+        }
+
+        let length = buf.len().min(self.inbound.len());
+        buf[..length].copy_from_slice(&self.inbound[..length]);
+        self.inbound.drain(..length);
+        Poll::Ready(Ok(length))
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.pending {
+            self.pending = false;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        self.pending = true;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Publish `PUBLICATIONS` messages and wait until the client terminates.
+fn run(client: Client<Socket>) {
+    let (handle, task) = client.spawn();
+
+    let publisher = async move {
+        for _ in 0..PUBLICATIONS {
+            publish("sensor/1/temperature", "21.3")
+                .emit(&handle)
+                .await
+                .unwrap();
+        }
+
+        // Wait for the CONNECT and all publications to be transmitted.
+        while handle
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_sent
+            < PUBLICATIONS + 1
+        {}
+        handle.disconnect().await.unwrap();
+    };
+
+    let (result, ()) = smol::block_on(futures::future::join(task, publisher));
+    result.unwrap();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aio client");
+    let connect = Connect::builder().client_id("bench").build();
+
+    group.bench_function(BenchmarkId::new("writer", "inline"), |b| {
+        b.iter(|| run(Client::new(connect.clone(), Socket::default())))
+    });
+
+    group.bench_function(BenchmarkId::new("writer", "dedicated"), |b| {
+        b.iter(|| run(Client::new(connect.clone(), Socket::default()).dedicated_writer()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
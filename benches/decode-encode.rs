@@ -1,10 +1,68 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use std::hint::black_box;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::{
+    hint::black_box,
+    io::Write,
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
 use tjiftjaf::{
-    ConnAck, Connect, Disconnect, Packet, PubAck, Publish, QoS, SubAck, Subscribe, UnsubAck,
-    Unsubscribe,
+    ConnAck, Connect, Disconnect, MqttBinding, Packet, PubAck, Publish, PublishTemplate, QoS,
+    SubAck, Subscribe, UnsubAck, Unsubscribe,
 };
 
+// The number of publications transmitted by each iteration of the throughput benchmark.
+const PUBLICATIONS: u64 = 1000;
+
+// Transmit `PUBLICATIONS` publications with a new binding to `socket`. Write every
+// packet separately, or coalesce them with `MqttBinding::poll_transmits_batch()`.
+fn transmit(socket: &mut TcpStream, batch: Option<usize>) {
+    let mut binding = MqttBinding::from_connect(Connect::builder().build());
+    let now = Instant::now();
+    let connect = binding.poll_transmits(now).unwrap().unwrap();
+    socket.write_all(&connect).unwrap();
+
+    let mut connack = Packet::from(ConnAck::builder().build()).into_bytes();
+    while !connack.is_empty() {
+        let rest = connack.split_off(binding.get_read_buffer().len());
+        binding.try_decode(connack, now);
+        connack = rest;
+    }
+
+    for _ in 0..PUBLICATIONS {
+        let publish = Publish::builder("sensors/temperature/1", r#"{"measurement": 19.2}"#);
+        binding.try_send(publish.build().into()).unwrap();
+    }
+
+    loop {
+        let transmit = match batch {
+            Some(max_bytes) => binding.poll_transmits_batch(now, max_bytes),
+            None => binding.poll_transmits(now),
+        };
+        match transmit.unwrap() {
+            Some(bytes) => socket.write_all(&bytes).unwrap(),
+            None => break,
+        }
+    }
+}
+
+fn throughput_benchmark(c: &mut Criterion) {
+    // A peer that discards everything it receives.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    std::thread::spawn(move || std::io::copy(&mut peer, &mut std::io::sink()));
+
+    let mut group = c.benchmark_group("transmit publications");
+    group.throughput(Throughput::Elements(PUBLICATIONS));
+    group.bench_function(BenchmarkId::new("writes", "per packet"), |b| {
+        b.iter(|| transmit(&mut socket, None))
+    });
+    group.bench_function(BenchmarkId::new("writes", "batched"), |b| {
+        b.iter(|| transmit(&mut socket, Some(16 * 1024)))
+    });
+    group.finish();
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("decode/encode Connect ", |b| {
         let packet: Packet = Connect::builder()
@@ -67,6 +125,17 @@ fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    // Encoding the same topic over and over, with a `Builder` and with a `PublishTemplate`.
+    let topic = "building/3/floor/2/room/14/sensors/temperature";
+    let payload = r#"{"measurement": 19.2}"#;
+    c.bench_function("encode Publish with Builder", |b| {
+        b.iter(|| Publish::builder(black_box(topic), black_box(payload)).build())
+    });
+    c.bench_function("encode Publish with PublishTemplate", |b| {
+        let template = PublishTemplate::new(topic).unwrap();
+        b.iter(|| template.with_payload(black_box(payload)))
+    });
+
     c.bench_function("decode/encode PubAck", |b| {
         let packet: Packet = PubAck::new(1337).into();
 
@@ -110,5 +179,5 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(benches, criterion_benchmark, throughput_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,102 @@
+//! Measure the throughput of the server routing the publications of many concurrent clients
+//! to one subscriber, with and without a spawner.
+use async_net::{TcpListener, TcpStream};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::join_all;
+use futures_lite::future;
+use smol::Executor;
+use std::thread;
+use tjiftjaf::{
+    aio::{server::Server, Client, ClientHandle, Emit},
+    publish, subscribe, Connect,
+};
+
+const CLIENTS: usize = 100;
+const PUBLICATIONS: usize = 10;
+const THREADS: usize = 4;
+
+static EXECUTOR: Executor<'static> = Executor::new();
+
+struct Setup {
+    publishers: Vec<ClientHandle>,
+    subscriber: ClientHandle,
+}
+
+// Start a server, connect a subscriber to all publications and `CLIENTS` publishers.
+async fn setup(spawner: bool) -> Setup {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut server = Server::new(listener);
+    if spawner {
+        server = server.spawner(|future| EXECUTOR.spawn(future).detach());
+    }
+    EXECUTOR.spawn(server.run()).detach();
+
+    let subscriber = connect(port, "subscriber".into()).await;
+    subscribe("bench/#").emit(&subscriber).await.unwrap();
+
+    let mut publishers = vec![];
+    for n in 0..CLIENTS {
+        publishers.push(connect(port, format!("publisher-{n}")).await);
+    }
+    Setup {
+        publishers,
+        subscriber,
+    }
+}
+
+async fn connect(port: u16, client_id: String) -> ClientHandle {
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let connect = Connect::builder().client_id(client_id).build();
+    let (handle, task) = Client::new(connect, stream).spawn();
+    EXECUTOR.spawn(task).detach();
+    handle
+}
+
+// Let every publisher publish concurrently, until the subscriber received all publications.
+async fn run(setup: &mut Setup) {
+    let Setup {
+        publishers,
+        subscriber,
+    } = setup;
+    let publishers = join_all(
+        publishers
+            .iter()
+            .enumerate()
+            .map(|(n, handle)| publications(n, handle)),
+    );
+    let subscriber = async {
+        for _ in 0..CLIENTS * PUBLICATIONS {
+            subscriber.subscriptions().await.unwrap();
+        }
+    };
+    futures::join!(publishers, subscriber);
+}
+
+async fn publications(n: usize, handle: &ClientHandle) {
+    let topic = format!("bench/{n}");
+    for _ in 0..PUBLICATIONS {
+        publish(&topic, "21.3").emit(handle).await.unwrap();
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    for _ in 0..THREADS {
+        thread::spawn(|| future::block_on(EXECUTOR.run(future::pending::<()>())));
+    }
+
+    let mut group = c.benchmark_group("server connections");
+    group.throughput(Throughput::Elements((CLIENTS * PUBLICATIONS) as u64));
+
+    for (name, spawner) in [("single task", false), ("spawner", true)] {
+        let mut setup = future::block_on(setup(spawner));
+        group.bench_function(BenchmarkId::new(name, CLIENTS), |b| {
+            b.iter(|| future::block_on(run(&mut setup)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
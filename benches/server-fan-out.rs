@@ -0,0 +1,49 @@
+//! Measure the throughput of the server delivering one publication to many subscribers.
+use async_channel::{Receiver, Sender};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tjiftjaf::{
+    aio::server::{FanOut, OverflowPolicy},
+    Packet, Publish,
+};
+
+const SUBSCRIBERS: usize = 10_000;
+
+// Deliver `packet` to all subscribers, then empty their queues for the next iteration.
+fn run(fan_out: &FanOut, packet: &Packet, queues: &[(Sender<Packet>, Receiver<Packet>)]) {
+    let subscribers: Vec<&Sender<Packet>> = queues.iter().map(|(sender, _)| sender).collect();
+    let failed = smol::block_on(fan_out.deliver(packet, &subscribers));
+    assert!(failed.is_empty());
+
+    for (_, receiver) in queues {
+        while receiver.try_recv().is_ok() {}
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server fan-out");
+    group.throughput(Throughput::Elements(SUBSCRIBERS as u64));
+
+    let packet: Packet = Publish::builder("sensor/1/temperature", "21.3")
+        .build()
+        .into();
+    let queues: Vec<_> = (0..SUBSCRIBERS)
+        .map(|_| async_channel::bounded(100))
+        .collect();
+
+    for workers in [1, 4, 16] {
+        let fan_out = FanOut::new().workers(workers);
+        group.bench_function(BenchmarkId::new("wait", workers), |b| {
+            b.iter(|| run(&fan_out, &packet, &queues))
+        });
+    }
+
+    let fan_out = FanOut::new().overflow_policy(OverflowPolicy::Drop);
+    group.bench_function(BenchmarkId::new("drop", 1), |b| {
+        b.iter(|| run(&fan_out, &packet, &queues))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
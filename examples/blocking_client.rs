@@ -1,7 +1,6 @@
 /// Run with `cargo run --example blocking_client --feature=blocking`
 use log::info;
 use std::env;
-use std::net::TcpStream;
 use tjiftjaf::{
     blocking::{Client, Emit},
     packet_identifier, publish, subscribe, Connect,
@@ -13,14 +12,12 @@ fn main() {
     let broker = env::args()
         .nth(1)
         .unwrap_or(String::from("test.mosquitto.org:1884"));
-    let stream = TcpStream::connect(broker).unwrap();
-
     let connect = Connect::builder()
         .client_id("tjiftjaf")
         .username("ro")
         .password("readonly")
         .build();
-    let client = Client::new(connect, stream);
+    let client = Client::connect(broker, connect).expect("Failed connecting to MQTT broker.");
 
     // Spawn the event loop that monitors the socket.
     // `handle` allows for sending and receiving MQTT packets.
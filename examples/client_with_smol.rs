@@ -1,4 +1,3 @@
-use async_net::TcpStream;
 use futures_lite::FutureExt;
 use log::info;
 use std::env;
@@ -14,16 +13,14 @@ fn main() {
         .unwrap_or(String::from("test.mosquitto.org:1884"));
 
     smol::block_on(async {
-        let stream = TcpStream::connect(broker)
-            .await
-            .expect("Failed connecting to MQTT broker.");
-
         let connect = Connect::builder()
             .client_id("tjiftjaf")
             .username("ro")
             .password("readonly")
             .build();
-        let client = Client::new(connect, stream);
+        let client = Client::connect_tcp(broker, connect)
+            .await
+            .expect("Failed connecting to MQTT broker.");
 
         // Spawn the event loop that monitors the socket.
         // `handle` allows for sending and receiving MQTT packets.
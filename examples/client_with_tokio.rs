@@ -2,11 +2,9 @@
 use log::info;
 use std::env;
 use tjiftjaf::{
-    aio::{Client, ClientHandle, Emit},
+    aio::{tokio::Client, ClientHandle, Emit},
     packet_identifier, publish, subscribe, Connect,
 };
-use tokio::net::TcpStream;
-use tokio_util::compat::TokioAsyncReadCompatExt;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
@@ -15,17 +13,14 @@ async fn main() {
         .nth(1)
         .unwrap_or(String::from("test.mosquitto.org:1884"));
 
-    let stream = TcpStream::connect(broker)
-        .await
-        .expect("Failed connecting to MQTT broker.")
-        .compat();
-
     let connect = Connect::builder()
         .client_id("tjiftjaf")
         .username("ro")
         .password("readonly")
         .build();
-    let client = Client::new(connect, stream);
+    let client = Client::connect(broker, connect)
+        .await
+        .expect("Failed connecting to MQTT broker.");
 
     // Spawn the event loop that monitors the socket.
     // `handle` allows for sending and receiving MQTT packets.
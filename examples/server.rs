@@ -13,7 +13,8 @@ fn main() {
             .await
             .expect("Failed to boot to MQTT server.");
 
-        let server = Server::new(listener);
+        // Handle every connection in a separate task.
+        let server = Server::new(listener).spawner(|future| smol::spawn(future).detach());
         server.run().await
     })
 }
@@ -0,0 +1,45 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tjiftjaf::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    // Decoding arbitrary bytes must fail with an error, not panic.
+    let Ok(packet) = Packet::try_from(data.to_vec()) else {
+        return;
+    };
+
+    // None of these calls should panic on a packet that decoded.
+    _ = format!("{packet:?}");
+    match &packet {
+        Packet::Connect(connect) => {
+            _ = connect.flags();
+            _ = connect.client_id();
+            _ = connect.keep_alive();
+            _ = connect.username();
+            _ = connect.password();
+            _ = connect.will();
+        }
+        Packet::Publish(publish) => {
+            _ = publish.topic();
+            _ = publish.payload();
+            _ = publish.qos();
+            _ = publish.packet_identifier();
+        }
+        Packet::Subscribe(subscribe) => {
+            _ = subscribe.packet_identifier();
+            for _ in subscribe.topics() {}
+        }
+        Packet::Unsubscribe(unsubscribe) => {
+            _ = unsubscribe.packet_identifier();
+            for _ in unsubscribe.topics() {}
+        }
+        Packet::SubAck(suback) => {
+            _ = suback.packet_identifier();
+            _ = suback.return_codes();
+        }
+        _ => {}
+    }
+
+    // Verify that the packet encodes to the bytes it was decoded from.
+    assert_eq!(packet.into_bytes(), data);
+});
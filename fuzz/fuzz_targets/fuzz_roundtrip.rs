@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tjiftjaf::Packet;
+
+fuzz_target!(|packet_1: Packet| {
+    // Every packet that can be built must decode to the same packet.
+    let bytes = packet_1.clone().into_bytes();
+    let packet_2 = Packet::try_from(bytes).unwrap();
+    assert_eq!(packet_1, packet_2);
+});
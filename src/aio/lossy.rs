@@ -0,0 +1,374 @@
+//! A transport that simulates latency, packet loss and reordering. Requires the feature `test-util`.
+//!
+//! [`LossyTransport`] wraps a socket and impairs the MQTT packets that pass through it, in
+//! both directions. It validates QoS and retry settings under realistic network conditions,
+//! entirely in-process.
+//!
+//! ```no_run
+//! # use async_net::TcpStream;
+//! # use std::time::Duration;
+//! use tjiftjaf::{aio::{lossy::LossyTransport, Client}, Connect};
+//! # smol::block_on(async {
+//! let stream = TcpStream::connect("localhost:1883").await.unwrap();
+//! let transport = LossyTransport::new(stream)
+//!     .latency(Duration::from_millis(50))
+//!     .jitter(Duration::from_millis(20))
+//!     .drop_rate(0.05)
+//!     .reorder_rate(0.01);
+//! let (handle, task) = Client::new(Connect::builder().build(), transport).spawn();
+//! # });
+//! ```
+//!
+//! The transport impairs whole packets, so the stream stays decodable. A dropped packet
+//! vanishes without a trace, like a packet that's lost on a connection that's
+//! re-established later. The impairments are random, but reproducible: the same
+//! [`LossyTransport::seed()`] and traffic lead to the same losses.
+//!
+//! Delayed packets are transmitted while the transport is polled. A [`Client`](super::Client)
+//! polls it continuously while it waits for inbound packets.
+use super::sleep::{AsyncIo, Sleep};
+use crate::decode::{self, DecodingError};
+use futures::{AsyncRead, AsyncWrite};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::Error,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// A socket that delays, drops and reorders the MQTT packets that pass through it.
+///
+/// See the [module documentation](crate::aio::lossy) for an example.
+pub struct LossyTransport<S, T: Sleep = AsyncIo> {
+    inner: S,
+
+    latency: Duration,
+    jitter: Duration,
+    drop_rate: f64,
+    reorder_rate: f64,
+    random: Random,
+
+    // Packets written by the application, on their way to `inner`.
+    outbound: Direction<T>,
+
+    // Packets read from `inner`, on their way to the application.
+    inbound: Direction<T>,
+
+    // Whether `inner` reached the end of the stream.
+    eof: bool,
+}
+
+impl<S> LossyTransport<S> {
+    /// Wrap `inner`. Without further configuration, the transport doesn't impair anything.
+    pub fn new(inner: S) -> Self {
+        LossyTransport {
+            inner,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            drop_rate: 0.0,
+            reorder_rate: 0.0,
+            random: Random::new(0),
+            outbound: Direction::new(),
+            inbound: Direction::new(),
+            eof: false,
+        }
+    }
+}
+
+impl<S, T: Sleep> LossyTransport<S, T> {
+    /// Wait for the delays with the timer `T2`. The default is the timer of async-io.
+    /// See [`Sleep`].
+    pub fn timer<T2: Sleep>(self) -> LossyTransport<S, T2> {
+        LossyTransport {
+            inner: self.inner,
+            latency: self.latency,
+            jitter: self.jitter,
+            drop_rate: self.drop_rate,
+            reorder_rate: self.reorder_rate,
+            random: self.random,
+            outbound: Direction::new(),
+            inbound: Direction::new(),
+            eof: self.eof,
+        }
+    }
+
+    /// Delay every packet by `latency`.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Delay every packet by up to `jitter` more than the latency, uniformly distributed.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Drop packets with a probability of `rate`, between 0 and 1.
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        self.drop_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Swap packets with the packet before them with a probability of `rate`, between 0 and 1.
+    /// Only a packet that's still delayed can be overtaken.
+    pub fn reorder_rate(mut self, rate: f64) -> Self {
+        self.reorder_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Seed the random impairments. The default seed is 0.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.random = Random::new(seed);
+        self
+    }
+
+    /// A reference to the wrapped socket.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Unwrap the socket. Packets that are delayed are lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    // Split the bytes in the buffer of `direction` into packets and schedule them.
+    fn schedule(&mut self, outbound: bool, now: Instant) {
+        let direction = match outbound {
+            true => &mut self.outbound,
+            false => &mut self.inbound,
+        };
+        while let Some(frame) = direction.next_frame() {
+            if self.random.chance(self.drop_rate) {
+                continue;
+            }
+
+            let jitter = self.jitter.mul_f64(self.random.fraction());
+            let due = now + self.latency + jitter;
+
+            // A packet can't overtake one that's partially transmitted.
+            let overtake =
+                direction.queue.len() > 1 || (direction.queue.len() == 1 && direction.cursor == 0);
+            if overtake && self.random.chance(self.reorder_rate) {
+                direction
+                    .queue
+                    .insert(direction.queue.len() - 1, (due, frame));
+            } else {
+                direction.queue.push_back((due, frame));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin, T: Sleep> LossyTransport<S, T> {
+    // Transmit the outbound packets that are due. Resolves when none are due anymore,
+    // and registers a wake up for the next one.
+    fn poll_transmit(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        loop {
+            let Some(&(due, _)) = self.outbound.queue.front() else {
+                return Poll::Ready(Ok(()));
+            };
+            if self.outbound.poll_due(due, cx).is_pending() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let (_, frame) = &self.outbound.queue[0];
+            let bytes = &frame[self.outbound.cursor..];
+            let n = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, bytes))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.outbound.advance(n);
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, T: Sleep> AsyncRead for LossyTransport<S, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+
+        // Reading might be the only activity of the application, so it moves the
+        // outbound packets along as well.
+        if let Poll::Ready(Err(error)) = this.poll_transmit(cx) {
+            return Poll::Ready(Err(error));
+        }
+
+        loop {
+            if let Some(&(due, _)) = this.inbound.queue.front() {
+                if this.inbound.poll_due(due, cx).is_ready() {
+                    let (_, frame) = &this.inbound.queue[0];
+                    let bytes = &frame[this.inbound.cursor..];
+                    let n = bytes.len().min(buf.len());
+                    buf[..n].copy_from_slice(&bytes[..n]);
+                    this.inbound.advance(n);
+                    return Poll::Ready(Ok(n));
+                }
+            }
+
+            if this.eof {
+                return match this.inbound.queue.is_empty() {
+                    true => Poll::Ready(Ok(0)),
+                    false => Poll::Pending,
+                };
+            }
+
+            let mut chunk = [0; 4096];
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(0)) => {
+                    // Bytes that don't form a complete packet are passed on as they are.
+                    this.eof = true;
+                    let rest = std::mem::take(&mut this.inbound.buffer);
+                    if !rest.is_empty() {
+                        this.inbound.queue.push_back((Instant::now(), rest));
+                    }
+                }
+                Poll::Ready(Ok(n)) => {
+                    this.inbound.buffer.extend_from_slice(&chunk[..n]);
+                    this.schedule(false, Instant::now());
+                }
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin, T: Sleep> AsyncWrite for LossyTransport<S, T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        this.outbound.buffer.extend_from_slice(buf);
+        this.schedule(true, Instant::now());
+
+        if let Poll::Ready(Err(error)) = this.poll_transmit(cx) {
+            return Poll::Ready(Err(error));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    // Packets that are delayed are in flight. They don't hold up the flush.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_transmit(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_transmit(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+// The packets travelling in one direction through a `LossyTransport`.
+struct Direction<T: Sleep> {
+    // Bytes that don't form a complete packet yet.
+    buffer: Vec<u8>,
+
+    // Packets in flight, with the moment they're due, in order of delivery.
+    queue: VecDeque<(Instant, Vec<u8>)>,
+
+    // The number of bytes of the first packet in `queue` that were already passed on.
+    cursor: usize,
+
+    // Wakes up the transport when the first packet in `queue` is due.
+    delay: Option<(Instant, Pin<Box<T::Delay>>)>,
+}
+
+impl<T: Sleep> Direction<T> {
+    fn new() -> Self {
+        Self {
+            buffer: vec![],
+            queue: VecDeque::new(),
+            cursor: 0,
+            delay: None,
+        }
+    }
+
+    // Take the next complete packet from the buffer. Bytes with an invalid fixed
+    // header are passed on as they are, so the receiver detects the error.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.buffer.len() < 2 {
+            return None;
+        }
+        let length = match decode::packet_length(&self.buffer[1..]) {
+            Ok(length) => length as usize,
+            Err(DecodingError::NotEnoughBytes { .. }) => return None,
+            Err(_) => self.buffer.len(),
+        };
+        if self.buffer.len() < length {
+            return None;
+        }
+
+        let rest = self.buffer.split_off(length);
+        Some(std::mem::replace(&mut self.buffer, rest))
+    }
+
+    // Whether `due` passed. If not, wake up the task at `due`.
+    fn poll_due(&mut self, due: Instant, cx: &mut Context<'_>) -> Poll<()> {
+        if due <= Instant::now() {
+            return Poll::Ready(());
+        }
+
+        let delay = match &mut self.delay {
+            Some((deadline, delay)) if *deadline == due => delay,
+            delay => &mut delay.insert((due, Box::pin(T::sleep_until(due)))).1,
+        };
+        match delay.as_mut().poll(cx) {
+            Poll::Ready(_) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    // Mark `n` bytes of the first packet as passed on.
+    fn advance(&mut self, n: usize) {
+        self.cursor += n;
+        if self
+            .queue
+            .front()
+            .is_some_and(|(_, frame)| self.cursor == frame.len())
+        {
+            self.queue.pop_front();
+            self.cursor = 0;
+        }
+    }
+}
+
+// A small pseudorandom number generator (xorshift64*). It's not suitable for cryptography,
+// but good enough to pick which packets are impaired.
+#[derive(Clone, Copy)]
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // The state of xorshift must not be 0.
+        Self((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // A number between 0 and 1.
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Returns `true` with a probability of `probability`.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.fraction() < probability
+    }
+}
@@ -40,27 +40,83 @@
 //!   }).await;
 //! });
 //! ```
-use std::time::Instant;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
+use crate::target;
+#[cfg(feature = "tls")]
+use crate::tls::{rustls, TlsStream};
 use crate::{
-    Connect, ConnectionError, Disconnect, MqttBinding, Packet, PubAck, PubComp, PubRec, PubRel,
-    Publish, QoS,
+    quirks::Quirks, subscribe_all, ClientDisconnected, Command, Connect, ConnectError,
+    DecodeErrorPolicy, Disconnect, HandleError, Interceptor, Lease, MqttBinding, Packet, Ping,
+    Publish, QoS, Session, SessionStore, Snapshot, Statistics, Subscribe, SubscribeError,
+    SubscriptionResult, Termination, TopicRewrite, Unsubscribe, WaitTimeoutError,
+};
+use async_channel::{self, Receiver, Sender, TrySendError, WeakReceiver, WeakSender};
+use async_io::Async;
+use futures::{
+    future::BoxFuture,
+    io::{ReadHalf, WriteHalf},
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, Stream,
 };
-use async_channel::{self, Receiver, SendError, Sender};
-use async_io::Timer;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt};
 use log::{error, info, trace};
+use service::{Capacity, PublishService, Queue};
+use sleep::{AsyncIo, Sleep, SleepFn};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::{
+    future::IntoFuture,
+    marker::PhantomData,
+    net::{TcpStream, ToSocketAddrs},
+    pin::Pin,
+    task::{Context, Poll},
+};
 
+#[cfg(feature = "test-util")]
+pub mod lossy;
 #[cfg(feature = "experimental")]
 pub mod server;
+pub mod service;
+pub mod sleep;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+// The event loop coalesces transmits into writes of about this many bytes.
+const MAX_BATCH: usize = 16 * 1024;
+
+// The maximum number of decoded publications waiting for their receivers, before
+// the event loop stops reading from the socket.
+const MAX_PENDING_DELIVERIES: usize = 100;
+
+// The maximum number of writes queued for a dedicated writer, before the event loop
+// waits for it. The handles then feel the backpressure of a slow socket.
+const MAX_QUEUED_WRITES: usize = 16;
+
+// The maximum number of publications a `Subscription` replays. The channel of the
+// `Subscription` holds all of them, so a larger request would allocate a huge channel.
+const MAX_REPLAY: usize = 10_000;
 
 /// An asynchronous client to interact with a MQTT broker.
 ///
 /// See the [module documentation](crate::aio) for more information.
-pub struct Client<S> {
+///
+/// The client waits for keep alives with the timer `T`. See [`Client::timer()`].
+pub struct Client<S, T = AsyncIo> {
     // Socket for interacting with the MQTT broker.
     socket: S,
     binding: MqttBinding,
+
+    // If set, transmits are written by a separate future. See `Client::dedicated_writer()`.
+    dedicated_writer: bool,
+
+    delivery_mode: DeliveryMode,
+
+    // When set, the event loop yields to the executor once it used up the budget.
+    budget: Option<Budget>,
+
+    timer: PhantomData<T>,
 }
 
 impl<S> Client<S>
@@ -71,153 +127,840 @@ where
         Self {
             socket,
             binding: MqttBinding::from_connect(connect),
+            dedicated_writer: false,
+            delivery_mode: DeliveryMode::default(),
+            budget: None,
+            timer: PhantomData,
+        }
+    }
+
+    /// Create a `Client` that continues a [`Session`] obtained with [`ClientHandle::suspend()`].
+    ///
+    /// After connecting, the client subscribes to the topics of the session again
+    /// and retransmits the publications that were not acknowledged.
+    pub fn resume(session: Session, socket: S) -> Self {
+        Self {
+            socket,
+            binding: MqttBinding::from_session(session),
+            dedicated_writer: false,
+            delivery_mode: DeliveryMode::default(),
+            budget: None,
+            timer: PhantomData,
+        }
+    }
+}
+
+impl<S, T> Client<S, T>
+where
+    S: AsyncRead + AsyncWrite + Send,
+    T: Sleep,
+{
+    /// Wait for keep alives with the timer `T2`, instead of the timer of async-io.
+    ///
+    /// See [`sleep`] for an example.
+    pub fn timer<T2: Sleep>(self) -> Client<S, T2> {
+        Client {
+            socket: self.socket,
+            binding: self.binding,
+            dedicated_writer: self.dedicated_writer,
+            delivery_mode: self.delivery_mode,
+            budget: self.budget,
+            timer: PhantomData,
         }
     }
 
+    /// Write to the socket from a dedicated writer, that runs concurrently with the event loop.
+    ///
+    /// By default, the event loop writes transmits to the socket itself. A slow write
+    /// then delays reading from the socket and acknowledging publications.
+    /// With a dedicated writer, the event loop only queues transmits. Transmits that queue
+    /// up while the writer is busy are coalesced into a single write. Once a few writes
+    /// queued up, the event loop waits for the writer, so a slow socket still slows down
+    /// the handles.
+    ///
+    /// The writer is part of the future returned by [`Client::spawn()`], no extra task is spawned.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// let client = Client::new(Connect::builder().build(), stream).dedicated_writer();
+    /// let (handle, task) = client.spawn();
+    /// # });
+    /// ```
+    pub fn dedicated_writer(mut self) -> Self {
+        self.dedicated_writer = true;
+        self
+    }
+
+    /// Configure who receives the publications of the broker. See [`DeliveryMode`].
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use futures::StreamExt;
+    /// # use tjiftjaf::{subscribe, Connect, aio::{Client, DeliveryMode, Emit}};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// let client = Client::new(Connect::builder().build(), stream)
+    ///     .delivery_mode(DeliveryMode::Broadcast);
+    /// let (handle, task) = client.spawn();
+    ///
+    /// // Both the logger and the dashboard receive every publication.
+    /// let mut logger = handle.broadcast().await.unwrap();
+    /// let mut dashboard = handle.broadcast().await.unwrap();
+    /// subscribe("sensor/#").emit(&handle).await.unwrap();
+    /// # });
+    /// ```
+    pub fn delivery_mode(mut self, mode: DeliveryMode) -> Self {
+        self.delivery_mode = mode;
+        self
+    }
+
+    /// Yield to the executor once the event loop used up `budget`. See [`Budget`].
+    ///
+    /// By default, the event loop only yields when it waits for the socket, a handle or
+    /// a timer. A flood of publications from the broker then keeps it busy, and starves
+    /// the other tasks on the same executor thread.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::{Budget, Client}};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// let client = Client::new(Connect::builder().build(), stream)
+    ///     .budget(Budget::default().max_decoded(16));
+    /// let (handle, task) = client.spawn();
+    /// # });
+    /// ```
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Configure what happens when the broker sends a frame that can't be decoded.
+    ///
+    /// By default, the client terminates the connection and the future returned
+    /// by [`Client::spawn()`] resolves to an error of kind [`std::io::ErrorKind::InvalidData`].
+    pub fn decode_error_policy(mut self, policy: DecodeErrorPolicy) -> Self {
+        self.binding.set_decode_error_policy(policy);
+        self
+    }
+
+    /// Terminate the connection when the broker sends a packet larger than `size` bytes.
+    ///
+    /// See [`MqttBinding::set_max_packet_size()`].
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.binding.set_max_packet_size(size);
+        self
+    }
+
+    /// Fail the [`DeliveryToken`]s of publications the broker didn't acknowledge
+    /// within `timeout` with [`HandleError::Timeout`].
+    ///
+    /// See [`MqttBinding::set_acknowledgement_timeout()`].
+    pub fn acknowledgement_timeout(mut self, timeout: Duration) -> Self {
+        self.binding.set_acknowledgement_timeout(timeout);
+        self
+    }
+
+    /// Tolerate a broker that deviates from the specification in the ways of `quirks`.
+    ///
+    /// See [`MqttBinding::set_quirks()`].
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.binding.set_quirks(quirks);
+        self
+    }
+
+    /// Limit the number of publications with QoS 1 or 2 that wait for an acknowledgement
+    /// of the broker.
+    ///
+    /// See [`MqttBinding::set_max_inflight()`].
+    pub fn max_inflight(mut self, limit: usize) -> Self {
+        self.binding.set_max_inflight(limit);
+        self
+    }
+
+    /// Limit the number of publications that wait for the application, before the client
+    /// throttles the broker.
+    ///
+    /// See [`MqttBinding::set_max_buffered_publications()`].
+    pub fn max_buffered_publications(mut self, limit: usize) -> Self {
+        self.binding.set_max_buffered_publications(limit);
+        self
+    }
+
+    /// Probe the broker after it was silent for `interval`, and terminate the connection
+    /// when it doesn't answer within `timeout`. This detects half-open connections quickly.
+    ///
+    /// See [`MqttBinding::set_probe()`].
+    pub fn probe(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.binding.set_probe(interval, timeout);
+        self
+    }
+
+    /// Terminate the connection when the broker doesn't answer the CONNECT within `timeout`.
+    /// The task of the client then resolves with an error of kind `TimedOut`,
+    /// that wraps a [`ConnectError`].
+    ///
+    /// See [`MqttBinding::set_connect_timeout()`].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.binding.set_connect_timeout(timeout);
+        self
+    }
+
+    /// Offer the broker to compress publications. Only a tjiftjaf server accepts the offer.
+    ///
+    /// See [`MqttBinding::set_compression()`].
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: crate::compression::Compression) -> Self {
+        self.binding.set_compression(compression);
+        self
+    }
+
+    /// Terminate the connection when the broker doesn't answer a PINGREQ within `grace`.
+    /// The task of the client then resolves with an error of kind `TimedOut`.
+    ///
+    /// See [`MqttBinding::set_keep_alive_timeout()`].
+    pub fn keep_alive_timeout(mut self, grace: Duration) -> Self {
+        self.binding.set_keep_alive_timeout(grace);
+        self
+    }
+
+    /// Drop redeliveries of QoS 1 publications that arrive within `window` of the original.
+    /// This is best-effort.
+    ///
+    /// See [`MqttBinding::set_deduplication_window()`].
+    pub fn deduplication_window(mut self, window: Duration) -> Self {
+        self.binding.set_deduplication_window(window);
+        self
+    }
+
+    /// Keep the last `capacity` publications received since the client connected, so
+    /// subscriptions created later can replay them.
+    ///
+    /// Components that start late in the life of an application would otherwise miss
+    /// the state published at startup. See [`ClientHandle::subscribe_stream_with_replay()`].
+    /// By default, no publications are kept.
+    pub fn replay_buffer(mut self, capacity: usize) -> Self {
+        self.binding.set_max_replay(capacity);
+        self
+    }
+
+    /// Rewrite the topics exchanged with the server. See [`TopicRewrite`].
+    pub fn topic_rewrite(mut self, rewrite: TopicRewrite) -> Self {
+        self.binding.add_topic_rewrite(rewrite);
+        self
+    }
+
+    /// Save the [`Session`] to `store` whenever it changes.
+    ///
+    /// See [`MqttBinding::set_session_store()`].
+    pub fn session_store(mut self, store: impl SessionStore + Send + 'static) -> Self {
+        self.binding.set_session_store(store);
+        self
+    }
+
+    /// Inspect, modify or drop the packets exchanged with the broker.
+    ///
+    /// See [`MqttBinding::add_interceptor()`].
+    pub fn interceptor(mut self, interceptor: impl Interceptor + Send + 'static) -> Self {
+        self.binding.add_interceptor(interceptor);
+        self
+    }
+
     /// Spawn an event loop that operates on the socket.
     pub fn spawn(
         self,
     ) -> (
         ClientHandle,
         impl std::future::Future<Output = Result<(), std::io::Error>>,
-    ) {
+    )
+    where
+        T::Delay: Send + 'static,
+    {
         // TODO: GH-83 decide on capacity of channel.
         // For communication _to_ the handler.
         let (to_tx, to_rx) = async_channel::bounded(100);
         // For communication _from_ the handler.
         let (from_tx, from_rx) = async_channel::bounded(100);
 
+        let termination = Termination::default();
+        let capacity = Capacity::default();
         let handle = ClientHandle {
             sender: from_tx,
             receiver: to_rx,
+            termination: termination.clone(),
+            capacity: capacity.clone(),
+            sleep: sleep::erase::<T>(),
+            delivery_mode: self.delivery_mode,
         };
-        (handle, self.run(to_tx, from_rx))
+        (handle, self.run(to_tx, from_rx, termination, capacity))
     }
 
     async fn run(
-        mut self,
+        self,
         sender: Sender<Packet>,
-        receiver: Receiver<Packet>,
+        receiver: Receiver<Command>,
+        termination: Termination,
+        capacity: Capacity,
     ) -> Result<(), std::io::Error> {
-        let mut socket = core::pin::pin!(self.socket);
-
-        // In this loop, check with the binding if any outbound
-        // packets are waiting. We call them 'transmits'. Send all pending
-        // transmits to the broker.
-        //
-        // When done, request a read buffer, read bytes from the broker until
-        // the buffer is full. Then, request the binding to decode the buffer.
-        // This operation might yield a mqtt::Packet for further processing.
+        let commands = Queue { receiver, capacity };
+        let (reader, writer) = self.socket.split();
+
+        // The binding and the channels outlive the event loop. That way, the handles learn
+        // that the client terminated before they observe the channels closing.
+        let mut binding = self.binding;
+        let result = if self.dedicated_writer {
+            let (queue, transmits) = async_channel::bounded(MAX_QUEUED_WRITES);
+            futures::future::try_join(
+                event_loop::<S, T>(
+                    &mut binding,
+                    reader,
+                    Writer::Queue(queue),
+                    &sender,
+                    &commands,
+                    self.delivery_mode,
+                    self.budget,
+                ),
+                write(writer, transmits),
+            )
+            .await
+            .map(|_| ())
+        } else {
+            let writer = Writer::Inline(writer);
+            event_loop::<S, T>(
+                &mut binding,
+                reader,
+                writer,
+                &sender,
+                &commands,
+                self.delivery_mode,
+                self.budget,
+            )
+            .await
+        };
+
+        termination.terminate();
+        result
+    }
+}
+
+impl Client<Async<TcpStream>> {
+    /// Connect to the broker at `addr`. That's anything that resolves to socket addresses,
+    /// like `"localhost:1883"`, `"[::1]:1883"`, `("broker.local", 1883)` or a `SocketAddr`.
+    ///
+    /// The address is resolved on a thread pool, so a slow DNS lookup doesn't block the
+    /// executor. The addresses are tried in order, until one accepts the connection. The
+    /// duration of the TCP connect is recorded in the [`Statistics`]. For a client of tokio,
+    /// use `aio::tokio::Client::connect()` instead.
+    pub async fn connect_tcp(
+        addr: impl ToSocketAddrs + Send + 'static,
+        connect: Connect,
+    ) -> Result<Self, std::io::Error> {
+        let (socket, connect_duration) = open_tcp(addr).await?;
+        let mut client = Self::new(connect, socket);
+        client.binding.statistics.connect_duration = Some(connect_duration);
+        Ok(client)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Client<TlsStream<Async<TcpStream>>> {
+    /// Connect to the broker at `addr`, formatted as `host:port`, and encrypt the connection with TLS.
+    /// Enclose an IPv6 address in brackets, like `[::1]:8883`.
+    ///
+    /// The host is used to verify the certificate of the broker. The TLS handshake completes
+    /// before this method returns. See [`crate::tls`] for an example of a `ClientConfig`.
+    ///
+    /// The durations of the TCP connect and of the handshake are recorded in the [`Statistics`].
+    pub async fn connect_tls(
+        addr: &str,
+        connect: Connect,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self, std::io::Error> {
+        let connection = crate::tls::client_connection(addr, config)?;
+        let (socket, connect_duration) = open_tcp(addr.to_owned()).await?;
+
+        let connected = Instant::now();
+        let mut socket = TlsStream::new(socket, connection);
+        socket.handshake().await?;
+        let handshake_duration = connected.elapsed();
+        let resumed = crate::tls::resumed(socket.connection());
+
+        let mut client = Self::new(connect, socket);
+        let statistics = &mut client.binding.statistics;
+        statistics.connect_duration = Some(connect_duration);
+        statistics.tls_handshake_duration = Some(handshake_duration);
+        statistics.tls_resumed = resumed;
+        Ok(client)
+    }
+}
+
+// Connect to the first address `addr` resolves to that accepts the connection.
+// Returns the socket and how long connecting to that address took.
+async fn open_tcp(
+    addr: impl ToSocketAddrs + Send + 'static,
+) -> Result<(Async<TcpStream>, Duration), std::io::Error> {
+    // Resolving a host name blocks the thread.
+    let addrs =
+        blocking::unblock(move || addr.to_socket_addrs().map(Iterator::collect::<Vec<_>>)).await?;
+
+    let mut last_error =
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to connect to");
+    for addr in addrs {
+        let start = Instant::now();
+        match Async::<TcpStream>::connect(addr).await {
+            Ok(socket) => return Ok((socket, start.elapsed())),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+async fn event_loop<S: AsyncRead + AsyncWrite, T: Sleep>(
+    binding: &mut MqttBinding,
+    mut reader: ReadHalf<S>,
+    mut writer: Writer<S>,
+    sender: &Sender<Packet>,
+    commands: &Queue,
+    delivery_mode: DeliveryMode,
+    budget: Option<Budget>,
+) -> Result<(), std::io::Error> {
+    // In this loop, check with the binding if any outbound
+    // packets are waiting. We call them 'transmits'. Send all pending
+    // transmits to the broker, coalesced into as few writes as possible.
+    //
+    // When done, request a read buffer, read bytes from the broker until
+    // the buffer is full. Then, request the binding to decode the buffer.
+    // This operation might yield a mqtt::Packet for further processing.
+    //
+    // Decoded publications are queued in `deliveries` until the handle accepts them.
+    // So a handle that lags doesn't stop the loop from acknowledging packets
+    // and emitting keep alives. The binding throttles the broker when too many
+    // publications are queued, see `MqttBinding::set_max_buffered_publications()`.
+    //
+    // With a budget, the loop yields to the executor once it decoded or wrote enough
+    // packets. The transmits that exceed the budget are written after yielding.
+    let mut deliveries = VecDeque::new();
+    let mut spent = Spent::new(binding);
+    loop {
+        if budget.is_some_and(|budget| spent.exceeds(budget, binding)) {
+            futures_lite::future::yield_now().await;
+            spent = Spent::new(binding);
+        }
+
+        commands.apply_pending(binding);
+
+        deliver(sender, &mut deliveries, binding)?;
+
         loop {
-            while let Ok(packet) = receiver.try_recv() {
-                self.binding.send(packet);
+            if budget.is_some_and(|budget| spent.exceeds(budget, binding)) {
+                break;
             }
-
-            loop {
-                match self.binding.poll_transmits(Instant::now()) {
-                    Ok(Some(bytes)) => {
-                        socket.write_all(&bytes).await?;
-                        // If the socket implementation is buffered, `bytes` will not be transmitted unless
-                        // the internal buffer is full or a call to flush is done.
-                        socket.flush().await?;
-                    }
-                    Ok(None) => break,
-                    Err(_) => {
-                        socket.close().await?;
-                        info!("The client disconnected.");
-                        return Ok(());
-                    }
+            match poll_transmits_batch(binding, spent.writes_left(budget, binding)) {
+                Ok(Some(bytes)) => {
+                    writer.write(bytes).await?;
+                }
+                Ok(None) => break,
+                Err(reason) => {
+                    writer.close().await?;
+                    return match reason {
+                        ClientDisconnected::Requested => {
+                            info!(target: target::AIO, "The client disconnected.");
+                            Ok(())
+                        }
+                        ClientDisconnected::Refused(connack) => Err(std::io::Error::new(
+                            std::io::ErrorKind::ConnectionRefused,
+                            ConnectError::Refused(connack.return_code()),
+                        )),
+                        ClientDisconnected::ConnectTimeout => Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            ConnectError::Timeout,
+                        )),
+                        ClientDisconnected::ProtocolError(_) => Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            reason.to_string(),
+                        )),
+                        ClientDisconnected::Unresponsive | ClientDisconnected::KeepAliveTimeout => {
+                            Err(std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                reason.to_string(),
+                            ))
+                        }
+                    };
                 }
             }
+        }
 
-            let timeout = self.binding.poll_timeout();
-            let mut buffer = self.binding.get_read_buffer();
+        // Yield before writing the rest of the transmits.
+        if budget.is_some_and(|budget| spent.exceeds(budget, binding)) {
+            continue;
+        }
 
-            futures::select! {
-                bytes_read = socket.read(&mut buffer).fuse() => {
-                    let bytes_read = bytes_read?;
+        let timeout = binding.poll_timeout(Instant::now());
+        let mut buffer = binding.get_read_buffer();
 
-                    if bytes_read == 0 {
-                        error!("Packet empty, reconnecting!");
-                        return Err(std::io::Error::other("Packet is empty"));
-                    }
+        // Stop accepting commands while the queue of the binding is full.
+        let has_capacity = binding.has_capacity();
+        let command = async {
+            if !has_capacity {
+                return futures::future::pending().await;
+            }
+            commands.receiver.recv().await
+        };
 
-                    trace!(
-                        "Received {bytes_read} bytes for a buffer of {}",
-                        buffer.len()
-                    );
-
-                    if let Some(packet) = self
-                        .binding
-                        .try_decode(buffer[0..bytes_read].to_vec(), Instant::now())
-                    {
-                        if let Packet::Publish(publish) = &packet {
-                            match (publish.qos(), publish.packet_identifier()) {
-                                (QoS::AtMostOnceDelivery, _) => {}
-                                (QoS::AtLeastOnceDelivery, Some(packet_identifier)) => {
-                                    self.binding.send(PubAck::new(packet_identifier).into());
-                                }
-                                (QoS::ExactlyOnceDelivery, Some(packet_identifier)) => {
-                                    self.binding.send(PubRec::new(packet_identifier).into());
-                                }
-                                (qos, maybe_packet_identifier) => {
-                                    panic!(
-                                        "Somehow this PUBLISH packet has {qos:?} and {maybe_packet_identifier:?}. That combination is not allowed and the tjiftjaf crate must not allow to create such packet. Please report a bug to https://github.com/eastern-oak/tjiftjaf/issues. {packet:?} "
-                                    )
-                                }
-                            }
-                        };
+        // Stop reading when too many packets are waiting for their receivers.
+        let read = async {
+            if deliveries.len() >= MAX_PENDING_DELIVERIES {
+                return futures::future::pending().await;
+            }
+            reader.read(&mut buffer).await
+        };
 
-                        if let Packet::PubRec(packet) = &packet {
-                            self.binding
-                                .send(PubRel::new(packet.packet_identifier()).into());
-                        }
+        // Wait until the receiver has capacity for the next delivery.
+        let next_delivery = deliveries
+            .front()
+            .map(|delivery| (delivery.receiver.clone(), delivery.packet.clone()));
+        let delivered = async {
+            match next_delivery {
+                Some((receiver, packet)) => receiver.send(packet).await.map_err(|_| receiver),
+                None => futures::future::pending().await,
+            }
+        };
 
-                        if let Packet::PubRel(packet) = &packet {
-                            self.binding
-                                .send(PubComp::new(packet.packet_identifier()).into());
-                        }
+        futures::select! {
+            bytes_read = read.fuse() => {
+                let bytes_read = bytes_read?;
+
+                if bytes_read == 0 {
+                    error!(target: target::AIO, "Packet empty, reconnecting!");
+                    return Err(std::io::Error::other("Packet is empty"));
+                }
+
+                trace!(target: target::AIO,
+                    "Received {bytes_read} bytes for a buffer of {}",
+                    buffer.len()
+                );
 
-                        if sender.send(packet).await.is_err() {
-                            // TODO: Change error type. std::io::Error is not really fitting here.
-                            return Err(std::io::Error::other("Failed to send message to handler"));
+                // The binding acknowledges the packets it decodes.
+                if let Some(packet) = binding
+                    .try_decode(buffer[0..bytes_read].to_vec(), Instant::now())
+                {
+                    let is_publication = matches!(packet, Packet::Publish(_));
+                    let mut routes = match &packet {
+                        Packet::Publish(publish) => {
+                            binding.remember(publish);
+                            match delivery_mode {
+                                DeliveryMode::Router => binding.routes(publish),
+                                DeliveryMode::Broadcast => binding.broadcasts(),
+                                DeliveryMode::SingleConsumer => vec![],
+                            }
                         }
+                        _ => vec![],
+                    };
+                    // The binding handled the acknowledgements and the other packets
+                    // already. Only publications reach the handle and the subscriptions,
+                    // so a handle that isn't drained doesn't hold up the acknowledgements.
+                    if is_publication && routes.is_empty() {
+                        routes.push(sender.clone());
+                    }
+
+                    // Delivering the last copy of a publication frees up room in the binding.
+                    let copies = routes.len();
+                    for (index, receiver) in routes.into_iter().enumerate() {
+                        deliveries.push_back(Delivery {
+                            receiver,
+                            packet: packet.clone(),
+                            releases_quota: is_publication && index + 1 == copies,
+                        });
+                    }
+
+                    // The raw packet streams receive a copy too, without affecting the quota.
+                    #[cfg(feature = "experimental")]
+                    for receiver in binding.raw_packets() {
+                        deliveries.push_back(Delivery {
+                            receiver,
+                            packet: packet.clone(),
+                            releases_quota: false,
+                        });
                     }
-                },
-                _ = Timer::at(timeout).fuse() => {
-                    self.binding.handle_timeout(Instant::now());
                 }
-                packet = receiver.recv().fuse() => {
-                    match packet {
-                        Ok(packet) => self.binding.send(packet),
-                        Err(_) => {
-                            return Err(std::io::Error::other("Failed to read message from channel"));
-                        }
+            },
+            result = delivered.fuse() => {
+                if let Err(receiver) = result {
+                    if receiver.same_channel(sender) {
+                        // TODO: Change error type. std::io::Error is not really fitting here.
+                        return Err(std::io::Error::other("Failed to send message to handler"));
+                    }
+                }
+                if let Some(delivery) = deliveries.pop_front() {
+                    if delivery.releases_quota {
+                        binding.publication_delivered();
+                    }
+                }
+            }
+            _ = T::sleep_until(timeout).fuse() => {
+                binding.handle_timeout(Instant::now());
+            }
+            command = command.fuse() => {
+                match command {
+                    Ok(command) => command.apply(binding),
+                    Err(_) => {
+                        return Err(std::io::Error::other("Failed to read message from channel"));
                     }
                 }
-            };
+            }
+        };
+    }
+}
+
+/// Who receives the publications of the broker. See [`Client::delivery_mode()`].
+///
+/// In every mode, a publication that has no other receiver is returned by
+/// [`ClientHandle::subscriptions()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Deliver a publication to every [`Subscription`] obtained with
+    /// [`ClientHandle::subscribe_stream()`] whose topic filter matches.
+    #[default]
+    Router,
+
+    /// Deliver every publication to every [`Subscription`] obtained with
+    /// [`ClientHandle::broadcast()`]. Independent parts of an application can each receive
+    /// all publications, without agreeing on topic filters. The slowest `Subscription`
+    /// sets the pace for all of them.
+    Broadcast,
+
+    /// Deliver every publication to [`ClientHandle::subscriptions()`].
+    SingleConsumer,
+}
+
+/// How many packets the event loop of a [`Client`] processes before it yields to
+/// the executor. See [`Client::budget()`].
+///
+/// The event loop yields once it decoded `max_decoded` packets or wrote `max_written`
+/// packets since it last yielded. Both default to 64.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Budget {
+    max_decoded: usize,
+    max_written: usize,
+}
+
+impl Budget {
+    /// Yield after decoding `packets` packets.
+    pub fn max_decoded(mut self, packets: usize) -> Self {
+        self.max_decoded = packets.max(1);
+        self
+    }
+
+    /// Yield after writing `packets` packets.
+    pub fn max_written(mut self, packets: usize) -> Self {
+        self.max_written = packets.max(1);
+        self
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            max_decoded: 64,
+            max_written: 64,
+        }
+    }
+}
+
+// The packets the event loop decoded and wrote since it last yielded, counted from
+// the statistics of the binding.
+struct Spent {
+    packets_read: usize,
+    packets_sent: usize,
+}
+
+impl Spent {
+    fn new(binding: &MqttBinding) -> Self {
+        Self {
+            packets_read: binding.statistics.packets_read,
+            packets_sent: binding.statistics.packets_sent,
+        }
+    }
+
+    fn exceeds(&self, budget: Budget, binding: &MqttBinding) -> bool {
+        let statistics = &binding.statistics;
+        statistics.packets_read.saturating_sub(self.packets_read) >= budget.max_decoded
+            || statistics.packets_sent.saturating_sub(self.packets_sent) >= budget.max_written
+    }
+
+    // The packets that may still be written before the event loop yields.
+    fn writes_left(&self, budget: Option<Budget>, binding: &MqttBinding) -> usize {
+        let Some(budget) = budget else {
+            return usize::MAX;
+        };
+        let written = binding
+            .statistics
+            .packets_sent
+            .saturating_sub(self.packets_sent);
+        budget.max_written.saturating_sub(written)
+    }
+}
+
+// A decoded packet waiting for its receiver.
+struct Delivery {
+    // Either the handle, or a `Subscription`.
+    receiver: Sender<Packet>,
+    packet: Packet,
+
+    // Whether delivering the packet must be reported with `MqttBinding::publication_delivered()`.
+    releases_quota: bool,
+}
+
+// Like `MqttBinding::poll_transmits_batch()`, but stop once the batch holds
+// `max_packets` packets. That way, a batch never writes past the budget.
+fn poll_transmits_batch(
+    binding: &mut MqttBinding,
+    max_packets: usize,
+) -> Result<Option<Vec<u8>>, ClientDisconnected> {
+    let now = Instant::now();
+    let Some(mut batch) = binding.poll_transmits(now)? else {
+        return Ok(None);
+    };
+    let mut packets = 1;
+    while batch.len() < MAX_BATCH && packets < max_packets {
+        match binding.poll_transmits(now) {
+            Ok(Some(bytes)) => batch.extend_from_slice(&bytes),
+            // The next call returns the `Err()` again.
+            Ok(None) | Err(_) => break,
+        }
+        packets += 1;
+    }
+    Ok(Some(batch))
+}
+
+// Pass queued packets to their receivers, as long as they have capacity.
+fn deliver(
+    sender: &Sender<Packet>,
+    deliveries: &mut VecDeque<Delivery>,
+    binding: &mut MqttBinding,
+) -> Result<(), std::io::Error> {
+    while let Some(delivery) = deliveries.pop_front() {
+        match delivery.receiver.try_send(delivery.packet) {
+            Ok(()) => {}
+            Err(TrySendError::Full(packet)) => {
+                deliveries.push_front(Delivery { packet, ..delivery });
+                break;
+            }
+            // A dropped `Subscription` is not an error.
+            Err(TrySendError::Closed(_)) if !delivery.receiver.same_channel(sender) => {}
+            Err(TrySendError::Closed(_)) => {
+                // TODO: Change error type. std::io::Error is not really fitting here.
+                return Err(std::io::Error::other("Failed to send message to handler"));
+            }
+        }
+
+        if delivery.releases_quota {
+            binding.publication_delivered();
+        }
+    }
+    Ok(())
+}
+
+// Destination of the transmits of the event loop.
+enum Writer<S> {
+    // The event loop writes to the socket itself.
+    Inline(WriteHalf<S>),
+
+    // The event loop queues the transmits for `write()`.
+    Queue(Sender<Vec<u8>>),
+}
+
+impl<S: AsyncWrite> Writer<S> {
+    async fn write(&mut self, bytes: Vec<u8>) -> Result<(), std::io::Error> {
+        match self {
+            Writer::Inline(socket) => {
+                socket.write_all(&bytes).await?;
+                // If the socket implementation is buffered, `bytes` will not be transmitted unless
+                // the internal buffer is full or a call to flush is done.
+                socket.flush().await
+            }
+            Writer::Queue(queue) => queue
+                .send(bytes)
+                .await
+                .map_err(|_| std::io::Error::other("The writer stopped")),
+        }
+    }
+
+    async fn close(self) -> Result<(), std::io::Error> {
+        match self {
+            Writer::Inline(mut socket) => socket.close().await,
+            // Dropping the queue stops `write()` after it wrote all transmits.
+            // `write()` closes the socket.
+            Writer::Queue(_) => Ok(()),
+        }
+    }
+}
+
+// Write the transmits queued by the event loop to the socket. Transmits that
+// queue up while a write is in progress are coalesced into a single write.
+async fn write<S: AsyncWrite>(
+    mut socket: WriteHalf<S>,
+    transmits: Receiver<Vec<u8>>,
+) -> Result<(), std::io::Error> {
+    while let Ok(mut bytes) = transmits.recv().await {
+        while let Ok(mut next) = transmits.try_recv() {
+            bytes.append(&mut next);
         }
+
+        socket.write_all(&bytes).await?;
+        socket.flush().await?;
     }
+
+    socket.close().await
 }
 
 /// A handle to interact with a [`Client`].
 ///
 /// See the [module documentation](crate::aio) for more information.
 pub struct ClientHandle {
-    // Send packets to the `Client`.
-    sender: Sender<Packet>,
+    // Send commands to the `Client`.
+    sender: Sender<Command>,
 
     // Receive packets from the `Client`
     receiver: Receiver<Packet>,
+
+    termination: Termination,
+
+    // Wakes the `PublishService`s once the `Client` made room for commands.
+    capacity: Capacity,
+
+    // The timer of the `Client`, for `DeliveryToken::wait_timeout()` and the like.
+    sleep: SleepFn,
+
+    delivery_mode: DeliveryMode,
 }
 
 impl ClientHandle {
-    pub(crate) async fn send(&self, packet: Packet) -> Result<(), SendError<Packet>> {
-        self.sender.send(packet).await
+    pub(crate) async fn send(&self, packet: Packet) -> Result<(), HandleError> {
+        self.command(Command::Packet(packet)).await
+    }
+
+    // Send a command to the `Client`.
+    async fn command(&self, command: Command) -> Result<(), HandleError> {
+        self.sender
+            .send(command)
+            .await
+            .map_err(|_| self.termination.error())
+    }
+
+    // Wait for the reply of the `Client` to a command.
+    async fn reply<T>(&self, reply: Receiver<T>) -> Result<T, HandleError> {
+        reply.recv().await.map_err(|_| self.termination.error())
     }
 
     /// Wait for the next [`Publish`] messages emitted by the broker.
     ///
+    /// A publication with QoS 2 is returned once, even if the broker retransmits it
+    /// before the handshake completes.
+    ///
     /// ```no_run
     /// # use async_net::TcpStream;
     /// # use futures_lite::FutureExt;
@@ -237,19 +980,818 @@ impl ClientHandle {
     /// }
     /// # });
     /// ```
-    pub async fn subscriptions(&mut self) -> Result<Publish, ConnectionError> {
+    pub async fn subscriptions(&mut self) -> Result<Publish, HandleError> {
         loop {
-            let packet = self.receiver.recv().await?;
+            let packet = self
+                .receiver
+                .recv()
+                .await
+                .map_err(|_| self.termination.error())?;
             if let Packet::Publish(publish) = packet {
                 return Ok(publish);
             }
         }
     }
 
-    /// Emit a [`Disconnect`] to terminate the connection.
-    pub async fn disconnect(self) -> Result<(), ConnectionError> {
-        self.send(Disconnect.into()).await?;
-        Ok(())
+    /// Subscribe to `topic_filter` and return a [`Subscription`] that yields the matching
+    /// publications. The filter may contain wildcards.
+    ///
+    /// Like [`ClientHandle::subscribe()`], this waits until the broker acknowledged the
+    /// subscription. It fails with [`SubscribeError::Rejected`] if the broker refused it.
+    ///
+    /// Publications delivered to a `Subscription` are _not_ yielded by
+    /// [`ClientHandle::subscriptions()`]. If several subscriptions match a publication,
+    /// each receives a copy. Dropping the `Subscription` stops the delivery, but does not
+    /// unsubscribe from the broker. For that, see [`ClientHandle::subscribe_stream_with_lease()`].
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use futures::StreamExt;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// let mut temperatures = handle.subscribe_stream("sensor/+/temperature").await.unwrap();
+    /// while let Some(publish) = temperatures.next().await {
+    ///     println!("{}: {:?}", publish.topic(), publish.payload());
+    /// }
+    /// # });
+    /// ```
+    pub async fn subscribe_stream(
+        &self,
+        topic_filter: &str,
+    ) -> Result<Subscription, SubscribeError> {
+        self.subscribe_stream_with_replay(topic_filter, 0).await
+    }
+
+    /// Like [`ClientHandle::subscribe_stream()`], but the [`Subscription`] first yields
+    /// up to `replay` recent publications that match `topic_filter`, oldest first.
+    /// At most 10 000 publications are replayed.
+    ///
+    /// The publications come from the buffer configured with [`Client::replay_buffer()`].
+    /// It only holds publications received since the client connected. A retained
+    /// publication might be yielded twice: once from the buffer, and once when
+    /// the broker answers the subscription.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use futures::StreamExt;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// let client = Client::new(connect, stream).replay_buffer(100);
+    /// let (handle, task) = client.spawn();
+    ///
+    /// // Later, catch up on the configuration that was published at startup.
+    /// let mut config = handle.subscribe_stream_with_replay("config/#", 10).await.unwrap();
+    /// while let Some(publish) = config.next().await {
+    ///     println!("{}: {:?}", publish.topic(), publish.payload());
+    /// }
+    /// # });
+    /// ```
+    pub async fn subscribe_stream_with_replay(
+        &self,
+        topic_filter: &str,
+        replay: usize,
+    ) -> Result<Subscription, SubscribeError> {
+        self.route(topic_filter, replay, None).await
+    }
+
+    /// Like [`ClientHandle::subscribe_stream()`], but unsubscribe from the broker once the
+    /// application stops consuming the [`Subscription`].
+    ///
+    /// The lease expires when the application doesn't poll the `Subscription` for `lease`,
+    /// or within `lease` after it dropped the `Subscription`. Waiting on the `Subscription`
+    /// counts as polling it, however long it takes for a publication to arrive. Once the
+    /// lease expired, the client unsubscribes from `topic_filter` and the stream ends.
+    /// That's useful for views of data that are opened on demand, like in a user interface.
+    ///
+    /// The client doesn't unsubscribe while another `Subscription` of the same topic filter
+    /// exists.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use futures::StreamExt;
+    /// # use std::time::Duration;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// let mut view = handle
+    ///     .subscribe_stream_with_lease("camera/1/frames", Duration::from_secs(30))
+    ///     .await
+    ///     .unwrap();
+    /// let frame = view.next().await;
+    ///
+    /// // The view is closed, so `view` is never polled again. After 30 seconds,
+    /// // the client unsubscribes from the frames.
+    /// # });
+    /// ```
+    pub async fn subscribe_stream_with_lease(
+        &self,
+        topic_filter: &str,
+        lease: Duration,
+    ) -> Result<Subscription, SubscribeError> {
+        self.route(topic_filter, 0, Some(Lease::new(lease))).await
+    }
+
+    // Route the publications matching `topic_filter` to a new `Subscription`, subscribe,
+    // and wait until the broker accepted the subscription.
+    async fn route(
+        &self,
+        topic_filter: &str,
+        replay: usize,
+        lease: Option<Lease>,
+    ) -> Result<Subscription, SubscribeError> {
+        let subscribe = Subscribe::builder(topic_filter, QoS::AtMostOnceDelivery).try_build()?;
+
+        // TODO: GH-83 decide on capacity of channel.
+        // The replayed publications must fit in the channel.
+        let replay = replay.min(MAX_REPLAY);
+        let (sender, receiver) = async_channel::bounded(100usize.saturating_add(replay));
+
+        // Register the route before subscribing. Otherwise, the first publications,
+        // like retained messages, might arrive before the route exists.
+        let route = Command::Route(topic_filter.to_string(), sender, replay, lease.clone());
+        self.command(route).await?;
+
+        // Waiting for the SUBACK counts as polling the `Subscription`. If the broker
+        // rejects the subscription, dropping `receiver` removes the route again.
+        if let Some(lease) = &lease {
+            lease.polled(true);
+        }
+        self.subscribe(subscribe).await?;
+        if let Some(lease) = &lease {
+            lease.polled(false);
+        }
+
+        Ok(Subscription {
+            receiver: Box::pin(receiver),
+            lease,
+        })
+    }
+
+    /// Returns a [`Subscription`] that yields every publication of the broker, once the
+    /// client uses [`DeliveryMode::Broadcast`].
+    ///
+    /// Each `Subscription` receives its own copy of the publications, starting with the
+    /// first publication after this call. Unlike [`ClientHandle::subscribe_stream()`], this
+    /// doesn't subscribe to a topic. See [`Client::delivery_mode()`] for an example.
+    ///
+    /// Fails with [`HandleError::Unsupported`] if the client uses another [`DeliveryMode`].
+    /// The `Subscription` would never yield a publication.
+    pub async fn broadcast(&self) -> Result<Subscription, HandleError> {
+        if self.delivery_mode != DeliveryMode::Broadcast {
+            return Err(HandleError::Unsupported);
+        }
+
+        // TODO: GH-83 decide on capacity of channel.
+        let (sender, receiver) = async_channel::bounded(100);
+        self.command(Command::Broadcast(sender)).await?;
+        Ok(Subscription {
+            receiver: Box::pin(receiver),
+            lease: None,
+        })
+    }
+
+    /// Returns a [`Stream`] of every [`Packet`] emitted by the broker.
+    ///
+    /// Unlike [`ClientHandle::subscriptions()`], this stream yields all packets, like
+    /// [`ConnAck`](crate::ConnAck), [`SubAck`](crate::SubAck) and [`PingResp`](crate::PingResp).
+    /// That is useful for protocol tooling, like analyzers and conformance testers.
+    ///
+    /// The stream receives a copy of each packet, starting with the first packet after this
+    /// call. Packets are still delivered to [`ClientHandle::subscriptions()`] and the other
+    /// [`Subscription`]s as usual. Like those, a stream that isn't consumed eventually
+    /// stops the client from reading.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use futures::StreamExt;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// let mut packets = handle.raw_packets().await.unwrap();
+    /// while let Some(packet) = packets.next().await {
+    ///     println!("{packet:?}");
+    /// }
+    /// # });
+    /// ```
+    #[cfg(feature = "experimental")]
+    pub async fn raw_packets(
+        &self,
+    ) -> Result<impl futures::Stream<Item = Packet> + Unpin, HandleError> {
+        // TODO: GH-83 decide on capacity of channel.
+        let (sender, receiver) = async_channel::bounded(100);
+        self.command(Command::RawPackets(sender)).await?;
+        Ok(Box::pin(receiver))
+    }
+
+    /// Capture the internal state of the [`Client`].
+    ///
+    /// Use it to diagnose issues, like a client that stopped receiving messages.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// let snapshot = handle.debug_snapshot().await.unwrap();
+    /// println!("{:?} - subscribed to {:?}", snapshot.connection_status, snapshot.subscriptions);
+    /// # });
+    /// ```
+    pub async fn debug_snapshot(&self) -> Result<Snapshot, HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Snapshot(tx)).await?;
+        self.reply(rx).await
+    }
+
+    /// Retrieve the counters of the traffic between the [`Client`] and the broker.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// let statistics = handle.statistics().await.unwrap();
+    /// println!("Sent {} bytes, received {} bytes", statistics.bytes_sent, statistics.bytes_read);
+    /// # });
+    /// ```
+    pub async fn statistics(&self) -> Result<Statistics, HandleError> {
+        Ok(self.debug_snapshot().await?.statistics)
+    }
+
+    /// Retrieve the most recent keep alives, oldest first. See [`MqttBinding::pings()`].
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// for ping in handle.pings().await.unwrap() {
+    ///     if ping.answered.is_none() {
+    ///         println!("PINGREQ {} due at {:?}, sent at {:?}: no PINGRESP", ping.sequence, ping.scheduled, ping.sent);
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn pings(&self) -> Result<Vec<Ping>, HandleError> {
+        Ok(self.debug_snapshot().await?.pings)
+    }
+
+    /// Terminate the connection, but preserve the [`Session`].
+    ///
+    /// This is useful when the connection must be dropped temporarily, for example
+    /// when a mobile application moves to the background. Use [`Client::resume()`] to continue
+    /// the session on a new connection.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().client_id("tjiftjaf").build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// # let task = smol::spawn(task);
+    /// let session = handle.suspend().await.unwrap();
+    /// task.await.unwrap();
+    ///
+    /// // Later...
+    /// let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// let (handle, task) = Client::resume(session, stream).spawn();
+    /// # });
+    /// ```
+    pub async fn suspend(self) -> Result<Session, HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Suspend(tx)).await?;
+        self.reply(rx).await
+    }
+
+    /// Replace the [`Connect`] of the [`Client`], for example to change the credentials
+    /// or the will. The current connection is unaffected. The [`Session`] returned by
+    /// [`ClientHandle::suspend()`] carries `connect`, so it applies when resuming.
+    ///
+    /// See [`MqttBinding::set_connect()`].
+    pub async fn reconfigure(&self, connect: Connect) -> Result<(), HandleError> {
+        self.command(Command::Reconfigure(connect)).await
+    }
+
+    /// Subscribe to the topics of `subscribe` and wait until the broker acknowledged it.
+    ///
+    /// Unlike [`Emit::emit()`], which returns once the [`Subscribe`] is queued, this
+    /// returns after the [`SubAck`](crate::SubAck) arrives. The returned [`SubscriptionResult`]
+    /// holds the QoS the broker granted per topic, which might be lower than requested.
+    /// If the broker rejects a topic, [`SubscribeError::Rejected`] is returned.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, QoS, Subscribe, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (handle, task) = client.spawn();
+    /// let result = handle
+    ///     .subscribe(Subscribe::builder("sensor/1/#", QoS::ExactlyOnceDelivery).build())
+    ///     .await
+    ///     .unwrap();
+    /// for topic in result.downgraded() {
+    ///     println!("The broker downgraded the QoS of {topic}");
+    /// }
+    /// # });
+    /// ```
+    pub async fn subscribe(
+        &self,
+        subscribe: Subscribe,
+    ) -> Result<SubscriptionResult, SubscribeError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Subscribe(subscribe, tx)).await?;
+        self.reply(rx).await?
+    }
+
+    /// Unsubscribe from the topics of `unsubscribe` and wait until the broker acknowledged it.
+    ///
+    /// Unlike [`Emit::emit()`], which returns once the [`Unsubscribe`] is queued, this
+    /// returns after the [`UnsubAck`](crate::UnsubAck) arrives. After that, the broker
+    /// doesn't send publications for these topics anymore, and the [`Subscription`]s
+    /// of these topic filters have ended.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{unsubscribe_many, Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn();
+    /// handle
+    ///     .unsubscribe(unsubscribe_many(&["sensor/1/#", "sensor/2/#"]).unwrap())
+    ///     .await
+    ///     .unwrap();
+    /// # });
+    /// ```
+    pub async fn unsubscribe(&self, unsubscribe: Unsubscribe) -> Result<(), HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Unsubscribe(unsubscribe, tx)).await?;
+        self.reply(rx).await?
+    }
+
+    /// Retrieve the topic filters the [`Client`] is subscribed to, with the requested QoS.
+    ///
+    /// Unlike the subscriptions of [`ClientHandle::debug_snapshot()`], these include
+    /// subscriptions that are still queued. Pass them to [`ClientHandle::import_subscriptions()`]
+    /// of another client to take over the subscriptions of this one.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (handle, task) = client.spawn();
+    /// for (topic_filter, qos) in handle.subscriptions_snapshot().await.unwrap() {
+    ///     println!("Subscribed to {topic_filter} with {qos:?}");
+    /// }
+    /// # });
+    /// ```
+    pub async fn subscriptions_snapshot(&self) -> Result<Vec<(String, QoS)>, HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Session(tx)).await?;
+        Ok(self.reply(rx).await?.subscriptions().to_vec())
+    }
+
+    /// Subscribe to all topic filters of `subscriptions` with a single [`Subscribe`],
+    /// and wait until the broker acknowledged it. See [`ClientHandle::subscribe()`].
+    ///
+    /// Without subscriptions, nothing is sent and an empty [`SubscriptionResult`] is returned.
+    /// Nor is anything sent if a topic filter is invalid.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let (blue, task) = Client::new(Connect::builder().build(), stream).spawn();
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let (green, task) = Client::new(Connect::builder().build(), stream).spawn();
+    /// let subscriptions = blue.subscriptions_snapshot().await.unwrap();
+    /// green.import_subscriptions(subscriptions).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn import_subscriptions(
+        &self,
+        subscriptions: impl IntoIterator<Item = (String, QoS)>,
+    ) -> Result<SubscriptionResult, SubscribeError> {
+        match subscribe_all(subscriptions)? {
+            Some(subscribe) => self.subscribe(subscribe).await,
+            None => Ok(SubscriptionResult::default()),
+        }
+    }
+
+    /// Publish `publish` and obtain a [`DeliveryToken`] to track its acknowledgement.
+    ///
+    /// Unlike [`Emit::emit()`], the returned token reports when the broker acknowledged
+    /// the publication, and the packet identifier the client assigned to it.
+    /// This returns once the publication is queued.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use std::time::Duration;
+    /// # use tjiftjaf::{Connect, Publish, QoS, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (handle, task) = client.spawn();
+    /// let publish = Publish::builder("sensor/1/temperature", "21.3")
+    ///     .qos(QoS::AtLeastOnceDelivery)
+    ///     .build();
+    /// let mut token = handle.publish(publish).await.unwrap();
+    /// println!("Publishing with packet identifier {:?}", token.packet_identifier());
+    /// token.wait_timeout(Duration::from_secs(5)).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn publish(&self, publish: Publish) -> Result<DeliveryToken, HandleError> {
+        let (queued, packet_identifier) = async_channel::bounded(1);
+        let (reply, acknowledgement) = async_channel::bounded(1);
+        self.command(Command::Publish(publish, queued, reply))
+            .await?;
+
+        Ok(DeliveryToken {
+            packet_identifier: self.reply(packet_identifier).await??,
+            acknowledgement,
+            acknowledged: false,
+            termination: self.termination.clone(),
+            sleep: self.sleep,
+        })
+    }
+
+    /// Publish every publication of `batch` and obtain a [`BatchToken`] to track their
+    /// acknowledgements.
+    ///
+    /// The batch is handed to the [`Client`] at once, and it's queued as a whole: other
+    /// packets don't end up between its publications. The returned token waits for the
+    /// acknowledgements of all publications with QoS 1 or 2. Dropping the token doesn't
+    /// affect the publications.
+    ///
+    /// Fails with [`HandleError::Backpressure`] if the batch doesn't fit
+    /// the queue of the client. See [`MqttBinding::set_max_pending_transmits()`].
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, Publish, QoS, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (handle, task) = client.spawn();
+    /// let batch = (0..100).map(|n| {
+    ///     Publish::builder(format!("sensor/{n}/temperature"), "21.3")
+    ///         .qos(QoS::AtLeastOnceDelivery)
+    ///         .build()
+    /// });
+    /// handle.publish_batch(batch).await.unwrap().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn publish_batch(
+        &self,
+        batch: impl IntoIterator<Item = Publish>,
+    ) -> Result<BatchToken, HandleError> {
+        let batch: Vec<Publish> = batch.into_iter().collect();
+        let (queued, packet_identifiers) = async_channel::bounded(1);
+        let (reply, acknowledgements) = async_channel::bounded(batch.len().max(1));
+        self.command(Command::PublishBatch(batch, queued, reply))
+            .await?;
+
+        let packet_identifiers = self.reply(packet_identifiers).await??;
+        Ok(BatchToken {
+            pending: packet_identifiers.iter().flatten().count(),
+            packet_identifiers,
+            acknowledgements,
+            termination: self.termination.clone(),
+            sleep: self.sleep,
+        })
+    }
+
+    /// Obtain a [`PublishService`] that publishes through this handle.
+    pub fn publish_service(&self) -> PublishService {
+        PublishService::new(
+            self.sender.clone(),
+            self.termination.clone(),
+            self.capacity.clone(),
+        )
+    }
+
+    /// Emit a [`Disconnect`] to terminate the connection.
+    pub async fn disconnect(self) -> Result<(), HandleError> {
+        self.send(Disconnect.into()).await?;
+        Ok(())
+    }
+
+    /// Switch over to the connection of `handle`, for example to rotate credentials
+    /// without an outage.
+    ///
+    /// `handle` must belong to a second [`Client`] that connects with the new credentials.
+    /// It's subscribed to all topics this handle is subscribed to. Once the broker
+    /// acknowledged that, this handle continues with the new connection and the old
+    /// connection is disconnected. If the broker rejects a subscription, this handle
+    /// is left untouched and [`SubscribeError::Rejected`] is returned.
+    ///
+    /// Mind that:
+    /// * The new [`Client`] needs a different client identifier. A broker drops
+    ///   the old connection when a second one connects with the same identifier.
+    /// * Until the old connection is disconnected, publications can arrive twice.
+    /// * Publications received, but not consumed, on the old connection are dropped,
+    ///   and [`Subscription`]s obtained from the old connection end.
+    /// * Everything published before the switch is transmitted on the old connection,
+    ///   but acknowledgements that are still pending might never arrive.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{Connect, aio::Client};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (mut handle, task) = client.spawn();
+    /// let connect = Connect::builder()
+    ///     .client_id("sensor-1-rotated")
+    ///     .username("sensor-1")
+    ///     .password("new-secret")
+    ///     .build();
+    /// let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// let (new_handle, new_task) = Client::new(connect, stream).spawn();
+    /// # let new_task = smol::spawn(new_task);
+    /// handle.rotate_credentials(new_handle).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn rotate_credentials(&mut self, handle: ClientHandle) -> Result<(), SubscribeError> {
+        let subscriptions = self.subscriptions_snapshot().await?;
+        handle.import_subscriptions(subscriptions).await?;
+
+        let old = std::mem::replace(self, handle);
+        // The old connection might have failed already, which is fine.
+        let _ = old.disconnect().await;
+        Ok(())
+    }
+
+    /// Obtain a [`WeakClientHandle`], a reference to the [`Client`] that doesn't keep it running.
+    ///
+    /// The event loop of the [`Client`] terminates once its `ClientHandle` is dropped.
+    /// Registries and caches that hold a `WeakClientHandle` instead don't prevent that.
+    ///
+    /// ```no_run
+    /// # use async_net::TcpStream;
+    /// # use tjiftjaf::{publish, Connect, aio::{Client, Emit}};
+    /// # smol::block_on(async {
+    /// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// let (handle, task) = client.spawn();
+    /// let weak = handle.downgrade();
+    ///
+    /// if let Some(handle) = weak.upgrade() {
+    ///     publish("sensor/1", "26.1").emit(&handle).await.unwrap();
+    /// }
+    ///
+    /// drop(handle);
+    /// let _ = task.await;
+    /// assert!(weak.upgrade().is_none());
+    /// # });
+    /// ```
+    pub fn downgrade(&self) -> WeakClientHandle {
+        WeakClientHandle {
+            sender: self.sender.downgrade(),
+            receiver: self.receiver.downgrade(),
+            termination: self.termination.clone(),
+            capacity: self.capacity.clone(),
+            sleep: self.sleep,
+            delivery_mode: self.delivery_mode,
+        }
+    }
+}
+
+/// A reference to a [`Client`] that doesn't keep it running. It's returned by
+/// [`ClientHandle::downgrade()`].
+#[derive(Clone)]
+pub struct WeakClientHandle {
+    sender: WeakSender<Command>,
+    receiver: WeakReceiver<Packet>,
+    termination: Termination,
+    capacity: Capacity,
+    sleep: SleepFn,
+    delivery_mode: DeliveryMode,
+}
+
+impl WeakClientHandle {
+    /// Obtain a [`ClientHandle`], unless the [`Client`] terminated or all its handles
+    /// were dropped.
+    ///
+    /// The returned handle keeps the `Client` running until it's dropped. It shares the
+    /// publications of [`ClientHandle::subscriptions()`] with the other handles: each
+    /// publication is returned to only one of them.
+    pub fn upgrade(&self) -> Option<ClientHandle> {
+        if self.termination.is_terminated() {
+            return None;
+        }
+        Some(ClientHandle {
+            sender: self.sender.upgrade()?,
+            receiver: self.receiver.upgrade()?,
+            termination: self.termination.clone(),
+            capacity: self.capacity.clone(),
+            sleep: self.sleep,
+            delivery_mode: self.delivery_mode,
+        })
+    }
+}
+
+/// Tracks the acknowledgement of a publication. It's returned by [`ClientHandle::publish()`].
+///
+/// The token is acknowledged when the broker sends a [`PubAck`](crate::PubAck) for QoS 1,
+/// or a [`PubComp`](crate::PubComp) for QoS 2. Publications with QoS 0 are never acknowledged
+/// by the broker, so their token is acknowledged once the publication is queued.
+///
+/// Awaiting the token is equivalent to [`DeliveryToken::wait()`].
+pub struct DeliveryToken {
+    packet_identifier: Option<u16>,
+    acknowledgement: Receiver<Result<(), HandleError>>,
+
+    // Whether the acknowledgement was received already.
+    acknowledged: bool,
+
+    termination: Termination,
+    sleep: SleepFn,
+}
+
+impl DeliveryToken {
+    /// The packet identifier the client assigned to the publication, if its QoS is 1 or 2.
+    ///
+    /// It differs from the identifier of the [`Publish`] if that one was still in use.
+    pub fn packet_identifier(&self) -> Option<u16> {
+        self.packet_identifier
+    }
+
+    /// Wait until the broker acknowledged the publication.
+    ///
+    /// Fails if the [`Client`] terminated before that.
+    pub async fn wait(mut self) -> Result<(), HandleError> {
+        self.acknowledged().await
+    }
+
+    /// Like [`DeliveryToken::wait()`], but give up after `timeout`.
+    ///
+    /// The token remains usable after a timeout, so it's possible to wait again.
+    /// The timeout uses the timer of the [`Client`], see [`Client::timer()`].
+    pub async fn wait_timeout(&mut self, timeout: Duration) -> Result<(), WaitTimeoutError> {
+        let deadline = (self.sleep)(Instant::now() + timeout);
+        futures::select! {
+            result = self.acknowledged().fuse() => {
+                result.map_err(|error| match error {
+                    HandleError::Timeout => WaitTimeoutError::Timeout,
+                    _ => WaitTimeoutError::Disconnected,
+                })
+            }
+            _ = deadline.fuse() => Err(WaitTimeoutError::Timeout),
+        }
+    }
+
+    async fn acknowledged(&mut self) -> Result<(), HandleError> {
+        if !self.acknowledged {
+            self.acknowledgement
+                .recv()
+                .await
+                .map_err(|_| self.termination.error())??;
+            self.acknowledged = true;
+        }
+        Ok(())
+    }
+}
+
+impl IntoFuture for DeliveryToken {
+    type Output = Result<(), HandleError>;
+    type IntoFuture = BoxFuture<'static, Self::Output>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
+
+/// Tracks the acknowledgements of a batch of publications. It's returned by
+/// [`ClientHandle::publish_batch()`].
+///
+/// The token is acknowledged once the broker acknowledged every publication with QoS 1 or 2
+/// of the batch. A batch with only publications with QoS 0 is acknowledged immediately.
+///
+/// Awaiting the token is equivalent to [`BatchToken::wait()`].
+pub struct BatchToken {
+    packet_identifiers: Vec<Option<u16>>,
+    acknowledgements: Receiver<Result<(), HandleError>>,
+
+    // The number of acknowledgements that weren't received yet.
+    pending: usize,
+
+    termination: Termination,
+    sleep: SleepFn,
+}
+
+impl BatchToken {
+    /// The packet identifiers the client assigned to the publications, in the order of
+    /// the batch. Publications with QoS 0 have none.
+    pub fn packet_identifiers(&self) -> &[Option<u16>] {
+        &self.packet_identifiers
+    }
+
+    /// Wait until the broker acknowledged every publication.
+    ///
+    /// Fails if the [`Client`] terminated before that, or if one of the acknowledgements
+    /// timed out. See [`Client::acknowledgement_timeout()`].
+    pub async fn wait(mut self) -> Result<(), HandleError> {
+        self.acknowledged().await
+    }
+
+    /// Like [`BatchToken::wait()`], but give up after `timeout`.
+    ///
+    /// The token remains usable after a timeout, so it's possible to wait again.
+    /// The timeout uses the timer of the [`Client`], see [`Client::timer()`].
+    pub async fn wait_timeout(&mut self, timeout: Duration) -> Result<(), WaitTimeoutError> {
+        let deadline = (self.sleep)(Instant::now() + timeout);
+        futures::select! {
+            result = self.acknowledged().fuse() => {
+                result.map_err(|error| match error {
+                    HandleError::Timeout => WaitTimeoutError::Timeout,
+                    _ => WaitTimeoutError::Disconnected,
+                })
+            }
+            _ = deadline.fuse() => Err(WaitTimeoutError::Timeout),
+        }
+    }
+
+    async fn acknowledged(&mut self) -> Result<(), HandleError> {
+        while self.pending > 0 {
+            self.acknowledgements
+                .recv()
+                .await
+                .map_err(|_| self.termination.error())??;
+            self.pending -= 1;
+        }
+        Ok(())
+    }
+}
+
+impl IntoFuture for BatchToken {
+    type Output = Result<(), HandleError>;
+    type IntoFuture = BoxFuture<'static, Self::Output>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
+
+/// A [`Stream`] of the publications matching a topic filter.
+///
+/// It's returned by [`ClientHandle::subscribe_stream()`]. The stream ends when the
+/// [`Client`] terminates, when the broker acknowledged an unsubscribe from the topic filter,
+/// or when its lease expired.
+///
+/// [`ClientHandle::broadcast()`] returns a `Subscription` of all publications. That stream
+/// only ends when the `Client` terminates.
+pub struct Subscription {
+    // Pinned, because the receiver is `!Unpin`.
+    receiver: Pin<Box<Receiver<Packet>>>,
+
+    // See `ClientHandle::subscribe_stream_with_lease()`.
+    lease: Option<Lease>,
+}
+
+impl Stream for Subscription {
+    type Item = Publish;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Publish>> {
+        let poll = loop {
+            match self.receiver.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Packet::Publish(publish))) => break Poll::Ready(Some(publish)),
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) => break Poll::Ready(None),
+                Poll::Pending => break Poll::Pending,
+            }
+        };
+        if let Some(lease) = &self.lease {
+            lease.polled(poll.is_pending());
+        }
+        poll
     }
 }
 
@@ -259,5 +1801,5 @@ pub trait Emit {
     fn emit(
         self,
         handler: &ClientHandle,
-    ) -> impl std::future::Future<Output = Result<(), ConnectionError>>;
+    ) -> impl std::future::Future<Output = Result<(), HandleError>>;
 }
@@ -1,75 +1,1073 @@
+#[cfg(feature = "compression")]
+use crate::compression::{self, Compression};
+use crate::target;
 use crate::{
-    packet::{self, connack::ReturnCode},
-    ConnAck, Connect, DecodingError, Packet, PingResp, SubAck,
+    packet::{self, connack::ReturnCode, suback},
+    subscribe_all,
+    topic::does_topic_match_subscription,
+    validate, ConnAck, Connect, DecodingError, Packet, PingResp, PubComp, PubRel, Publish, QoS,
+    SubAck, UnsubAck,
 };
-use async_channel::{SendError, Sender};
+use async_channel::{Receiver, SendError, Sender, TrySendError};
+use async_io::Timer;
 use async_net::{TcpListener, TcpStream};
 use futures::FutureExt;
 use futures::{
+    future::{join_all, BoxFuture},
     io::{AsyncReadExt, AsyncWriteExt},
     stream::{FuturesOrdered, StreamExt},
     AsyncRead,
 };
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Spawn a future on an executor.
+///
+/// [`Server`] uses it to run each connection, and the routing of publications, in its own task.
+/// It's implemented for closures, so a spawner for smol looks like:
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// # use tjiftjaf::aio::server::Server;
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+/// let server = Server::new(listener).spawner(|future| smol::spawn(future).detach());
+/// # });
+/// ```
+pub trait Spawn: Send + Sync {
+    /// Run `future` to completion in the background.
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+}
+
+impl<F> Spawn for F
+where
+    F: Fn(BoxFuture<'static, ()>) + Send + Sync,
+{
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        self(future)
+    }
+}
+
+/// Observe the [`Event`]s of a [`Server`], for example to notify an external system
+/// that handles authorization or billing. See [`Server::hook()`].
+///
+/// It's implemented for closures. The hook runs on the task that routes publications,
+/// so it must return quickly. Hand slow work, like an HTTP request, to another task.
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// use tjiftjaf::aio::server::{Event, Server};
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+/// let (sender, receiver) = async_channel::unbounded::<Event>();
+/// let server = Server::new(listener).hook(move |event: &Event| {
+///     // A separate task POSTs `event.to_json()` to a webhook.
+///     _ = sender.try_send(event.clone());
+/// });
+/// # });
+/// ```
+pub trait Hook: Send + Sync {
+    /// Called for every event, in the order they occur.
+    fn on_event(&self, event: &Event);
+}
+
+impl<F> Hook for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Decides whether the [`Server`] accepts a connection. See [`Server::authenticator()`].
+///
+/// It's implemented for closures. It's called with the client id, username and password
+/// of every CONNECT. Any return code other than [`ReturnCode::ConnectionAccepted`] refuses
+/// the connection: the server answers with a CONNACK carrying that return code and closes
+/// the connection.
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// use tjiftjaf::{aio::server::Server, packet::connack::ReturnCode};
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+/// let server = Server::new(listener).authenticator(
+///     |_client_id: &str, username: Option<&str>, password: Option<&[u8]>| {
+///         match (username, password) {
+///             (Some("sensor"), Some(b"secret")) => ReturnCode::ConnectionAccepted,
+///             _ => ReturnCode::ConnectionRefusedBadUsernameOrPassword,
+///         }
+///     },
+/// );
+/// # });
+/// ```
+pub trait Authenticator: Send + Sync {
+    /// Returns [`ReturnCode::ConnectionAccepted`] to accept the connection, or the reason
+    /// to refuse it.
+    fn authenticate(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+    ) -> ReturnCode;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&str, Option<&str>, Option<&[u8]>) -> ReturnCode + Send + Sync,
+{
+    fn authenticate(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+    ) -> ReturnCode {
+        self(client_id, username, password)
+    }
+}
+
+/// Decides which topics a client may publish to and subscribe to. See [`Server::authorizer()`].
+///
+/// The server asks before it routes a publication, including wills, and drops the
+/// publications that aren't authorized. It asks for every filter of a SUBSCRIBE, and
+/// answers the filters that aren't authorized with
+/// [`Failure`](crate::packet::suback::ReturnCode::Failure) in the SUBACK.
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// use tjiftjaf::aio::server::{Authorizer, Server};
+///
+/// // Every client may only use the topics under its own client id.
+/// struct OwnTopics;
+///
+/// impl Authorizer for OwnTopics {
+///     fn can_publish(&self, client_id: &str, topic: &str) -> bool {
+///         topic.strip_prefix(client_id).is_some_and(|rest| rest.starts_with('/'))
+///     }
+///
+///     fn can_subscribe(&self, client_id: &str, filter: &str) -> bool {
+///         self.can_publish(client_id, filter)
+///     }
+/// }
+///
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+/// let server = Server::new(listener).authorizer(OwnTopics);
+/// # });
+/// ```
+pub trait Authorizer: Send + Sync {
+    /// Whether `client_id` may publish to `topic`.
+    fn can_publish(&self, client_id: &str, topic: &str) -> bool;
+
+    /// Whether `client_id` may subscribe to `filter`.
+    fn can_subscribe(&self, client_id: &str, filter: &str) -> bool;
+}
 
+/// An event in the life of a connection to the [`Server`]. See [`Hook`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A client connected.
+    Connected { client_id: String },
+
+    /// A client subscribed to one or more topic filters.
+    Subscribed {
+        client_id: String,
+        filters: Vec<String>,
+    },
+
+    /// A client disconnected. `graceful` is `true` if it sent a DISCONNECT first.
+    Disconnected { client_id: String, graceful: bool },
+}
+
+impl Event {
+    /// The id of the client the event is about.
+    pub fn client_id(&self) -> &str {
+        match self {
+            Self::Connected { client_id }
+            | Self::Subscribed { client_id, .. }
+            | Self::Disconnected { client_id, .. } => client_id,
+        }
+    }
+
+    /// Serialize the event as a JSON object, like the body of a webhook.
+    ///
+    /// ```
+    /// use tjiftjaf::aio::server::Event;
+    ///
+    /// let event = Event::Disconnected { client_id: "sensor-1".into(), graceful: false };
+    /// assert_eq!(
+    ///     event.to_json(),
+    ///     r#"{"event":"disconnected","client_id":"sensor-1","graceful":false}"#
+    /// );
+    /// ```
+    pub fn to_json(&self) -> String {
+        let client_id = json_string(self.client_id());
+        match self {
+            Self::Connected { .. } => {
+                format!(r#"{{"event":"connected","client_id":{client_id}}}"#)
+            }
+            Self::Subscribed { filters, .. } => {
+                let filters: Vec<String> = filters.iter().map(|f| json_string(f)).collect();
+                format!(
+                    r#"{{"event":"subscribed","client_id":{client_id},"filters":[{}]}}"#,
+                    filters.join(",")
+                )
+            }
+            Self::Disconnected { graceful, .. } => {
+                format!(
+                    r#"{{"event":"disconnected","client_id":{client_id},"graceful":{graceful}}}"#
+                )
+            }
+        }
+    }
+}
+
+// Encode `value` as a JSON string, including the quotes.
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// An MQTT broker, meant for testing clients.
+///
+/// Besides regular subscriptions, the server supports shared subscriptions of the form
+/// `$share/{group}/{filter}`. A publication that matches `filter` is delivered to one
+/// member of the group, in turns.
+///
+/// The server keeps the last publication with the retain flag of every topic, and delivers
+/// it to new subscribers. A retained publication with an empty payload removes it.
+/// See [`Server::retained_limits()`] to bound the memory they use.
+///
+/// The will of a client is published when its connection closes without a DISCONNECT,
+/// for example after a socket error or when the client exceeds its keep alive interval.
+///
+/// A client that connects with the client id of a connected client takes over: the server
+/// closes the existing connection, which publishes its will. Unless the new connection asks
+/// for a clean session, it continues the subscriptions of the old one. A client with an empty
+/// client id gets a unique one assigned.
+///
+/// A [`Hook`] observes connections, subscriptions and disconnections.
+/// [`TopicMetrics`] count the publications per topic.
 pub struct Server {
     listener: TcpListener,
 
-    // Map client ids to topics.
-    subscriptions: HashMap<String, (Sender<Packet>, Vec<String>)>,
+    // The clients that connected, indexed by client id.
+    clients: HashMap<String, Peer>,
+
+    // The number of publications delivered to each shared subscription, indexed by
+    // the group and filter. It selects the member that receives the next publication.
+    shares: HashMap<(String, String), usize>,
+
+    // The retained publication of every topic.
+    retained: BTreeMap<String, Retained>,
+
+    retained_limits: RetainedLimits,
+
+    // When set, every connection and the routing core are handled in separate tasks.
+    // Otherwise, all are driven by the future returned by `Server::run()`.
+    spawner: Option<Box<dyn Spawn>>,
+
+    handshake: Handshake,
+
+    rate_limit: Option<RateLimit>,
+
+    // Observes the events of the server, if set.
+    hook: Option<Box<dyn Hook>>,
+
+    // Decides which connections are accepted. Without one, every connection is.
+    authenticator: Option<Arc<dyn Authenticator>>,
+
+    // Decides which topics a client may publish to and subscribe to. Without one, every
+    // topic is allowed. The routing core checks publications, the task of each client
+    // checks subscriptions.
+    authorizer: Option<Arc<dyn Authorizer>>,
+
+    // Counts the publications per topic, if set.
+    metrics: Option<TopicMetrics>,
+
+    fan_out: FanOut,
+
+    // How to compress publications for clients that offer it, if enabled.
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
+}
+
+/// Limits on the inbound traffic of a single client. See [`Server::rate_limit()`].
+///
+/// Each limit is a token bucket: a client may send a burst of up to one second's
+/// worth of traffic, after which it's limited to the configured rate.
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// use tjiftjaf::aio::server::{RateLimit, RateLimitPolicy, Server};
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+///
+/// let limit = RateLimit::new()
+///     .messages_per_second(100)
+///     .bytes_per_second(64 * 1024)
+///     .policy(RateLimitPolicy::Disconnect);
+/// let server = Server::new(listener).rate_limit(limit);
+/// # });
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct RateLimit {
+    messages_per_second: Option<u32>,
+    bytes_per_second: Option<u32>,
+    policy: RateLimitPolicy,
+}
+
+/// What the [`Server`] does with a client that exceeds its [`RateLimit`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Stop reading from the client until it's within its limits again.
+    /// TCP flow control then slows down the client.
+    #[default]
+    Throttle,
+
+    /// Close the connection.
+    Disconnect,
+}
+
+impl RateLimit {
+    /// A limit that doesn't restrict anything yet. It throttles clients that exceed it.
+    pub fn new() -> Self {
+        Self {
+            messages_per_second: None,
+            bytes_per_second: None,
+            policy: RateLimitPolicy::default(),
+        }
+    }
+
+    /// Limit the number of PUBLISH packets a client may send per second.
+    pub fn messages_per_second(mut self, limit: u32) -> Self {
+        self.messages_per_second = Some(limit);
+        self
+    }
+
+    /// Limit the number of bytes a client may send per second.
+    pub fn bytes_per_second(mut self, limit: u32) -> Self {
+        self.bytes_per_second = Some(limit);
+        self
+    }
+
+    /// Configure what happens with a client that exceeds the limit.
+    pub fn policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Limits on the retained publications of the [`Server`]. See [`Server::retained_limits()`].
+///
+/// A retained publication that exceeds a limit is forwarded to the current subscribers,
+/// but not retained.
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// # use std::time::Duration;
+/// use tjiftjaf::aio::server::{RetainedLimits, Server};
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+///
+/// let limits = RetainedLimits::new()
+///     .max_messages(1000)
+///     .max_payload_size(4 * 1024)
+///     .expiry(Duration::from_secs(3600));
+/// let server = Server::new(listener).retained_limits(limits);
+/// # });
+/// ```
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RetainedLimits {
+    max_messages: Option<usize>,
+    max_payload_size: Option<usize>,
+    expiry: Option<Duration>,
+}
+
+impl RetainedLimits {
+    /// Limits that don't restrict anything yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the number of topics with a retained publication. When the limit is reached,
+    /// retained publications for other topics are dropped. Existing ones can still be replaced.
+    pub fn max_messages(mut self, limit: usize) -> Self {
+        self.max_messages = Some(limit);
+        self
+    }
+
+    /// Limit the size of the payload of a retained publication, in bytes.
+    pub fn max_payload_size(mut self, limit: usize) -> Self {
+        self.max_payload_size = Some(limit);
+        self
+    }
+
+    /// Remove retained publications `ttl` after they're stored.
+    pub fn expiry(mut self, ttl: Duration) -> Self {
+        self.expiry = Some(ttl);
+        self
+    }
+}
+
+// The topic on which `TopicMetrics` reports the busiest topics.
+const BUSIEST_TOPICS: &str = "$SYS/broker/topics/busiest";
+
+/// Counts the publications the [`Server`] receives, per topic. See [`Server::topic_metrics()`].
+///
+/// Clones share the same counters. Keep a clone to find the busiest topics, for example
+/// to spot a noisy device, while the server runs.
+///
+/// To bound the memory the counters use, a topic is counted by its first
+/// [`depth`](TopicMetrics::depth()) levels, and at most
+/// [`max_topics`](TopicMetrics::max_topics()) topics are tracked. Once that limit is
+/// reached, publications on other topics are counted under `#`.
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// # use std::time::Duration;
+/// use tjiftjaf::aio::server::{Server, TopicMetrics};
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+///
+/// let metrics = TopicMetrics::new()
+///     .depth(2)
+///     .max_topics(100)
+///     .report(Duration::from_secs(10), 5);
+/// smol::spawn(Server::new(listener).topic_metrics(metrics.clone()).run()).detach();
+///
+/// for stats in metrics.top(5) {
+///     println!("{}: {} messages, {} bytes", stats.topic, stats.messages, stats.bytes);
+/// }
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct TopicMetrics {
+    depth: Option<usize>,
+    max_topics: usize,
+
+    // How often the busiest topics are published, and how many.
+    report: Option<(Duration, usize)>,
+
+    counters: Arc<Mutex<HashMap<String, TopicStats>>>,
+}
+
+/// The counters of a topic, or of a group of topics. See [`TopicMetrics::top()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicStats {
+    /// The topic. Topics that are aggregated by their prefix end in `#`.
+    pub topic: String,
+
+    /// The number of publications.
+    pub messages: u64,
+
+    /// The number of bytes in the payloads of the publications.
+    pub bytes: u64,
+}
+
+impl TopicStats {
+    fn new(topic: String) -> Self {
+        Self {
+            topic,
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    // Serialize the counters as a JSON object.
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"topic":{},"messages":{},"bytes":{}}}"#,
+            json_string(&self.topic),
+            self.messages,
+            self.bytes
+        )
+    }
+}
+
+impl TopicMetrics {
+    /// Metrics that track up to 1000 topics at full depth, without reporting them.
+    pub fn new() -> Self {
+        Self {
+            depth: None,
+            max_topics: 1000,
+            report: None,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Count topics by their first `levels` levels. Deeper topics are aggregated,
+    /// `sensor/1/temperature` is counted as `sensor/1/#` with a depth of 2.
+    pub fn depth(mut self, levels: usize) -> Self {
+        self.depth = Some(levels.max(1));
+        self
+    }
+
+    /// Limit the number of topics that are tracked. Defaults to 1000.
+    pub fn max_topics(mut self, limit: usize) -> Self {
+        self.max_topics = limit;
+        self
+    }
+
+    /// Publish the `top` busiest topics on `$SYS/broker/topics/busiest` every `interval`.
+    ///
+    /// The payload is a JSON array, like
+    /// `[{"topic":"sensor/1/#","messages":120,"bytes":960}]`.
+    pub fn report(mut self, interval: Duration, top: usize) -> Self {
+        self.report = Some((interval, top));
+        self
+    }
+
+    /// The `n` topics with the most publications, the busiest first.
+    pub fn top(&self, n: usize) -> Vec<TopicStats> {
+        let mut stats: Vec<TopicStats> = self.counters.lock().unwrap().values().cloned().collect();
+        stats.sort_by(|a, b| {
+            (b.messages, b.bytes)
+                .cmp(&(a.messages, a.bytes))
+                .then_with(|| a.topic.cmp(&b.topic))
+        });
+        stats.truncate(n);
+        stats
+    }
+
+    /// Reset all counters.
+    pub fn clear(&self) {
+        self.counters.lock().unwrap().clear();
+    }
+
+    // Count a publication on `topic` with a payload of `bytes`.
+    fn record(&self, topic: &str, bytes: usize) {
+        let mut key = match self.depth {
+            Some(depth) => match topic.match_indices('/').nth(depth - 1) {
+                Some((index, _)) => format!("{}/#", &topic[..index]),
+                None => topic.to_owned(),
+            },
+            None => topic.to_owned(),
+        };
+
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.contains_key(&key) && counters.len() >= self.max_topics {
+            key = "#".to_owned();
+        }
+        let stats = counters
+            .entry(key)
+            .or_insert_with_key(|key| TopicStats::new(key.clone()));
+        stats.messages += 1;
+        stats.bytes += bytes as u64;
+    }
+
+    // The publication that reports the busiest topics.
+    fn report_publication(&self, top: usize) -> Publish {
+        let stats: Vec<String> = self.top(top).iter().map(TopicStats::to_json).collect();
+        Publish::builder(BUSIEST_TOPICS, format!("[{}]", stats.join(","))).build()
+    }
+}
+
+impl Default for TopicMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Delivers a publication to its subscribers. See [`Server::fan_out()`].
+///
+/// The subscribers are split over a small set of workers that send concurrently,
+/// so one slow subscriber delays only the subscribers of its worker. Every subscriber
+/// has a queue of packets. The [`OverflowPolicy`] decides what happens when that queue
+/// is full.
+///
+/// ```no_run
+/// # use async_net::TcpListener;
+/// use tjiftjaf::aio::server::{FanOut, OverflowPolicy, Server};
+/// # smol::block_on(async {
+/// # let listener = TcpListener::bind("127.0.0.1:1883").await.unwrap();
+///
+/// let fan_out = FanOut::new().workers(8).overflow_policy(OverflowPolicy::Drop);
+/// let server = Server::new(listener).fan_out(fan_out);
+/// # });
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct FanOut {
+    workers: usize,
+    policy: OverflowPolicy,
+}
+
+/// What the [`Server`] does when the queue of a subscriber is full. See [`FanOut`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the subscriber catches up. A slow subscriber delays the others.
+    #[default]
+    Wait,
+
+    /// Don't deliver the publication to the subscriber.
+    Drop,
+
+    /// Close the connection of the subscriber.
+    Disconnect,
+}
+
+impl FanOut {
+    /// Deliver with 4 workers, waiting for slow subscribers.
+    pub fn new() -> Self {
+        Self {
+            workers: 4,
+            policy: OverflowPolicy::default(),
+        }
+    }
+
+    /// Split the subscribers over `workers` concurrent workers. A value of 0 is treated as 1.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Configure what happens when the queue of a subscriber is full.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Send `packet` to every subscriber.
+    ///
+    /// Returns the indices of the subscribers that must be disconnected, because their
+    /// queue is closed, or because it's full and the policy is [`OverflowPolicy::Disconnect`].
+    pub async fn deliver(&self, packet: &Packet, subscribers: &[&Sender<Packet>]) -> Vec<usize> {
+        if self.policy != OverflowPolicy::Wait {
+            return subscribers
+                .iter()
+                .enumerate()
+                .filter(
+                    |(_, subscriber)| match subscriber.try_send(packet.clone()) {
+                        Ok(()) => false,
+                        Err(TrySendError::Full(_)) => self.policy == OverflowPolicy::Disconnect,
+                        Err(TrySendError::Closed(_)) => true,
+                    },
+                )
+                .map(|(index, _)| index)
+                .collect();
+        }
+
+        let size = subscribers.len().div_ceil(self.workers).max(1);
+        let workers = subscribers
+            .chunks(size)
+            .enumerate()
+            .map(|(chunk, subscribers)| async move {
+                let mut failed = vec![];
+                for (index, subscriber) in subscribers.iter().enumerate() {
+                    if subscriber.send(packet.clone()).await.is_err() {
+                        failed.push(chunk * size + index);
+                    }
+                }
+                failed
+            });
+        join_all(workers).await.into_iter().flatten().collect()
+    }
+}
+
+impl Default for FanOut {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A retained publication.
+struct Retained {
+    publish: Publish,
+
+    // When the publication is removed, if it expires at all.
+    expires: Option<Instant>,
+}
+
+impl Retained {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+// A token bucket that holds up to one second of tokens.
+// The balance goes negative when a client overdraws it.
+struct TokenBucket {
+    rate: f64,
+    balance: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            balance: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Take `tokens` from the bucket. Returns how long the client must wait
+    // before the balance is positive again.
+    fn take(&mut self, tokens: usize, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.balance = (self.balance + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        self.balance -= tokens as f64;
+        if self.balance >= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(-self.balance / self.rate))
+    }
+}
+
+// The state of a `RateLimit` for a single client.
+struct Limiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    policy: RateLimitPolicy,
+
+    // The number of times the client exceeded its limits.
+    violations: usize,
+}
+
+impl Limiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            messages: limit.messages_per_second.map(TokenBucket::new),
+            bytes: limit.bytes_per_second.map(TokenBucket::new),
+            policy: limit.policy,
+            violations: 0,
+        }
+    }
+
+    // Account for an inbound packet. Returns how long the client must wait
+    // before the next packet is read.
+    fn record(&mut self, packet: &Packet, now: Instant) -> Option<Duration> {
+        let mut delay = None;
+        if let (Some(bucket), Packet::Publish(_)) = (&mut self.messages, packet) {
+            delay = bucket.take(1, now);
+        }
+        if let Some(bucket) = &mut self.bytes {
+            delay = delay.max(bucket.take(packet.length(), now));
+        }
+
+        if delay.is_some() {
+            self.violations += 1;
+        }
+        delay
+    }
+}
+
+// Limits that apply to a connection until it sent a valid CONNECT.
+#[derive(Copy, Clone, Debug)]
+struct Handshake {
+    timeout: Duration,
+
+    // The maximum number of bytes a client may send before its CONNECT is complete.
+    max_size: usize,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_size: 64 * 1024,
+        }
+    }
 }
 
 impl Server {
     pub fn new(listener: TcpListener) -> Self {
         Self {
             listener,
-            subscriptions: HashMap::default(),
+            clients: HashMap::default(),
+            shares: HashMap::default(),
+            retained: BTreeMap::default(),
+            retained_limits: RetainedLimits::default(),
+            spawner: None,
+            handshake: Handshake::default(),
+            rate_limit: None,
+            hook: None,
+            authenticator: None,
+            authorizer: None,
+            metrics: None,
+            fan_out: FanOut::default(),
+            #[cfg(feature = "compression")]
+            compression: None,
+        }
+    }
+
+    /// Limit the inbound traffic of every client. See [`RateLimit`].
+    ///
+    /// By default, clients are not limited.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Limit the number, size and lifetime of retained publications. See [`RetainedLimits`].
+    ///
+    /// By default, retained publications are not limited.
+    pub fn retained_limits(mut self, limits: RetainedLimits) -> Self {
+        self.retained_limits = limits;
+        self
+    }
+
+    /// Drop connections that don't send a complete CONNECT within `timeout`.
+    ///
+    /// Defaults to 10 seconds.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake.timeout = timeout;
+        self
+    }
+
+    /// Drop connections that send more than `size` bytes before their CONNECT is complete.
+    ///
+    /// Defaults to 64 KiB.
+    pub fn max_connect_size(mut self, size: usize) -> Self {
+        self.handshake.max_size = size;
+        self
+    }
+
+    /// Handle every connection in a separate task, spawned by `spawner`. The routing of
+    /// publications gets a task of its own too, which exchanges packets with the connections
+    /// over channels.
+    ///
+    /// By default, all connections are handled by the future returned from [`Server::run()`].
+    /// With a multi-threaded executor, a spawner allows connections to be processed in parallel.
+    /// A slow client no longer stalls the others.
+    pub fn spawner(mut self, spawner: impl Spawn + 'static) -> Self {
+        self.spawner = Some(Box::new(spawner));
+        self
+    }
+
+    /// Call `hook` for every [`Event`] of the server.
+    pub fn hook(mut self, hook: impl Hook + 'static) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Accept only the connections that `authenticator` accepts. See [`Authenticator`].
+    ///
+    /// By default, every connection is accepted.
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Only route the publications and accept the subscriptions that `authorizer`
+    /// authorizes. See [`Authorizer`].
+    ///
+    /// By default, every client may publish and subscribe to every topic.
+    pub fn authorizer(mut self, authorizer: impl Authorizer + 'static) -> Self {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Count the publications per topic in `metrics`. See [`TopicMetrics`].
+    pub fn topic_metrics(mut self, metrics: TopicMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Configure how publications are delivered to their subscribers. See [`FanOut`].
+    ///
+    /// By default, 4 workers deliver a publication, waiting for slow subscribers.
+    pub fn fan_out(mut self, fan_out: FanOut) -> Self {
+        self.fan_out = fan_out;
+        self
+    }
+
+    /// Accept the offer of tjiftjaf clients to compress publications.
+    /// See the module [`compression`].
+    ///
+    /// By default, the offer is ignored and publications are exchanged as is.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    // Pass `event` to the hook, if any.
+    fn emit(&self, event: Event) {
+        if let Some(hook) = &self.hook {
+            hook.on_event(&event);
         }
     }
 
     // Process an event from a client
     async fn handle_client_message(&mut self, message: Message) -> Result<(), SendError<Packet>> {
         match message {
-            Message::Register(client_id, sender) => {
-                if self
-                    .subscriptions
-                    .insert(client_id.clone(), (sender, vec![]))
-                    .is_some()
-                {
-                    info!("{client_id} - Reconnected");
+            Message::Register {
+                client_id,
+                connection,
+                clean_session,
+                sender,
+            } => {
+                let mut topics = vec![];
+                if let Some(previous) = self.clients.remove(&client_id) {
+                    if previous.connected {
+                        // [MQTT-3.1.4-2] The existing connection of the client is closed.
+                        info!(target: target::SERVER, "{client_id} - Taking over the existing connection");
+                        previous.sender.close();
+                        self.emit(Event::Disconnected {
+                            client_id: client_id.clone(),
+                            graceful: false,
+                        });
+                    } else {
+                        info!(target: target::SERVER, "{client_id} - Reconnected");
+                    }
+
+                    // [MQTT-3.1.2-4] Without a clean session, the session continues.
+                    if !clean_session {
+                        topics = previous.topics;
+                    }
+                }
+
+                self.clients.insert(
+                    client_id.clone(),
+                    Peer {
+                        connection,
+                        sender,
+                        topics,
+                        connected: true,
+                        #[cfg(feature = "compression")]
+                        compressed: false,
+                    },
+                );
+                self.emit(Event::Connected { client_id });
+            }
+
+            Message::Disconnected(client_id, connection, graceful) => {
+                // A connection that was taken over already reported its disconnection.
+                let Some(peer) = self
+                    .clients
+                    .get_mut(&client_id)
+                    .filter(|peer| peer.connection == connection)
+                else {
+                    return Ok(());
                 };
+                peer.connected = false;
+                self.emit(Event::Disconnected {
+                    client_id,
+                    graceful,
+                });
             }
 
-            Message::Packet(client_id, Packet::Subscribe(subscribe)) => {
-                let Some((_, topics)) = self.subscriptions.get_mut(&client_id) else {
-                    error!("{client_id} - SUBSCRIBE packet for an unknown client.");
+            Message::Packet(client_id, connection, Packet::Subscribe(subscribe)) => {
+                let Some(Peer {
+                    sender: peer,
+                    topics,
+                    ..
+                }) = self
+                    .clients
+                    .get_mut(&client_id)
+                    .filter(|peer| peer.connection == connection)
+                else {
+                    error!(target: target::SERVER, "{client_id} - SUBSCRIBE packet for an unknown connection.");
                     return Ok(());
                 };
 
+                let filters: Vec<String> = subscribe
+                    .topics()
+                    .map(|(topic, _)| topic.to_owned())
+                    .collect();
                 for (topic, _) in subscribe.topics() {
                     topics.push(topic.to_owned());
+
+                    // Shared subscriptions don't receive retained publications.
+                    if parse_shared_subscription(topic).is_some() {
+                        continue;
+                    }
+                    let now = Instant::now();
+                    for retained in self.retained.values() {
+                        if !retained.is_expired(now)
+                            && does_topic_match_subscription(topic, retained.publish.topic())
+                        {
+                            peer.send(Packet::Publish(retained.publish.clone())).await?;
+                        }
+                    }
                 }
+                self.emit(Event::Subscribed { client_id, filters });
             }
-            Message::Packet(_, Packet::Publish(publish)) => {
-                let mut disconnected_clients: Vec<String> = Vec::new();
-                let needle = publish.topic();
-                let subscriptions = self.subscriptions.iter().filter(|(_, (_, topics))| {
-                    topics
-                        .iter()
-                        .any(|subscription| does_topic_match_subscription(subscription, needle))
-                });
-
-                for (client_id, (peer, _)) in subscriptions {
-                    if let Err(error) = peer.send(Packet::Publish(publish.clone())).await {
-                        warn!("{client_id} - Failed to send packet: {error:?}");
-                        disconnected_clients.push(client_id.clone());
-                    };
+            Message::Packet(client_id, connection, Packet::Unsubscribe(unsubscribe)) => {
+                let Some(peer) = self
+                    .clients
+                    .get_mut(&client_id)
+                    .filter(|peer| peer.connection == connection)
+                else {
+                    error!(target: target::SERVER, "{client_id} - UNSUBSCRIBE packet for an unknown connection.");
+                    return Ok(());
+                };
+                peer.topics
+                    .retain(|topic| !unsubscribe.topics().any(|filter| filter == topic));
+            }
+            #[cfg(feature = "compression")]
+            Message::Packet(client_id, connection, Packet::Publish(publish))
+                if self.compression.is_some() && compression::is_offer(&publish) =>
+            {
+                let Some(peer) = self
+                    .clients
+                    .get_mut(&client_id)
+                    .filter(|peer| peer.connection == connection)
+                else {
+                    return Ok(());
+                };
+                debug!(target: target::SERVER, "{client_id} - Compressing publications");
+                peer.compressed = true;
+                peer.sender.send(compression::offer().into()).await?;
+            }
+            Message::Packet(client_id, _, Packet::Publish(publish)) => {
+                #[cfg(feature = "compression")]
+                let publish = match self.compression.unwrap_or_default().decompress(&publish) {
+                    Ok(inflated) => inflated.unwrap_or(publish),
+                    Err(error) => {
+                        warn!(target: target::SERVER, "{client_id} - Not inflating the publication on {:?}: {error}", publish.topic());
+                        publish
+                    }
+                };
+                if let Some(authorizer) = &self.authorizer {
+                    if !authorizer.can_publish(&client_id, publish.topic()) {
+                        warn!(target: target::SERVER, "{client_id} is not authorized to publish to {:?}, dropping the publication.", publish.topic());
+                        return Ok(());
+                    }
                 }
-
-                for client in disconnected_clients {
-                    self.subscriptions.remove(&client);
+                if let Some(metrics) = &self.metrics {
+                    metrics.record(publish.topic(), publish.payload().len());
                 }
+                if publish.retain() {
+                    self.retain(&publish, Instant::now());
+                }
+
+                // [MQTT-3.3.1-9] The retain flag must be 0 when a publication is
+                // delivered to an established subscription.
+                let publish = if publish.retain() {
+                    clear_retain(&publish)
+                } else {
+                    publish
+                };
+                self.route(publish).await;
             }
 
             _ => {}
@@ -77,35 +1075,176 @@ impl Server {
         Ok(())
     }
 
-    pub async fn run(mut self) {
-        let listener = self.listener.clone();
-        let (tx_inbound, rx_inbound) = async_channel::bounded::<Message>(100);
+    // Deliver `publish` to every client with a matching subscription.
+    async fn route(&mut self, publish: Publish) {
+        let mut disconnected_clients: Vec<String> = Vec::new();
+        let needle = publish.topic();
+        let mut recipients: Vec<&String> = vec![];
 
-        let outbound_messages = async {
-            loop {
-                futures::select! {
-                    message = rx_inbound.recv().fuse() => {
-                        match message {
-                            Ok(message) => _ = self.handle_client_message(message).await,
-                            Err(error) => {
-                                error!("Fatal error, the receiver died: {error:?}");
-                                return
-                            }
+        // The members of every shared subscription that matches, sorted by client id.
+        let mut groups: BTreeMap<(&str, &str), Vec<&String>> = BTreeMap::new();
+        for (client_id, peer) in &self.clients {
+            let mut regular = false;
+            for topic in &peer.topics {
+                match parse_shared_subscription(topic) {
+                    Some((group, filter)) => {
+                        if does_topic_match_subscription(filter, needle) {
+                            groups.entry((group, filter)).or_default().push(client_id);
                         }
                     }
+                    None => regular |= does_topic_match_subscription(topic, needle),
                 }
             }
+            if regular {
+                recipients.push(client_id);
+            }
+        }
+
+        for ((group, filter), mut members) in groups {
+            members.sort();
+            members.dedup();
+            let count = self
+                .shares
+                .entry((group.to_owned(), filter.to_owned()))
+                .or_default();
+            recipients.push(members[*count % members.len()]);
+            *count += 1;
+        }
+
+        // Clients that accepted compression receive the deflated copy, if any.
+        let deflated = self.deflate(&publish);
+        let (compressed, plain): (Vec<&String>, Vec<&String>) = recipients
+            .into_iter()
+            .partition(|client_id| deflated.is_some() && self.clients[*client_id].is_compressed());
+
+        for (recipients, publish) in [(plain, Some(publish)), (compressed, deflated)] {
+            let Some(publish) = publish else {
+                continue;
+            };
+            let senders: Vec<&Sender<Packet>> = recipients
+                .iter()
+                .map(|client_id| &self.clients[*client_id].sender)
+                .collect();
+            let packet = Packet::Publish(publish);
+            for index in self.fan_out.deliver(&packet, &senders).await {
+                let client_id = recipients[index];
+                warn!(target: target::SERVER, "{client_id} - Failed to send packet, closing connection.");
+                disconnected_clients.push(client_id.clone());
+            }
+        }
+
+        for client in disconnected_clients {
+            self.clients.remove(&client);
+        }
+    }
+
+    // Deflate `publish` for clients that accepted compression, if deflating pays off.
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn deflate(&self, publish: &Publish) -> Option<Publish> {
+        #[cfg(feature = "compression")]
+        return self
+            .compression
+            .and_then(|compression| compression.compress(publish));
+        #[cfg(not(feature = "compression"))]
+        None
+    }
+
+    // Store `publish` as the retained publication of its topic, within the limits.
+    fn retain(&mut self, publish: &Publish, now: Instant) {
+        let topic = publish.topic();
+
+        // [MQTT-3.3.1-10] A PUBLISH with the retain flag and an empty payload
+        // removes the retained publication of the topic.
+        if publish.payload().is_empty() {
+            self.retained.remove(topic);
+            return;
+        }
+
+        let limits = self.retained_limits;
+        if limits
+            .max_payload_size
+            .is_some_and(|limit| publish.payload().len() > limit)
+        {
+            warn!(target: target::SERVER, "Not retaining the publication on '{topic}', its payload is too large.");
+            // The new publication replaces the previous one, even if it can't be stored.
+            self.retained.remove(topic);
+            return;
+        }
+        if !self.retained.contains_key(topic)
+            && limits
+                .max_messages
+                .is_some_and(|limit| self.retained.len() >= limit)
+        {
+            warn!(target: target::SERVER, "Not retaining the publication on '{topic}', the limit of retained publications is reached.");
+            return;
+        }
+
+        let retained = Retained {
+            publish: publish.clone(),
+            expires: limits.expiry.map(|ttl| now + ttl),
+        };
+        self.retained.insert(topic.to_owned(), retained);
+    }
+
+    // Remove the retained publications that expired.
+    fn sweep_retained(&mut self, now: Instant) {
+        self.retained.retain(|topic, retained| {
+            let expired = retained.is_expired(now);
+            if expired {
+                debug!(target: target::SERVER, "The retained publication on '{topic}' expired.");
+            }
+            !expired
+        });
+    }
+
+    pub async fn run(mut self) {
+        let listener = self.listener.clone();
+        let spawner = self.spawner.take();
+        let handshake = self.handshake;
+        let rate_limit = self.rate_limit;
+        let authenticator = self.authenticator.clone();
+        let authorizer = self.authorizer.clone();
+        let (tx_inbound, rx_inbound) = async_channel::bounded::<Message>(100);
+
+        // With a spawner, the routing core runs in a task of its own. Then, accepting
+        // connections and routing publications don't wait on each other. The task
+        // drops `stopped` when it ends, to stop the server. Dropping this future drops
+        // `_stop`, which ends the task.
+        let (_stop, should_stop) = async_channel::bounded::<()>(1);
+        let routing = Box::pin(self.route_messages(rx_inbound, should_stop));
+        let routing: BoxFuture<'static, ()> = match &spawner {
+            Some(spawner) => {
+                let (stopped, is_stopped) = async_channel::bounded::<()>(1);
+                spawner.spawn(Box::pin(async move {
+                    routing.await;
+                    drop(stopped);
+                }));
+                Box::pin(async move { _ = is_stopped.recv().await })
+            }
+            None => routing,
         };
 
         let new_clients = async {
             let mut futures = FuturesOrdered::new();
 
+            // Every connection is numbered, to tell apart connections with the same client id.
+            let mut connections = 0;
+
             loop {
                 futures::select! {
                     peer  = listener.accept().fuse() => {
                         match peer {
                             Ok((stream, _)) => {
-                                futures.push_back(on_new_connection(stream, tx_inbound.clone()));
+                                connections += 1;
+                                let connection = on_new_connection(stream, connections, tx_inbound.clone(), handshake, rate_limit, authenticator.clone(), authorizer.clone());
+                                match &spawner {
+                                    Some(spawner) => spawner.spawn(Box::pin(async {
+                                        if let Err(error) = connection.await {
+                                            warn!(target: target::SERVER, "Client disconnected: {error:?}");
+                                        }
+                                    })),
+                                    None => futures.push_back(connection),
+                                }
                             }
                             Err(error) => {
                                 panic!("Failed to connect new clients: {error:?}");
@@ -114,19 +1253,18 @@ impl Server {
                     }
                     result = futures.next() => {
                         if let Some(Err(error)) = result {
-                            warn!("Client disconnected: {error:?}");
+                            warn!(target: target::SERVER, "Client disconnected: {error:?}");
                         }
                     }
                 }
             }
         };
-        smol::pin!(outbound_messages);
         smol::pin!(new_clients);
-        let mut outbound_messages = outbound_messages.fuse();
+        let mut routing = routing.fuse();
         let mut new_clients = new_clients.fuse();
 
         futures::select! {
-            _ = outbound_messages => {
+            _ = routing => {
                 panic!("Fatal error when processing messages")
             }
             _ = new_clients => {
@@ -134,31 +1272,172 @@ impl Server {
             }
         };
     }
+
+    // Handle the messages of all connections, and route their publications. Only returns
+    // when `inbound` closes, or the sender of `stop` is dropped.
+    async fn route_messages(mut self, inbound: Receiver<Message>, stop: Receiver<()>) {
+        // When the busiest topics are reported next, if at all.
+        let report = self.metrics.as_ref().and_then(|metrics| metrics.report);
+        let mut next_report = report.map(|(interval, _)| Instant::now() + interval);
+
+        loop {
+            // Wake up when the next retained publication expires.
+            let expires = self
+                .retained
+                .values()
+                .filter_map(|retained| retained.expires)
+                .min();
+            let sweep = async {
+                match expires {
+                    Some(expires) => _ = Timer::at(expires).await,
+                    None => futures::future::pending().await,
+                }
+            };
+
+            futures::select! {
+                message = inbound.recv().fuse() => {
+                    match message {
+                        Ok(message) => _ = self.handle_client_message(message).await,
+                        Err(error) => {
+                            error!(target: target::SERVER, "Fatal error, the receiver died: {error:?}");
+                            return
+                        }
+                    }
+                }
+                _ = stop.recv().fuse() => {
+                    debug!(target: target::SERVER, "The server stopped, no longer routing messages.");
+                    return
+                }
+                _ = sweep.fuse() => self.sweep_retained(Instant::now()),
+                _ = report_at(next_report).fuse() => {
+                    if let (Some(metrics), Some((interval, top))) = (&self.metrics, report) {
+                        let publish = metrics.report_publication(top);
+                        self.route(publish).await;
+                        next_report = Some(Instant::now() + interval);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Resolve at `deadline`, or never without one.
+async fn report_at(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => _ = Timer::at(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+// Copy `publish`, with the retain flag set to 0.
+fn clear_retain(publish: &Publish) -> Publish {
+    let mut builder = Publish::builder(publish.topic(), publish.payload())
+        .qos(publish.qos())
+        .duplicate(publish.duplicate());
+    if let Some(packet_identifier) = publish.packet_identifier() {
+        builder = builder.packet_identifier(packet_identifier);
+    }
+    builder.build()
+}
+
+// Split a shared subscription, like `$share/{group}/{filter}`, in its group and filter.
+// Returns `None` for a regular subscription.
+fn parse_shared_subscription(topic: &str) -> Option<(&str, &str)> {
+    let (group, filter) = topic.strip_prefix("$share/")?.split_once('/')?;
+    if group.is_empty() || group.contains(['+', '#']) || filter.is_empty() {
+        return None;
+    }
+    Some((group, filter))
 }
 
 async fn on_new_connection(
     mut stream: TcpStream,
+    connection: u64,
     funnel: Sender<Message>,
+    handshake: Handshake,
+    rate_limit: Option<RateLimit>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    authorizer: Option<Arc<dyn Authorizer>>,
 ) -> Result<(), ClientError> {
-    let packet = read_packet(&mut stream).await?;
+    let packet = futures::select! {
+        packet = read_packet(&mut stream, handshake.max_size).fuse() => packet?,
+        _ = FutureExt::fuse(Timer::after(handshake.timeout)) => {
+            return Err(ClientError::HandshakeTimeout);
+        }
+    };
     let Packet::Connect(connect) = packet else {
         return Err(ClientError::UnexpectedPacket);
     };
     let client_id = connect.client_id();
-    debug!("{client_id} <-- {connect:?}");
+    debug!(target: target::SERVER, "{client_id} <-- {connect:?}");
 
-    let ack = ConnAck::builder()
-        .return_code(ReturnCode::ConnectionAccepted)
-        .build();
+    let return_code = authenticator.map_or(ReturnCode::ConnectionAccepted, |authenticator| {
+        authenticator.authenticate(client_id, connect.username(), connect.password())
+    });
+    let ack = ConnAck::builder().return_code(return_code).build();
+    if return_code != ReturnCode::ConnectionAccepted {
+        // [MQTT-3.2.2-5] After a CONNACK with a non-zero return code, the connection is closed.
+        info!(target: target::SERVER, "{client_id} --> {ack:?}");
+        stream.write_all(&Packet::from(ack).into_bytes()).await?;
+        return Err(ClientError::Refused(return_code));
+    }
 
-    let mut client = Client::new(stream, connect);
+    let mut client = Client::new(stream, connect, connection);
+    client.limiter = rate_limit.map(Limiter::new);
+    client.authorizer = authorizer;
     client.send(ack.into()).await?;
 
-    client
-        .run(funnel)
-        .await
-        .inspect(|_| info!("{} disconnected", client.client_id()))
-        .inspect_err(|error| error!("{} disconnected: {error:?}", client.client_id()))
+    let result = client.run(funnel.clone()).await;
+
+    // [MQTT-3.1.2-8] The will is published when the network connection is closed
+    // without the client sending a DISCONNECT first.
+    if result.is_err() {
+        if let Some(will) = will_publication(&client.connect) {
+            info!(target: target::SERVER, "{} --> publishing will {will:?}", client.client_id());
+            let _ = funnel
+                .send(Message::Packet(
+                    client.client_id().to_owned(),
+                    connection,
+                    will.into(),
+                ))
+                .await;
+        }
+    }
+
+    let _ = funnel
+        .send(Message::Disconnected(
+            client.client_id().to_owned(),
+            connection,
+            result.is_ok(),
+        ))
+        .await;
+
+    if let Some(limiter) = client
+        .limiter
+        .as_ref()
+        .filter(|limiter| limiter.violations > 0)
+    {
+        warn!(target: target::SERVER,
+            "{} exceeded its rate limit {} times",
+            client.client_id(),
+            limiter.violations
+        );
+    }
+    result
+        .inspect(|_| info!(target: target::SERVER, "{} disconnected", client.client_id()))
+        .inspect_err(|error| error!(target: target::SERVER, "{} disconnected: {error:?}", client.client_id()))
+}
+
+// Build the publication of the will in `connect`, if it has any.
+fn will_publication(connect: &Connect) -> Option<Publish> {
+    let will = connect.will()?;
+    let mut builder = Publish::builder(will.topic(), will.message())
+        .qos(will.qos())
+        .retain(will.retain());
+    if will.qos() != QoS::AtMostOnceDelivery {
+        builder = builder.packet_identifier(crate::packet_identifier());
+    }
+    Some(builder.build())
 }
 
 #[allow(dead_code)]
@@ -175,6 +1454,22 @@ enum ClientError {
     // The server received a packet it didn't expect. For example,
     // a second CONNECT packet, a CONNACK, a SUBACK, etc.
     UnexpectedPacket,
+
+    // The client didn't send a CONNECT in time.
+    HandshakeTimeout,
+
+    // The `Authenticator` refused the CONNECT.
+    Refused(ReturnCode),
+
+    // The client didn't send a packet within one and a half times its keep alive interval.
+    KeepAliveTimeout,
+
+    // The client exceeded its `RateLimit` and the policy is `RateLimitPolicy::Disconnect`.
+    RateLimited,
+
+    // Another connection with the same client id took over, or the queue of the client
+    // overflowed and the policy is `OverflowPolicy::Disconnect`.
+    ClosedByServer,
 }
 
 impl From<DecodingError> for ClientError {
@@ -198,89 +1493,222 @@ impl From<SendError<Message>> for ClientError {
 struct Client {
     stream: TcpStream,
     connect: Connect,
+
+    // The number of the connection. See `Server::run()`.
+    connection: u64,
+
+    // The client id of `connect`, or a unique one if that's empty.
+    client_id: String,
+
+    // Enforces the `RateLimit` of the client, if any.
+    limiter: Option<Limiter>,
+
+    // Decides which filters the client may subscribe to, if any.
+    authorizer: Option<Arc<dyn Authorizer>>,
 }
 
 impl Client {
     // Construct a new `Client`.
-    pub fn new(stream: TcpStream, connect: Connect) -> Self {
-        Self { stream, connect }
+    pub fn new(stream: TcpStream, connect: Connect, connection: u64) -> Self {
+        // [MQTT-3.1.3-6] The server assigns a unique client id to a client without one.
+        let client_id = match connect.client_id() {
+            "" => format!("tjiftjaf-{connection}"),
+            client_id => client_id.to_owned(),
+        };
+        Self {
+            stream,
+            connect,
+            connection,
+            client_id,
+            limiter: None,
+            authorizer: None,
+        }
     }
 
     // Retrieve the id of the client.
     fn client_id(&self) -> &str {
-        self.connect.client_id()
+        &self.client_id
+    }
+
+    // Whether the client may subscribe to `filter`.
+    fn can_subscribe(&self, filter: &str) -> bool {
+        self.authorizer
+            .as_ref()
+            .is_none_or(|authorizer| authorizer.can_subscribe(&self.client_id, filter))
     }
 
     // Send a packet to the client.
     async fn send(&mut self, packet: Packet) -> Result<(), ClientError> {
-        info!("{} --> {packet:?}", self.client_id());
+        info!(target: target::SERVER, "{} --> {packet:?}", self.client_id());
         self.stream.write_all(&packet.into_bytes()).await?;
         Ok(())
     }
 
+    // Send multiple packets to the client with a single write.
+    async fn send_batch(&mut self, packets: Vec<Packet>) -> Result<(), ClientError> {
+        let mut bytes = vec![];
+        for packet in packets {
+            info!(target: target::SERVER, "{} --> {packet:?}", self.client_id());
+            bytes.extend_from_slice(&packet.into_bytes());
+        }
+        self.stream.write_all(&bytes).await?;
+        Ok(())
+    }
+
     // Start the client. It'll perform 2 tasks in parallel:
     // * reading packets from the tcp stream and forwarding some to `inbound` channel.
     // * reading outbound packets from `receiver` and write them to the tcp stream.
     async fn run(&mut self, funnel: Sender<Message>) -> Result<(), ClientError> {
         let (tx, rx) = async_channel::bounded(100);
         funnel
-            .send(Message::Register(self.client_id().to_owned(), tx))
+            .send(Message::Register {
+                client_id: self.client_id().to_owned(),
+                connection: self.connection,
+                clean_session: self.connect.flags().clean_session(),
+                sender: tx,
+            })
             .await?;
 
+        // While throttled, the client isn't read from until this moment.
+        let mut throttled_until: Option<Instant> = None;
+
+        // [MQTT-3.1.2-24] A client that doesn't send a packet within one and a half times
+        // its keep alive interval is disconnected. A keep alive of 0 disables this.
+        let keep_alive = u64::from(self.connect.keep_alive());
+        let grace = (keep_alive > 0).then(|| Duration::from_millis(keep_alive * 1500));
+        let mut last_read = Instant::now();
+
         loop {
+            let stream = &mut self.stream;
+            let read = async {
+                if let Some(deadline) = throttled_until {
+                    Timer::at(deadline).await;
+                }
+                read_packet(stream, usize::MAX).await
+            };
+
+            // Time spent throttled doesn't count as silence of the client.
+            let silent_since = throttled_until.map_or(last_read, |until| until.max(last_read));
+            let expired = async move {
+                match grace {
+                    Some(grace) => {
+                        Timer::at(silent_since + grace).await;
+                    }
+                    None => futures::future::pending().await,
+                }
+            };
+
             futures::select! {
-                packet = read_packet(&mut self.stream).fuse() =>  {
+                packet = read.fuse() =>  {
                     let packet = packet?;
-                    info!("{} <-- {packet:?}", self.client_id());
+                    info!(target: target::SERVER, "{} <-- {packet:?}", self.client_id());
+
+                    let now = Instant::now();
+                    last_read = now;
+                    throttled_until = None;
+                    if let Some(limiter) = &mut self.limiter {
+                        if let Some(delay) = limiter.record(&packet, now) {
+                            if limiter.policy == RateLimitPolicy::Disconnect {
+                                warn!(target: target::SERVER, "{} exceeded its rate limit, closing connection.", self.client_id());
+                                return Err(ClientError::RateLimited);
+                            }
+                            debug!(target: target::SERVER, "{} exceeded its rate limit, throttling for {delay:?}.", self.client_id());
+                            throttled_until = Some(now + delay);
+                        }
+                    }
 
                     let packet = match packet {
                         Packet::PingReq(..) => Some(Packet::PingResp(PingResp)),
                         Packet::Disconnect(..) => {
-                            info!("{} Client disconnected deliberately.", self.client_id());
+                            info!(target: target::SERVER, "{} Client disconnected deliberately.", self.client_id());
                             return Ok(());
                         }
                         Packet::Subscribe(subscribe) => {
-                            let mut topics = subscribe.topics();
+                            // The authorizer is asked here, rather than by the server, because the
+                            // SUBACK must carry a return code for every filter.
+                            let mut return_codes = vec![];
+                            let mut allowed = vec![];
+                            for (topic, qos) in subscribe.topics() {
+                                if validate::topic_filter(topic).is_err() {
+                                    warn!(target: target::SERVER, "{} subscribed to the invalid topic filter {topic:?}.", self.client_id());
+                                    return_codes.push(suback::ReturnCode::Failure);
+                                } else if self.can_subscribe(topic) {
+                                    return_codes.push(suback::ReturnCode::from(qos));
+                                    allowed.push((topic.to_owned(), qos));
+                                } else {
+                                    warn!(target: target::SERVER, "{} is not authorized to subscribe to {topic:?}.", self.client_id());
+                                    return_codes.push(suback::ReturnCode::Failure);
+                                }
+                            }
 
                             // This should not panic, as subscribe must contain 1 topic.
-                            let (_, qos) = topics.next().unwrap();
+                            let (first, rest) = return_codes.split_first().unwrap();
+                            let builder = rest.iter().fold(
+                                SubAck::builder(subscribe.packet_identifier(), *first),
+                                |builder, return_code| builder.add_return_code(*return_code),
+                            );
 
-                            let mut builder = SubAck::builder(subscribe.packet_identifier(), qos);
-                            for (_, qos) in topics {
-                                builder = builder.add_return_code(qos);
+                            // The topic filters were validated above.
+                            if let Ok(Some(subscribe)) = subscribe_all(allowed) {
+                                funnel
+                                    .send(Message::Packet(self.client_id().to_owned(), self.connection, Packet::Subscribe(subscribe)))
+                                    .await?;
                             }
-                            funnel
-                                .send(Message::Packet(self.client_id().to_owned(), Packet::Subscribe(subscribe)))
-                                .await?;
                             Some(builder.build_packet())
                         }
 
                         Packet::Publish(publish) => {
                             funnel
-                                .send(Message::Packet(self.client_id().to_owned(), Packet::Publish(publish)))
+                                .send(Message::Packet(self.client_id().to_owned(), self.connection, Packet::Publish(publish)))
                                 .await?;
                             None
                         }
-                        Packet::Connect(..) | Packet::SubAck(..) | Packet::PubAck(..) => {
-                            warn!("Client sent packet only a broker is allowed to send, closing connection.");
+                        Packet::Unsubscribe(unsubscribe) => {
+                            let unsuback = UnsubAck::new(unsubscribe.packet_identifier());
+                            funnel
+                                .send(Message::Packet(self.client_id().to_owned(), self.connection, Packet::Unsubscribe(unsubscribe)))
+                                .await?;
+                            Some(unsuback.into())
+                        }
+                        // Subscribers acknowledge publications with QoS 1 and 2, like wills.
+                        // The server doesn't retransmit, so it only completes the handshake
+                        // of QoS 2.
+                        Packet::PubAck(..) | Packet::PubComp(..) => None,
+                        Packet::PubRec(pubrec) => Some(PubRel::new(pubrec.packet_identifier()).into()),
+                        Packet::PubRel(pubrel) => Some(PubComp::new(pubrel.packet_identifier()).into()),
+                        Packet::Connect(..)
+                        | Packet::ConnAck(..)
+                        | Packet::SubAck(..)
+                        | Packet::UnsubAck(..)
+                        | Packet::PingResp(..) => {
+                            warn!(target: target::SERVER, "Client sent packet only a broker is allowed to send, closing connection.");
                             return Err(ClientError::UnexpectedPacket);
                         }
-
-                        other => todo!("{other:?} is not yet implemented"),
                     };
 
                     if let Some(packet) = packet {
                         self.send(packet).await?;
                     }
                 },
+                _ = expired.fuse() => {
+                    warn!(target: target::SERVER, "{} exceeded its keep alive interval, closing connection.", self.client_id());
+                    return Err(ClientError::KeepAliveTimeout);
+                }
                 packet = rx.recv().fuse()=> {
                     match packet {
                         Ok(packet) => {
-                            self.send(packet).await?;
+                            // Write the packets that queued up in one go.
+                            let mut batch = vec![packet];
+                            while let Ok(packet) = rx.try_recv() {
+                                batch.push(packet);
+                            }
+                            self.send_batch(batch).await?;
                         }
-                        Err(error) => {
-                            warn!("{} - connection lost: {error:?}", self.client_id());
-                            return Err(ClientError::ServerError);
+                        // The server closes the channel when another connection takes over,
+                        // or when the client can't keep up with its publications.
+                        Err(_) => {
+                            info!(target: target::SERVER, "{} - Closed by the server, closing connection.", self.client_id());
+                            return Err(ClientError::ClosedByServer);
                         }
                     }
                 }
@@ -289,20 +1717,26 @@ impl Client {
     }
 }
 
-async fn read_packet<R>(reader: &mut R) -> Result<Packet, DecodingError>
+// Read a packet from `reader`. Fails with `DecodingError::TooManyBytes` if
+// the packet is larger than `limit` bytes.
+async fn read_packet<R>(reader: &mut R, limit: usize) -> Result<Packet, DecodingError>
 where
     R: AsyncRead + Unpin,
 {
     let mut parser = Parser::new();
     loop {
-        let bytes_required = parser.bytes_required() as usize;
+        let bytes_required = parser.bytes_required()? as usize;
         if bytes_required == 0 {
             return parser.parse();
         }
 
+        if parser.len() + bytes_required > limit {
+            return Err(DecodingError::TooManyBytes);
+        }
+
         let mut buf = vec![0; bytes_required];
         if let Err(error) = reader.read_exact(&mut buf).await {
-            error!("Failed to read data from client's TCP connection: {error:?}");
+            error!(target: target::SERVER, "Failed to read data from client's TCP connection: {error:?}");
             return Err(DecodingError::TooManyBytes);
         }
         parser.push(&buf);
@@ -322,7 +1756,11 @@ impl Parser {
         self.inner.append(&mut data.to_vec());
     }
 
-    fn bytes_required(&self) -> u32 {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn bytes_required(&self) -> Result<u32, DecodingError> {
         packet::min_bytes_required(&self.inner)
     }
 
@@ -337,86 +1775,42 @@ impl Parser {
     }
 }
 
-#[derive(Clone)]
-enum Message {
-    Register(String, Sender<Packet>),
-    Packet(String, Packet),
-}
-
-// Verify if a topic match a subscription. The subscription may
-// include wildcards like `#` and `+`.
-fn does_topic_match_subscription(subscription: &str, topic: &str) -> bool {
-    // If no wild cards are used, check for exact match
-    if !subscription.contains('#') && !subscription.contains('+') {
-        return subscription == topic;
-    }
-
-    if let Some((prefix, _)) = subscription.split_once('#') {
-        return topic.starts_with(prefix);
-    }
-
-    let mut topic_segments = topic.split('/');
+// A client known to the server: its current connection and its subscriptions.
+struct Peer {
+    // The number of the current connection. See `Server::run()`.
+    connection: u64,
+    sender: Sender<Packet>,
+    topics: Vec<String>,
 
-    for filter in subscription.split('/') {
-        // The topic and a subscription using `+` must have the same
-        // number of segments. If the topic has less segments, it is no match.
-        let Some(segment) = topic_segments.next() else {
-            return false;
-        };
-
-        if filter == "+" {
-            continue;
-        }
+    // Whether the current connection is open.
+    connected: bool,
 
-        if filter != segment {
-            return false;
-        }
-    }
+    // Whether the current connection accepted to compress publications.
+    #[cfg(feature = "compression")]
+    compressed: bool,
+}
 
-    // The topic and a subscription using `+` must have the same
-    // number of segments. If the topic has more segments, it is no match.
-    if topic_segments.next().is_some() {
-        return false;
+impl Peer {
+    fn is_compressed(&self) -> bool {
+        #[cfg(feature = "compression")]
+        return self.compressed;
+        #[cfg(not(feature = "compression"))]
+        false
     }
-
-    true
 }
 
-#[cfg(test)]
-mod test {
-    use super::does_topic_match_subscription;
-
-    #[test]
-    fn test_does_topic_match_subscription() {
-        assert!(does_topic_match_subscription(
-            "sensors/3/value",
-            "sensors/3/value"
-        ));
-
-        assert!(does_topic_match_subscription(
-            "sensors/+/value",
-            "sensors/3/value"
-        ));
-
-        assert!(does_topic_match_subscription(
-            "sensors/+/+",
-            "sensors/3/value"
-        ));
-
-        assert!(does_topic_match_subscription(
-            "sensors/#",
-            "sensors/3/value"
-        ));
-
-        // These topics don't match
-        assert!(!does_topic_match_subscription(
-            "sensors/3/value",
-            "sensors/1/value"
-        ));
+// Messages from the connections to the server. Each carries the client id and
+// the number of the connection.
+#[derive(Clone)]
+enum Message {
+    Register {
+        client_id: String,
+        connection: u64,
+        clean_session: bool,
+        sender: Sender<Packet>,
+    },
+    Packet(String, u64, Packet),
 
-        assert!(!does_topic_match_subscription(
-            "sensors/+/value",
-            "sensors/1/name"
-        ));
-    }
+    // The connection of a client closed. The flag is set if the client sent a DISCONNECT.
+    Disconnected(String, u64, bool),
 }
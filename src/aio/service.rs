@@ -0,0 +1,171 @@
+//! A [`PublishService`] to compose publishing with middleware.
+//!
+//! Frameworks with middleware stacks, like [tower](https://docs.rs/tower), model a request
+//! as a service with `poll_ready()` and `call()`. `PublishService` follows that shape,
+//! so retries, rate limits and metrics can be layered around publishing.
+//! It doesn't depend on tower itself. Implementing `tower::Service` is a thin wrapper:
+//!
+//! ```ignore
+//! impl tower::Service<Publish> for MyService {
+//!     type Response = ();
+//!     type Error = HandleError;
+//!     type Future = Acknowledgement;
+//!
+//!     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+//!         self.0.poll_ready(cx)
+//!     }
+//!
+//!     fn call(&mut self, publish: Publish) -> Self::Future {
+//!         self.0.call(publish)
+//!     }
+//! }
+//! ```
+use crate::{Command, HandleError, MqttBinding, Publish, Termination};
+use async_channel::{Receiver, Sender};
+use event_listener::{Event, EventListener};
+use futures::future::BoxFuture;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// The future returned by [`PublishService::call()`].
+///
+/// It resolves once the server acknowledged the publication.
+pub type Acknowledgement = BoxFuture<'static, Result<(), HandleError>>;
+
+/// Publish through a [`ClientHandle`](super::ClientHandle), in the shape of a `tower::Service`.
+///
+/// Obtain it with [`ClientHandle::publish_service()`](super::ClientHandle::publish_service()).
+///
+/// ```no_run
+/// # use async_net::TcpStream;
+/// # use tjiftjaf::{publish, Connect, aio::Client};
+/// # smol::block_on(async {
+/// # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+/// # let client = Client::new(Connect::builder().build(), stream);
+/// # let (handle, task) = client.spawn();
+/// let mut service = handle.publish_service();
+/// futures::future::poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+/// service.call(publish("sensor/1/temperature", "21.3")).await.unwrap();
+/// # });
+/// ```
+pub struct PublishService {
+    sender: Sender<Command>,
+    termination: Termination,
+    capacity: Capacity,
+
+    // Set while `poll_ready()` waits for room in the queue.
+    listener: Option<EventListener>,
+}
+
+impl PublishService {
+    pub(crate) fn new(
+        sender: Sender<Command>,
+        termination: Termination,
+        capacity: Capacity,
+    ) -> Self {
+        Self {
+            sender,
+            termination,
+            capacity,
+            listener: None,
+        }
+    }
+
+    /// Returns `Poll::Ready(Ok(()))` when the queue of the [`Client`](super::Client) has capacity.
+    ///
+    /// Fails if the `Client` terminated.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), HandleError>> {
+        loop {
+            if self.sender.is_closed() {
+                self.listener = None;
+                return Poll::Ready(Err(self.termination.error()));
+            }
+            if !self.sender.is_full() {
+                self.listener = None;
+                return Poll::Ready(Ok(()));
+            }
+
+            // The channel doesn't notify when room frees up, the `Client` does. Check
+            // the channel again after listening, so a notification can't be missed.
+            match &mut self.listener {
+                Some(listener) => {
+                    futures::ready!(Pin::new(listener).poll(cx));
+                    self.listener = None;
+                }
+                None => self.listener = Some(self.capacity.0.listen()),
+            }
+        }
+    }
+
+    /// Publish `publish`.
+    ///
+    /// The returned future resolves once the server acknowledged the publication. That is a
+    /// [`PubAck`](crate::PubAck) for QoS 1, and a [`PubComp`](crate::PubComp) for QoS 2.
+    /// Publications with QoS 0 are never acknowledged, so those resolve once the `Client` queued it.
+    ///
+    /// Fails with [`HandleError::Backpressure`] if the `Client` has no packet identifier
+    /// available for the publication.
+    pub fn call(&mut self, publish: Publish) -> Acknowledgement {
+        let sender = self.sender.clone();
+        let termination = self.termination.clone();
+        Box::pin(async move {
+            let (queued, _) = async_channel::bounded(1);
+            let (reply, acknowledgement) = async_channel::bounded(1);
+            sender
+                .send(Command::Publish(publish, queued, reply))
+                .await
+                .map_err(|_| termination.error())?;
+            acknowledgement
+                .recv()
+                .await
+                .map_err(|_| termination.error())?
+        })
+    }
+}
+
+impl Clone for PublishService {
+    fn clone(&self) -> Self {
+        Self::new(
+            self.sender.clone(),
+            self.termination.clone(),
+            self.capacity.clone(),
+        )
+    }
+}
+
+// Wakes the `PublishService`s waiting for room in the queue of a `Client`.
+#[derive(Clone, Default)]
+pub(crate) struct Capacity(Arc<Event>);
+
+impl Capacity {
+    fn notify(&self) {
+        self.0.notify(usize::MAX);
+    }
+}
+
+// The commands for a `Client`. Once the event loop took commands from it, it wakes
+// the services waiting for room. It closes when it's dropped, so the services also learn
+// when the future of the event loop is dropped.
+pub(crate) struct Queue {
+    pub(crate) receiver: Receiver<Command>,
+    pub(crate) capacity: Capacity,
+}
+
+impl Queue {
+    // Like `Command::apply_pending()`, but wake the services waiting for room.
+    pub(crate) fn apply_pending(&self, binding: &mut MqttBinding) {
+        Command::apply_pending(&self.receiver, binding);
+        self.capacity.notify();
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.receiver.close();
+        self.capacity.notify();
+    }
+}
@@ -0,0 +1,70 @@
+//! Timers for the event loop of the [`Client`](crate::aio::Client).
+//!
+//! The event loop wakes up to emit keep alives. By default, it waits with the timer of
+//! [async-io](https://docs.rs/async-io), which runs its own reactor thread. Applications that
+//! use another runtime can pick a timer of that runtime with
+//! [`Client::timer()`](crate::aio::Client::timer()), or implement [`Sleep`] themselves.
+//!
+//! ```no_run
+//! # #[cfg(feature = "tokio")]
+//! # async fn example() {
+//! use tjiftjaf::{aio::{sleep::Tokio, Client}, Connect};
+//! use tokio::net::TcpStream;
+//! use tokio_util::compat::TokioAsyncReadCompatExt;
+//!
+//! let stream = TcpStream::connect("localhost:1883").await.unwrap().compat();
+//! let client = Client::new(Connect::builder().build(), stream).timer::<Tokio>();
+//! let (handle, task) = client.spawn();
+//! # }
+//! ```
+use futures::{future::BoxFuture, FutureExt};
+use std::{future::Future, time::Instant};
+
+/// A timer of an async runtime.
+pub trait Sleep {
+    /// The future returned by [`Sleep::sleep_until()`].
+    type Delay: Future;
+
+    /// Returns a future that resolves at `deadline`.
+    fn sleep_until(deadline: Instant) -> Self::Delay;
+}
+
+// `Sleep::sleep_until()` of a timer, with the type of the timer erased. The handles
+// and tokens of a `Client` use it, so they don't need to be generic over the timer.
+pub(crate) type SleepFn = fn(Instant) -> BoxFuture<'static, ()>;
+
+// Returns the `SleepFn` of the timer `T`.
+pub(crate) fn erase<T: Sleep>() -> SleepFn
+where
+    T::Delay: Send + 'static,
+{
+    |deadline| T::sleep_until(deadline).map(|_| ()).boxed()
+}
+
+/// The timer of [async-io](https://docs.rs/async-io). It's the default.
+#[derive(Debug)]
+pub struct AsyncIo;
+
+impl Sleep for AsyncIo {
+    type Delay = async_io::Timer;
+
+    fn sleep_until(deadline: Instant) -> Self::Delay {
+        async_io::Timer::at(deadline)
+    }
+}
+
+/// The timer of [tokio](https://docs.rs/tokio). Requires the feature `tokio`.
+///
+/// The future of the client must run within a tokio runtime that has the time driver enabled.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Sleep for Tokio {
+    type Delay = tokio::time::Sleep;
+
+    fn sleep_until(deadline: Instant) -> Self::Delay {
+        tokio::time::sleep_until(deadline.into())
+    }
+}
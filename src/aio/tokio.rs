@@ -0,0 +1,128 @@
+//! A [`Client`] that runs on [tokio](https://docs.rs/tokio). Requires the feature `tokio`.
+//!
+//! The client of [`aio`](crate::aio) accepts any socket that implements the I/O traits of
+//! [futures](https://docs.rs/futures). The I/O traits of tokio are different, so sockets of tokio
+//! need an adapter. This module provides that adapter, [`Compat`], and a [`Client`] that combines
+//! it with a socket of tokio and the timer [`Tokio`]. No bridge to another runtime is needed.
+//!
+//! ```no_run
+//! use tjiftjaf::{aio::{tokio::Client, Emit}, subscribe, Connect};
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() {
+//!     let connect = Connect::builder().client_id("tjiftjaf").build();
+//!     let client = Client::connect("localhost:1883", connect).await.unwrap();
+//!     let (mut handle, task) = client.spawn();
+//!
+//!     tokio::select! {
+//!         _ = task => {}
+//!         _ = async {
+//!             subscribe("sensor/+/temperature").emit(&handle).await.unwrap();
+//!             while let Ok(publication) = handle.subscriptions().await {
+//!                 println!("{}: {:?}", publication.topic(), publication.payload());
+//!             }
+//!         } => {}
+//!     }
+//! }
+//! ```
+use super::sleep::Tokio;
+use crate::{Connect, Session};
+use ::tokio::{
+    io::ReadBuf,
+    net::{TcpStream, ToSocketAddrs},
+};
+use std::{
+    io::Error,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// A client of [`aio`](crate::aio) with a socket of tokio, that waits for keep alives
+/// with the timer of tokio.
+///
+/// Create one with [`Client::connect()`], or wrap an existing socket with
+/// [`Client::from_tokio()`]. All other methods are those of [`aio::Client`](crate::aio::Client).
+pub type Client<S = TcpStream> = super::Client<Compat<S>, Tokio>;
+
+impl Client {
+    /// Connect to the broker at `addr`.
+    ///
+    /// The duration of the TCP connect is recorded in the [`Statistics`](crate::Statistics).
+    pub async fn connect(addr: impl ToSocketAddrs, connect: Connect) -> Result<Self, Error> {
+        let start = Instant::now();
+        let socket = TcpStream::connect(addr).await?;
+        let mut client = Self::from_tokio(connect, socket);
+        client.binding.statistics.connect_duration = Some(start.elapsed());
+        Ok(client)
+    }
+}
+
+impl<S> super::Client<Compat<S>, Tokio>
+where
+    S: ::tokio::io::AsyncRead + ::tokio::io::AsyncWrite + Unpin + Send,
+{
+    /// Create a `Client` from a socket of tokio. The given [`Connect`] is
+    /// the first message emitted to the server.
+    pub fn from_tokio(connect: Connect, socket: S) -> Self {
+        super::Client::new(connect, Compat(socket)).timer()
+    }
+
+    /// Create a `Client` from a socket of tokio, that continues a [`Session`].
+    ///
+    /// See [`aio::Client::resume()`](crate::aio::Client::resume()).
+    pub fn resume_tokio(session: Session, socket: S) -> Self {
+        super::Client::resume(session, Compat(socket)).timer()
+    }
+}
+
+/// Implements the I/O traits of futures for a type that implements the I/O traits of tokio.
+#[derive(Debug)]
+pub struct Compat<S>(S);
+
+impl<S> Compat<S> {
+    /// Wrap `socket`.
+    pub fn new(socket: S) -> Self {
+        Self(socket)
+    }
+
+    /// A reference to the wrapped socket.
+    pub fn get_ref(&self) -> &S {
+        &self.0
+    }
+
+    /// Unwrap the socket.
+    pub fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+impl<S: ::tokio::io::AsyncRead + Unpin> futures::AsyncRead for Compat<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        let mut buf = ReadBuf::new(buf);
+        futures::ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl<S: ::tokio::io::AsyncWrite + Unpin> futures::AsyncWrite for Compat<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
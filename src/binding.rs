@@ -0,0 +1,4111 @@
+// The sans-io state machine of the client, and the commands that the handles of
+// a client send to it. It requires the standard library.
+#[cfg(feature = "compression")]
+use crate::compression;
+use crate::{
+    decode, intercept, packet, packet_identifier, quirks, rewrite, target, wire, ConnAck, Connect,
+    DecodingError, Disconnect, EncodingError, Interceptor, Packet, PacketType, PingReq, PubAck,
+    PubComp, PubRec, PubRel, Publish, QoS, SessionStore, SubAck, Subscribe, TopicRewrite, UnsubAck,
+    Unsubscribe,
+};
+#[cfg(feature = "async")]
+use crate::{topic, unsubscribe};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use crate::{HandleError, SubscribeError, SubscriptionResult};
+use core::{error::Error, fmt::Display};
+use log::{debug, error, trace, warn};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+#[derive(Default, Debug)]
+enum State {
+    // The state machine is waiting for the start of a new packet.
+    #[default]
+    StartOfHeader,
+
+    // The state machine processed first half of a header and is waiting
+    // for the remainder of the header.
+    EndOfHeader {
+        partial_header: Vec<u8>,
+    },
+
+    // The state machine processed the header and it knows the length
+    // of the entire packet. Now it waits for the remaining bytes
+    // to complete the packet.
+    RestOfPacket {
+        header: Vec<u8>,
+        // The number of bytes that are pending.
+        bytes_remaining: u32,
+    },
+}
+
+// The default limit of `MqttBinding::set_max_pending_transmits()`.
+const DEFAULT_MAX_PENDING_TRANSMITS: usize = 1024;
+
+// The default limit of `MqttBinding::set_max_inflight()`.
+const DEFAULT_MAX_INFLIGHT: usize = u16::MAX as usize;
+
+// The default limit of `MqttBinding::set_max_buffered_publications()`.
+const DEFAULT_MAX_BUFFERED_PUBLICATIONS: usize = 100;
+
+// The number of keep alives returned by `MqttBinding::pings()`.
+const PING_HISTORY: usize = 16;
+
+// See `MqttBinding::set_probe()` and `MqttBinding::set_keep_alive_timeout()`.
+#[derive(Copy, Clone, Debug)]
+struct Probe {
+    // How long the server may be silent before it's probed. Without an interval, only
+    // the PINGREQs of the keep alive and of the application are timed.
+    interval: Option<Duration>,
+
+    // How long a PINGREQ may remain unanswered.
+    timeout: Duration,
+}
+
+pub struct MqttBinding {
+    connection_status: ConnectionStatus,
+    state: State,
+
+    // Packets waiting to be transmitted, in order of transmission.
+    transmits: VecDeque<Packet>,
+
+    // The number of transmits above which `MqttBinding::try_send()` refuses packets.
+    max_pending_transmits: usize,
+
+    // The number of outbound publications with QoS > 0 that may be
+    // unacknowledged at the same time.
+    max_inflight: usize,
+
+    // Outbound PUBLISH packets with QoS > 0 that are not yet
+    // acknowledged by the server, indexed by their packet identifier.
+    inflight: BTreeMap<u16, Publish>,
+
+    // The number of inbound publications that may wait for the application,
+    // before the binding withholds the acknowledgements of new ones.
+    max_buffered_publications: usize,
+
+    // Inbound publications returned by `MqttBinding::try_decode()` that the event
+    // loop didn't deliver to the application yet.
+    buffered_publications: usize,
+
+    // Acknowledgements of inbound publications that exceeded `max_buffered_publications`,
+    // oldest first. One is released for every publication delivered to the application.
+    withheld_acknowledgements: VecDeque<Packet>,
+
+    // Outbound QoS 2 publications for which the server sent a PUBREC,
+    // but not yet a PUBCOMP. The client must never retransmit these PUBLISH
+    // packets, only the PUBREL.
+    released: BTreeSet<u16>,
+
+    // Inbound QoS 2 publications delivered to the application, for which
+    // the server didn't send a PUBREL yet. A retransmission of these must
+    // not be delivered again.
+    received: BTreeSet<u16>,
+
+    // How long inbound QoS 1 publications are remembered to drop redeliveries, if configured.
+    deduplication_window: Option<Duration>,
+
+    // Inbound QoS 1 publications delivered within `deduplication_window`, oldest first.
+    delivered: VecDeque<(u16, String, Instant)>,
+
+    // The packet identifiers and topics of `delivered`, for a quick lookup. Maps to
+    // the last delivery, so an earlier delivery that's evicted doesn't remove the entry.
+    delivered_index: HashMap<(u16, String), Instant>,
+
+    // SUBSCRIBE packets that are not yet acknowledged by the server,
+    // indexed by their packet identifier.
+    pending_subscriptions: BTreeMap<u16, Subscribe>,
+
+    // UNSUBSCRIBE packets that are not yet acknowledged by the server,
+    // indexed by their packet identifier.
+    pending_unsubscriptions: BTreeMap<u16, Unsubscribe>,
+
+    // Topic filters the client subscribed to.
+    subscriptions: Vec<(String, QoS)>,
+
+    decode_error_policy: DecodeErrorPolicy,
+
+    // The size of the largest inbound packet the binding accepts, if limited.
+    max_packet_size: Option<usize>,
+
+    // Deviations from the specification that inbound packets may have.
+    quirks: quirks::Quirks,
+
+    // Why the connection was terminated.
+    disconnected: Option<ClientDisconnected>,
+
+    // Handles waiting for the acknowledgement of an outbound publication
+    // or unsubscribe, indexed by packet identifier. The packet identifiers
+    // bound it to 65535 entries.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    acknowledgements: BTreeMap<u16, Waiter>,
+
+    // How long a handle waits for an acknowledgement before it's resolved
+    // with `HandleError::Timeout`, if limited.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    acknowledgement_timeout: Option<Duration>,
+
+    // Handles waiting for the SUBACK of a subscribe, indexed by packet identifier.
+    // They time out like `acknowledgements`.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    subscribe_replies: BTreeMap<u16, Waiter<SubscribeReply>>,
+
+    // The `aio::Subscription`s that receive the publications matching their topic filter.
+    #[cfg(feature = "async")]
+    routes: Vec<Route>,
+
+    // The `aio::Subscription`s that receive every publication, with `aio::DeliveryMode::Broadcast`.
+    #[cfg(feature = "async")]
+    broadcasts: Vec<async_channel::Sender<Packet>>,
+
+    // The streams of `aio::ClientHandle::raw_packets()` that receive a copy of every packet.
+    #[cfg(all(feature = "async", feature = "experimental"))]
+    raw_packets: Vec<async_channel::Sender<Packet>>,
+
+    // The most recent inbound publications, oldest first. Routes registered later
+    // may replay them. At most `max_replay` are kept.
+    #[cfg(feature = "async")]
+    replay: VecDeque<Publish>,
+    #[cfg(feature = "async")]
+    max_replay: usize,
+
+    // Where the session is saved after every change.
+    store: Option<Box<dyn SessionStore + Send>>,
+
+    // Rules that map the topics of the application to the topics of the server.
+    topic_rewrites: Vec<TopicRewrite>,
+
+    // Hooks that inspect packets, in the order they're added.
+    interceptors: Vec<Box<dyn Interceptor + Send>>,
+
+    pub(crate) statistics: Statistics,
+
+    // The most recent keep alives, oldest first. At most `PING_HISTORY` are kept.
+    pings: VecDeque<Ping>,
+
+    // The sequence number of the next keep alive.
+    next_ping: u64,
+
+    // Detects half-open connections and unanswered keep alives, if configured.
+    probe: Option<Probe>,
+
+    // How long the binding waits for the CONNACK, if limited.
+    connect_timeout: Option<Duration>,
+
+    // How to compress publications, if offered to the server.
+    #[cfg(feature = "compression")]
+    compression: Option<compression::Compression>,
+
+    // Whether the server accepted the offer to compress publications.
+    #[cfg(feature = "compression")]
+    compression_negotiated: bool,
+
+    // The last time bytes were received from the server, or the CONNECT was transmitted.
+    // `None` until the CONNECT is transmitted.
+    last_read: Option<Instant>,
+
+    // The last time a packet was transmitted.
+    last_io: Option<Instant>,
+
+    // The keep alive interval in seconds of the current connection. It's taken from
+    // the CONNECT when it's transmitted, so `MqttBinding::set_connect()` doesn't affect it.
+    keep_alive: u16,
+    connect: Connect,
+}
+
+impl MqttBinding {
+    /// Construct an new `MqttBinding`. The given `Connect` is
+    /// the first message emitted to the server.
+    pub fn from_connect(connect: Connect) -> Self {
+        Self {
+            connection_status: ConnectionStatus::default(),
+            state: State::default(),
+            transmits: VecDeque::new(),
+            max_pending_transmits: DEFAULT_MAX_PENDING_TRANSMITS,
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+            inflight: BTreeMap::new(),
+            max_buffered_publications: DEFAULT_MAX_BUFFERED_PUBLICATIONS,
+            buffered_publications: 0,
+            withheld_acknowledgements: VecDeque::new(),
+            released: BTreeSet::new(),
+            received: BTreeSet::new(),
+            deduplication_window: None,
+            delivered: VecDeque::new(),
+            delivered_index: HashMap::new(),
+            pending_subscriptions: BTreeMap::new(),
+            pending_unsubscriptions: BTreeMap::new(),
+            subscriptions: vec![],
+            decode_error_policy: DecodeErrorPolicy::default(),
+            max_packet_size: None,
+            quirks: quirks::Quirks::NONE,
+            disconnected: None,
+            #[cfg(any(feature = "blocking", feature = "async"))]
+            acknowledgements: BTreeMap::new(),
+            #[cfg(any(feature = "blocking", feature = "async"))]
+            acknowledgement_timeout: None,
+            #[cfg(any(feature = "blocking", feature = "async"))]
+            subscribe_replies: BTreeMap::new(),
+            #[cfg(feature = "async")]
+            routes: vec![],
+            #[cfg(feature = "async")]
+            broadcasts: vec![],
+            #[cfg(all(feature = "async", feature = "experimental"))]
+            raw_packets: vec![],
+            #[cfg(feature = "async")]
+            replay: VecDeque::new(),
+            #[cfg(feature = "async")]
+            max_replay: 0,
+            store: None,
+            topic_rewrites: vec![],
+            interceptors: vec![],
+            statistics: Statistics::default(),
+            pings: VecDeque::new(),
+            next_ping: 0,
+            probe: None,
+            connect_timeout: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "compression")]
+            compression_negotiated: false,
+            last_read: None,
+            last_io: None,
+            keep_alive: connect.keep_alive(),
+            connect,
+        }
+    }
+
+    /// Construct a new `MqttBinding` that continues a [`Session`].
+    ///
+    /// After connecting, the binding subscribes to the topics of the session again
+    /// and retransmits all publications of the session.
+    pub fn from_session(session: Session) -> Self {
+        let mut binding = Self::from_connect(session.connect);
+        binding.received = session.received.into_iter().collect();
+
+        // Retransmissions must keep their packet identifiers.
+        for publish in session.publications {
+            binding.push(publish.into());
+        }
+        for packet_identifier in session.released {
+            binding.released.insert(packet_identifier);
+            binding.push(PubRel::new(packet_identifier).into());
+        }
+
+        // The SUBSCRIBE gets an identifier that doesn't collide with the retransmissions,
+        // but it's transmitted first.
+        let mut subscriptions = session.subscriptions.into_iter();
+        if let Some((topic, qos)) = subscriptions.next() {
+            let mut builder = Subscribe::builder(topic, qos);
+            for (topic, qos) in subscriptions {
+                builder = builder.add_topic(topic, qos);
+            }
+            binding.send(builder.build_packet());
+            binding.transmits.rotate_right(1);
+        }
+
+        binding
+    }
+
+    /// Configure what happens when the server sends a frame that can't be decoded.
+    pub fn set_decode_error_policy(&mut self, policy: DecodeErrorPolicy) {
+        self.decode_error_policy = policy;
+    }
+
+    /// Terminate the connection with [`DecodingError::PacketTooLarge`] when the server sends
+    /// a packet larger than `size` bytes, including its fixed header.
+    ///
+    /// The packet is refused as soon as its fixed header is received, before the rest of it is
+    /// buffered. By default, packets are only limited by
+    /// [`MAX_REMAINING_LENGTH`](crate::MAX_REMAINING_LENGTH).
+    ///
+    /// The limit applies to outbound packets too: a PUBLISH, SUBSCRIBE or UNSUBSCRIBE larger
+    /// than `size` bytes is dropped instead of sent, and its handle fails with
+    /// [`HandleError::Dropped`]. Refuse a publication before it's queued with
+    /// [`publish::Builder::max_packet_size()`](packet::publish::Builder::max_packet_size()).
+    pub fn set_max_packet_size(&mut self, size: usize) {
+        self.max_packet_size = Some(size);
+    }
+
+    /// Give up waiting for the acknowledgement of an outbound publication, subscribe or
+    /// unsubscribe `timeout` after it was transmitted.
+    ///
+    /// The handle waiting for it, like an [`aio::DeliveryToken`](crate::aio::DeliveryToken),
+    /// then fails with [`HandleError::Timeout`], or [`SubscribeError::Timeout`] for a
+    /// subscribe. The binding still retransmits the publication and keeps its packet
+    /// identifier until the server acknowledges it. By default, handles wait as long as the
+    /// connection lasts.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    pub fn set_acknowledgement_timeout(&mut self, timeout: Duration) {
+        self.acknowledgement_timeout = Some(timeout);
+    }
+
+    /// Tolerate inbound packets that deviate from the specification in the ways of `quirks`.
+    ///
+    /// See [`quirks`] for the deviations. By default, none are tolerated.
+    pub fn set_quirks(&mut self, quirks: quirks::Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Configure how many packets may wait for transmission before
+    /// [`MqttBinding::try_send()`] returns [`QueueFull`]. The default is 1024.
+    pub fn set_max_pending_transmits(&mut self, limit: usize) {
+        self.max_pending_transmits = limit;
+    }
+
+    /// Configure how many publications with QoS 1 or 2 may be unacknowledged by the
+    /// server at the same time. The default is 65535, the number of packet identifiers.
+    ///
+    /// When the window is full, [`MqttBinding::poll_transmits()`] holds back these
+    /// publications until the server acknowledges earlier ones. Other packets, like
+    /// acknowledgements and keep alives, are transmitted as usual.
+    pub fn set_max_inflight(&mut self, limit: usize) {
+        self.max_inflight = limit;
+    }
+
+    /// Configure how many inbound publications may wait for the application before the
+    /// binding throttles the server. The default is 100.
+    ///
+    /// An event loop reports with [`MqttBinding::publication_delivered()`] that the application
+    /// accepted a publication returned by [`MqttBinding::try_decode()`]. Once the limit is
+    /// reached, the binding withholds the PUBACK or PUBREC of new publications with QoS 1 or 2,
+    /// until the application catches up. The server stops sending these publications when
+    /// its window of unacknowledged publications is full. Control packets, like keep alives,
+    /// keep flowing in the meantime.
+    ///
+    /// MQTT 3.1.1 has no flow control for publications with QoS 0, so these are returned
+    /// by `try_decode()` regardless of the limit.
+    pub fn set_max_buffered_publications(&mut self, limit: usize) {
+        self.max_buffered_publications = limit;
+    }
+
+    /// Report that the application accepted a publication returned by
+    /// [`MqttBinding::try_decode()`]. See [`MqttBinding::set_max_buffered_publications()`].
+    pub fn publication_delivered(&mut self) {
+        self.buffered_publications = self.buffered_publications.saturating_sub(1);
+
+        // The oldest withheld publication moves into the window.
+        if let Some(acknowledgement) = self.withheld_acknowledgements.pop_front() {
+            self.transmits.push_back(acknowledgement);
+        }
+    }
+
+    /// Detect half-open connections within seconds, instead of after a full keep alive cycle.
+    ///
+    /// A connection whose peer vanished, for example because a NAT dropped its mapping,
+    /// goes unnoticed until the TCP stack gives up retransmitting. That takes many minutes.
+    /// With a probe, the binding sends a PINGREQ when nothing was received from the server
+    /// for `interval`, regardless of the keep alive interval. If a PINGREQ isn't answered
+    /// within `timeout`, the binding terminates the connection with
+    /// [`ClientDisconnected::Unresponsive`].
+    ///
+    /// On Linux, the socket option `TCP_USER_TIMEOUT` complements the probe: it bounds how
+    /// long transmitted data may remain unacknowledged by the peer. Set it on the socket
+    /// before passing it to a client, for example with `set_tcp_user_timeout()` of
+    /// [socket2](https://docs.rs/socket2).
+    ///
+    /// The `timeout` replaces the grace period of [`MqttBinding::set_keep_alive_timeout()`].
+    pub fn set_probe(&mut self, interval: Duration, timeout: Duration) {
+        self.probe = Some(Probe {
+            interval: Some(interval),
+            timeout,
+        });
+    }
+
+    /// Terminate the connection with [`ClientDisconnected::KeepAliveTimeout`] when the server
+    /// doesn't answer a PINGREQ within `grace`.
+    ///
+    /// By default, unanswered keep alives are only recorded in [`MqttBinding::pings()`], and
+    /// the binding keeps waiting for the server. The grace period applies to every PINGREQ,
+    /// including those of a probe and those sent by the application. Pick it well below
+    /// the keep alive interval to notice a dead connection before the next keep alive.
+    ///
+    /// It's a probe without an interval. With a probe configured by
+    /// [`MqttBinding::set_probe()`], this replaces its timeout, and the connection
+    /// terminates with [`ClientDisconnected::Unresponsive`] instead.
+    pub fn set_keep_alive_timeout(&mut self, grace: Duration) {
+        match &mut self.probe {
+            Some(probe) => probe.timeout = grace,
+            None => {
+                self.probe = Some(Probe {
+                    interval: None,
+                    timeout: grace,
+                })
+            }
+        }
+    }
+
+    /// Terminate the connection with [`ClientDisconnected::ConnectTimeout`] when the server
+    /// doesn't answer the CONNECT with a CONNACK within `timeout`.
+    ///
+    /// By default, the binding waits for the CONNACK as long as the connection lasts.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = Some(timeout);
+    }
+
+    /// Offer the server to compress publications. See the module [`compression`].
+    ///
+    /// The offer is sent after the server accepted the connection. Until the server
+    /// answers, and if it never does, publications are sent as is.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, compression: compression::Compression) {
+        self.compression = Some(compression);
+    }
+
+    /// Drop redeliveries of inbound QoS 1 publications that arrive within `window`
+    /// of the original.
+    ///
+    /// A server redelivers QoS 1 publications it didn't receive a PUBACK for, for example
+    /// after a reconnect. By default, these reach the application again. With a window,
+    /// a publication with the DUP flag set is acknowledged but dropped, if a publication
+    /// with the same packet identifier and topic was delivered within `window`.
+    ///
+    /// This is best-effort: a redelivery after the window passes is delivered, and
+    /// a server may redeliver with another packet identifier. Delivered publications are
+    /// only remembered by this binding, not by a [`Session`]. Applications that can't
+    /// tolerate duplicates should use QoS 2 or be idempotent.
+    pub fn set_deduplication_window(&mut self, window: Duration) {
+        self.deduplication_window = Some(window);
+    }
+
+    /// Replace the [`Connect`] used to connect to the server, for example to change
+    /// the credentials or the will.
+    ///
+    /// If the CONNECT wasn't transmitted yet, `connect` is transmitted instead. Otherwise,
+    /// the current connection is unaffected, and `connect` applies to the next connection:
+    /// it's part of the [`Session`] returned by [`MqttBinding::session()`]
+    /// and [`MqttBinding::suspend()`].
+    pub fn set_connect(&mut self, connect: Connect) {
+        self.connect = connect;
+        self.persist();
+    }
+
+    /// Rewrite the topics exchanged with the server. See [`TopicRewrite`].
+    ///
+    /// Rules are tried in the order they're added. The first matching rule is applied.
+    pub fn add_topic_rewrite(&mut self, rewrite: TopicRewrite) {
+        self.topic_rewrites.push(rewrite);
+    }
+
+    /// Returns the error that made the binding terminate the connection, if any.
+    ///
+    /// See [`DecodeErrorPolicy`].
+    pub fn decoding_error(&self) -> Option<&DecodingError> {
+        match &self.disconnected {
+            Some(ClientDisconnected::ProtocolError(error)) => Some(error),
+            _ => None,
+        }
+    }
+
+    // Terminate the connection.
+    fn disconnect(&mut self, reason: ClientDisconnected) {
+        self.connection_status = ConnectionStatus::Disconnected;
+        self.disconnected = Some(reason);
+    }
+
+    // Refuse an inbound packet of `size` bytes, if it exceeds `max_packet_size`.
+    fn check_packet_size(&self, size: u32) -> Result<(), DecodingError> {
+        match self.max_packet_size {
+            Some(limit) if size as usize > limit => Err(DecodingError::PacketTooLarge {
+                size: size as usize,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Refuse an outbound PUBLISH, SUBSCRIBE or UNSUBSCRIBE, if it exceeds `max_packet_size`.
+    // Other packets are small, and dropping them would break the protocol.
+    fn check_outbound_size(&self, packet: &Packet) -> Result<(), EncodingError> {
+        if !matches!(
+            packet,
+            Packet::Publish(..) | Packet::Subscribe(..) | Packet::Unsubscribe(..)
+        ) {
+            return Ok(());
+        }
+        let size = packet.length();
+        match self.max_packet_size {
+            Some(limit) if size > limit => Err(EncodingError::PacketTooLarge { size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    // Apply the `DecodeErrorPolicy` to a frame that failed to decode.
+    // `skippable` indicates whether the binding knows where the next frame starts.
+    fn handle_decoding_error(&mut self, error: DecodingError, skippable: bool) {
+        self.state = State::StartOfHeader;
+
+        if skippable && self.decode_error_policy == DecodeErrorPolicy::SkipPacket {
+            warn!(target: target::CODEC, "Skipping a packet that failed to decode: {error:?}");
+            return;
+        }
+
+        error!(target: target::CODEC, "Terminating the connection, because a packet failed to decode: {error:?}");
+        self.disconnect(ClientDisconnected::ProtocolError(error));
+    }
+
+    // Acknowledge an inbound publication. Returns `false` if the publication is a
+    // retransmission of a publication that was already delivered to the application.
+    //
+    // The state of an inbound QoS 2 publication is tracked in `received`:
+    // PUBLISH --> insert, reply PUBREC
+    // PUBREL  --> remove, reply PUBCOMP
+    fn handle_publish(&mut self, publish: &Publish, now: Instant) -> bool {
+        match (publish.qos(), publish.packet_identifier()) {
+            (QoS::AtLeastOnceDelivery, Some(packet_identifier)) => {
+                if self.is_redelivery(publish, packet_identifier, now) {
+                    self.transmits
+                        .push_back(PubAck::new(packet_identifier).into());
+
+                    debug!(target: target::BINDING, "Dropping redelivery of PUBLISH {packet_identifier}");
+                    return false;
+                }
+
+                self.acknowledge_publication(PubAck::new(packet_identifier).into());
+                true
+            }
+            (QoS::ExactlyOnceDelivery, Some(packet_identifier)) => {
+                if self.received.insert(packet_identifier) {
+                    self.acknowledge_publication(PubRec::new(packet_identifier).into());
+                    return true;
+                }
+
+                // A retransmission is acknowledged as well. The PUBREC of the
+                // original publication might have been lost.
+                self.transmits
+                    .push_back(PubRec::new(packet_identifier).into());
+
+                debug!(target: target::BINDING, "Dropping retransmission of PUBLISH {packet_identifier}");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Whether an inbound QoS 1 publication was delivered within the deduplication window.
+    // Otherwise, it's remembered for the window.
+    fn is_redelivery(&mut self, publish: &Publish, packet_identifier: u16, now: Instant) -> bool {
+        let Some(window) = self.deduplication_window else {
+            return false;
+        };
+
+        while let Some((_, _, delivered)) = self.delivered.front() {
+            if now.saturating_duration_since(*delivered) < window {
+                break;
+            }
+            let Some((id, topic, delivered)) = self.delivered.pop_front() else {
+                break;
+            };
+            let key = (id, topic);
+            if self.delivered_index.get(&key) == Some(&delivered) {
+                self.delivered_index.remove(&key);
+            }
+        }
+
+        let key = (packet_identifier, publish.topic().to_string());
+        if publish.duplicate() && self.delivered_index.contains_key(&key) {
+            return true;
+        }
+
+        self.delivered.push_back((key.0, key.1.clone(), now));
+        self.delivered_index.insert(key, now);
+        false
+    }
+
+    // Transmit the acknowledgement of an inbound publication, unless too many
+    // publications wait for the application already.
+    fn acknowledge_publication(&mut self, acknowledgement: Packet) {
+        if self.buffered_publications >= self.max_buffered_publications {
+            self.withheld_acknowledgements.push_back(acknowledgement);
+            return;
+        }
+        self.transmits.push_back(acknowledgement);
+    }
+
+    // Complete an inbound QoS 2 publication. The server may retransmit the PUBREL,
+    // so it's acknowledged even if the publication is not known.
+    fn handle_pubrel(&mut self, pubrel: &PubRel) {
+        self.received.remove(&pubrel.packet_identifier());
+        self.transmits
+            .push_back(PubComp::new(pubrel.packet_identifier()).into());
+    }
+
+    fn handle_connack(&mut self, connack: &ConnAck) {
+        if connack.return_code() == packet::connack::ReturnCode::ConnectionAccepted {
+            self.connection_status = ConnectionStatus::Connected;
+            #[cfg(feature = "compression")]
+            if self.compression.is_some() {
+                self.transmits.push_back(compression::offer().into());
+            }
+            return;
+        }
+
+        warn!(target: target::BINDING,
+            "The server refused the connection: {:?}",
+            connack.return_code()
+        );
+        self.disconnect(ClientDisconnected::Refused(connack.clone()));
+    }
+
+    // Notify the handle waiting for the acknowledgement of a publication
+    // or unsubscribe, if any.
+    #[cfg_attr(
+        not(any(feature = "blocking", feature = "async")),
+        allow(unused_variables)
+    )]
+    fn acknowledge(&mut self, packet_identifier: u16) {
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        if let Some(waiter) = self.remove_waiter(packet_identifier) {
+            _ = waiter.reply.try_send(Ok(()));
+        }
+    }
+
+    // Register the handle waiting for the acknowledgement of `packet_identifier`.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn insert_waiter(&mut self, packet_identifier: u16, reply: Reply) {
+        let waiter = Waiter { reply, sent: None };
+        self.acknowledgements.insert(packet_identifier, waiter);
+        self.statistics.pending_acknowledgements = self.acknowledgements.len();
+    }
+
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn remove_waiter(&mut self, packet_identifier: u16) -> Option<Waiter> {
+        let waiter = self.acknowledgements.remove(&packet_identifier);
+        self.statistics.pending_acknowledgements = self.acknowledgements.len();
+        waiter
+    }
+
+    // Resolve the handles that waited longer than the acknowledgement timeout.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn evict_waiters(&mut self, now: Instant) {
+        let Some(timeout) = self.acknowledgement_timeout else {
+            return;
+        };
+        self.subscribe_replies.retain(|packet_identifier, waiter| {
+            let expired = waiter.sent.is_some_and(|sent| now >= sent + timeout);
+            if expired {
+                warn!(target: target::BINDING,
+                    "The server didn't acknowledge subscribe {packet_identifier} within {timeout:?}."
+                );
+                _ = waiter.reply.try_send(Err(SubscribeError::Timeout));
+            }
+            !expired
+        });
+        self.acknowledgements.retain(|packet_identifier, waiter| {
+            let expired = waiter.sent.is_some_and(|sent| now >= sent + timeout);
+            if expired {
+                warn!(target: target::BINDING,
+                    "The server didn't acknowledge packet {packet_identifier} within {timeout:?}."
+                );
+                _ = waiter.reply.try_send(Err(HandleError::Timeout));
+            }
+            !expired
+        });
+        self.statistics.pending_acknowledgements = self.acknowledgements.len();
+    }
+
+    // Stop routing publications for the topic filters the server unsubscribed from.
+    // That ends their `aio::Subscription`s.
+    #[cfg_attr(not(feature = "async"), allow(unused_variables))]
+    fn handle_unsuback(&mut self, unsuback: &UnsubAck) {
+        let packet_identifier = unsuback.packet_identifier();
+        let unsubscribe = self.pending_unsubscriptions.remove(&packet_identifier);
+        #[cfg(feature = "async")]
+        if let Some(unsubscribe) = unsubscribe {
+            // A topic filter that was subscribed to again in the meantime keeps its routes.
+            let topics: Vec<&str> = unsubscribe
+                .topics()
+                .filter(|topic| !self.subscriptions.iter().any(|(filter, _)| filter == topic))
+                .collect();
+            self.routes
+                .retain(|route| !topics.contains(&route.filter.as_str()));
+        }
+        self.acknowledge(packet_identifier);
+    }
+
+    // The channels of the routes that match the topic of `publish`.
+    // Routes whose `aio::Subscription` is dropped are removed.
+    #[cfg(feature = "async")]
+    pub(crate) fn routes(&mut self, publish: &Publish) -> Vec<async_channel::Sender<Packet>> {
+        self.remove_routes(|route| route.sender.is_closed());
+        self.routes
+            .iter()
+            .filter(|route| topic::does_topic_match_subscription(&route.filter, publish.topic()))
+            .map(|route| route.sender.clone())
+            .collect()
+    }
+
+    // The channels of the `aio::Subscription`s that receive every publication.
+    // Channels whose `aio::Subscription` is dropped are removed.
+    #[cfg(feature = "async")]
+    pub(crate) fn broadcasts(&mut self) -> Vec<async_channel::Sender<Packet>> {
+        self.broadcasts.retain(|sender| !sender.is_closed());
+        self.broadcasts.clone()
+    }
+
+    // The channels of the streams that receive a copy of every packet.
+    // Channels whose stream is dropped are removed.
+    #[cfg(all(feature = "async", feature = "experimental"))]
+    pub(crate) fn raw_packets(&mut self) -> Vec<async_channel::Sender<Packet>> {
+        self.raw_packets.retain(|sender| !sender.is_closed());
+        self.raw_packets.clone()
+    }
+
+    // Remove the routes for which `remove` returns `true`. If a removed route had a lease,
+    // and no other route has its topic filter, unsubscribe from the topic filter.
+    #[cfg(feature = "async")]
+    fn remove_routes(&mut self, remove: impl Fn(&Route) -> bool) {
+        let (removed, kept): (Vec<Route>, Vec<Route>) = core::mem::take(&mut self.routes)
+            .into_iter()
+            .partition(remove);
+        self.routes = kept;
+
+        let mut unsubscribed: Vec<String> = vec![];
+        for route in removed {
+            if route.lease.is_none()
+                || unsubscribed.contains(&route.filter)
+                || self.routes.iter().any(|other| other.filter == route.filter)
+            {
+                continue;
+            }
+
+            debug!(target: target::BINDING,
+                "The lease of the subscription to '{}' expired, unsubscribing.",
+                route.filter
+            );
+            if let Err(error) = self.enqueue(unsubscribe(&route.filter).into()) {
+                error!(target: target::BINDING, "Dropping {:?}: {error}", error.0.packet_type());
+            }
+            unsubscribed.push(route.filter);
+        }
+    }
+
+    // Keep the last `limit` inbound publications, so routes registered later can replay them.
+    #[cfg(feature = "async")]
+    pub(crate) fn set_max_replay(&mut self, limit: usize) {
+        self.max_replay = limit;
+        self.replay.truncate(limit);
+    }
+
+    // Remember the inbound `publish` for routes that are registered later.
+    #[cfg(feature = "async")]
+    pub(crate) fn remember(&mut self, publish: &Publish) {
+        if self.max_replay == 0 {
+            return;
+        }
+        if self.replay.len() == self.max_replay {
+            self.replay.pop_front();
+        }
+        self.replay.push_back(publish.clone());
+    }
+
+    // Register a route for `filter` and pass it the last `replay` remembered publications
+    // that match, oldest first.
+    #[cfg(feature = "async")]
+    fn add_route(
+        &mut self,
+        filter: String,
+        sender: async_channel::Sender<Packet>,
+        replay: usize,
+        lease: Option<Lease>,
+    ) {
+        let matching: Vec<&Publish> = self
+            .replay
+            .iter()
+            .filter(|publish| topic::does_topic_match_subscription(&filter, publish.topic()))
+            .collect();
+        for publish in &matching[matching.len().saturating_sub(replay)..] {
+            _ = sender.try_send(Packet::Publish((*publish).clone()));
+        }
+        self.routes.push(Route {
+            filter,
+            sender,
+            lease,
+        });
+    }
+
+    // The server received an outbound QoS 2 publication. From now on, only its PUBREL
+    // may be retransmitted.
+    //
+    // The state of an outbound QoS 2 publication moves from `inflight` to `released`:
+    // PUBLISH --> insert in `inflight`
+    // PUBREC  --> move to `released`, reply PUBREL
+    // PUBCOMP --> remove from `released`
+    fn handle_pubrec(&mut self, pubrec: &PubRec) {
+        if self.inflight.remove(&pubrec.packet_identifier()).is_some() {
+            self.released.insert(pubrec.packet_identifier());
+        }
+        self.transmits
+            .push_back(PubRel::new(pubrec.packet_identifier()).into());
+    }
+
+    // Forget the topics that the server rejected.
+    fn handle_suback(&mut self, suback: &SubAck) {
+        let Some(subscribe) = self
+            .pending_subscriptions
+            .remove(&suback.packet_identifier())
+        else {
+            return;
+        };
+
+        for topic in subscribe.rejected_topics(suback) {
+            warn!(target: target::BINDING, "The server rejected the subscription to '{topic}'.");
+            self.subscriptions.retain(|(filter, _)| filter != topic);
+        }
+
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        if let Some(waiter) = self.subscribe_replies.remove(&suback.packet_identifier()) {
+            _ = waiter.reply.try_send(subscribe.check(suback));
+        }
+    }
+
+    /// Handle the expiry of the deadline returned by [`MqttBinding::poll_timeout()`].
+    ///
+    /// The binding never reads the clock itself, `now` is the only source of time.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        // Nothing is timed before the CONNECT is transmitted.
+        let (Some(last_read), Some(last_io)) = (self.last_read, self.last_io) else {
+            return;
+        };
+
+        #[cfg(feature = "async")]
+        self.remove_routes(|route| {
+            route.sender.is_closed() || route.lease.as_ref().is_some_and(|lease| lease.expired(now))
+        });
+
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        self.evict_waiters(now);
+
+        if let Some(deadline) = self.connect_deadline() {
+            if now >= deadline {
+                warn!(target: target::BINDING, "The server didn't answer the CONNECT in time.");
+                self.disconnect(ClientDisconnected::ConnectTimeout);
+                return;
+            }
+        }
+
+        if let Some(probe) = self.probe {
+            if let Some(sent) = self.unanswered_ping() {
+                if now >= sent + probe.timeout {
+                    warn!(target: target::BINDING,
+                        "The server didn't answer a PINGREQ within {:?}.",
+                        probe.timeout
+                    );
+                    self.disconnect(match probe.interval {
+                        Some(_) => ClientDisconnected::Unresponsive,
+                        None => ClientDisconnected::KeepAliveTimeout,
+                    });
+                }
+                return;
+            }
+
+            let silent = probe
+                .interval
+                .is_some_and(|interval| now >= last_read + interval);
+            if silent && !self.pings.iter().any(|ping| ping.sent.is_none()) {
+                debug!(target: target::BINDING,
+                    "The server was silent for {:?}, probing it.",
+                    now - last_read
+                );
+                self.record_ping(now);
+                self.transmits.push_back(Packet::PingReq(PingReq));
+                return;
+            }
+        }
+
+        // A keep alive of 0 seconds turns the keep alive mechanism off. The binding is
+        // still woken up for other deadlines, like expiring leases, but it must never
+        // emit a PINGREQ for them.
+        if self.keep_alive != 0
+            && now.saturating_duration_since(last_io).as_secs() >= self.keep_alive as u64
+        {
+            let scheduled = self.poll_timeout(now);
+            self.record_ping(scheduled);
+            self.transmits.push_back(Packet::PingReq(PingReq))
+        }
+    }
+
+    // Start tracking a keep alive that's due at `scheduled`.
+    fn record_ping(&mut self, scheduled: Instant) {
+        if self.pings.len() == PING_HISTORY {
+            self.pings.pop_front();
+        }
+        self.pings.push_back(Ping {
+            sequence: self.next_ping,
+            scheduled,
+            sent: None,
+            answered: None,
+        });
+        self.next_ping += 1;
+    }
+
+    // When the oldest keep alive that was sent, but not yet answered, was sent.
+    fn unanswered_ping(&self) -> Option<Instant> {
+        self.pings
+            .iter()
+            .find(|ping| ping.answered.is_none())
+            .and_then(|ping| ping.sent)
+    }
+
+    // Mark the oldest unanswered keep alive as answered.
+    fn handle_pingresp(&mut self, now: Instant) {
+        match self
+            .pings
+            .iter_mut()
+            .find(|ping| ping.sent.is_some() && ping.answered.is_none())
+        {
+            Some(ping) => ping.answered = Some(now),
+            None => warn!(target: target::BINDING, "Received a PINGRESP without a PINGREQ."),
+        }
+    }
+
+    /// The most recent keep alives, oldest first.
+    ///
+    /// Each [`Ping`] records when its PINGREQ was due, when it was transmitted
+    /// and when the server answered with a PINGRESP. A keep alive that was sent,
+    /// but never answered, points at a server or network that drops packets.
+    pub fn pings(&self) -> impl Iterator<Item = &Ping> {
+        self.pings.iter()
+    }
+
+    /// The moment [`MqttBinding::handle_timeout()`] must be called.
+    ///
+    /// Until the CONNECT is transmitted nothing is scheduled, and the binding asks to be
+    /// polled at `now`.
+    pub fn poll_timeout(&self, now: Instant) -> Instant {
+        self.deadline().unwrap_or(now)
+    }
+
+    // The moment the binding gives up waiting for the CONNACK, if it's limited.
+    fn connect_deadline(&self) -> Option<Instant> {
+        if self.connection_status != ConnectionStatus::Connecting {
+            return None;
+        }
+        // Nothing but the CONNECT is transmitted before the CONNACK arrives.
+        Some(self.last_io? + self.connect_timeout?)
+    }
+
+    // The deadline of the next keep alive, probe, lease, acknowledgement timeout
+    // or connect timeout, if the CONNECT was transmitted.
+    fn deadline(&self) -> Option<Instant> {
+        let (last_read, last_io) = (self.last_read?, self.last_io?);
+
+        let mut interval = self.keep_alive as u64;
+        if interval == 0 {
+            // If keep_alive() interval is 0 seconds, the client is not supposed
+            // to emit PINGREQ requests. Therefore, binding does not have to be woken up
+            // X seconds after the last IO to schedule a PINGREQ.
+            //
+            // Unfortunately, there is not a way to obtain the maximum value of `Instant`.
+            // For example,  `Instant::MAX` does not exists. So we return an `Instant`
+            // roughly 30 years from now. It is inspired by Tokio's `Instant::far_future()`
+            // https://github.com/tokio-rs/tokio/blob/365269adaf6ec75743c0693f2378c3c6d04f806b/tokio/src/time/instant.rs#L57-L63
+            //
+            // See also https://internals.rust-lang.org/t/instant-systemtime-min-max/21375/16
+            interval = 86400 * 365 * 30
+        }
+
+        let mut deadline = last_io.checked_add(Duration::from_secs(interval)).unwrap();
+
+        let unanswered = self.unanswered_ping();
+        if let Some(connect) = self.connect_deadline() {
+            deadline = deadline.min(connect);
+        }
+
+        if let Some(probe) = self.probe {
+            let probe = match (unanswered, probe.interval) {
+                (Some(sent), _) => Some(sent + probe.timeout),
+                (None, Some(interval)) => Some(last_read + interval),
+                (None, None) => None,
+            };
+            deadline = deadline.min(probe.unwrap_or(deadline));
+        }
+
+        #[cfg(feature = "async")]
+        for lease in self.routes.iter().filter_map(|route| route.lease.as_ref()) {
+            deadline = deadline.min(lease.expiry().unwrap_or(deadline));
+        }
+
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        if let Some(timeout) = self.acknowledgement_timeout {
+            let subscribes = self.subscribe_replies.values().map(|waiter| waiter.sent);
+            for sent in self
+                .acknowledgements
+                .values()
+                .map(|waiter| waiter.sent)
+                .chain(subscribes)
+                .flatten()
+            {
+                deadline = deadline.min(sent + timeout);
+            }
+        }
+        Some(deadline)
+    }
+
+    /// Retrieve an input buffer. The event loop must fill the buffer and pass it to `Self::try_decode()`.
+    pub fn get_read_buffer(&mut self) -> Vec<u8> {
+        match self.state {
+            State::StartOfHeader => {
+                trace!(target: target::CODEC, "Waiting for start of header.");
+                vec![0; 2]
+            }
+            State::EndOfHeader { .. } => {
+                trace!(target: target::CODEC, "Waiting for end of the header.");
+                vec![0; 2]
+            }
+            State::RestOfPacket {
+                bytes_remaining, ..
+            } => {
+                trace!(target: target::CODEC, "Waiting for remainder of the packet.");
+                vec![0; bytes_remaining as usize]
+            }
+        }
+    }
+
+    /// Like [`MqttBinding::poll_transmits()`], but coalesce the transmits that are ready
+    /// into a single buffer. That way, the event loop writes to the socket once for
+    /// many small packets.
+    ///
+    /// Transmits are added until the buffer holds at least `max_bytes`. If the connection
+    /// must be closed, the transmits before that are returned first, and the next call
+    /// returns the `Err()`.
+    pub fn poll_transmits_batch(
+        &mut self,
+        now: Instant,
+        max_bytes: usize,
+    ) -> Result<Option<Vec<u8>>, ClientDisconnected> {
+        let Some(mut batch) = self.poll_transmits(now)? else {
+            return Ok(None);
+        };
+        while batch.len() < max_bytes {
+            match self.poll_transmits(now) {
+                Ok(Some(bytes)) => batch.extend_from_slice(&bytes),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        Ok(Some(batch))
+    }
+
+    /// Retrieve bytes that must be transmitted to the server.
+    ///
+    /// Packets are transmitted in the order they're queued, except for publications
+    /// that are held back by [`MqttBinding::set_max_inflight()`].
+    ///
+    /// `Ok(None)` indicates no bytes are ready to be sent.
+    /// `Err()` indicates that the connection must be closed.
+    pub fn poll_transmits(&mut self, now: Instant) -> Result<Option<Vec<u8>>, ClientDisconnected> {
+        if let Some(reason) = &self.disconnected {
+            return Err(reason.clone());
+        }
+
+        // The event loop calls this after every change, so it's where leases learn the time.
+        #[cfg(feature = "async")]
+        for lease in self.routes.iter().filter_map(|route| route.lease.as_ref()) {
+            lease.renew(now);
+        }
+
+        if self.connection_status == ConnectionStatus::NotConnected {
+            self.connection_status = ConnectionStatus::Connecting;
+
+            self.keep_alive = self.connect.keep_alive();
+            let packet: Packet = self.connect.clone().into();
+            wire::log(wire::Direction::Outbound, &packet);
+            self.statistics.record_outbound_packet(&packet, now);
+
+            // The keep alive and the probe are timed from here on.
+            self.last_io = Some(now);
+            self.last_read.get_or_insert(now);
+            return Ok(Some(packet.into_bytes()));
+        }
+        if self.connection_status == ConnectionStatus::Connecting {
+            return Ok(None);
+        }
+
+        while let Some(mut packet) = self.next_transmit() {
+            if !self.intercept_outbound(&mut packet) {
+                continue;
+            }
+
+            // A packet that would escape the rewritten topics is dropped.
+            let rewritten = match rewrite::outbound(&self.topic_rewrites, &packet) {
+                Ok(rewritten) => rewritten,
+                Err(error) => {
+                    warn!(target: target::BINDING, "Dropping {:?}, its topics can't be rewritten: {error}", packet.packet_type());
+                    self.discard(&packet);
+                    continue;
+                }
+            };
+
+            // The server would disconnect for a packet that exceeds the limit.
+            if let Err(error) = self.check_outbound_size(rewritten.as_ref().unwrap_or(&packet)) {
+                warn!(target: target::BINDING, "Dropping {:?}: {error}", packet.packet_type());
+                self.discard(&packet);
+                continue;
+            }
+
+            match &packet {
+                Packet::Disconnect(..) => self.disconnect(ClientDisconnected::Requested),
+                Packet::PingReq(..) => {
+                    // The application might send a PINGREQ itself.
+                    if !self.pings.iter().any(|ping| ping.sent.is_none()) {
+                        self.record_ping(now);
+                    }
+                    if let Some(ping) = self.pings.iter_mut().find(|ping| ping.sent.is_none()) {
+                        ping.sent = Some(now);
+                    }
+                }
+                Packet::Publish(publish) => {
+                    if let Some(packet_identifier) = publish.packet_identifier() {
+                        self.inflight.insert(packet_identifier, publish.clone());
+                    }
+                }
+                Packet::Subscribe(subscribe) => {
+                    for (topic, qos) in subscribe.topics() {
+                        self.subscriptions.retain(|(filter, _)| filter != topic);
+                        self.subscriptions.push((topic.to_owned(), qos));
+                    }
+                    self.pending_subscriptions
+                        .insert(subscribe.packet_identifier(), subscribe.clone());
+                }
+                Packet::Unsubscribe(unsubscribe) => {
+                    for topic in unsubscribe.topics() {
+                        self.subscriptions.retain(|(filter, _)| filter != topic);
+                    }
+                    self.pending_unsubscriptions
+                        .insert(unsubscribe.packet_identifier(), unsubscribe.clone());
+                }
+                _ => {}
+            };
+            // A retransmission doesn't restart the acknowledgement timeout.
+            #[cfg(any(feature = "blocking", feature = "async"))]
+            if let Some(packet_identifier) = packet.allocated_packet_identifier() {
+                let sent = match packet {
+                    Packet::Subscribe(..) => self
+                        .subscribe_replies
+                        .get_mut(&packet_identifier)
+                        .map(|waiter| &mut waiter.sent),
+                    _ => self
+                        .acknowledgements
+                        .get_mut(&packet_identifier)
+                        .map(|waiter| &mut waiter.sent),
+                };
+                if let Some(sent) = sent {
+                    sent.get_or_insert(now);
+                }
+            }
+            // The bookkeeping above uses the topics of the application.
+            let packet = rewritten.unwrap_or(packet);
+            #[cfg(feature = "compression")]
+            let packet = self.compress(packet);
+            self.last_io = Some(now);
+            wire::log(wire::Direction::Outbound, &packet);
+            self.statistics.record_outbound_packet(&packet, now);
+
+            return Ok(Some(packet.into_bytes()));
+        }
+
+        Ok(None)
+    }
+
+    // Compress an outbound publication, if the server accepted compression.
+    #[cfg(feature = "compression")]
+    fn compress(&self, packet: Packet) -> Packet {
+        let Some(compression) = self.compression.filter(|_| self.compression_negotiated) else {
+            return packet;
+        };
+        match &packet {
+            Packet::Publish(publish) => compression
+                .compress(publish)
+                .map(Packet::from)
+                .unwrap_or(packet),
+            _ => packet,
+        }
+    }
+
+    // Inflate an inbound publication, if it was compressed. Returns `None` if the packet
+    // is the server's answer to the offer to compress publications.
+    #[cfg(feature = "compression")]
+    fn decompress(&mut self, packet: Packet) -> Option<Packet> {
+        let Packet::Publish(publish) = &packet else {
+            return Some(packet);
+        };
+        let Some(compression) = self.compression else {
+            return Some(packet);
+        };
+        if compression::is_offer(publish) {
+            debug!(target: target::BINDING, "The server accepted to compress publications.");
+            self.compression_negotiated = true;
+            return None;
+        }
+        match compression.decompress(publish) {
+            Ok(Some(inflated)) => Some(inflated.into()),
+            Ok(None) => Some(packet),
+            Err(error) => {
+                warn!(target: target::BINDING, "Not inflating the publication on {:?}: {error}", publish.topic());
+                Some(packet)
+            }
+        }
+    }
+
+    // Forget an outbound PUBLISH, SUBSCRIBE or UNSUBSCRIBE that is dropped instead of
+    // transmitted, and resolve the handle waiting for it with `HandleError::Dropped`.
+    fn discard(&mut self, packet: &Packet) {
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        match packet {
+            Packet::Subscribe(subscribe) => {
+                if let Some(waiter) = self
+                    .subscribe_replies
+                    .remove(&subscribe.packet_identifier())
+                {
+                    _ = waiter.reply.try_send(Err(HandleError::Dropped.into()));
+                }
+            }
+            packet => {
+                if let Some(waiter) = packet
+                    .allocated_packet_identifier()
+                    .and_then(|packet_identifier| self.remove_waiter(packet_identifier))
+                {
+                    _ = waiter.reply.try_send(Err(HandleError::Dropped));
+                }
+            }
+        }
+
+        // A retransmission of a restored session might be inflight already.
+        if let Packet::Publish(publish) = packet {
+            if let Some(packet_identifier) = publish.packet_identifier() {
+                self.inflight.remove(&packet_identifier);
+                self.persist();
+            }
+        }
+    }
+
+    // Run the interceptors on an outbound packet. Returns `false` if one drops it.
+    fn intercept_outbound(&mut self, packet: &mut Packet) -> bool {
+        let dropped = self
+            .interceptors
+            .iter_mut()
+            .any(|interceptor| interceptor.on_outbound(packet) == intercept::Verdict::Drop);
+        if !dropped {
+            return true;
+        }
+
+        // Dropping any other packet would break the protocol, or the state of the binding.
+        if !matches!(
+            packet,
+            Packet::Publish(_) | Packet::Subscribe(_) | Packet::Unsubscribe(_)
+        ) {
+            warn!(target: target::BINDING, "Interceptors can't drop {:?}, sending it anyway", packet.packet_type());
+            return true;
+        }
+
+        debug!(target: target::BINDING, "An interceptor dropped {packet:?}");
+        self.discard(packet);
+        false
+    }
+
+    // Run the interceptors on an inbound packet. Returns `None` if one drops it.
+    fn intercept_inbound(&mut self, mut packet: Packet) -> Option<Packet> {
+        let dropped = self
+            .interceptors
+            .iter_mut()
+            .any(|interceptor| interceptor.on_inbound(&mut packet) == intercept::Verdict::Drop);
+        if !dropped {
+            return Some(packet);
+        }
+
+        debug!(target: target::BINDING, "An interceptor dropped {packet:?}");
+        // The application never sees the publication, so it's delivered as far
+        // as flow control is concerned.
+        if let Packet::Publish(_) = packet {
+            self.publication_delivered();
+        }
+        None
+    }
+
+    // Take the first transmit that may be sent. If the inflight window is full,
+    // publications that require an acknowledgement are skipped.
+    fn next_transmit(&mut self) -> Option<Packet> {
+        if self.inflight.len() + self.released.len() < self.max_inflight {
+            return self.transmits.pop_front();
+        }
+
+        let position = self.transmits.iter().position(|packet| match packet {
+            Packet::Publish(publish) => publish.packet_identifier().is_none(),
+            _ => true,
+        })?;
+        self.transmits.remove(position)
+    }
+
+    /// Try parsing the bytes as a Packet.
+    pub fn try_decode(&mut self, mut buf: Vec<u8>, now: Instant) -> Option<Packet> {
+        self.last_read = Some(now);
+        let (state, packet) = match &self.state {
+            State::StartOfHeader => {
+                // MQTT uses between 1 and 3 (including) bytes to encode the
+                // length of the packet.
+                let packet_length = match decode::packet_length(&buf[1..]) {
+                    Ok(packet_length) => packet_length,
+                    // `buf` doesn't contain enough bytes to decode the length.
+                    // At maximum, 2 more bytes are required to make the header complete.
+                    Err(decode::DecodingError::NotEnoughBytes { .. }) => {
+                        self.state = State::EndOfHeader {
+                            partial_header: buf,
+                        };
+                        return None;
+                    }
+                    Err(error) => {
+                        self.handle_decoding_error(error, false);
+                        return None;
+                    }
+                };
+
+                if let Err(error) = self.check_packet_size(packet_length) {
+                    self.handle_decoding_error(error, false);
+                    return None;
+                }
+
+                let bytes_remaining = packet_length - buf.len() as u32;
+                if bytes_remaining == 0 {
+                    self.quirks.apply(&mut buf);
+                    match Packet::try_from(buf) {
+                        Ok(packet) => {
+                            wire::log(wire::Direction::Inbound, &packet);
+                            self.statistics.record_inbound_packet(&packet, now);
+                            if let Packet::PingResp(..) = packet {
+                                self.handle_pingresp(now);
+                            }
+
+                            return self.intercept_inbound(packet);
+                        }
+                        Err(error) => {
+                            self.handle_decoding_error(error, true);
+                            return None;
+                        }
+                    };
+                }
+
+                (
+                    State::RestOfPacket {
+                        header: buf,
+                        bytes_remaining,
+                    },
+                    None,
+                )
+            }
+            State::EndOfHeader { ref partial_header } => {
+                let header = {
+                    // TODO: Remove to_owned()
+                    let mut partial_header = partial_header.to_owned();
+                    partial_header.append(&mut buf);
+                    partial_header
+                };
+
+                let packet_length = match decode::packet_length(&header[1..]) {
+                    Ok(packet_length) => packet_length,
+                    Err(error) => {
+                        self.handle_decoding_error(error, false);
+                        return None;
+                    }
+                };
+
+                if let Err(error) = self.check_packet_size(packet_length) {
+                    self.handle_decoding_error(error, false);
+                    return None;
+                }
+
+                let bytes_remaining = packet_length - header.len() as u32;
+                (
+                    State::RestOfPacket {
+                        // TODO: remove clone
+                        header: header.clone(),
+                        bytes_remaining,
+                    },
+                    None,
+                )
+            }
+
+            State::RestOfPacket {
+                header: ref prefix,
+                bytes_remaining: length,
+            } => {
+                if buf.len() < *length as usize {
+                    let remaining_length = length - buf.len() as u32;
+                    let partial_header: Vec<u8> = {
+                        // TODO: remove to_owned()
+                        let mut prefix = prefix.to_owned();
+                        prefix.append(&mut buf);
+                        prefix
+                    };
+
+                    self.state = State::RestOfPacket {
+                        header: partial_header,
+                        bytes_remaining: remaining_length,
+                    };
+                    return None;
+                }
+
+                let mut frame = {
+                    // TODO: remove to_owned()
+                    let mut prefix = prefix.to_owned();
+                    prefix.append(&mut buf);
+                    prefix
+                };
+                self.quirks.apply(&mut frame);
+
+                let packet = match Packet::try_from(frame) {
+                    Ok(packet) => packet,
+                    Err(error) => {
+                        self.handle_decoding_error(error, true);
+                        return None;
+                    }
+                };
+
+                self.statistics.record_inbound_packet(&packet, now);
+                #[cfg(feature = "compression")]
+                let Some(packet) = self.decompress(packet) else {
+                    self.state = State::StartOfHeader;
+                    return None;
+                };
+                let mut retransmission = false;
+                match &packet {
+                    Packet::ConnAck(connack) => self.handle_connack(connack),
+                    Packet::Publish(publish) => retransmission = !self.handle_publish(publish, now),
+                    Packet::PubAck(ack) => {
+                        self.inflight.remove(&ack.packet_identifier());
+                        self.acknowledge(ack.packet_identifier());
+                    }
+                    Packet::PubRec(ack) => self.handle_pubrec(ack),
+                    Packet::PubRel(ack) => self.handle_pubrel(ack),
+                    Packet::PubComp(ack) => {
+                        self.released.remove(&ack.packet_identifier());
+                        self.acknowledge(ack.packet_identifier());
+                    }
+                    Packet::SubAck(suback) => self.handle_suback(suback),
+                    Packet::UnsubAck(unsuback) => self.handle_unsuback(unsuback),
+                    _ => {}
+                }
+
+                if retransmission {
+                    self.state = State::StartOfHeader;
+                    return None;
+                }
+                if let Packet::Publish(_) = packet {
+                    self.buffered_publications += 1;
+                }
+
+                let changed = match &packet {
+                    Packet::Publish(publish) => publish.qos() == QoS::ExactlyOnceDelivery,
+                    packet => matches!(
+                        packet.packet_type(),
+                        PacketType::PubAck
+                            | PacketType::PubRec
+                            | PacketType::PubRel
+                            | PacketType::PubComp
+                            | PacketType::SubAck
+                    ),
+                };
+                if changed {
+                    self.persist();
+                }
+
+                let packet = match rewrite::inbound(&self.topic_rewrites, &packet) {
+                    Ok(Some(rewritten)) => Some(rewritten),
+                    Ok(None) => Some(packet),
+                    Err(error) => {
+                        // The application never sees the publication, so it's delivered as far
+                        // as flow control is concerned.
+                        warn!(target: target::BINDING, "Dropping {:?}, its topic can't be rewritten: {error}", packet.packet_type());
+                        self.publication_delivered();
+                        None
+                    }
+                };
+                (
+                    State::StartOfHeader,
+                    packet.and_then(|packet| self.intercept_inbound(packet)),
+                )
+            }
+        };
+
+        self.state = state;
+        if let Some(packet) = &packet {
+            wire::log(wire::Direction::Inbound, packet);
+        }
+        packet
+    }
+
+    /// Whether [`MqttBinding::try_send()`] accepts another packet.
+    pub fn has_capacity(&self) -> bool {
+        self.transmits.len() < self.max_pending_transmits
+    }
+
+    /// Push a packet to the inner queue, unless the queue is full.
+    ///
+    /// See [`MqttBinding::set_max_pending_transmits()`]. Acknowledgements, keep alives
+    /// and [`Disconnect`] are always accepted. Otherwise, the binding could never
+    /// complete a handshake or terminate the connection.
+    ///
+    /// If the packet identifier of the packet is still in use by another packet, it's replaced
+    /// with one that isn't. When all 65535 packet identifiers are in use, the packet is refused.
+    pub fn try_send(&mut self, packet: Packet) -> Result<(), QueueFull> {
+        self.enqueue(packet).map(|_| ())
+    }
+
+    // Like `MqttBinding::try_send()`, but returns the packet identifier of the queued packet.
+    // It differs from the original identifier if that one was still in use.
+    pub(crate) fn enqueue(&mut self, mut packet: Packet) -> Result<Option<u16>, QueueFull> {
+        let bounded = matches!(
+            packet,
+            Packet::Publish(..) | Packet::Subscribe(..) | Packet::Unsubscribe(..)
+        );
+        if bounded && !self.has_capacity() {
+            return Err(QueueFull(packet));
+        }
+
+        let packet_identifier = match self.assign_packet_identifier(&mut packet) {
+            Ok(packet_identifier) => packet_identifier,
+            Err(error) => {
+                error!(target: target::BINDING, "Refusing {:?}: {error}", packet.packet_type());
+                return Err(QueueFull(packet));
+            }
+        };
+        self.push(packet);
+        Ok(packet_identifier)
+    }
+
+    /// Push a packet to the inner queue.
+    ///
+    /// Unlike [`MqttBinding::try_send()`], this ignores the limit of the queue. If all packet
+    /// identifiers are in use, the packet is queued with its original identifier.
+    pub fn send(&mut self, mut packet: Packet) {
+        if let Err(error) = self.assign_packet_identifier(&mut packet) {
+            warn!(target: target::BINDING, "Queuing {:?} anyway: {error}", packet.packet_type());
+        }
+        self.push(packet);
+    }
+
+    /// Allocate a packet identifier that isn't used by any packet that is in flight,
+    /// or waiting for transmission.
+    ///
+    /// Identifiers come from [`packet_identifier()`], so they wrap from 65535 to 1.
+    pub fn allocate_packet_identifier(&self) -> Result<u16, PacketIdentifiersExhausted> {
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        let acknowledgements = self.acknowledgements.keys();
+        #[cfg(not(any(feature = "blocking", feature = "async")))]
+        let acknowledgements = std::iter::empty();
+
+        let in_use: BTreeSet<u16> = self
+            .inflight
+            .keys()
+            .chain(self.released.iter())
+            .chain(self.pending_subscriptions.keys())
+            .chain(self.pending_unsubscriptions.keys())
+            .chain(acknowledgements)
+            .copied()
+            .chain(
+                self.transmits
+                    .iter()
+                    .filter_map(Packet::allocated_packet_identifier),
+            )
+            .collect();
+
+        if in_use.len() >= u16::MAX as usize {
+            return Err(PacketIdentifiersExhausted);
+        }
+        loop {
+            let packet_identifier = packet_identifier();
+            if !in_use.contains(&packet_identifier) {
+                return Ok(packet_identifier);
+            }
+        }
+    }
+
+    // Whether a packet in flight, or waiting for transmission, uses `packet_identifier`.
+    fn is_packet_identifier_in_use(&self, packet_identifier: u16) -> bool {
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        if self.acknowledgements.contains_key(&packet_identifier) {
+            return true;
+        }
+
+        self.inflight.contains_key(&packet_identifier)
+            || self.released.contains(&packet_identifier)
+            || self.pending_subscriptions.contains_key(&packet_identifier)
+            || self
+                .pending_unsubscriptions
+                .contains_key(&packet_identifier)
+            || self
+                .transmits
+                .iter()
+                .any(|packet| packet.allocated_packet_identifier() == Some(packet_identifier))
+    }
+
+    // Replace the packet identifier of `packet` if another packet uses it.
+    // Returns the packet identifier of `packet`, if it has one.
+    fn assign_packet_identifier(
+        &self,
+        packet: &mut Packet,
+    ) -> Result<Option<u16>, PacketIdentifiersExhausted> {
+        let Some(original) = packet.allocated_packet_identifier() else {
+            return Ok(None);
+        };
+        if !self.is_packet_identifier_in_use(original) {
+            return Ok(Some(original));
+        }
+
+        let packet_identifier = self.allocate_packet_identifier()?;
+        debug!(target: target::BINDING, "Packet identifier {original} is in use, replacing it with {packet_identifier}.");
+        packet.set_packet_identifier(packet_identifier);
+        Ok(Some(packet_identifier))
+    }
+
+    // Queue the publications of `batch` as a whole, or not at all. Returns their packet
+    // identifiers, in the order of the batch.
+    //
+    // Like a single publication, a batch is accepted while the queue has room. It may fill
+    // the queue beyond its limit, which holds back later packets until the queue drained.
+    // Otherwise, a large batch could wait forever for room. Batches that exceed the limit
+    // by themselves are refused.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn enqueue_batch(&mut self, batch: Vec<Publish>) -> Result<Vec<Option<u16>>, HandleError> {
+        if !self.has_capacity() || batch.len() > self.max_pending_transmits {
+            error!(target: target::BINDING,
+                "Dropping a batch of {} publications: the queue of transmits is full", batch.len()
+            );
+            return Err(HandleError::Backpressure);
+        }
+
+        let queued = self.transmits.len();
+        let mut packet_identifiers = Vec::with_capacity(batch.len());
+        for publish in batch {
+            let mut packet = Packet::from(publish);
+            match self.assign_packet_identifier(&mut packet) {
+                Ok(packet_identifier) => packet_identifiers.push(packet_identifier),
+                Err(error) => {
+                    error!(target: target::BINDING, "Dropping a batch of publications: {error}");
+                    self.transmits.truncate(queued);
+                    return Err(HandleError::Backpressure);
+                }
+            }
+            self.transmits.push_back(packet);
+        }
+
+        // Save the session once, rather than after every publication.
+        if packet_identifiers.iter().any(Option::is_some) {
+            self.persist();
+        }
+        Ok(packet_identifiers)
+    }
+
+    // Push a packet to the inner queue, as is.
+    fn push(&mut self, packet: Packet) {
+        let changed = match &packet {
+            Packet::Publish(publish) => publish.packet_identifier().is_some(),
+            Packet::Subscribe(..) | Packet::Unsubscribe(..) => true,
+            _ => false,
+        };
+
+        self.transmits.push_back(packet);
+        if changed {
+            self.persist();
+        }
+    }
+
+    /// Counters of the traffic between client and server.
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+
+    /// Capture the internal state of the binding.
+    ///
+    /// The [`Snapshot`] is meant for diagnostics, for example to investigate
+    /// why a client stopped receiving messages.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            connection_status: self.connection_status,
+            pending_transmits: self.transmits.iter().map(Packet::packet_type).collect(),
+            inflight: self
+                .inflight
+                .keys()
+                .chain(self.released.iter())
+                .copied()
+                .collect(),
+            subscriptions: self.subscriptions.clone(),
+            last_io: self.last_io,
+            next_timeout: self.deadline(),
+            statistics: self.statistics.clone(),
+            pings: self.pings.iter().cloned().collect(),
+        }
+    }
+
+    /// Capture the [`Session`] without terminating the connection.
+    ///
+    /// The session includes the pending publications. See also [`MqttBinding::suspend()`].
+    pub fn session(&self) -> Session {
+        let mut subscriptions = self.subscriptions.clone();
+
+        // Publications that are transmitted before, but not acknowledged
+        // must be marked as duplicate when they're retransmitted.
+        let mut publications: Vec<Publish> = self
+            .inflight
+            .values()
+            .map(|publish| {
+                let mut builder = Publish::builder(publish.topic(), publish.payload())
+                    .qos(publish.qos())
+                    .retain(publish.retain())
+                    .duplicate(true);
+                if let Some(packet_identifier) = publish.packet_identifier() {
+                    builder = builder.packet_identifier(packet_identifier);
+                }
+                builder.build()
+            })
+            .collect();
+
+        for packet in self.transmits.iter() {
+            match packet {
+                Packet::Publish(publish) => publications.push(publish.clone()),
+                Packet::Subscribe(subscribe) => {
+                    for (topic, qos) in subscribe.topics() {
+                        subscriptions.retain(|(filter, _)| filter != topic);
+                        subscriptions.push((topic.to_owned(), qos));
+                    }
+                }
+                Packet::Unsubscribe(unsubscribe) => {
+                    for topic in unsubscribe.topics() {
+                        subscriptions.retain(|(filter, _)| filter != topic);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Session {
+            connect: self.connect.clone(),
+            subscriptions,
+            publications,
+            released: self.released.iter().copied().collect(),
+            received: self.received.iter().copied().collect(),
+        }
+    }
+
+    /// Capture the [`Session`] and terminate the connection.
+    ///
+    /// All pending transmits are discarded and a [`Disconnect`] is queued.
+    /// The session includes those pending publications, so they're not lost.
+    /// Use [`MqttBinding::from_session()`] to continue the session later.
+    pub fn suspend(&mut self) -> Session {
+        let session = self.session();
+        self.transmits.clear();
+        self.send(Disconnect.into());
+        self.persist();
+        session
+    }
+
+    /// Add an [`Interceptor`] that inspects the packets exchanged with the server.
+    ///
+    /// Interceptors run in the order they're added. Once one drops a packet,
+    /// the interceptors after it don't see the packet. Outbound packets are intercepted
+    /// before their topics are rewritten, inbound packets after. See [`TopicRewrite`].
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor + Send + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Save the [`Session`] to `store` whenever it changes.
+    ///
+    /// That is, when a publication with QoS 1 or 2, a subscription or an acknowledgement is
+    /// sent or received. Saving captures the whole session, so that's not free.
+    /// Combine it with [`SessionStore::load()`] and [`MqttBinding::from_session()`] to let
+    /// unacknowledged publications survive a crash or restart of the application.
+    pub fn set_session_store(&mut self, store: impl SessionStore + Send + 'static) {
+        self.store = Some(Box::new(store));
+        self.persist();
+    }
+
+    // Save the session to the store, if any.
+    fn persist(&mut self) {
+        let Some(mut store) = self.store.take() else {
+            return;
+        };
+
+        if let Err(error) = store.save(&self.session()) {
+            error!(target: target::BINDING, "Failed to save the session: {error}");
+        }
+        self.store = Some(store);
+    }
+}
+
+/// The state of a client that must survive a reconnect.
+///
+/// Obtain it with [`MqttBinding::suspend()`], or through the handle of a client.
+/// For the broker to preserve its part of the session, the client must connect
+/// with the clean session flag set to 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub(crate) connect: Connect,
+    pub(crate) subscriptions: Vec<(String, QoS)>,
+    pub(crate) publications: Vec<Publish>,
+    pub(crate) released: Vec<u16>,
+    pub(crate) received: Vec<u16>,
+}
+
+impl Session {
+    /// The [`Connect`] used to (re)connect to the broker.
+    pub fn connect(&self) -> &Connect {
+        &self.connect
+    }
+
+    /// The topic filters the client subscribed to.
+    pub fn subscriptions(&self) -> &[(String, QoS)] {
+        &self.subscriptions
+    }
+
+    /// The publications that are not yet acknowledged by the broker.
+    pub fn publications(&self) -> &[Publish] {
+        &self.publications
+    }
+
+    /// Packet identifiers of outbound QoS 2 publications that the broker received,
+    /// but that are not yet completed. When resuming, a [`PubRel`] is sent for each of them.
+    pub fn released(&self) -> &[u16] {
+        &self.released
+    }
+
+    /// Packet identifiers of inbound QoS 2 publications that are delivered to the
+    /// application, but not yet released by the broker. Retransmissions of these
+    /// publications are not delivered again.
+    pub fn received(&self) -> &[u16] {
+        &self.received
+    }
+
+    /// Encode the session, so it can be stored and survive a restart of the process.
+    ///
+    /// The encoding is a sequence of MQTT packets. Decode it with [`Session::try_from()`].
+    ///
+    /// ```
+    /// use tjiftjaf::{Connect, MqttBinding, Session};
+    ///
+    /// let mut binding = MqttBinding::from_connect(Connect::builder().client_id("durable").build());
+    /// let session = binding.suspend();
+    ///
+    /// let bytes = session.clone().into_bytes();
+    /// assert_eq!(Session::try_from(bytes).unwrap(), session);
+    /// ```
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut packets: Vec<Packet> = vec![self.connect.into()];
+
+        let mut subscriptions = self.subscriptions.into_iter();
+        if let Some((topic, qos)) = subscriptions.next() {
+            let mut builder = Subscribe::builder(topic, qos).packet_identifier(1);
+            for (topic, qos) in subscriptions {
+                builder = builder.add_topic(topic, qos);
+            }
+            packets.push(builder.build_packet());
+        }
+
+        packets.extend(self.publications.into_iter().map(Packet::from));
+        packets.extend(self.released.into_iter().map(|id| PubRel::new(id).into()));
+        packets.extend(self.received.into_iter().map(|id| PubRec::new(id).into()));
+
+        packets.into_iter().flat_map(Packet::into_bytes).collect()
+    }
+}
+
+impl TryFrom<Vec<u8>> for Session {
+    type Error = DecodingError;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut packets = vec![];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let length = decode::packet_length(bytes.get(offset + 1..).unwrap_or_default())?;
+            let end = offset + length as usize;
+            let frame = bytes
+                .get(offset..end)
+                .ok_or(DecodingError::NotEnoughBytes {
+                    minimum: end,
+                    actual: bytes.len(),
+                })?;
+            packets.push(Packet::try_from(frame.to_vec())?);
+            offset = end;
+        }
+
+        let mut packets = packets.into_iter();
+        let Some(Packet::Connect(connect)) = packets.next() else {
+            return Err(DecodingError::InvalidValue(
+                "A session must start with a CONNECT packet.".into(),
+            ));
+        };
+
+        let mut session = Session {
+            connect,
+            subscriptions: vec![],
+            publications: vec![],
+            released: vec![],
+            received: vec![],
+        };
+        for packet in packets {
+            match packet {
+                Packet::Subscribe(subscribe) => session.subscriptions.extend(
+                    subscribe
+                        .topics()
+                        .map(|(topic, qos)| (topic.to_string(), qos)),
+                ),
+                Packet::Publish(publish) => session.publications.push(publish),
+                Packet::PubRel(pubrel) => session.released.push(pubrel.packet_identifier()),
+                Packet::PubRec(pubrec) => session.received.push(pubrec.packet_identifier()),
+                packet => {
+                    return Err(DecodingError::InvalidValue(format!(
+                        "A session can't contain a {:?} packet.",
+                        packet.packet_type()
+                    )))
+                }
+            }
+        }
+
+        Ok(session)
+    }
+}
+
+/// What a [`MqttBinding`] does when it receives a frame that it can't decode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Terminate the connection. [`MqttBinding::decoding_error()`] returns the error.
+    #[default]
+    FailFast,
+
+    /// Drop the frame and continue with the next one.
+    ///
+    /// That's only possible if the length of the frame can be decoded. Otherwise, it's unknown
+    /// where the next frame starts and the connection is terminated anyway.
+    SkipPacket,
+}
+
+/// The status of the connection between client and server.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ConnectionStatus {
+    /// The client did not yet emit a [`Connect`].
+    #[default]
+    NotConnected,
+
+    /// The client emitted a [`Connect`] and waits for a [`ConnAck`].
+    Connecting,
+
+    /// The server accepted the connection.
+    Connected,
+
+    /// The client has terminated the connection.
+    Disconnected,
+}
+
+/// A point-in-time view on the internal state of a [`MqttBinding`].
+///
+/// Obtain it with [`MqttBinding::snapshot()`], or through the handle of a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Snapshot {
+    /// The status of the connection.
+    pub connection_status: ConnectionStatus,
+
+    /// The types of packets waiting to be transmitted, in order of transmission.
+    pub pending_transmits: Vec<PacketType>,
+
+    /// Packet identifiers of [`Publish`] packets that are not yet acknowledged by the server.
+    pub inflight: Vec<u16>,
+
+    /// The topic filters the client subscribed to.
+    pub subscriptions: Vec<(String, QoS)>,
+
+    /// The moment the binding last transmitted a packet. `None` until the CONNECT is transmitted.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub last_io: Option<Instant>,
+
+    /// The moment the binding must be woken up to emit a keep alive. `None` until the
+    /// CONNECT is transmitted.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub next_timeout: Option<Instant>,
+
+    /// Counters of the traffic between client and server.
+    pub statistics: Statistics,
+
+    /// The most recent keep alives, oldest first. See [`MqttBinding::pings()`].
+    pub pings: Vec<Ping>,
+}
+
+/// A keep alive: a PINGREQ and the PINGRESP that answers it.
+///
+/// Obtain them with [`MqttBinding::pings()`], or through the handle of a client.
+/// Comparing the moments reveals a keep alive that was sent late, or a PINGRESP
+/// that never arrived.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Ping {
+    /// The sequence number of the keep alive, starting at 0 for every connection.
+    pub sequence: u64,
+    /// The moment the PINGREQ was due.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize")
+    )]
+    pub scheduled: Instant,
+    /// The moment the PINGREQ was transmitted.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub sent: Option<Instant>,
+    /// The moment the PINGRESP was received.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub answered: Option<Instant>,
+}
+
+/// An error indicating that the client terminated the connection with the server.
+///
+/// It's returned by [`MqttBinding::poll_transmits()`] and explains why the connection ended.
+#[derive(Clone, Debug)]
+pub enum ClientDisconnected {
+    /// The application sent a [`Disconnect`].
+    Requested,
+
+    /// The server refused the connection with this [`ConnAck`].
+    Refused(ConnAck),
+
+    /// The server sent a frame that can't be decoded. See [`DecodeErrorPolicy`].
+    ProtocolError(DecodingError),
+
+    /// The server didn't answer a probe in time. See [`MqttBinding::set_probe()`].
+    Unresponsive,
+
+    /// The server didn't answer a PINGREQ within the grace period.
+    /// See [`MqttBinding::set_keep_alive_timeout()`].
+    KeepAliveTimeout,
+
+    /// The server didn't answer the CONNECT in time. See [`MqttBinding::set_connect_timeout()`].
+    ConnectTimeout,
+}
+
+impl ClientDisconnected {
+    /// Whether the connection ended before the server accepted it, and why.
+    pub fn connect_error(&self) -> Option<ConnectError> {
+        match self {
+            Self::Refused(connack) => Some(ConnectError::Refused(connack.return_code())),
+            Self::ConnectTimeout => Some(ConnectError::Timeout),
+            _ => None,
+        }
+    }
+}
+
+impl Error for ClientDisconnected {}
+
+impl Display for ClientDisconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Requested => write!(f, "the client disconnected"),
+            Self::Refused(connack) => write!(
+                f,
+                "the server refused the connection: {:?}",
+                connack.return_code()
+            ),
+            Self::ProtocolError(error) => write!(f, "the server violated the protocol: {error}"),
+            Self::Unresponsive => write!(f, "the server didn't answer a probe in time"),
+            Self::KeepAliveTimeout => write!(f, "the server didn't answer a keep alive in time"),
+            Self::ConnectTimeout => write!(f, "the server didn't answer the CONNECT in time"),
+        }
+    }
+}
+
+/// An error indicating that the server didn't accept the connection.
+///
+/// When the handshake fails, the [`aio::Client`](crate::aio::Client) and the
+/// [`blocking::Client`](crate::blocking::Client) terminate with an [`std::io::Error`] that
+/// wraps it. Use [`std::io::Error::get_ref()`] to inspect it:
+///
+/// ```
+/// use tjiftjaf::{packet::connack::ReturnCode, ConnectError};
+///
+/// # let error = std::io::Error::new(
+/// #     std::io::ErrorKind::ConnectionRefused,
+/// #     ConnectError::Refused(ReturnCode::ConnectionRefusedNotAuthorized),
+/// # );
+/// let reason = error
+///     .get_ref()
+///     .and_then(|error| error.downcast_ref::<ConnectError>());
+/// if let Some(ConnectError::Refused(ReturnCode::ConnectionRefusedNotAuthorized)) = reason {
+///     println!("Check the credentials.");
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectError {
+    /// The server refused the connection with this return code.
+    Refused(packet::connack::ReturnCode),
+
+    /// The server didn't answer the CONNECT in time. See [`MqttBinding::set_connect_timeout()`].
+    Timeout,
+}
+
+impl Error for ConnectError {}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Refused(return_code) => {
+                write!(f, "the server refused the connection: {return_code:?}")
+            }
+            Self::Timeout => write!(f, "the server didn't answer the CONNECT in time"),
+        }
+    }
+}
+
+/// An error indicating that the queue of [`MqttBinding`] is full.
+///
+/// It's returned by [`MqttBinding::try_send()`] and holds the packet that was refused.
+/// The binding also refuses packets when all packet identifiers are in use,
+/// see [`PacketIdentifiersExhausted`].
+#[derive(Debug)]
+pub struct QueueFull(Packet);
+
+impl QueueFull {
+    /// Take back the packet that was refused.
+    pub fn into_packet(self) -> Packet {
+        self.0
+    }
+}
+
+impl Error for QueueFull {}
+
+impl Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the queue of transmits is full")
+    }
+}
+
+/// An error indicating that all 65535 packet identifiers are in use.
+///
+/// It's returned by [`MqttBinding::allocate_packet_identifier()`]. Identifiers become
+/// available again when the server acknowledges the packets that use them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketIdentifiersExhausted;
+
+impl Error for PacketIdentifiersExhausted {}
+
+impl Display for PacketIdentifiersExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "all packet identifiers are in use")
+    }
+}
+
+/// Counters of the traffic between client and server.
+///
+/// Obtain them with [`MqttBinding::statistics()`], or through the handle of a client.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Statistics {
+    /// The number of bytes received.
+    pub bytes_read: usize,
+    /// The number of bytes sent.
+    pub bytes_sent: usize,
+    /// The number of packets received.
+    pub packets_read: usize,
+    /// The number of packets sent.
+    pub packets_sent: usize,
+    /// The number of packets received, per packet type.
+    pub packets_read_by_type: BTreeMap<PacketType, usize>,
+    /// The number of packets sent, per packet type.
+    pub packets_sent_by_type: BTreeMap<PacketType, usize>,
+    /// The moment the last packet was received.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub last_read: Option<Instant>,
+    /// The moment the last packet was sent.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub last_sent: Option<Instant>,
+    /// The moment the server accepted the connection.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub connected_at: Option<Instant>,
+    /// The moment the last PINGREQ was sent.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::timestamp::serialize_option")
+    )]
+    pub last_ping: Option<Instant>,
+    /// The number of handles waiting for the acknowledgement of a publication or unsubscribe.
+    pub pending_acknowledgements: usize,
+    /// How long opening the TCP connection to the broker took. Only recorded by clients
+    /// that open the connection themselves, like `connect_tls()`.
+    pub connect_duration: Option<Duration>,
+    /// How long the TLS handshake took. `None` if the connection isn't encrypted.
+    pub tls_handshake_duration: Option<Duration>,
+    /// Whether the TLS handshake resumed an earlier session. See the module `tls`.
+    pub tls_resumed: bool,
+}
+
+impl Statistics {
+    fn record_inbound_packet(&mut self, packet: &Packet, now: Instant) {
+        self.bytes_read += packet.length();
+        self.packets_read += 1;
+        *self
+            .packets_read_by_type
+            .entry(packet.packet_type())
+            .or_default() += 1;
+        self.last_read = Some(now);
+
+        if let Packet::ConnAck(connack) = packet {
+            if connack.return_code() == packet::connack::ReturnCode::ConnectionAccepted {
+                self.connected_at = Some(now);
+            }
+        }
+    }
+
+    fn record_outbound_packet(&mut self, packet: &Packet, now: Instant) {
+        self.bytes_sent += packet.length();
+        self.packets_sent += 1;
+        *self
+            .packets_sent_by_type
+            .entry(packet.packet_type())
+            .or_default() += 1;
+        self.last_sent = Some(now);
+
+        if packet.packet_type() == PacketType::PingReq {
+            self.last_ping = Some(now);
+        }
+    }
+
+    /// How long ago the last packet was received, at `now`. `None` if nothing
+    /// was received yet.
+    ///
+    /// The moments are taken from a monotonic clock, so changes to the wall clock
+    /// of the system don't affect the result.
+    pub fn since_last_read(&self, now: Instant) -> Option<Duration> {
+        self.last_read
+            .map(|last_read| now.saturating_duration_since(last_read))
+    }
+}
+
+// A handle waiting for an acknowledgement.
+#[cfg(any(feature = "blocking", feature = "async"))]
+struct Waiter<R = Reply> {
+    reply: R,
+
+    // When the packet was transmitted, `None` while it's queued.
+    sent: Option<Instant>,
+}
+
+// Publications matching `filter` are delivered to `sender`, an `aio::Subscription`.
+#[cfg(feature = "async")]
+struct Route {
+    filter: String,
+    sender: async_channel::Sender<Packet>,
+    lease: Option<Lease>,
+}
+
+// Records whether the application consumes an `aio::Subscription`. Once it doesn't poll
+// the subscription for `duration`, the lease expires. A subscription that the application
+// is waiting on is in use, however long it waits.
+#[cfg(feature = "async")]
+#[derive(Clone, Debug)]
+pub(crate) struct Lease {
+    duration: Duration,
+    activity: std::sync::Arc<std::sync::Mutex<Activity>>,
+}
+
+// What the subscription of a `Lease` did, as far as the binding knows.
+#[cfg(feature = "async")]
+#[derive(Debug, Default)]
+struct Activity {
+    // Whether the subscription was polled since the lease was last renewed.
+    polled: bool,
+
+    // Whether the last poll of the subscription is still pending.
+    pending: bool,
+
+    // When the binding last renewed the lease, `None` until it first sees it.
+    renewed: Option<Instant>,
+}
+
+#[cfg(feature = "async")]
+impl Lease {
+    pub(crate) fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            activity: std::sync::Arc::default(),
+        }
+    }
+
+    // Must be called by the subscription every time it's polled.
+    pub(crate) fn polled(&self, pending: bool) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.polled = true;
+        activity.pending = pending;
+    }
+
+    // Renew the lease at `now`, if the subscription was polled since it was last renewed,
+    // or a poll is pending. The subscription doesn't read the clock, the binding does.
+    fn renew(&self, now: Instant) {
+        let mut activity = self.activity.lock().unwrap();
+        if activity.polled || activity.pending || activity.renewed.is_none() {
+            activity.renewed = Some(now);
+            activity.polled = false;
+        }
+    }
+
+    // The moment the lease expires, unless the subscription is polled before.
+    fn expiry(&self) -> Option<Instant> {
+        Some(self.activity.lock().unwrap().renewed? + self.duration)
+    }
+
+    // Whether the lease expired at `now`.
+    fn expired(&self, now: Instant) -> bool {
+        self.renew(now);
+        self.expiry().is_some_and(|expiry| now >= expiry)
+    }
+}
+
+// The channel that receives the outcome of a `Command`.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) type Reply = async_channel::Sender<Result<(), HandleError>>;
+
+// The channel that receives the outcome of a `Command::Subscribe`.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) type SubscribeReply = async_channel::Sender<Result<SubscriptionResult, SubscribeError>>;
+
+// A request sent by a handle to the event loop of a client.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) enum Command {
+    // Transmit a packet to the server.
+    Packet(Packet),
+
+    // Transmit a publication to the server. The first channel receives the packet identifier
+    // once the publication is queued. The second one receives a reply once the server
+    // acknowledged it. Publications with QoS 0 are never acknowledged, so the reply
+    // is sent immediately. If the binding refuses the publication, both receive
+    // `HandleError::Backpressure`.
+    Publish(
+        Publish,
+        async_channel::Sender<Result<Option<u16>, HandleError>>,
+        Reply,
+    ),
+
+    // Like `Command::Publish`, but for a batch of publications that is queued as a whole,
+    // or not at all. The first channel receives the packet identifiers, in the order
+    // of the batch. Every publication with QoS 1 or 2 sends a reply to the second channel
+    // once the server acknowledged it.
+    PublishBatch(
+        Vec<Publish>,
+        async_channel::Sender<Result<Vec<Option<u16>>, HandleError>>,
+        Reply,
+    ),
+
+    // Deliver the publications matching a topic filter to a channel, instead of
+    // to the handle. The channel first receives up to the given number of recent
+    // publications that match.
+    // With a lease, the binding unsubscribes once the application stops consuming the channel.
+    #[cfg(feature = "async")]
+    Route(String, async_channel::Sender<Packet>, usize, Option<Lease>),
+
+    // Deliver every publication to a channel, in addition to the other broadcast channels.
+    #[cfg(feature = "async")]
+    Broadcast(async_channel::Sender<Packet>),
+
+    // Deliver a copy of every packet to a channel, in addition to the regular delivery.
+    #[cfg(all(feature = "async", feature = "experimental"))]
+    RawPackets(async_channel::Sender<Packet>),
+
+    // Transmit a subscribe to the server. Reply once the server acknowledged it.
+    Subscribe(Subscribe, SubscribeReply),
+
+    // Transmit an unsubscribe to the server. Reply once the server acknowledged it.
+    Unsubscribe(Unsubscribe, Reply),
+
+    // Replace the `Connect` used for the next connection.
+    Reconfigure(Connect),
+
+    // Capture the state of the `MqttBinding` and send it back.
+    Snapshot(async_channel::Sender<Snapshot>),
+
+    // Capture the `Session`, including queued subscriptions, and send it back.
+    Session(async_channel::Sender<Session>),
+
+    // Terminate the connection and send back the `Session`.
+    Suspend(async_channel::Sender<Session>),
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl Command {
+    // Apply the command to the binding.
+    pub(crate) fn apply(self, binding: &mut MqttBinding) {
+        match self {
+            Command::Packet(packet) => {
+                if let Err(error) = binding.try_send(packet) {
+                    error!(target: target::BINDING, "Dropping {:?}: {error}", error.0.packet_type());
+                }
+            }
+            Command::Publish(publish, queued, reply) => {
+                let packet_identifier = match binding.enqueue(publish.into()) {
+                    Ok(packet_identifier) => packet_identifier,
+                    Err(error) => {
+                        error!(target: target::BINDING, "Dropping {:?}: {error}", error.0.packet_type());
+                        _ = queued.try_send(Err(HandleError::Backpressure));
+                        _ = reply.try_send(Err(HandleError::Backpressure));
+                        return;
+                    }
+                };
+                _ = queued.try_send(Ok(packet_identifier));
+
+                match packet_identifier {
+                    Some(packet_identifier) => {
+                        binding.insert_waiter(packet_identifier, reply);
+                    }
+                    None => _ = reply.try_send(Ok(())),
+                }
+            }
+            Command::PublishBatch(batch, queued, reply) => {
+                let packet_identifiers = match binding.enqueue_batch(batch) {
+                    Ok(packet_identifiers) => packet_identifiers,
+                    Err(error) => {
+                        _ = queued.try_send(Err(error));
+                        return;
+                    }
+                };
+                for packet_identifier in packet_identifiers.iter().flatten() {
+                    binding.insert_waiter(*packet_identifier, reply.clone());
+                }
+                _ = queued.try_send(Ok(packet_identifiers));
+            }
+            #[cfg(feature = "async")]
+            Command::Route(filter, sender, replay, lease) => {
+                binding.add_route(filter, sender, replay, lease)
+            }
+            #[cfg(feature = "async")]
+            Command::Broadcast(sender) => binding.broadcasts.push(sender),
+            #[cfg(all(feature = "async", feature = "experimental"))]
+            Command::RawPackets(sender) => binding.raw_packets.push(sender),
+            Command::Subscribe(subscribe, reply) => match binding.enqueue(subscribe.into()) {
+                Ok(Some(packet_identifier)) => {
+                    let waiter = Waiter { reply, sent: None };
+                    binding.subscribe_replies.insert(packet_identifier, waiter);
+                }
+                Ok(None) => unreachable!("a SUBSCRIBE has a packet identifier"),
+                Err(error) => {
+                    error!(target: target::BINDING, "Dropping {:?}: {error}", error.0.packet_type());
+                    _ = reply.try_send(Err(HandleError::Backpressure.into()));
+                }
+            },
+            Command::Unsubscribe(unsubscribe, reply) => match binding.enqueue(unsubscribe.into()) {
+                Ok(Some(packet_identifier)) => {
+                    binding.insert_waiter(packet_identifier, reply);
+                }
+                Ok(None) => unreachable!("an UNSUBSCRIBE has a packet identifier"),
+                Err(error) => {
+                    error!(target: target::BINDING, "Dropping {:?}: {error}", error.0.packet_type());
+                    _ = reply.try_send(Err(HandleError::Backpressure));
+                }
+            },
+            Command::Reconfigure(connect) => binding.set_connect(connect),
+            Command::Snapshot(reply) => _ = reply.try_send(binding.snapshot()),
+            Command::Session(reply) => _ = reply.try_send(binding.session()),
+            Command::Suspend(reply) => _ = reply.try_send(binding.suspend()),
+        }
+    }
+
+    // Apply the commands waiting in `receiver`, as long as the binding has room for them.
+    // The remaining commands stay in the channel, which applies backpressure to the handles.
+    pub(crate) fn apply_pending(
+        receiver: &async_channel::Receiver<Command>,
+        binding: &mut MqttBinding,
+    ) {
+        while binding.has_capacity() {
+            let Ok(command) = receiver.try_recv() else {
+                return;
+            };
+            command.apply(binding);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    use crate::subscribe;
+    use crate::{connect, publish, unsubscribe, Frame, PingResp};
+    use std::io::{Cursor, Read};
+
+    fn as_str(bytes: &[u8]) -> &str {
+        std::str::from_utf8(bytes).expect("Failed to parse bytes as UTF-8.")
+    }
+
+    fn decode_message(packet: Packet) -> Packet {
+        let bytes = packet.into_bytes();
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        let mut offset = 0;
+
+        loop {
+            let buffer = binding.get_read_buffer();
+            let size = buffer.len();
+
+            if let Some(packet) =
+                binding.try_decode(bytes[offset..offset + size].to_vec(), Instant::now())
+            {
+                return packet;
+            }
+            offset += size;
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let packet = publish("zigbee2mqtt/light/state", r#"{"state":"on"}"#);
+
+        let packet = decode_message(packet.into());
+
+        assert_eq!(packet.length(), 41);
+        assert_eq!(packet.packet_type(), PacketType::Publish);
+        // assert_eq!(packet.topic(), "$SYS/broker/uptime");
+        assert_eq!(as_str(packet.payload()), r#"{"state":"on"}"#);
+
+        let packet = publish("$SYS/broker/uptime", r#"388641 seconds"#);
+
+        let packet = decode_message(packet.into());
+        assert_eq!(packet.packet_type(), PacketType::Publish);
+        assert_eq!(packet.length(), 36);
+        // assert_eq!(packet.topic(), "$SYS/broker/uptime");
+        assert_eq!(as_str(packet.payload()), "388641 seconds");
+
+        let packet = publish(
+            "zigbee2mqtt/binary-switch",
+            r#"{"action":"off","battery":100,"linkquality":3,"voltage":1400}"#,
+        );
+        let packet = decode_message(packet.into());
+        assert_eq!(packet.packet_type(), PacketType::Publish);
+        // assert_eq!(packet.topic(), "zigbee2mqtt/binary-switch");
+        assert_eq!(
+            as_str(packet.payload()),
+            "{\"action\":\"off\",\"battery\":100,\"linkquality\":3,\"voltage\":1400}"
+        );
+
+        let packet = publish(
+            "zigbee2mqtt/thermo-hygrometer",
+            r#"{"battery":100,"comfort_humidity_max":60,"comfort_humidity_min":40,"comfort_temperature_max":27,"comfort_temperature_min":19,"humidity":47.2,"linkquality":105,"temperature":24,"temperature_units":"fahrenheit","update":{"installed_version":4105,"latest_version":8960,"state":"available"}}"#,
+        );
+
+        let packet = decode_message(packet.into());
+
+        assert_eq!(packet.packet_type(), PacketType::Publish);
+        // assert_eq!(packet.topic(), "zigbee2mqtt/thermo-hygrometer");
+        pretty_assertions::assert_eq!(
+            as_str(packet.payload()),
+            "{\"battery\":100,\"comfort_humidity_max\":60,\"comfort_humidity_min\":40,\"comfort_temperature_max\":27,\"comfort_temperature_min\":19,\"humidity\":47.2,\"linkquality\":105,\"temperature\":24,\"temperature_units\":\"fahrenheit\",\"update\":{\"installed_version\":4105,\"latest_version\":8960,\"state\":\"available\"}}"
+        );
+    }
+
+    /// Verify that `MqttBinding.get_read_buffer()`
+    /// and `MqttBinding.try_decode()` correctly decode `Packet`s.
+    ///
+    /// This test iterates over a series of valid packets. Each packet
+    /// is deserialized as `Bytes` and fed to `MqttBinding`. The latter
+    /// should correctly decode the `Bytes` back into the `Packet` we started with.
+    #[test]
+    fn test_mqtt_binding_decoding_packets() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+
+        for test in valid_packets() {
+            let mut input = Cursor::new(test.clone().into_bytes());
+
+            let mut iterations = 0;
+            // A mini-event loop that requests one or more read buffers
+            // to decode packets.
+            let packet = loop {
+                iterations += 1;
+                let mut buffer = binding.get_read_buffer();
+                let _ = input.read(&mut buffer).unwrap();
+
+                if let Some(packet) = binding.try_decode(buffer, Instant::now()) {
+                    break packet;
+                }
+            };
+
+            assert!(iterations > 0);
+            assert!(iterations < 4);
+
+            assert_eq!(test.into_bytes(), packet.into_bytes());
+        }
+    }
+
+    // A collection of valid `Packet`s.
+    fn valid_packets() -> Vec<Packet> {
+        vec![
+            PingReq.into(),
+            connect("test".to_string(), 300),
+            Connect::builder()
+                .username("admin")
+                .password("secret")
+                .build()
+                .into(),
+            ConnAck::builder().build().into(),
+        ]
+    }
+
+    // Feed the bytes of `packet` to the binding until it's decoded.
+    fn feed(binding: &mut MqttBinding, packet: Packet) -> Packet {
+        feed_at(binding, packet, Instant::now())
+    }
+
+    // Like `feed()`, but the bytes are received at `now`.
+    fn feed_at(binding: &mut MqttBinding, packet: Packet, now: Instant) -> Packet {
+        try_feed_at(binding, packet.into_bytes(), now).expect("The binding dropped the packet.")
+    }
+
+    // Like `feed_at()`, but returns `None` if the binding drops the packet. It takes the
+    // bytes of a packet, so it can feed malformed packets too.
+    fn try_feed_at(
+        binding: &mut MqttBinding,
+        bytes: impl Into<Vec<u8>>,
+        now: Instant,
+    ) -> Option<Packet> {
+        let bytes = bytes.into();
+        let length = bytes.len() as u64;
+        let mut input = Cursor::new(bytes);
+        while input.position() < length {
+            let mut buffer = binding.get_read_buffer();
+            let _ = input.read(&mut buffer).unwrap();
+
+            if let Some(packet) = binding.try_decode(buffer, now) {
+                return Some(packet);
+            }
+        }
+        None
+    }
+
+    // Verify that `MqttBinding.snapshot()` reflects subscriptions,
+    // pending transmits and unacknowledged publications.
+    #[test]
+    fn test_snapshot() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        let snapshot = binding.snapshot();
+        assert_eq!(snapshot.connection_status, ConnectionStatus::NotConnected);
+        assert!(snapshot.subscriptions.is_empty());
+
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        binding.send(Subscribe::builder("sensor/#", QoS::AtLeastOnceDelivery).build_packet());
+        binding.send(
+            Publish::builder("sensor/1", "26.1")
+                .qos(QoS::AtLeastOnceDelivery)
+                .packet_identifier(1568)
+                .build_packet(),
+        );
+
+        let snapshot = binding.snapshot();
+        assert_eq!(snapshot.connection_status, ConnectionStatus::Connected);
+        assert_eq!(
+            snapshot.pending_transmits,
+            vec![PacketType::Subscribe, PacketType::Publish]
+        );
+
+        while binding.poll_transmits(Instant::now()).unwrap().is_some() {}
+
+        let snapshot = binding.snapshot();
+        assert!(snapshot.pending_transmits.is_empty());
+        assert_eq!(snapshot.inflight, vec![1568]);
+        assert_eq!(
+            snapshot.subscriptions,
+            vec![("sensor/#".to_string(), QoS::AtLeastOnceDelivery)]
+        );
+        assert_eq!(snapshot.statistics.packets_sent, 3);
+        assert_eq!(
+            snapshot.statistics.packets_sent_by_type,
+            BTreeMap::from([
+                (PacketType::Connect, 1),
+                (PacketType::Publish, 1),
+                (PacketType::Subscribe, 1)
+            ])
+        );
+        assert_eq!(
+            snapshot.statistics.packets_read_by_type,
+            BTreeMap::from([(PacketType::ConnAck, 1)])
+        );
+        assert!(snapshot.statistics.last_read.is_some());
+        assert!(snapshot.statistics.last_sent.is_some());
+        assert_eq!(
+            snapshot.statistics.connected_at,
+            snapshot.statistics.last_read
+        );
+        assert!(snapshot.statistics.last_ping.is_none());
+        assert_eq!(binding.statistics(), &snapshot.statistics);
+
+        feed(&mut binding, PubAck::new(1568).into());
+        assert!(binding.snapshot().inflight.is_empty());
+
+        binding.send(unsubscribe("sensor/#").into());
+        binding.poll_transmits(Instant::now()).unwrap();
+        assert!(binding.snapshot().subscriptions.is_empty());
+    }
+
+    // Verify that a snapshot serializes, with its moments as wall-clock time.
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_snapshot() {
+        use std::time::{Duration, SystemTime};
+
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        let start = SystemTime::now();
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let json = serde_json::to_value(binding.snapshot()).unwrap();
+        assert_eq!(json["connection_status"], "Connected");
+        assert_eq!(json["statistics"]["packets_sent"], 1);
+
+        let last_io: SystemTime = serde_json::from_value(json["last_io"].clone()).unwrap();
+        assert!(last_io >= start - Duration::from_secs(1));
+        assert!(last_io <= SystemTime::now() + Duration::from_secs(1));
+    }
+
+    // Verify that packets are transmitted in the order they're queued.
+    #[test]
+    fn test_transmits_are_fifo() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        for topic in ["sensor/1", "sensor/2", "sensor/3"] {
+            binding.send(publish(topic, "26.1").into());
+        }
+
+        let mut topics = vec![];
+        while let Some(bytes) = binding.poll_transmits(Instant::now()).unwrap() {
+            let Packet::Publish(publish) = Packet::try_from(bytes).unwrap() else {
+                panic!("Expected a PUBLISH");
+            };
+            topics.push(publish.topic().to_string());
+        }
+        assert_eq!(topics, ["sensor/1", "sensor/2", "sensor/3"]);
+    }
+
+    // Verify that `MqttBinding::try_send()` refuses publications when the queue
+    // is full, but still accepts acknowledgements.
+    #[test]
+    fn test_queue_full() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_max_pending_transmits(2);
+
+        binding
+            .try_send(publish("sensor/1", "26.1").into())
+            .unwrap();
+        binding
+            .try_send(publish("sensor/2", "26.1").into())
+            .unwrap();
+        assert!(!binding.has_capacity());
+
+        let error = binding
+            .try_send(publish("sensor/3", "26.1").into())
+            .unwrap_err();
+        let Packet::Publish(refused) = error.into_packet() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(refused.topic(), "sensor/3");
+
+        binding.try_send(PubAck::new(1).into()).unwrap();
+        assert_eq!(binding.snapshot().pending_transmits.len(), 3);
+    }
+
+    // Verify that handles learn about a refused publication through `HandleError::Backpressure`,
+    // instead of a closed channel.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    #[test]
+    fn test_command_backpressure() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_max_pending_transmits(1);
+
+        let (queued, packet_identifier) = async_channel::bounded(1);
+        let (reply, acknowledgement) = async_channel::bounded(1);
+        Command::Publish(publish("sensor/1", "26.1"), queued, reply).apply(&mut binding);
+        assert_eq!(packet_identifier.try_recv().unwrap(), Ok(None));
+        assert_eq!(acknowledgement.try_recv().unwrap(), Ok(()));
+
+        let (queued, packet_identifier) = async_channel::bounded(1);
+        let (reply, acknowledgement) = async_channel::bounded(1);
+        Command::Publish(publish("sensor/2", "26.1"), queued, reply).apply(&mut binding);
+        assert_eq!(
+            packet_identifier.try_recv().unwrap(),
+            Err(HandleError::Backpressure)
+        );
+        assert_eq!(
+            acknowledgement.try_recv().unwrap(),
+            Err(HandleError::Backpressure)
+        );
+
+        let (reply, acknowledgement) = async_channel::bounded(1);
+        Command::Unsubscribe(unsubscribe("sensor/#"), reply).apply(&mut binding);
+        assert_eq!(
+            acknowledgement.try_recv().unwrap(),
+            Err(HandleError::Backpressure)
+        );
+    }
+
+    // Verify that a batch is queued as a whole or not at all, and that every publication
+    // with QoS 1 or 2 replies once it's acknowledged.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    #[test]
+    fn test_command_publish_batch() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_max_pending_transmits(3);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let batch = |size: usize| -> Vec<Publish> {
+            (0..size)
+                .map(|n| {
+                    let qos = if n == 0 {
+                        QoS::AtMostOnceDelivery
+                    } else {
+                        QoS::AtLeastOnceDelivery
+                    };
+                    Publish::builder(format!("sensor/{n}"), "26.1")
+                        .qos(qos)
+                        .build()
+                })
+                .collect()
+        };
+
+        let (queued, packet_identifiers) = async_channel::bounded(1);
+        let (reply, _) = async_channel::bounded(4);
+        Command::PublishBatch(batch(4), queued, reply).apply(&mut binding);
+        assert_eq!(
+            packet_identifiers.try_recv().unwrap(),
+            Err(HandleError::Backpressure)
+        );
+        assert!(binding.snapshot().pending_transmits.is_empty());
+
+        let (queued, packet_identifiers) = async_channel::bounded(1);
+        let (reply, acknowledgements) = async_channel::bounded(3);
+        Command::PublishBatch(batch(3), queued, reply).apply(&mut binding);
+        let packet_identifiers = packet_identifiers.try_recv().unwrap().unwrap();
+        assert_eq!(packet_identifiers.len(), 3);
+        assert_eq!(packet_identifiers[0], None);
+        assert_eq!(binding.snapshot().pending_transmits.len(), 3);
+        assert_eq!(binding.statistics().pending_acknowledgements, 2);
+
+        while binding.poll_transmits(Instant::now()).unwrap().is_some() {}
+        for packet_identifier in packet_identifiers.into_iter().flatten() {
+            assert!(acknowledgements.try_recv().is_err());
+            try_feed_at(&mut binding, PubAck::new(packet_identifier), Instant::now());
+            assert_eq!(acknowledgements.try_recv().unwrap(), Ok(()));
+        }
+    }
+
+    // Verify that handles stop waiting for an acknowledgement after the acknowledgement
+    // timeout, while the publication itself stays inflight.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    #[test]
+    fn test_acknowledgement_timeout() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_acknowledgement_timeout(Duration::from_secs(5));
+        let now = Instant::now();
+        binding.poll_transmits(now).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let (mut packet_identifiers, mut acknowledgements) = (vec![], vec![]);
+        for topic in ["sensor/1", "sensor/2"] {
+            let (queued, packet_identifier) = async_channel::bounded(1);
+            let (reply, acknowledgement) = async_channel::bounded(1);
+            let publish = Publish::builder(topic, "26.1")
+                .qos(QoS::AtLeastOnceDelivery)
+                .build();
+            Command::Publish(publish, queued, reply).apply(&mut binding);
+            packet_identifiers.push(packet_identifier.try_recv().unwrap().unwrap().unwrap());
+            acknowledgements.push(acknowledgement);
+        }
+        assert_eq!(binding.statistics().pending_acknowledgements, 2);
+
+        // The timeout starts when the publication is transmitted, not when it's queued.
+        binding.handle_timeout(now + Duration::from_secs(10));
+        assert_eq!(binding.statistics().pending_acknowledgements, 2);
+
+        let sent = now + Duration::from_secs(10);
+        while binding.poll_transmits(sent).unwrap().is_some() {}
+        assert_eq!(binding.poll_timeout(sent), sent + Duration::from_secs(5));
+
+        feed(&mut binding, PubAck::new(packet_identifiers[0]).into());
+        assert_eq!(acknowledgements[0].try_recv().unwrap(), Ok(()));
+        assert_eq!(binding.statistics().pending_acknowledgements, 1);
+
+        binding.handle_timeout(sent + Duration::from_secs(4));
+        assert!(acknowledgements[1].is_empty());
+
+        binding.handle_timeout(sent + Duration::from_secs(5));
+        assert_eq!(
+            acknowledgements[1].try_recv().unwrap(),
+            Err(HandleError::Timeout)
+        );
+        assert_eq!(binding.statistics().pending_acknowledgements, 0);
+        assert_eq!(binding.snapshot().inflight, vec![packet_identifiers[1]]);
+
+        // A subscribe times out the same way.
+        let (reply, subscribed) = async_channel::bounded(1);
+        let subscribe = Subscribe::builder("sensor/+", QoS::AtMostOnceDelivery).build();
+        Command::Subscribe(subscribe, reply).apply(&mut binding);
+        while binding.poll_transmits(sent).unwrap().is_some() {}
+        assert_eq!(binding.poll_timeout(sent), sent + Duration::from_secs(5));
+
+        binding.handle_timeout(sent + Duration::from_secs(5));
+        assert!(matches!(
+            subscribed.try_recv().unwrap(),
+            Err(SubscribeError::Timeout)
+        ));
+    }
+
+    // Verify that a handle stops waiting for an acknowledgement without the binding
+    // emitting a PINGREQ, while the keep alive is disabled.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    #[test]
+    fn test_acknowledgement_timeout_without_keep_alive() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(0).build());
+        binding.set_acknowledgement_timeout(Duration::from_secs(5));
+        let start = Instant::now();
+        binding.poll_transmits(start).unwrap();
+        feed_at(&mut binding, ConnAck::builder().build().into(), start);
+
+        let (queued, _) = async_channel::bounded(1);
+        let (reply, acknowledgement) = async_channel::bounded(1);
+        let publish = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .build();
+        Command::Publish(publish, queued, reply).apply(&mut binding);
+        while binding.poll_transmits(start).unwrap().is_some() {}
+
+        let timeout = binding.poll_timeout(start);
+        assert_eq!(timeout, start + Duration::from_secs(5));
+        binding.handle_timeout(timeout);
+        assert_eq!(
+            acknowledgement.try_recv().unwrap(),
+            Err(HandleError::Timeout)
+        );
+        assert!(binding.poll_transmits(timeout).unwrap().is_none());
+        assert_eq!(binding.pings().count(), 0);
+    }
+
+    // Verify that publications with QoS > 0 are held back while the inflight
+    // window is full, without blocking other packets.
+    #[test]
+    fn test_max_inflight() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_max_inflight(1);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        for packet_identifier in [1, 2] {
+            binding.send(
+                Publish::builder("sensor/1", "26.1")
+                    .qos(QoS::AtLeastOnceDelivery)
+                    .packet_identifier(packet_identifier)
+                    .build_packet(),
+            );
+        }
+        binding.send(publish("sensor/2", "26.1").into());
+
+        let mut transmits = vec![];
+        while let Some(bytes) = binding.poll_transmits(Instant::now()).unwrap() {
+            transmits.push(Packet::try_from(bytes).unwrap());
+        }
+        assert_eq!(transmits.len(), 2);
+        assert_eq!(binding.snapshot().inflight, vec![1]);
+        assert_eq!(
+            binding.snapshot().pending_transmits,
+            vec![PacketType::Publish]
+        );
+
+        feed(&mut binding, PubAck::new(1).into());
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let Packet::Publish(publish) = Packet::try_from(bytes).unwrap() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publish.packet_identifier(), Some(2));
+    }
+
+    // Verify that the binding drops outbound packets that exceed the maximum packet size,
+    // and terminates the connection when the server sends one, before it's complete.
+    #[test]
+    fn test_max_packet_size() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_max_packet_size(16);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        binding.send(publish("sensor/1", "26.1 degrees").into());
+        binding.send(publish("sensor/1", "26.1").into());
+        let transmit = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        assert_eq!(transmit, publish("sensor/1", "26.1").into_bytes());
+        assert!(binding.poll_transmits(Instant::now()).unwrap().is_none());
+
+        let packet = publish("sensor/1", "26.1").into();
+        assert_eq!(
+            feed(&mut binding, packet),
+            publish("sensor/1", "26.1").into()
+        );
+
+        let bytes = Packet::from(publish("sensor/1", "26.1 degrees")).into_bytes();
+        let mut buffer = binding.get_read_buffer();
+        buffer.truncate(2);
+        buffer.copy_from_slice(&bytes[..2]);
+        assert!(binding.try_decode(buffer, Instant::now()).is_none());
+        assert_eq!(
+            binding.decoding_error(),
+            Some(&DecodingError::PacketTooLarge {
+                size: 24,
+                limit: 16
+            })
+        );
+    }
+
+    // Verify that the statistics record when the connection was accepted, when the
+    // last PINGREQ was sent, and how long ago the server was last heard from.
+    #[test]
+    fn test_statistics_timestamps() {
+        let start = Instant::now();
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(start).unwrap();
+        assert_eq!(binding.statistics().since_last_read(start), None);
+
+        let connected_at = start + Duration::from_millis(50);
+        feed_at(
+            &mut binding,
+            ConnAck::builder().build().into(),
+            connected_at,
+        );
+
+        let pinged_at = start + Duration::from_secs(1);
+        binding.send(PingReq.into());
+        binding.poll_transmits(pinged_at).unwrap();
+
+        let statistics = binding.statistics();
+        assert_eq!(statistics.connected_at, Some(connected_at));
+        assert_eq!(statistics.last_ping, Some(pinged_at));
+        assert_eq!(statistics.last_sent, Some(pinged_at));
+        assert_eq!(
+            statistics.since_last_read(start + Duration::from_secs(3)),
+            Some(Duration::from_millis(2950))
+        );
+        assert_eq!(statistics.since_last_read(start), Some(Duration::ZERO));
+    }
+
+    // Verify that the acknowledgements of inbound publications are withheld while
+    // the application lags, and released once it catches up. Keep alives are not affected.
+    #[test]
+    fn test_max_buffered_publications() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_max_buffered_publications(1);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let qos = [QoS::AtLeastOnceDelivery, QoS::ExactlyOnceDelivery];
+        for (packet_identifier, qos) in [1, 2].into_iter().zip(qos) {
+            feed(
+                &mut binding,
+                Publish::builder("sensor/1", "26.1")
+                    .qos(qos)
+                    .packet_identifier(packet_identifier)
+                    .build_packet(),
+            );
+        }
+        binding.send(PingReq.into());
+
+        let mut transmits = vec![];
+        while let Some(bytes) = binding.poll_transmits(Instant::now()).unwrap() {
+            transmits.push(Packet::try_from(bytes).unwrap().packet_type());
+        }
+        assert_eq!(transmits, vec![PacketType::PubAck, PacketType::PingReq]);
+
+        binding.publication_delivered();
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let Packet::PubRec(pubrec) = Packet::try_from(bytes).unwrap() else {
+            panic!("Expected a PUBREC");
+        };
+        assert_eq!(pubrec.packet_identifier(), 2);
+        assert!(binding.poll_transmits(Instant::now()).unwrap().is_none());
+    }
+
+    // Feed `bytes` to the binding and collect the decoded packets.
+    fn feed_bytes(binding: &mut MqttBinding, bytes: &[u8]) -> Vec<Packet> {
+        let mut input = Cursor::new(bytes);
+        let mut packets = vec![];
+        while (input.position() as usize) < bytes.len() {
+            let mut buffer = binding.get_read_buffer();
+            let n = input.read(&mut buffer).unwrap();
+            buffer.truncate(n);
+
+            if let Some(packet) = binding.try_decode(buffer, Instant::now()) {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+
+    fn connected_binding(policy: DecodeErrorPolicy) -> MqttBinding {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_decode_error_policy(policy);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+        binding
+    }
+
+    // A CONNACK with the reserved flags set, followed by a SUBACK with an illegal
+    // return code. Both have a valid remaining length.
+    const INVALID_FRAMES: [&[u8]; 2] = [&[0b0010_0001, 2, 0, 0], &[0b1001_0000, 3, 0, 1, 5]];
+
+    #[test]
+    fn test_decode_error_policy_fail_fast() {
+        for frame in INVALID_FRAMES {
+            let mut binding = connected_binding(DecodeErrorPolicy::FailFast);
+            let mut bytes = frame.to_vec();
+            bytes.append(&mut Packet::from(PingReq).into_bytes());
+
+            assert!(feed_bytes(&mut binding, &bytes).len() <= 1);
+            assert!(binding.decoding_error().is_some(), "{frame:?}");
+            assert!(binding.poll_transmits(Instant::now()).is_err());
+            assert_eq!(
+                binding.snapshot().connection_status,
+                ConnectionStatus::Disconnected
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_error_policy_skip_packet() {
+        for frame in INVALID_FRAMES {
+            let mut binding = connected_binding(DecodeErrorPolicy::SkipPacket);
+            let mut bytes = frame.to_vec();
+            bytes.append(&mut Packet::from(PingReq).into_bytes());
+
+            let packets = feed_bytes(&mut binding, &bytes);
+            assert_eq!(packets.len(), 1, "{frame:?}");
+            assert_eq!(packets[0].packet_type(), PacketType::PingReq);
+            assert!(binding.decoding_error().is_none());
+            assert!(binding.poll_transmits(Instant::now()).is_ok());
+        }
+
+        // If the remaining length is invalid, it's unknown where the next frame starts.
+        let mut binding = connected_binding(DecodeErrorPolicy::SkipPacket);
+        feed_bytes(&mut binding, &[0b0011_0000, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(binding.decoding_error().is_some());
+        assert!(binding.poll_transmits(Instant::now()).is_err());
+    }
+
+    // Verify that the binding forgets topic filters that the server rejected.
+    #[test]
+    fn test_rejected_subscription() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let subscribe = Subscribe::builder("sensor/#", QoS::AtLeastOnceDelivery)
+            .add_topic("$SYS/#", QoS::AtLeastOnceDelivery)
+            .build();
+        binding.send(subscribe.clone().into());
+        binding.poll_transmits(Instant::now()).unwrap();
+        assert_eq!(binding.snapshot().subscriptions.len(), 2);
+
+        let suback = SubAck::builder(subscribe.packet_identifier(), QoS::AtLeastOnceDelivery)
+            .add_return_code(packet::suback::ReturnCode::Failure)
+            .build();
+        feed(&mut binding, suback.into());
+        assert_eq!(
+            binding.snapshot().subscriptions,
+            vec![("sensor/#".to_string(), QoS::AtLeastOnceDelivery)]
+        );
+    }
+
+    // Verify that the binding gives up on a server that doesn't answer the CONNECT.
+    #[test]
+    fn test_connect_timeout() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_connect_timeout(Duration::from_secs(5));
+        let now = Instant::now();
+        binding.poll_transmits(now).unwrap();
+        assert_eq!(binding.poll_timeout(now), now + Duration::from_secs(5));
+
+        binding.handle_timeout(now + Duration::from_secs(4));
+        assert_eq!(
+            binding.snapshot().connection_status,
+            ConnectionStatus::Connecting
+        );
+
+        binding.handle_timeout(now + Duration::from_secs(5));
+        let Err(reason) = binding.poll_transmits(now + Duration::from_secs(5)) else {
+            panic!("Expected the connection to time out.");
+        };
+        assert!(matches!(reason, ClientDisconnected::ConnectTimeout));
+        assert_eq!(reason.connect_error(), Some(ConnectError::Timeout));
+
+        // Once connected, the timeout doesn't apply.
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(60).build());
+        binding.set_connect_timeout(Duration::from_secs(5));
+        binding.poll_transmits(now).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+        assert_eq!(binding.poll_timeout(now), now + Duration::from_secs(60));
+        binding.handle_timeout(now + Duration::from_secs(5));
+        assert_eq!(
+            binding.snapshot().connection_status,
+            ConnectionStatus::Connected
+        );
+    }
+
+    // Verify that `MqttBinding::poll_transmits()` explains why the connection ended.
+    #[test]
+    fn test_disconnect_reason() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(Instant::now()).unwrap();
+        let connack = ConnAck::builder()
+            .return_code(packet::connack::ReturnCode::ConnectionRefusedNotAuthorized)
+            .build();
+        feed(&mut binding, connack.clone().into());
+        assert_eq!(
+            binding.snapshot().connection_status,
+            ConnectionStatus::Disconnected
+        );
+        let Err(ClientDisconnected::Refused(refused)) = binding.poll_transmits(Instant::now())
+        else {
+            panic!("Expected the connection to be refused.");
+        };
+        assert_eq!(refused, connack);
+        assert_eq!(
+            ClientDisconnected::Refused(refused).connect_error(),
+            Some(ConnectError::Refused(
+                packet::connack::ReturnCode::ConnectionRefusedNotAuthorized
+            ))
+        );
+
+        let mut binding = connected_binding(DecodeErrorPolicy::FailFast);
+        binding.send(Disconnect.into());
+        binding.poll_transmits(Instant::now()).unwrap();
+        assert!(matches!(
+            binding.poll_transmits(Instant::now()),
+            Err(ClientDisconnected::Requested)
+        ));
+
+        let mut binding = connected_binding(DecodeErrorPolicy::FailFast);
+        feed_bytes(&mut binding, INVALID_FRAMES[0]);
+        assert!(matches!(
+            binding.poll_transmits(Instant::now()),
+            Err(ClientDisconnected::ProtocolError(_))
+        ));
+    }
+
+    // Verify that the binding rewrites outbound topics, but keeps track of
+    // subscriptions using the topics of the application.
+    #[test]
+    fn test_topic_rewrite() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.add_topic_rewrite(TopicRewrite::prefix("old/", "new/"));
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        binding.send(Subscribe::builder("old/#", QoS::AtLeastOnceDelivery).build_packet());
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let Ok(Packet::Subscribe(subscribe)) = Packet::try_from(bytes) else {
+            panic!("Expected a SUBSCRIBE");
+        };
+        assert_eq!(
+            subscribe.topics().collect::<Vec<_>>(),
+            vec![("new/#", QoS::AtLeastOnceDelivery)]
+        );
+        assert_eq!(
+            binding.snapshot().subscriptions,
+            vec![("old/#".to_string(), QoS::AtLeastOnceDelivery)]
+        );
+
+        let packets = feed_bytes(
+            &mut binding,
+            &Packet::from(publish("new/1", "a")).into_bytes(),
+        );
+        let [Packet::Publish(publish)] = packets.as_slice() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publish.topic(), "old/1");
+    }
+
+    // Verify that a lease is only renewed with the instants passed to it, once its
+    // subscription was polled or while a poll is pending.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_lease() {
+        let lease = Lease::new(Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(lease.expiry(), None);
+        assert!(!lease.expired(start));
+        assert!(!lease.expired(start + Duration::from_secs(5)));
+        assert!(lease.expired(start + Duration::from_secs(10)));
+
+        lease.polled(false);
+        assert!(!lease.expired(start + Duration::from_secs(15)));
+        assert_eq!(lease.expiry(), Some(start + Duration::from_secs(25)));
+        assert!(lease.expired(start + Duration::from_secs(25)));
+
+        lease.polled(true);
+        assert!(!lease.expired(start + Duration::from_secs(60)));
+    }
+
+    // Verify that the binding unsubscribes once a lease expires, without emitting
+    // a PINGREQ while the keep alive is disabled.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_lease_expiry_without_keep_alive() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(0).build());
+        let start = Instant::now();
+        binding.poll_transmits(start).unwrap();
+        feed_at(&mut binding, ConnAck::builder().build().into(), start);
+
+        let (sender, _receiver) = async_channel::bounded(1);
+        let lease = Lease::new(Duration::from_secs(10));
+        Command::Route("sensor/#".to_string(), sender, 0, Some(lease)).apply(&mut binding);
+        assert!(binding.poll_transmits(start).unwrap().is_none());
+
+        let expiry = binding.poll_timeout(start);
+        assert_eq!(expiry, start + Duration::from_secs(10));
+        binding.handle_timeout(expiry);
+
+        let mut transmits = vec![];
+        while let Some(bytes) = binding.poll_transmits(expiry).unwrap() {
+            transmits.push(Packet::try_from(bytes).unwrap().packet_type());
+        }
+        assert_eq!(transmits, vec![PacketType::Unsubscribe]);
+        assert_eq!(binding.pings().count(), 0);
+    }
+
+    // Verify that the binding drops the packets whose topics can't be rewritten, instead
+    // of sending them with the topics of the application.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    #[test]
+    fn test_topic_rewrite_failure() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.add_topic_rewrite(TopicRewrite::prefix("a/", "x".repeat(65535)));
+        binding.add_topic_rewrite(TopicRewrite::prefix("y".repeat(65535), "b/"));
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let (queued, _) = async_channel::bounded(1);
+        let (reply, acknowledgement) = async_channel::bounded(1);
+        let publication = Publish::builder("a/1", "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .build();
+        Command::Publish(publication, queued, reply).apply(&mut binding);
+        let (reply, suback) = async_channel::bounded(1);
+        Command::Subscribe(subscribe("a/#"), reply).apply(&mut binding);
+
+        assert!(binding.poll_transmits(Instant::now()).unwrap().is_none());
+        assert_eq!(
+            acknowledgement.try_recv().unwrap(),
+            Err(HandleError::Dropped)
+        );
+        assert!(matches!(
+            suback.try_recv().unwrap(),
+            Err(SubscribeError::Connection(HandleError::Dropped))
+        ));
+        assert!(binding.snapshot().inflight.is_empty());
+        assert!(binding.snapshot().subscriptions.is_empty());
+        assert_eq!(binding.statistics().pending_acknowledgements, 0);
+
+        let packet = Packet::from(publish("b/1", "26.1"));
+        assert!(feed_bytes(&mut binding, &packet.into_bytes()).is_empty());
+    }
+
+    // Verify that the binding offers compression after the CONNACK, and only deflates
+    // publications once the server accepted. Inbound publications are inflated.
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compression() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_compression(compression::Compression::deflate().threshold(100));
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let Ok(Packet::Publish(offer)) = Packet::try_from(bytes) else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(offer.topic(), compression::NEGOTIATION_TOPIC);
+
+        let large = publish("sensor/1", vec![b'a'; 1000]);
+        binding.send(large.clone().into());
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        assert_eq!(Packet::try_from(bytes).unwrap(), large.clone().into());
+
+        assert!(try_feed_at(&mut binding, offer, Instant::now()).is_none());
+        binding.send(large.clone().into());
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let Ok(Packet::Publish(deflated)) = Packet::try_from(bytes) else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(deflated.topic(), "$tjiftjaf/deflate/sensor/1");
+        assert!(deflated.payload().len() < 100);
+
+        assert_eq!(feed(&mut binding, deflated.into()), large.into());
+    }
+
+    // Verify that a `Session` captured with `MqttBinding.suspend()` includes
+    // subscriptions and unacknowledged publications. And that `MqttBinding::from_session()`
+    // restores them.
+    #[test]
+    fn test_suspend_and_resume() {
+        let connect = Connect::builder().client_id("test").build();
+        let mut binding = MqttBinding::from_connect(connect.clone());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        binding.send(Subscribe::builder("sensor/#", QoS::AtLeastOnceDelivery).build_packet());
+        binding.send(
+            Publish::builder("sensor/1", "26.1")
+                .qos(QoS::AtLeastOnceDelivery)
+                .packet_identifier(1)
+                .build_packet(),
+        );
+        while binding.poll_transmits(Instant::now()).unwrap().is_some() {}
+
+        // This publication is never transmitted.
+        binding.send(publish("sensor/2", "26.2").into());
+
+        let session = binding.suspend();
+        assert_eq!(session.connect(), &connect);
+        assert_eq!(
+            session.subscriptions(),
+            &[("sensor/#".to_string(), QoS::AtLeastOnceDelivery)]
+        );
+        let [first, second] = session.publications() else {
+            panic!("Expected 2 publications.");
+        };
+        assert_eq!(first.packet_identifier(), Some(1));
+        assert!(first.duplicate());
+        assert_eq!(second.topic(), "sensor/2");
+        assert!(!second.duplicate());
+
+        // The binding emits DISCONNECT and nothing else.
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        assert_eq!(bytes, Packet::from(Disconnect).into_bytes());
+        assert!(binding.poll_transmits(Instant::now()).is_err());
+
+        let mut binding = MqttBinding::from_session(session);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let mut transmits = vec![];
+        while let Some(bytes) = binding.poll_transmits(Instant::now()).unwrap() {
+            transmits.push(Packet::try_from(bytes).unwrap().packet_type());
+        }
+        assert_eq!(
+            transmits,
+            vec![
+                PacketType::Subscribe,
+                PacketType::Publish,
+                PacketType::Publish
+            ]
+        );
+    }
+
+    // Verify that the binding acknowledges inbound publications and drives both
+    // directions of the QoS 2 handshake.
+    #[test]
+    fn test_acknowledgements() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let transmits = |binding: &mut MqttBinding| {
+            let mut packets = vec![];
+            while let Some(bytes) = binding.poll_transmits(Instant::now()).unwrap() {
+                packets.push(Packet::try_from(bytes).unwrap().into_bytes());
+            }
+            packets
+        };
+
+        // Inbound publications.
+        feed(&mut binding, publish("sensor/1", "26.1").into());
+        assert!(transmits(&mut binding).is_empty());
+
+        let qos_1 = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .packet_identifier(1)
+            .build_packet();
+        feed(&mut binding, qos_1);
+        assert_eq!(
+            transmits(&mut binding),
+            vec![Packet::from(PubAck::new(1)).into_bytes()]
+        );
+
+        let qos_2 = Packet::from(
+            Publish::builder("sensor/1", "26.1")
+                .qos(QoS::ExactlyOnceDelivery)
+                .packet_identifier(2)
+                .build(),
+        )
+        .into_bytes();
+        assert_eq!(feed_bytes(&mut binding, &qos_2).len(), 1);
+        assert!(feed_bytes(&mut binding, &qos_2).is_empty());
+        assert_eq!(
+            transmits(&mut binding),
+            vec![
+                Packet::from(PubRec::new(2)).into_bytes(),
+                Packet::from(PubRec::new(2)).into_bytes()
+            ]
+        );
+
+        feed(&mut binding, PubRel::new(2).into());
+        assert_eq!(
+            transmits(&mut binding),
+            vec![Packet::from(PubComp::new(2)).into_bytes()]
+        );
+        assert!(binding.suspend().received().is_empty());
+
+        // An outbound publication.
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        binding.send(
+            Publish::builder("sensor/1", "26.1")
+                .qos(QoS::ExactlyOnceDelivery)
+                .packet_identifier(3)
+                .build_packet(),
+        );
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, PubRec::new(3).into());
+        assert_eq!(
+            transmits(&mut binding),
+            vec![Packet::from(PubRel::new(3)).into_bytes()]
+        );
+
+        feed(&mut binding, PubComp::new(3).into());
+        assert!(binding.suspend().released().is_empty());
+    }
+
+    // Verify that the state of QoS 2 handshakes survives encoding and decoding
+    // the `Session`. Outbound publications that the server received are released,
+    // not retransmitted. Retransmissions of inbound publications are not delivered twice.
+    #[test]
+    fn test_suspend_and_resume_qos_2() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let outbound = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::ExactlyOnceDelivery)
+            .packet_identifier(1)
+            .build();
+        binding.send(outbound.into());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, PubRec::new(1).into());
+
+        let inbound = Publish::builder("sensor/2", "26.2")
+            .qos(QoS::ExactlyOnceDelivery)
+            .packet_identifier(2)
+            .build();
+        let bytes = Packet::from(inbound.clone()).into_bytes();
+        assert_eq!(feed_bytes(&mut binding, &bytes).len(), 1);
+
+        let session = binding.suspend();
+        assert!(session.publications().is_empty());
+        assert_eq!(session.released(), &[1]);
+        assert_eq!(session.received(), &[2]);
+
+        let session = Session::try_from(session.clone().into_bytes()).unwrap();
+        let mut binding = MqttBinding::from_session(session);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let Ok(Packet::PubRel(pubrel)) = Packet::try_from(bytes) else {
+            panic!("Expected a PUBREL");
+        };
+        assert_eq!(pubrel.packet_identifier(), 1);
+
+        // The retransmission is acknowledged, but not delivered.
+        assert!(feed_bytes(&mut binding, &Packet::from(inbound).into_bytes()).is_empty());
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let Ok(Packet::PubRec(pubrec)) = Packet::try_from(bytes) else {
+            panic!("Expected a PUBREC");
+        };
+        assert_eq!(pubrec.packet_identifier(), 2);
+
+        feed(&mut binding, PubRel::new(2).into());
+        feed(&mut binding, PubComp::new(1).into());
+        assert!(binding.snapshot().inflight.is_empty());
+        assert!(binding.suspend().received().is_empty());
+    }
+
+    // Verify that `packet_identifier()` never returns 0 and wraps around after 65535.
+    #[test]
+    fn test_packet_identifier_wraps_around() {
+        let mut previous = packet_identifier();
+        let mut wrapped = false;
+        for _ in 0..70_000 {
+            let packet_identifier = packet_identifier();
+            assert_ne!(packet_identifier, 0);
+            wrapped |= packet_identifier < previous;
+            previous = packet_identifier;
+        }
+        assert!(wrapped);
+    }
+
+    // Verify that the binding replaces packet identifiers that are still in use,
+    // and that replacing them keeps the rest of the packet intact.
+    #[test]
+    fn test_packet_identifier_collision() {
+        let mut binding = connected_binding(DecodeErrorPolicy::default());
+        let publish = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::ExactlyOnceDelivery)
+            .retain(true)
+            .packet_identifier(7)
+            .build();
+        binding.try_send(publish.clone().into()).unwrap();
+        binding.poll_transmits(Instant::now()).unwrap().unwrap();
+
+        let packets: [Packet; 3] = [
+            publish.into(),
+            Subscribe::builder("sensor/#", QoS::AtLeastOnceDelivery)
+                .add_topic("status/#", QoS::AtMostOnceDelivery)
+                .packet_identifier(7)
+                .build_packet(),
+            Unsubscribe::builder("sensor/#")
+                .packet_identifier(7)
+                .build_packet(),
+        ];
+        for packet in packets {
+            binding.try_send(packet.clone()).unwrap();
+            let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+            let mut sent = Packet::try_from(bytes).unwrap();
+            let packet_identifier = sent.allocated_packet_identifier().unwrap();
+            assert_ne!(packet_identifier, 7);
+
+            sent.set_packet_identifier(7);
+            assert_eq!(sent.into_bytes(), packet.into_bytes());
+        }
+    }
+
+    // Verify that the binding refuses packets when all packet identifiers are in use.
+    #[test]
+    fn test_packet_identifiers_exhausted() {
+        let mut binding = connected_binding(DecodeErrorPolicy::default());
+        let publish = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .packet_identifier(1)
+            .build();
+        for packet_identifier in 1..=u16::MAX {
+            binding.inflight.insert(packet_identifier, publish.clone());
+        }
+
+        assert_eq!(
+            binding.allocate_packet_identifier(),
+            Err(PacketIdentifiersExhausted)
+        );
+        assert!(binding.try_send(publish.clone().into()).is_err());
+
+        feed(&mut binding, PubAck::new(42).into());
+        assert_eq!(binding.allocate_packet_identifier(), Ok(42));
+        binding.try_send(publish.into()).unwrap();
+        let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+        let sent = Publish::try_from(bytes).unwrap();
+        assert_eq!(sent.packet_identifier(), Some(42));
+    }
+
+    // Publish more than 65535 publications with QoS 1, while 100 of them are in flight.
+    // Their packet identifiers repeat every 100 publications, so most collide with
+    // one in flight. The binding must never transmit an identifier that is in flight.
+    #[test]
+    fn test_packet_identifier_stress() {
+        let mut binding = connected_binding(DecodeErrorPolicy::default());
+        let mut inflight = VecDeque::new();
+
+        for n in 0..70_000_u32 {
+            let publish = Publish::builder("sensor/1", n.to_be_bytes().to_vec())
+                .qos(QoS::AtLeastOnceDelivery)
+                .packet_identifier((n % 100) as u16 + 1)
+                .build();
+            binding.try_send(publish.into()).unwrap();
+
+            let bytes = binding.poll_transmits(Instant::now()).unwrap().unwrap();
+            let sent = Publish::try_from(bytes).unwrap();
+            assert_eq!(sent.payload(), n.to_be_bytes());
+
+            let packet_identifier = sent.packet_identifier().unwrap();
+            assert!(
+                !inflight.contains(&packet_identifier),
+                "{packet_identifier}"
+            );
+            inflight.push_back(packet_identifier);
+
+            if inflight.len() == 100 {
+                let packet_identifier = inflight.pop_front().unwrap();
+                feed(&mut binding, PubAck::new(packet_identifier).into());
+            }
+        }
+        assert_eq!(binding.inflight.len(), 99);
+    }
+
+    // Verify that `MqttBinding::poll_transmits_batch()` coalesces transmits, and returns
+    // the transmits before a DISCONNECT before it reports the disconnect.
+    #[test]
+    fn test_poll_transmits_batch() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        let now = Instant::now();
+        assert_eq!(
+            binding.poll_transmits_batch(now, 1024).unwrap(),
+            Some(Packet::from(Connect::builder().build()).into_bytes())
+        );
+        assert_eq!(binding.poll_transmits_batch(now, 1024).unwrap(), None);
+        feed_at(&mut binding, ConnAck::builder().build().into(), now);
+
+        let publish = Publish::builder("sensor/1", "26.1").build();
+        let length = publish.as_bytes().len();
+        for _ in 0..3 {
+            binding.send(publish.clone().into());
+        }
+        binding.send(Disconnect.into());
+
+        // The batch may exceed `max_bytes` by the last transmit.
+        let batch = binding
+            .poll_transmits_batch(now, length + 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch, [publish.as_bytes(), publish.as_bytes()].concat());
+
+        let batch = binding.poll_transmits_batch(now, 1024).unwrap().unwrap();
+        let disconnect = Packet::from(Disconnect).into_bytes();
+        assert_eq!(batch, [publish.as_bytes(), &disconnect].concat());
+        assert!(matches!(
+            binding.poll_transmits_batch(now, 1024),
+            Err(ClientDisconnected::Requested)
+        ));
+    }
+
+    // Verify that the binding probes a silent server, and terminates the connection
+    // when a probe isn't answered in time.
+    #[test]
+    fn test_probe() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(60).build());
+        binding.set_probe(Duration::from_secs(2), Duration::from_secs(1));
+        let start = Instant::now();
+        binding.poll_transmits(start).unwrap();
+        feed_at(&mut binding, ConnAck::builder().build().into(), start);
+
+        // The server is probed long before the keep alive is due.
+        let now = binding.poll_timeout(start);
+        assert_eq!(now, start + Duration::from_secs(2));
+        binding.handle_timeout(now);
+        assert_eq!(
+            binding.poll_transmits(now).unwrap(),
+            Some(Packet::from(PingReq).into_bytes())
+        );
+        feed_at(&mut binding, PingResp.into(), now);
+        assert!(binding.pings().all(|ping| ping.answered.is_some()));
+
+        // A probe that isn't answered.
+        let sent = binding.poll_timeout(now);
+        assert_eq!(sent, now + Duration::from_secs(2));
+        binding.handle_timeout(sent);
+        binding.poll_transmits(sent).unwrap().unwrap();
+        assert_eq!(binding.poll_timeout(sent), sent + Duration::from_secs(1));
+
+        binding.handle_timeout(sent + Duration::from_millis(500));
+        assert!(binding.poll_transmits(sent).unwrap().is_none());
+
+        binding.handle_timeout(sent + Duration::from_secs(1));
+        assert!(matches!(
+            binding.poll_transmits(sent),
+            Err(ClientDisconnected::Unresponsive)
+        ));
+    }
+
+    // Verify that the binding terminates the connection when a keep alive
+    // isn't answered within the grace period.
+    #[test]
+    fn test_keep_alive_timeout() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(5).build());
+        binding.set_keep_alive_timeout(Duration::from_secs(2));
+        let start = Instant::now();
+        binding.poll_transmits(start).unwrap();
+        feed_at(&mut binding, ConnAck::builder().build().into(), start);
+
+        // An answered keep alive.
+        let now = binding.poll_timeout(start);
+        binding.handle_timeout(now);
+        binding.poll_transmits(now).unwrap().unwrap();
+        assert_eq!(binding.poll_timeout(now), now + Duration::from_secs(2));
+        feed_at(&mut binding, PingResp.into(), now + Duration::from_secs(1));
+        assert_eq!(binding.poll_timeout(now), now + Duration::from_secs(5));
+
+        // A keep alive that isn't answered.
+        let sent = binding.poll_timeout(now);
+        binding.handle_timeout(sent);
+        binding.poll_transmits(sent).unwrap().unwrap();
+        let now = binding.poll_timeout(sent);
+        assert_eq!(now, sent + Duration::from_secs(2));
+
+        binding.handle_timeout(now - Duration::from_millis(1));
+        assert!(binding.poll_transmits(now).unwrap().is_none());
+        binding.handle_timeout(now);
+        assert!(matches!(
+            binding.poll_transmits(now),
+            Err(ClientDisconnected::KeepAliveTimeout)
+        ));
+    }
+
+    // Verify that quirks let frames with reserved bits set decode.
+    #[test]
+    fn test_quirks() {
+        use crate::quirks::Quirks;
+
+        // A CONNACK with reserved acknowledge flags, and a PUBACK with reserved header flags.
+        let connack = [0x20, 2, 0b10, 0];
+        let puback = [0x42, 2, 0, 1];
+
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.poll_transmits(Instant::now()).unwrap();
+        assert!(try_feed_at(&mut binding, connack, Instant::now()).is_none());
+        assert!(binding.decoding_error().is_some());
+
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_quirks(Quirks::RESERVED_HEADER_FLAGS | Quirks::RESERVED_CONNACK_FLAGS);
+        binding.poll_transmits(Instant::now()).unwrap();
+        assert!(matches!(
+            try_feed_at(&mut binding, connack, Instant::now()),
+            Some(Packet::ConnAck(_))
+        ));
+        assert_eq!(binding.connection_status, ConnectionStatus::Connected);
+        let Some(Packet::PubAck(puback)) = try_feed_at(&mut binding, puback, Instant::now()) else {
+            panic!("Expected a PUBACK");
+        };
+        assert_eq!(puback.packet_identifier(), 1);
+    }
+
+    // Verify that interceptors can drop outbound packets and modify inbound packets.
+    #[test]
+    fn test_interceptor() {
+        use crate::intercept::Verdict;
+
+        struct Censor;
+
+        impl Interceptor for Censor {
+            fn on_inbound(&mut self, packet: &mut Packet) -> Verdict {
+                if let Packet::Publish(publish) = packet {
+                    *packet = Publish::builder(publish.topic(), "censored").build_packet();
+                }
+                Verdict::Pass
+            }
+
+            fn on_outbound(&mut self, packet: &mut Packet) -> Verdict {
+                match packet {
+                    Packet::Publish(publish) if publish.topic() == "secret" => Verdict::Drop,
+                    Packet::PingReq(_) => Verdict::Drop,
+                    _ => Verdict::Pass,
+                }
+            }
+        }
+
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.add_interceptor(Censor);
+        let now = Instant::now();
+        binding.poll_transmits(now).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        binding.send(publish("secret", "42").into());
+        binding.send(publish("public", "42").into());
+        let transmit = binding.poll_transmits(now).unwrap().unwrap();
+        assert_eq!(transmit, publish("public", "42").into_bytes());
+        assert!(binding.poll_transmits(now).unwrap().is_none());
+
+        // Control packets pass, whatever the interceptor decides.
+        binding.send(PingReq.into());
+        let transmit = binding.poll_transmits(now).unwrap().unwrap();
+        assert_eq!(transmit, Packet::from(PingReq).into_bytes());
+
+        let Packet::Publish(publication) = feed(&mut binding, publish("public", "42").into())
+        else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publication.payload(), b"censored");
+    }
+
+    // Verify that a replaced `Connect` is transmitted if the binding didn't connect yet,
+    // and otherwise only ends up in the session, without changing the keep alive.
+    #[test]
+    fn test_set_connect() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(5).build());
+        let connect = Connect::builder()
+            .client_id("replaced")
+            .keep_alive(5)
+            .build();
+        binding.set_connect(connect.clone());
+        let start = Instant::now();
+        let transmit = binding.poll_transmits(start).unwrap().unwrap();
+        assert_eq!(transmit, connect.into_bytes());
+        feed_at(&mut binding, ConnAck::builder().build().into(), start);
+
+        let connect = Connect::builder()
+            .client_id("replaced")
+            .username("admin")
+            .password("secret")
+            .keep_alive(60)
+            .build();
+        binding.set_connect(connect.clone());
+        assert_eq!(binding.session().connect(), &connect);
+        assert_eq!(binding.poll_timeout(start), start + Duration::from_secs(5));
+    }
+
+    // Verify that redeliveries of QoS 1 publications within the deduplication window
+    // are acknowledged, but not returned.
+    #[test]
+    fn test_deduplication_window() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+        binding.set_deduplication_window(Duration::from_secs(10));
+        let start = Instant::now();
+        binding.poll_transmits(start).unwrap();
+        feed_at(&mut binding, ConnAck::builder().build().into(), start);
+
+        let publication = |topic: &str, duplicate: bool| {
+            Publish::builder(topic, "21.3")
+                .qos(QoS::AtLeastOnceDelivery)
+                .packet_identifier(1)
+                .duplicate(duplicate)
+                .build()
+        };
+        let acknowledged = |binding: &mut MqttBinding, now: Instant| {
+            let transmit = binding.poll_transmits(now).unwrap().unwrap();
+            assert_eq!(transmit, Packet::from(PubAck::new(1)).into_bytes());
+        };
+
+        assert!(try_feed_at(&mut binding, publication("sensor/1", false), start).is_some());
+        acknowledged(&mut binding, start);
+
+        // A redelivery within the window is dropped.
+        let now = start + Duration::from_secs(5);
+        assert!(try_feed_at(&mut binding, publication("sensor/1", true), now).is_none());
+        acknowledged(&mut binding, now);
+
+        // Publications without the DUP flag, or with another topic, are delivered.
+        assert!(try_feed_at(&mut binding, publication("sensor/1", false), now).is_some());
+        acknowledged(&mut binding, now);
+        assert!(try_feed_at(&mut binding, publication("sensor/2", true), now).is_some());
+        acknowledged(&mut binding, now);
+
+        // The window runs from the last delivery, when the first one is forgotten.
+        let now = start + Duration::from_secs(12);
+        assert!(try_feed_at(&mut binding, publication("sensor/1", true), now).is_none());
+        acknowledged(&mut binding, now);
+
+        // A redelivery after the window is delivered.
+        let now = start + Duration::from_secs(20);
+        assert!(try_feed_at(&mut binding, publication("sensor/1", true), now).is_some());
+        acknowledged(&mut binding, now);
+    }
+
+    // Verify that `MqttBinding.pings()` records when each keep alive was due,
+    // sent and answered.
+    #[test]
+    fn test_pings() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(5).build());
+        let start = Instant::now();
+        binding.poll_transmits(start).unwrap();
+        feed_at(&mut binding, ConnAck::builder().build().into(), start);
+
+        let scheduled = binding.poll_timeout(start);
+        assert_eq!(scheduled, start + Duration::from_secs(5));
+        let now = scheduled + Duration::from_millis(200);
+        binding.handle_timeout(now);
+        assert_eq!(
+            binding.pings().collect::<Vec<_>>(),
+            [&Ping {
+                sequence: 0,
+                scheduled,
+                sent: None,
+                answered: None
+            }]
+        );
+
+        binding.poll_transmits(now).unwrap().unwrap();
+        feed_at(&mut binding, PingResp.into(), now);
+        let [ping] = binding.snapshot().pings.try_into().unwrap();
+        assert_eq!(ping.sent, Some(now));
+        assert!(ping.answered.is_some());
+
+        // A keep alive that is never answered.
+        let now = binding.poll_timeout(now);
+        binding.handle_timeout(now);
+        binding.poll_transmits(now).unwrap().unwrap();
+        let pings: Vec<_> = binding.pings().collect();
+        assert_eq!(pings[1].sequence, 1);
+        assert_eq!(pings[1].sent, Some(now));
+        assert_eq!(pings[1].answered, None);
+
+        for _ in 0..PING_HISTORY {
+            let now = binding.poll_timeout(now);
+            binding.handle_timeout(now);
+            binding.poll_transmits(now).unwrap().unwrap();
+        }
+        assert_eq!(binding.pings().count(), PING_HISTORY);
+        assert_eq!(binding.pings().next().unwrap().sequence, 2);
+    }
+
+    // Issue #53 tracks a bug where the MqttBinding enters a hot loop
+    // when the keep alive interval is 0.
+    //
+    // This test verifies the fix for that. First, it creates a binding with
+    // a keep alive interval of 5 seconds. After the CONNECT is transmitted,
+    // `MqttBinding.poll_timeout()` returns an Instant 5 seconds in the future.
+    //
+    // Then, the test is repeated with a keep alive interval of 0. Now, the Instant
+    // is 30 years in the future instead of 0 seconds.
+    #[test]
+    fn gh_53_test_fix_for_keep_alive_interval_of_0() {
+        let connect = Connect::builder().keep_alive(5).build();
+
+        let now = Instant::now();
+        let mut binding = MqttBinding::from_connect(connect);
+        binding.poll_transmits(now).unwrap();
+        let interval = binding.poll_timeout(now) - now;
+        assert_eq!(interval, Duration::from_secs(5));
+
+        // Now, try again with a keep alive interval of 0 seconds.
+        let connect = Connect::builder().keep_alive(0).build();
+
+        let mut binding = MqttBinding::from_connect(connect);
+        binding.poll_transmits(now).unwrap();
+        let interval = binding.poll_timeout(now) - now;
+
+        assert_eq!(interval, Duration::from_secs(946080000));
+    }
+
+    // Verify that nothing is timed before the CONNECT is transmitted, and that
+    // the binding only uses the time it's given.
+    #[test]
+    fn test_timeout_before_connect() {
+        let mut binding = MqttBinding::from_connect(Connect::builder().keep_alive(5).build());
+        binding.set_probe(Duration::from_secs(2), Duration::from_secs(1));
+
+        // An arbitrary moment in the future, long after the binding was created.
+        let start = Instant::now() + Duration::from_secs(3600);
+        assert_eq!(binding.poll_timeout(start), start);
+        assert_eq!(binding.snapshot().next_timeout, None);
+        binding.handle_timeout(start);
+        assert_eq!(binding.pings().count(), 0);
+
+        binding.poll_transmits(start).unwrap().unwrap();
+        assert_eq!(binding.snapshot().last_io, Some(start));
+        assert_eq!(binding.poll_timeout(start), start + Duration::from_secs(2));
+    }
+}
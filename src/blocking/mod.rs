@@ -5,6 +5,9 @@
 //! allowing an application to [subscribe](crate::Subscribe::emit()) to topics, [publish](crate::Publish::emit()) messages and [retrieve
 //! publications](ClientHandle::publication()).
 //!
+//! Applications that don't want to manage a `Client` and its `ClientHandle` can use
+//! [`SimpleClient`](simple::SimpleClient) instead.
+//!
 //! Below you find a small snippet. Also, take a look at [examples/blocking_client.rs](https://github.com/eastern-oak/tjiftjaf/blob/master/examples/blocking_client.rs)
 //! for a more complete example.
 //!
@@ -28,7 +31,7 @@
 //!    .unwrap();
 //!
 //! // ...to publish messages...
-//! publish("some-topic", r"payload".into())
+//! publish("some-topic", r"payload")
 //!    .emit(&handle)
 //!    .unwrap();
 //!
@@ -36,17 +39,34 @@
 //! let publication = handle.publication().unwrap();
 //! println!("Received message on topic {}", publication.topic());
 //! ```
-use crate::{Connect, ConnectionError, Disconnect, MqttBinding, Packet, Publish};
-use async_channel::{Receiver, Sender};
-use log::info;
+use crate::target;
+#[cfg(feature = "tls")]
+use crate::tls::rustls;
+use crate::{
+    quirks::Quirks, subscribe_all, ClientDisconnected, Command, Connect, ConnectError,
+    DecodeErrorPolicy, Disconnect, HandleError, Interceptor, MqttBinding, Packet, Ping, Publish,
+    QoS, Session, SessionStore, Snapshot, Statistics, Subscribe, SubscribeError,
+    SubscriptionResult, Termination, TopicRewrite, Unsubscribe, WaitTimeoutError,
+};
+use async_channel::{Receiver, Sender, TrySendError};
+use log::{info, warn};
 use mio::{Events, Interest, Poll, Token, Waker};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::{
-    io::{Read, Write},
-    net::{Shutdown, TcpStream},
+    collections::VecDeque,
+    future::Future,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    pin::pin,
+    sync::Arc,
+    task::{Context, Wake},
     thread::{self, JoinHandle},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+pub mod simple;
+
 const CLIENT: Token = Token(0);
 const PUBLISH: Token = Token(1);
 
@@ -54,143 +74,693 @@ const PUBLISH: Token = Token(1);
 ///
 /// See the [module documentation](crate::blocking) for more information.
 pub struct Client {
-    socket: mio::net::TcpStream,
+    socket: Socket,
     binding: MqttBinding,
+
+    // If set, the connection is encrypted with TLS. See `Client::connect_tls()`.
+    #[cfg(feature = "tls")]
+    tls: Option<rustls::ClientConnection>,
 }
 
 impl Client {
     /// Create a new `Client`.
     pub fn new(connect: Connect, socket: TcpStream) -> Self {
         Self {
-            socket: mio::net::TcpStream::from_std(socket),
+            socket: Socket::Tcp(socket),
+            binding: MqttBinding::from_connect(connect),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Create a `Client` that continues a [`Session`] obtained with [`ClientHandle::suspend()`].
+    ///
+    /// After connecting, the client subscribes to the topics of the session again
+    /// and retransmits the publications that were not acknowledged.
+    pub fn resume(session: Session, socket: TcpStream) -> Self {
+        Self {
+            socket: Socket::Tcp(socket),
+            binding: MqttBinding::from_session(session),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Create a new `Client` that talks to a broker on the same machine over a Unix domain socket.
+    ///
+    /// ```no_run
+    /// use std::os::unix::net::UnixStream;
+    /// use tjiftjaf::{blocking::Client, Connect};
+    ///
+    /// let socket = UnixStream::connect("/var/run/mosquitto.sock").unwrap();
+    /// let client = Client::new_unix(Connect::builder().build(), socket);
+    /// let (handle, _task) = client.spawn().unwrap();
+    /// ```
+    #[cfg(unix)]
+    pub fn new_unix(connect: Connect, socket: UnixStream) -> Self {
+        Self {
+            socket: Socket::Unix(socket),
             binding: MqttBinding::from_connect(connect),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Like [`Client::resume()`], but over a Unix domain socket. See [`Client::new_unix()`].
+    #[cfg(unix)]
+    pub fn resume_unix(session: Session, socket: UnixStream) -> Self {
+        Self {
+            socket: Socket::Unix(socket),
+            binding: MqttBinding::from_session(session),
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
+    /// Connect to the broker at `addr`. That's anything that resolves to socket addresses,
+    /// like `"localhost:1883"`, `"[::1]:1883"`, `("broker.local", 1883)` or a `SocketAddr`.
+    ///
+    /// The addresses are tried in order, until one accepts the connection. The duration
+    /// of the TCP connect is recorded in the [`Statistics`].
+    pub fn connect(addr: impl ToSocketAddrs, connect: Connect) -> Result<Self, std::io::Error> {
+        let start = Instant::now();
+        let socket = TcpStream::connect(addr)?;
+        let mut client = Self::new(connect, socket);
+        client.binding.statistics.connect_duration = Some(start.elapsed());
+        Ok(client)
+    }
+
+    /// Connect to the broker at `addr`, formatted as `host:port`, and encrypt the connection with TLS.
+    /// Enclose an IPv6 address in brackets, like `[::1]:8883`.
+    ///
+    /// The host is used to verify the certificate of the broker. The TLS handshake
+    /// completes before this method returns. See [`crate::tls`] for an example of a `ClientConfig`.
+    ///
+    /// The durations of the TCP connect and of the handshake are recorded in the [`Statistics`].
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        addr: &str,
+        connect: Connect,
+        config: std::sync::Arc<rustls::ClientConfig>,
+    ) -> Result<Self, std::io::Error> {
+        let mut connection = crate::tls::client_connection(addr, config)?;
+        let start = Instant::now();
+        let mut socket = TcpStream::connect(addr)?;
+        let connected = Instant::now();
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+
+        let mut client = Self::new(connect, socket);
+        let statistics = &mut client.binding.statistics;
+        statistics.connect_duration = Some(connected - start);
+        statistics.tls_handshake_duration = Some(connected.elapsed());
+        statistics.tls_resumed = crate::tls::resumed(&connection);
+        client.tls = Some(connection);
+        Ok(client)
+    }
+
+    /// Configure what happens when the broker sends a frame that can't be decoded.
+    ///
+    /// By default, the client terminates the connection and the thread returns
+    /// an error of kind [`ErrorKind::InvalidData`].
+    pub fn decode_error_policy(mut self, policy: DecodeErrorPolicy) -> Self {
+        self.binding.set_decode_error_policy(policy);
+        self
+    }
+
+    /// Terminate the connection when the broker sends a packet larger than `size` bytes.
+    ///
+    /// See [`MqttBinding::set_max_packet_size()`].
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.binding.set_max_packet_size(size);
+        self
+    }
+
+    /// Fail the [`DeliveryToken`]s of publications the broker didn't acknowledge
+    /// within `timeout` with [`HandleError::Timeout`].
+    ///
+    /// See [`MqttBinding::set_acknowledgement_timeout()`].
+    pub fn acknowledgement_timeout(mut self, timeout: Duration) -> Self {
+        self.binding.set_acknowledgement_timeout(timeout);
+        self
+    }
+
+    /// Tolerate a broker that deviates from the specification in the ways of `quirks`.
+    ///
+    /// See [`MqttBinding::set_quirks()`].
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.binding.set_quirks(quirks);
+        self
+    }
+
+    /// Limit the number of publications with QoS 1 or 2 that wait for an acknowledgement
+    /// of the broker.
+    ///
+    /// See [`MqttBinding::set_max_inflight()`].
+    pub fn max_inflight(mut self, limit: usize) -> Self {
+        self.binding.set_max_inflight(limit);
+        self
+    }
+
+    /// Limit the number of publications that wait for the application, before the client
+    /// throttles the broker.
+    ///
+    /// See [`MqttBinding::set_max_buffered_publications()`].
+    pub fn max_buffered_publications(mut self, limit: usize) -> Self {
+        self.binding.set_max_buffered_publications(limit);
+        self
+    }
+
+    /// Probe the broker after it was silent for `interval`, and terminate the connection
+    /// when it doesn't answer within `timeout`. This detects half-open connections quickly.
+    ///
+    /// See [`MqttBinding::set_probe()`].
+    pub fn probe(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.binding.set_probe(interval, timeout);
+        self
+    }
+
+    /// Terminate the connection when the broker doesn't answer the CONNECT within `timeout`.
+    /// The event loop then returns an error of kind `TimedOut`, that wraps a [`ConnectError`].
+    ///
+    /// See [`MqttBinding::set_connect_timeout()`].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.binding.set_connect_timeout(timeout);
+        self
+    }
+
+    /// Offer the broker to compress publications. Only a tjiftjaf server accepts the offer.
+    ///
+    /// See [`MqttBinding::set_compression()`].
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: crate::compression::Compression) -> Self {
+        self.binding.set_compression(compression);
+        self
+    }
+
+    /// Terminate the connection when the broker doesn't answer a PINGREQ within `grace`.
+    /// The event loop then returns an error of kind `TimedOut`.
+    ///
+    /// See [`MqttBinding::set_keep_alive_timeout()`].
+    pub fn keep_alive_timeout(mut self, grace: Duration) -> Self {
+        self.binding.set_keep_alive_timeout(grace);
+        self
+    }
+
+    /// Drop redeliveries of QoS 1 publications that arrive within `window` of the original.
+    /// This is best-effort.
+    ///
+    /// See [`MqttBinding::set_deduplication_window()`].
+    pub fn deduplication_window(mut self, window: Duration) -> Self {
+        self.binding.set_deduplication_window(window);
+        self
+    }
+
+    /// Rewrite the topics exchanged with the server. See [`TopicRewrite`].
+    pub fn topic_rewrite(mut self, rewrite: TopicRewrite) -> Self {
+        self.binding.add_topic_rewrite(rewrite);
+        self
+    }
+
+    /// Save the [`Session`] to `store` whenever it changes.
+    ///
+    /// See [`MqttBinding::set_session_store()`].
+    pub fn session_store(mut self, store: impl SessionStore + Send + 'static) -> Self {
+        self.binding.set_session_store(store);
+        self
+    }
+
+    /// Inspect, modify or drop the packets exchanged with the broker.
+    ///
+    /// See [`MqttBinding::add_interceptor()`].
+    pub fn interceptor(mut self, interceptor: impl Interceptor + Send + 'static) -> Self {
+        self.binding.add_interceptor(interceptor);
+        self
+    }
+
     /// Start a new thread and move the `Client` to it.
     pub fn spawn(
         self,
     ) -> Result<(ClientHandle, JoinHandle<Result<(), std::io::Error>>), std::io::Error> {
+        // `mio` requires the socket to be in non-blocking mode.
+        self.socket.set_nonblocking(true)?;
+
         let poll = Poll::new()?;
         let waker = Waker::new(poll.registry(), PUBLISH)?;
+        let socket = self.socket.try_clone()?;
 
         // TODO: GH-83 decide on capacity of channel.
         // For communication _to_ the handler.
         let (to_tx, to_rx) = async_channel::bounded(100);
         // For communication _from_ the handler.
         let (from_tx, from_rx) = async_channel::bounded(100);
-        let handle = ClientHandle::new(from_tx, to_rx, waker);
+        let termination = Termination::default();
+        let handle = ClientHandle::new(from_tx, to_rx, waker, socket, termination.clone());
 
         Ok((
             handle,
-            thread::spawn(move || self.run(poll, to_tx, from_rx)),
+            thread::spawn(move || self.run(poll, to_tx, from_rx, termination)),
         ))
     }
 
     fn run(
-        mut self,
-        mut poll: Poll,
+        self,
+        poll: Poll,
         sender: Sender<Packet>,
-        receiver: Receiver<Packet>,
+        receiver: Receiver<Command>,
+        termination: Termination,
     ) -> Result<(), std::io::Error> {
-        let mut events = Events::with_capacity(128);
-        poll.registry()
-            .register(&mut self.socket, CLIENT, Interest::READABLE)?;
-
-        // In this loop, check with the binding if any outbound
-        // packets are waiting. We call them 'transmits'. Send all pending
-        // transmits to the broker.
-        //
-        // When done, request a read buffer, read bytes from the broker until
-        // the buffer is full. Then, request the binding to decode the buffer.
-        // This operation might yield a mqtt::Packet for further processing.
-        loop {
-            while let Ok(packet) = receiver.try_recv() {
-                self.binding.send(packet);
+        let Self {
+            socket,
+            mut binding,
+            #[cfg(feature = "tls")]
+            tls,
+        } = self;
+        // Boxed, so the event loop handles every transport the same way.
+        let mut socket: Box<dyn Transport> = match socket {
+            Socket::Tcp(socket) => {
+                let socket = mio::net::TcpStream::from_std(socket);
+                #[cfg(feature = "tls")]
+                let socket: Box<dyn Transport> = match tls {
+                    Some(connection) => Box::new(rustls::StreamOwned::new(connection, socket)),
+                    None => Box::new(socket),
+                };
+                #[cfg(not(feature = "tls"))]
+                let socket = Box::new(socket);
+                socket
             }
+            #[cfg(unix)]
+            Socket::Unix(socket) => Box::new(mio::net::UnixStream::from_std(socket)),
+        };
 
-            loop {
-                match self.binding.poll_transmits(Instant::now()) {
-                    Ok(Some(bytes)) => {
-                        self.socket.write_all(&bytes)?;
-                    }
-                    Ok(None) => break,
-                    Err(_) => {
-                        self.socket.shutdown(Shutdown::Both)?;
-                        info!("The client disconnected.");
-                        return Ok(());
+        let result = event_loop(&mut *socket, &mut binding, poll, &sender, &receiver);
+        termination.terminate();
+        result
+    }
+}
+
+fn event_loop(
+    socket: &mut dyn Transport,
+    binding: &mut MqttBinding,
+    mut poll: Poll,
+    sender: &Sender<Packet>,
+    receiver: &Receiver<Command>,
+) -> Result<(), std::io::Error> {
+    let mut events = Events::with_capacity(128);
+    poll.registry()
+        .register(socket.source(), CLIENT, Interest::READABLE)?;
+
+    // In this loop, check with the binding if any outbound
+    // packets are waiting. We call them 'transmits'. Send all pending
+    // transmits to the broker.
+    //
+    // When done, request a read buffer, read bytes from the broker until
+    // the buffer is full. Then, request the binding to decode the buffer.
+    // This operation might yield a mqtt::Packet for further processing.
+    //
+    // Decoded publications are queued in `deliveries` until the handle accepts them.
+    // So a handle that lags doesn't stop the loop from acknowledging packets
+    // and emitting keep alives. The binding handled the other packets already.
+    // They don't reach the handle, so a handle that isn't drained doesn't hold up
+    // the acknowledgements.
+    //
+    // The socket is non-blocking. Transmits wait in `outbox` until the socket accepts
+    // them, and the loop waits for the socket to become writable while they do.
+    //
+    // The socket is edge-triggered. The loop keeps reading until the socket is drained,
+    // otherwise packets that arrived together are stuck until the next event. It only
+    // pauses while too many packets are waiting for the handle, and resumes once
+    // the handle caught up.
+    let mut deliveries = VecDeque::new();
+    let mut outbox = Outbox::default();
+    let mut readable = false;
+    loop {
+        Command::apply_pending(receiver, binding);
+        deliver(sender, &mut deliveries, binding)?;
+        if readable {
+            readable = read(socket, binding, &mut deliveries)?;
+            deliver(sender, &mut deliveries, binding)?;
+        }
+
+        loop {
+            match binding.poll_transmits(Instant::now()) {
+                Ok(Some(bytes)) => outbox.push(&bytes),
+                Ok(None) => {
+                    // Draining the transmits made room for the commands that didn't fit
+                    // before. Their handles don't wake the loop again, so apply them now.
+                    if receiver.is_empty() || !binding.has_capacity() {
+                        break;
                     }
+                    Command::apply_pending(receiver, binding);
+                }
+                Err(reason) => {
+                    outbox.drain(socket, &mut poll, &mut events)?;
+                    socket.shutdown()?;
+                    return match reason {
+                        ClientDisconnected::Requested => {
+                            info!(target: target::BLOCKING, "The client disconnected.");
+                            Ok(())
+                        }
+                        ClientDisconnected::Refused(connack) => Err(std::io::Error::new(
+                            ErrorKind::ConnectionRefused,
+                            ConnectError::Refused(connack.return_code()),
+                        )),
+                        ClientDisconnected::ConnectTimeout => Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            ConnectError::Timeout,
+                        )),
+                        ClientDisconnected::ProtocolError(_) => Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            reason.to_string(),
+                        )),
+                        ClientDisconnected::Unresponsive | ClientDisconnected::KeepAliveTimeout => {
+                            Err(std::io::Error::new(ErrorKind::TimedOut, reason.to_string()))
+                        }
+                    };
                 }
             }
+        }
+        outbox.flush(socket, poll.registry())?;
 
-            let timeout = self.binding.poll_timeout();
-            poll.poll(&mut events, Some(timeout - Instant::now()))?;
+        // `mio` can't wait for capacity of the channel. While packets are waiting
+        // for the handle, retry delivering them periodically. Once the handle made room,
+        // continue reading right away.
+        let now = Instant::now();
+        let mut timeout = binding.poll_timeout(now);
+        if readable && deliveries.len() < MAX_PENDING_DELIVERIES {
+            timeout = now;
+        } else if !deliveries.is_empty() {
+            timeout = timeout.min(now + DELIVERY_RETRY_INTERVAL);
+        }
+        poll.poll(&mut events, Some(timeout.saturating_duration_since(now)))?;
 
-            for event in events.iter() {
-                if event.token() == PUBLISH {
-                    while let Ok(packet) = receiver.try_recv() {
-                        self.binding.send(packet);
-                    }
-                }
+        let now = Instant::now();
+        if now >= binding.poll_timeout(now) {
+            binding.handle_timeout(now);
+        }
 
-                if event.token() != CLIENT {
-                    continue;
-                }
+        for event in events.iter() {
+            if event.token() == PUBLISH {
+                Command::apply_pending(receiver, binding);
+            }
 
-                if !event.is_readable() {
-                    continue;
-                }
+            if event.token() != CLIENT {
+                continue;
+            }
 
-                loop {
-                    let mut buffer = self.binding.get_read_buffer();
-                    self.socket.read_exact(&mut buffer)?;
-
-                    // TODO: If packet is invalid, try_decode() never returns a `Some`,
-                    // And thus the `loop` never breaks.
-                    // Maybe `try_decode` should return an Error. Maybe with variant `NotEnoughBytes`
-                    // to indicate that more bytes are expected and event loop should continue.
-                    // Any other error indicates an issue and event loop must break the loop
-                    if let Some(packet) = self.binding.try_decode(buffer, Instant::now()) {
-                        sender
-                            .send_blocking(packet)
-                            .map_err(std::io::Error::other)?;
-                        break;
-                    };
-                }
+            if event.is_writable() {
+                outbox.flush(socket, poll.registry())?;
+            }
+
+            if event.is_readable() {
+                readable = true;
+            }
+        }
+    }
+}
+
+// Read and decode packets until the socket is drained, or too many publications are waiting
+// for the handle. Returns whether the socket might hold more bytes.
+fn read(
+    socket: &mut dyn Transport,
+    binding: &mut MqttBinding,
+    deliveries: &mut VecDeque<Packet>,
+) -> Result<bool, std::io::Error> {
+    while deliveries.len() < MAX_PENDING_DELIVERIES {
+        let mut buffer = binding.get_read_buffer();
+        let bytes_read = match socket.read(&mut buffer) {
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "The broker closed the connection.",
+                ))
+            }
+            Ok(bytes_read) => bytes_read,
+            Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        buffer.truncate(bytes_read);
+
+        // TODO: If packet is invalid, try_decode() never returns a `Some`,
+        // And thus the `loop` never breaks.
+        // Maybe `try_decode` should return an Error. Maybe with variant `NotEnoughBytes`
+        // to indicate that more bytes are expected and event loop should continue.
+        // Any other error indicates an issue and event loop must break the loop
+        if let Some(packet @ Packet::Publish(_)) = binding.try_decode(buffer, Instant::now()) {
+            deliveries.push_back(packet);
+        };
+    }
+    Ok(true)
+}
+
+// The maximum number of decoded publications waiting for the handle, before
+// the event loop stops reading from the socket.
+const MAX_PENDING_DELIVERIES: usize = 100;
+
+// How often the event loop retries to deliver packets to a handle that lags.
+const DELIVERY_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// How long the event loop waits for the socket to accept the last transmits, before
+// it closes the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The transmits that the socket didn't accept yet.
+#[derive(Default)]
+struct Outbox {
+    bytes: VecDeque<u8>,
+
+    // Whether the socket is registered for writable events.
+    writable: bool,
+}
+
+impl Outbox {
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+    }
+
+    // Write as much as the socket accepts. While anything remains, including data that
+    // TLS buffered, the socket is registered for writable events.
+    fn flush(
+        &mut self,
+        socket: &mut dyn Transport,
+        registry: &mio::Registry,
+    ) -> std::io::Result<()> {
+        let done = self.write(socket)?;
+        if done == self.writable {
+            let interest = if done {
+                Interest::READABLE
+            } else {
+                Interest::READABLE | Interest::WRITABLE
+            };
+            registry.reregister(socket.source(), CLIENT, interest)?;
+            self.writable = !done;
+        }
+        Ok(())
+    }
+
+    // Returns whether all bytes were written.
+    fn write(&mut self, socket: &mut dyn Transport) -> std::io::Result<bool> {
+        while !self.bytes.is_empty() {
+            let (bytes, _) = self.bytes.as_slices();
+            match socket.write(bytes) {
+                // TLS accepts nothing while its buffer is full, until the socket is writable.
+                Ok(0) => return Ok(false),
+                Ok(written) => _ = self.bytes.drain(..written),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        match socket.flush() {
+            Ok(()) => Ok(!socket.wants_write()),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Wait until everything is written, or `CLOSE_TIMEOUT` passed.
+    fn drain(
+        &mut self,
+        socket: &mut dyn Transport,
+        poll: &mut Poll,
+        events: &mut Events,
+    ) -> std::io::Result<()> {
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        loop {
+            self.flush(socket, poll.registry())?;
+            let now = Instant::now();
+            if !self.writable || now >= deadline {
+                return Ok(());
+            }
+            poll.poll(events, Some(deadline - now))?;
+        }
+    }
+}
+
+// Pass queued publications to the handle, as long as it has capacity.
+fn deliver(
+    sender: &Sender<Packet>,
+    deliveries: &mut VecDeque<Packet>,
+    binding: &mut MqttBinding,
+) -> Result<(), std::io::Error> {
+    while let Some(packet) = deliveries.pop_front() {
+        match sender.try_send(packet) {
+            Ok(()) => {}
+            Err(TrySendError::Full(packet)) => {
+                deliveries.push_front(packet);
+                break;
             }
+            Err(error) => return Err(std::io::Error::other(error)),
+        }
+
+        binding.publication_delivered();
+    }
+    Ok(())
+}
+
+// The socket passed to a `Client`.
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Socket::Tcp(socket) => socket.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Socket::Tcp(socket) => socket.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.try_clone().map(Socket::Unix),
+        }
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        match self {
+            Socket::Tcp(socket) => socket.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.shutdown(Shutdown::Both),
         }
     }
 }
 
+/// A connection the event loop of a [`Client`] exchanges packets over.
+///
+/// It's implemented for the sockets the `Client` connects with: TCP, TLS over TCP and,
+/// on Unix, Unix domain sockets. The socket must be in non-blocking mode.
+pub trait Transport: Read + Write {
+    /// The underlying socket, to register it with `mio`.
+    fn source(&mut self) -> &mut dyn mio::event::Source;
+
+    /// Whether the transport holds data that must be written to the socket, like the
+    /// records of a TLS connection. Returns `false` by default.
+    fn wants_write(&self) -> bool {
+        false
+    }
+
+    /// Shut down both directions of the underlying socket.
+    fn shutdown(&self) -> std::io::Result<()>;
+}
+
+impl Transport for mio::net::TcpStream {
+    fn source(&mut self) -> &mut dyn mio::event::Source {
+        self
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        mio::net::TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Transport for rustls::StreamOwned<rustls::ClientConnection, mio::net::TcpStream> {
+    fn source(&mut self) -> &mut dyn mio::event::Source {
+        &mut self.sock
+    }
+
+    fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.sock.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for mio::net::UnixStream {
+    fn source(&mut self) -> &mut dyn mio::event::Source {
+        self
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        mio::net::UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
 /// A handle to interact with a [`Client`].
 ///
 /// See the [module documentation](crate::blocking) for more information.
 pub struct ClientHandle {
-    // Send packets to the `Client`.
-    sender: Sender<Packet>,
+    // Send commands to the `Client`.
+    sender: Sender<Command>,
 
     // Receive packets from the `Client`
     receiver: Receiver<Packet>,
 
     waker: Waker,
+
+    // A clone of the socket of the `Client`, to force it to terminate.
+    socket: Socket,
+
+    termination: Termination,
 }
 
 impl ClientHandle {
-    fn new(sender: Sender<Packet>, receiver: Receiver<Packet>, waker: Waker) -> Self {
+    fn new(
+        sender: Sender<Command>,
+        receiver: Receiver<Packet>,
+        waker: Waker,
+        socket: Socket,
+        termination: Termination,
+    ) -> Self {
         Self {
             sender,
             receiver,
             waker,
+            socket,
+            termination,
         }
     }
 
     /// Send any `Packet` to the broker.
-    pub(crate) fn send(&self, packet: Packet) -> Result<(), ConnectionError> {
-        self.sender.send_blocking(packet)?;
-        self.waker.wake().map_err(|_| ConnectionError)?;
+    pub(crate) fn send(&self, packet: Packet) -> Result<(), HandleError> {
+        self.command(Command::Packet(packet))
+    }
+
+    // Send a command to the `Client` and wake it up.
+    fn command(&self, command: Command) -> Result<(), HandleError> {
+        self.sender
+            .send_blocking(command)
+            .map_err(|_| self.termination.error())?;
+        self.waker.wake().map_err(|_| self.termination.error())?;
         Ok(())
     }
 
+    // Block until the `Client` replies to a command.
+    fn reply<T>(&self, reply: Receiver<T>) -> Result<T, HandleError> {
+        reply.recv_blocking().map_err(|_| self.termination.error())
+    }
+
     /// Wait for the next [`Publish`] messages emitted by the broker.
     ///
     /// ```no_run
@@ -211,23 +781,481 @@ impl ClientHandle {
     ///   );
     /// }
     /// ```
-    pub fn publication(&mut self) -> Result<Publish, ConnectionError> {
+    pub fn publication(&mut self) -> Result<Publish, HandleError> {
         loop {
-            let packet = self.receiver.recv_blocking()?;
+            let packet = self
+                .receiver
+                .recv_blocking()
+                .map_err(|_| self.termination.error())?;
             if let Packet::Publish(publish) = packet {
                 return Ok(publish);
             }
         }
     }
 
+    /// Capture the internal state of the [`Client`].
+    ///
+    /// Use it to diagnose issues, like a client that stopped receiving messages.
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, _task) = client.spawn().unwrap();
+    /// let snapshot = handle.debug_snapshot().unwrap();
+    /// println!("{:?} - subscribed to {:?}", snapshot.connection_status, snapshot.subscriptions);
+    /// ```
+    pub fn debug_snapshot(&self) -> Result<Snapshot, HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Snapshot(tx))?;
+        self.reply(rx)
+    }
+
+    /// Retrieve the counters of the traffic between the [`Client`] and the broker.
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, _task) = client.spawn().unwrap();
+    /// let statistics = handle.statistics().unwrap();
+    /// println!("Sent {} bytes, received {} bytes", statistics.bytes_sent, statistics.bytes_read);
+    /// ```
+    pub fn statistics(&self) -> Result<Statistics, HandleError> {
+        Ok(self.debug_snapshot()?.statistics)
+    }
+
+    /// Retrieve the most recent keep alives, oldest first. See [`MqttBinding::pings()`].
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, _task) = client.spawn().unwrap();
+    /// for ping in handle.pings().unwrap() {
+    ///     if ping.answered.is_none() {
+    ///         println!("PINGREQ {} due at {:?}, sent at {:?}: no PINGRESP", ping.sequence, ping.scheduled, ping.sent);
+    ///     }
+    /// }
+    /// ```
+    pub fn pings(&self) -> Result<Vec<Ping>, HandleError> {
+        Ok(self.debug_snapshot()?.pings)
+    }
+
+    /// Terminate the connection, but preserve the [`Session`].
+    ///
+    /// This is useful when the connection must be dropped temporarily, for example
+    /// when a mobile application moves to the background. Use [`Client::resume()`] to continue
+    /// the session on a new connection.
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let connect = Connect::builder().client_id("tjiftjaf").build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, task) = client.spawn().unwrap();
+    /// let session = handle.suspend().unwrap();
+    /// task.join().unwrap().unwrap();
+    ///
+    /// // Later...
+    /// let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// let (handle, task) = Client::resume(session, stream).spawn().unwrap();
+    /// ```
+    pub fn suspend(&self) -> Result<Session, HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Suspend(tx))?;
+        self.reply(rx)
+    }
+
+    /// Replace the [`Connect`] of the [`Client`], for example to change the credentials
+    /// or the will. The current connection is unaffected. The [`Session`] returned by
+    /// [`ClientHandle::suspend()`] carries `connect`, so it applies when resuming.
+    ///
+    /// See [`MqttBinding::set_connect()`].
+    pub fn reconfigure(&self, connect: Connect) -> Result<(), HandleError> {
+        self.command(Command::Reconfigure(connect))
+    }
+
+    /// Subscribe to the topics of `subscribe` and block until the broker acknowledged it.
+    ///
+    /// Unlike [`Emit::emit()`], which returns once the [`Subscribe`] is queued, this
+    /// returns after the [`SubAck`](crate::SubAck) arrives. After that, the broker sends
+    /// the publications for these topics. The returned [`SubscriptionResult`] holds the QoS
+    /// the broker granted per topic. If the broker rejects a topic,
+    /// [`SubscribeError::Rejected`] is returned.
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{subscribe, Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (mut handle, _task) = client.spawn().unwrap();
+    /// handle.subscribe(subscribe("sensor/1/#")).unwrap();
+    /// let publication = handle.publication().unwrap();
+    /// ```
+    pub fn subscribe(&self, subscribe: Subscribe) -> Result<SubscriptionResult, SubscribeError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Subscribe(subscribe, tx))?;
+        self.reply(rx)?
+    }
+
+    /// Like [`ClientHandle::subscribe()`], but give up waiting for the acknowledgement
+    /// after `timeout`. Then, [`SubscribeError::Timeout`] is returned.
+    pub fn subscribe_timeout(
+        &self,
+        subscribe: Subscribe,
+        timeout: Duration,
+    ) -> Result<SubscriptionResult, SubscribeError> {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Subscribe(subscribe, tx))?;
+        match block_on_until(rx.recv(), deadline) {
+            Some(result) => result.map_err(|_| self.termination.error())?,
+            None => Err(SubscribeError::Timeout),
+        }
+    }
+
+    /// Unsubscribe from the topics of `unsubscribe` and block until the broker acknowledged it.
+    ///
+    /// Unlike [`Emit::emit()`], which returns once the [`Unsubscribe`] is queued, this
+    /// returns after the [`UnsubAck`](crate::UnsubAck) arrives. After that, the broker
+    /// doesn't send publications for these topics anymore.
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{unsubscribe_many, Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let connect = Connect::builder().build();
+    /// # let client = Client::new(connect, stream);
+    /// # let (handle, _task) = client.spawn().unwrap();
+    /// handle
+    ///     .unsubscribe(unsubscribe_many(&["sensor/1/#", "sensor/2/#"]).unwrap())
+    ///     .unwrap();
+    /// ```
+    pub fn unsubscribe(&self, unsubscribe: Unsubscribe) -> Result<(), HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Unsubscribe(unsubscribe, tx))?;
+        self.reply(rx)?
+    }
+
+    /// Retrieve the topic filters the [`Client`] is subscribed to, with the requested QoS.
+    ///
+    /// Unlike the subscriptions of [`ClientHandle::debug_snapshot()`], these include
+    /// subscriptions that are still queued. Pass them to [`ClientHandle::import_subscriptions()`]
+    /// of another client to take over the subscriptions of this one.
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (handle, _task) = client.spawn().unwrap();
+    /// for (topic_filter, qos) in handle.subscriptions_snapshot().unwrap() {
+    ///     println!("Subscribed to {topic_filter} with {qos:?}");
+    /// }
+    /// ```
+    pub fn subscriptions_snapshot(&self) -> Result<Vec<(String, QoS)>, HandleError> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.command(Command::Session(tx))?;
+        Ok(self.reply(rx)?.subscriptions().to_vec())
+    }
+
+    /// Subscribe to all topic filters of `subscriptions` with a single [`Subscribe`],
+    /// and block until the broker acknowledged it. See [`ClientHandle::subscribe()`].
+    ///
+    /// Without subscriptions, nothing is sent and an empty [`SubscriptionResult`] is returned.
+    /// Nor is anything sent if a topic filter is invalid.
+    ///
+    /// ```no_run
+    /// # use std::net::TcpStream;
+    /// # use tjiftjaf::{Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let (blue, _task) = Client::new(Connect::builder().build(), stream).spawn().unwrap();
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let (green, _task) = Client::new(Connect::builder().build(), stream).spawn().unwrap();
+    /// let subscriptions = blue.subscriptions_snapshot().unwrap();
+    /// green.import_subscriptions(subscriptions).unwrap();
+    /// ```
+    pub fn import_subscriptions(
+        &self,
+        subscriptions: impl IntoIterator<Item = (String, QoS)>,
+    ) -> Result<SubscriptionResult, SubscribeError> {
+        match subscribe_all(subscriptions)? {
+            Some(subscribe) => self.subscribe(subscribe),
+            None => Ok(SubscriptionResult::default()),
+        }
+    }
+
+    /// Publish `publish` and obtain a [`DeliveryToken`] to track its acknowledgement.
+    ///
+    /// Unlike [`Emit::emit()`], the returned token reports when the broker acknowledged
+    /// the publication, and the packet identifier the client assigned to it.
+    /// This returns once the publication is queued.
+    ///
+    /// ```no_run
+    /// # use std::{net::TcpStream, time::Duration};
+    /// # use tjiftjaf::{Connect, Publish, QoS, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (handle, _task) = client.spawn().unwrap();
+    /// let publish = Publish::builder("sensor/1/temperature", "21.3")
+    ///     .qos(QoS::AtLeastOnceDelivery)
+    ///     .build();
+    /// let mut token = handle.publish(publish).unwrap();
+    /// println!("Publishing with packet identifier {:?}", token.packet_identifier());
+    /// token.wait_timeout(Duration::from_secs(5)).unwrap();
+    /// ```
+    pub fn publish(&self, publish: Publish) -> Result<DeliveryToken, HandleError> {
+        let (queued, packet_identifier) = async_channel::bounded(1);
+        let (reply, acknowledgement) = async_channel::bounded(1);
+        self.command(Command::Publish(publish, queued, reply))?;
+
+        Ok(DeliveryToken {
+            packet_identifier: self.reply(packet_identifier)??,
+            acknowledgement,
+            acknowledged: false,
+            termination: self.termination.clone(),
+        })
+    }
+
+    /// Publish every publication of `batch` and obtain a [`BatchToken`] to track their
+    /// acknowledgements.
+    ///
+    /// The batch is handed to the [`Client`] at once, and it's queued as a whole: other
+    /// packets don't end up between its publications. The returned token waits for the
+    /// acknowledgements of all publications with QoS 1 or 2. Dropping the token doesn't
+    /// affect the publications.
+    ///
+    /// Fails with [`HandleError::Backpressure`] if the batch doesn't fit
+    /// the queue of the client. See [`MqttBinding::set_max_pending_transmits()`].
+    ///
+    /// ```no_run
+    /// # use std::{net::TcpStream, time::Duration};
+    /// # use tjiftjaf::{Connect, Publish, QoS, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// # let (handle, _task) = client.spawn().unwrap();
+    /// let batch = (0..100).map(|n| {
+    ///     Publish::builder(format!("sensor/{n}/temperature"), "21.3")
+    ///         .qos(QoS::AtLeastOnceDelivery)
+    ///         .build()
+    /// });
+    /// let mut token = handle.publish_batch(batch).unwrap();
+    /// token.wait_timeout(Duration::from_secs(5)).unwrap();
+    /// ```
+    pub fn publish_batch(
+        &self,
+        batch: impl IntoIterator<Item = Publish>,
+    ) -> Result<BatchToken, HandleError> {
+        let batch: Vec<Publish> = batch.into_iter().collect();
+        let (queued, packet_identifiers) = async_channel::bounded(1);
+        let (reply, acknowledgements) = async_channel::bounded(batch.len().max(1));
+        self.command(Command::PublishBatch(batch, queued, reply))?;
+
+        let packet_identifiers = self.reply(packet_identifiers)??;
+        Ok(BatchToken {
+            pending: packet_identifiers.iter().flatten().count(),
+            packet_identifiers,
+            acknowledgements,
+            termination: self.termination.clone(),
+        })
+    }
+
     /// Emit a [`Disconnect`] to terminate the connection.
-    pub fn disconnect(&self) -> Result<(), ConnectionError> {
+    pub fn disconnect(&self) -> Result<(), HandleError> {
         self.send(Disconnect.into())
     }
+
+    /// Terminate the thread of the [`Client`], waiting at most `timeout` for a graceful exit.
+    ///
+    /// First, a [`Disconnect`] is emitted and the thread is given `timeout` to transmit it
+    /// and exit. If it doesn't, for example because the broker stopped reading, the socket
+    /// is shut down. That ends the event loop, which then returns an error. Either way,
+    /// joining the thread doesn't block for long after this returns.
+    ///
+    /// Publications that arrive while waiting are dropped.
+    ///
+    /// ```no_run
+    /// # use std::{net::TcpStream, time::Duration};
+    /// # use tjiftjaf::{Connect, blocking::Client};
+    /// # let stream = TcpStream::connect("localhost:1883").unwrap();
+    /// # let client = Client::new(Connect::builder().build(), stream);
+    /// let (handle, task) = client.spawn().unwrap();
+    /// handle.shutdown(Duration::from_secs(1)).unwrap();
+    /// let _ = task.join().unwrap();
+    /// ```
+    pub fn shutdown(self, timeout: Duration) -> Result<(), std::io::Error> {
+        let deadline = Instant::now() + timeout;
+
+        // Unlike `ClientHandle::disconnect()`, don't block on a full queue.
+        match self.sender.try_send(Command::Packet(Disconnect.into())) {
+            Ok(()) => {
+                let _ = self.waker.wake();
+
+                // The channel closes once the event loop returned.
+                let drained = async { while self.receiver.recv().await.is_ok() {} };
+                if block_on_until(drained, deadline).is_some() {
+                    return Ok(());
+                }
+            }
+            Err(TrySendError::Closed(_)) => return Ok(()),
+            Err(TrySendError::Full(_)) => {}
+        }
+
+        warn!(target: target::BLOCKING, "The client didn't terminate within {timeout:?}, shutting down the socket.");
+        match self.socket.shutdown() {
+            Err(error) if error.kind() != ErrorKind::NotConnected => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Tracks the acknowledgement of a publication. It's returned by [`ClientHandle::publish()`].
+///
+/// The token is acknowledged when the broker sends a [`PubAck`](crate::PubAck) for QoS 1,
+/// or a [`PubComp`](crate::PubComp) for QoS 2. Publications with QoS 0 are never acknowledged
+/// by the broker, so their token is acknowledged once the publication is queued.
+pub struct DeliveryToken {
+    packet_identifier: Option<u16>,
+    acknowledgement: Receiver<Result<(), HandleError>>,
+
+    // Whether the acknowledgement was received already.
+    acknowledged: bool,
+
+    termination: Termination,
+}
+
+impl DeliveryToken {
+    /// The packet identifier the client assigned to the publication, if its QoS is 1 or 2.
+    ///
+    /// It differs from the identifier of the [`Publish`] if that one was still in use.
+    pub fn packet_identifier(&self) -> Option<u16> {
+        self.packet_identifier
+    }
+
+    /// Block until the broker acknowledged the publication.
+    ///
+    /// Fails if the [`Client`] terminated before that.
+    pub fn wait(self) -> Result<(), HandleError> {
+        if !self.acknowledged {
+            self.acknowledgement
+                .recv_blocking()
+                .map_err(|_| self.termination.error())??;
+        }
+        Ok(())
+    }
+
+    /// Like [`DeliveryToken::wait()`], but give up after `timeout`.
+    ///
+    /// The token remains usable after a timeout, so it's possible to wait again.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<(), WaitTimeoutError> {
+        if self.acknowledged {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + timeout;
+        match block_on_until(self.acknowledgement.recv(), deadline) {
+            Some(Ok(Ok(()))) => {
+                self.acknowledged = true;
+                Ok(())
+            }
+            Some(Ok(Err(HandleError::Timeout))) | None => Err(WaitTimeoutError::Timeout),
+            Some(_) => Err(WaitTimeoutError::Disconnected),
+        }
+    }
+}
+
+/// Tracks the acknowledgements of a batch of publications. It's returned by
+/// [`ClientHandle::publish_batch()`].
+///
+/// The token is acknowledged once the broker acknowledged every publication with QoS 1 or 2
+/// of the batch. A batch with only publications with QoS 0 is acknowledged immediately.
+pub struct BatchToken {
+    packet_identifiers: Vec<Option<u16>>,
+    acknowledgements: Receiver<Result<(), HandleError>>,
+
+    // The number of acknowledgements that weren't received yet.
+    pending: usize,
+
+    termination: Termination,
+}
+
+impl BatchToken {
+    /// The packet identifiers the client assigned to the publications, in the order of
+    /// the batch. Publications with QoS 0 have none.
+    pub fn packet_identifiers(&self) -> &[Option<u16>] {
+        &self.packet_identifiers
+    }
+
+    /// Block until the broker acknowledged every publication.
+    ///
+    /// Fails if the [`Client`] terminated before that, or if one of the acknowledgements
+    /// timed out. See [`Client::acknowledgement_timeout()`].
+    pub fn wait(mut self) -> Result<(), HandleError> {
+        while self.pending > 0 {
+            self.acknowledgements
+                .recv_blocking()
+                .map_err(|_| self.termination.error())??;
+            self.pending -= 1;
+        }
+        Ok(())
+    }
+
+    /// Like [`BatchToken::wait()`], but give up after `timeout`.
+    ///
+    /// The token remains usable after a timeout, so it's possible to wait again.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<(), WaitTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        while self.pending > 0 {
+            match block_on_until(self.acknowledgements.recv(), deadline) {
+                Some(Ok(Ok(()))) => self.pending -= 1,
+                Some(Ok(Err(HandleError::Timeout))) | None => {
+                    return Err(WaitTimeoutError::Timeout)
+                }
+                Some(_) => return Err(WaitTimeoutError::Disconnected),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Drive `future` on the current thread until it completes, or until `deadline` passes.
+fn block_on_until<F: Future>(future: F, deadline: Instant) -> Option<F::Output> {
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = std::task::Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return Some(output);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        // Spurious wake ups are fine, the loop polls the future again.
+        thread::park_timeout(deadline - now);
+    }
 }
 
 /// A trait for sending messages via [`ClientHandle`] to a server.
 pub trait Emit {
     /// Send a message via the the client to the broker.
-    fn emit(self, handler: &ClientHandle) -> Result<(), ConnectionError>;
+    fn emit(self, handler: &ClientHandle) -> Result<(), HandleError>;
 }
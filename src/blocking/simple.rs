@@ -0,0 +1,192 @@
+//! A [`SimpleClient`] for applications that don't want to manage a [`Client`] and its [`ClientHandle`].
+//!
+//! ```no_run
+//! use std::net::TcpStream;
+//! use tjiftjaf::{blocking::simple::SimpleClient, Connect};
+//!
+//! let stream = TcpStream::connect("localhost:1883").unwrap();
+//! let connect = Connect::builder().client_id("tjiftjaf").build();
+//! let client = SimpleClient::connect(connect, stream).unwrap();
+//!
+//! let temperatures = client.subscribe("sensor/+/temperature").unwrap();
+//! client.publish("sensor/1/temperature", "21.3").unwrap();
+//!
+//! for publish in temperatures.iter().take(10) {
+//!     println!("{}: {:?}", publish.topic(), publish.payload());
+//! }
+//!
+//! client.close().unwrap();
+//! ```
+use super::{Client, ClientHandle};
+use crate::{
+    publish, subscribe, topic::does_topic_match_subscription, Connect, HandleError, Packet,
+    Publish, Snapshot, SubscribeError,
+};
+use async_channel::Receiver;
+use std::{
+    net::TcpStream,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// A client that hides the split between [`Client`] and [`ClientHandle`].
+///
+/// The `Client` and a thread that routes publications to subscribers run in
+/// the background. `SimpleClient` is cheap to clone and can be shared between threads.
+#[derive(Clone)]
+pub struct SimpleClient {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Router {
+    // Topic filters and the channels of their subscribers.
+    routes: Vec<(String, Sender<Publish>)>,
+}
+
+struct Inner {
+    handle: ClientHandle,
+
+    router: Arc<Mutex<Router>>,
+
+    // The threads running the `Client` and the router.
+    // They're taken by `SimpleClient::close()`.
+    threads: Mutex<Option<Threads>>,
+}
+
+struct Threads {
+    client: JoinHandle<Result<(), std::io::Error>>,
+    router: JoinHandle<()>,
+}
+
+impl SimpleClient {
+    /// Start a [`Client`] on `socket` and connect to the broker.
+    pub fn connect(connect: Connect, socket: TcpStream) -> Result<Self, std::io::Error> {
+        let (handle, client) = Client::new(connect, socket).spawn()?;
+
+        let router = Arc::new(Mutex::new(Router::default()));
+        let routing = {
+            let receiver = handle.receiver.clone();
+            let router = Arc::clone(&router);
+            thread::spawn(move || route(receiver, router))
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                handle,
+                router,
+                threads: Mutex::new(Some(Threads {
+                    client,
+                    router: routing,
+                })),
+            }),
+        })
+    }
+
+    /// Publish `payload` on `topic`.
+    pub fn publish(&self, topic: &str, payload: impl Into<Vec<u8>>) -> Result<(), HandleError> {
+        self.inner.handle.send(publish(topic, payload).into())
+    }
+
+    /// Subscribe to `topic`. The topic may contain wildcards.
+    ///
+    /// This method blocks until the broker acknowledged the subscription.
+    /// If the broker rejects it, [`SubscribeError::Rejected`] is returned.
+    ///
+    /// The returned receiver yields all publications matching `topic`.
+    /// Drop the receiver to stop receiving them. Note that this does not
+    /// unsubscribe from the broker.
+    pub fn subscribe(&self, topic: &str) -> Result<mpsc::Receiver<Publish>, SubscribeError> {
+        let (subscriber, receiver) = mpsc::channel();
+
+        // The broker may send the first publications before the SUBACK, so the route
+        // is registered before subscribing. If the broker rejects the subscription,
+        // the receiver is dropped and the router removes the route.
+        self.inner
+            .router
+            .lock()
+            // The router panicked.
+            .map_err(|_| HandleError::ClientGone)?
+            .routes
+            .push((topic.to_string(), subscriber));
+
+        self.inner.handle.subscribe(subscribe(topic))?;
+        Ok(receiver)
+    }
+
+    /// Capture the internal state of the [`Client`].
+    ///
+    /// See [`ClientHandle::debug_snapshot()`].
+    pub fn debug_snapshot(&self) -> Result<Snapshot, HandleError> {
+        self.inner.handle.debug_snapshot()
+    }
+
+    /// Disconnect from the broker and wait for the background threads to finish.
+    ///
+    /// Calling `close()` again, possibly on a clone, returns `Ok(())`.
+    pub fn close(&self) -> Result<(), HandleError> {
+        let Some(threads) = self
+            .inner
+            .threads
+            .lock()
+            .map_err(|_| HandleError::ClientGone)?
+            .take()
+        else {
+            return Ok(());
+        };
+
+        self.inner.handle.disconnect()?;
+
+        let result = threads.client.join();
+        _ = threads.router.join();
+
+        match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(HandleError::Disconnected),
+            Err(_) => Err(HandleError::ClientGone),
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if matches!(self.threads.get_mut(), Ok(Some(_))) {
+            _ = self.handle.disconnect();
+        }
+    }
+}
+
+impl Router {
+    // Forward `publish` to the subscribers with a matching topic filter.
+    fn publish(&mut self, publish: Publish) {
+        // Also remove the routes whose receiver has been dropped.
+        self.routes.retain(|(filter, sender)| {
+            if !does_topic_match_subscription(filter, publish.topic()) {
+                return true;
+            }
+            sender.send(publish.clone()).is_ok()
+        });
+    }
+}
+
+// Forward every publication to the subscribers with a matching topic filter.
+// When the `Client` terminates, all routes are dropped. That disconnects the
+// receivers of the subscribers.
+fn route(receiver: Receiver<Packet>, router: Arc<Mutex<Router>>) {
+    while let Ok(packet) = receiver.recv_blocking() {
+        let Ok(mut router) = router.lock() else {
+            return;
+        };
+
+        if let Packet::Publish(publish) = packet {
+            router.publish(publish);
+        }
+    }
+
+    if let Ok(mut router) = router.lock() {
+        *router = Router::default();
+    }
+}
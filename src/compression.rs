@@ -0,0 +1,184 @@
+//! Compress publications between a tjiftjaf client and a tjiftjaf server.
+//!
+//! Compression is a convention of this crate, MQTT 3.1.1 doesn't define it. Only enable it
+//! for links where both ends are under your control: a client of this crate and the
+//! experimental [`Server`](crate::aio::server::Server). Requires the feature `compression`.
+//!
+//! After the server accepted the connection, the client offers compression by publishing
+//! `deflate` to [`NEGOTIATION_TOPIC`]. A server that supports it answers with the same
+//! publication. From then on, both ends deflate the payload of publications that reach the
+//! threshold, and send them on their topic prefixed with [`PREFIX`]. The receiving end inflates
+//! them and strips the prefix, so applications only see the original publications.
+//!
+//! A server that doesn't support compression never answers, and the client sends its
+//! publications as is.
+//!
+//! ```
+//! use tjiftjaf::{compression::Compression, Connect, MqttBinding};
+//!
+//! let mut binding = MqttBinding::from_connect(Connect::builder().build());
+//! binding.set_compression(Compression::deflate().threshold(512));
+//! ```
+use crate::{Publish, MAX_REMAINING_LENGTH};
+use alloc::string::String;
+use core::fmt::Display;
+
+/// The topic on which the client and the server agree to compress publications.
+pub const NEGOTIATION_TOPIC: &str = "$tjiftjaf/compression";
+
+/// The prefix of the topic of a compressed publication.
+pub const PREFIX: &str = "$tjiftjaf/deflate/";
+
+// The payload of the offer and of its answer.
+const OFFER: &[u8] = b"deflate";
+
+/// How to compress publications. See the module [`compression`](crate::compression).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    threshold: usize,
+    level: u8,
+    max_inflated: usize,
+}
+
+impl Compression {
+    /// Deflate payloads of at least 1 KiB, at level 6. Inflate payloads up to 1 MiB.
+    pub fn deflate() -> Self {
+        Self {
+            threshold: 1024,
+            level: 6,
+            max_inflated: 1024 * 1024,
+        }
+    }
+
+    /// Only compress payloads of at least `bytes` bytes.
+    pub fn threshold(mut self, bytes: usize) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    /// Trade speed for size, from 0 (no compression) to 10 (smallest). Higher levels
+    /// are treated as 10.
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = level.min(10);
+        self
+    }
+
+    /// Don't inflate payloads to more than `bytes` bytes. A publication that would
+    /// inflate to more is passed on compressed. That way, a small publication can't
+    /// exhaust the memory of the receiving end.
+    pub fn max_inflated(mut self, bytes: usize) -> Self {
+        self.max_inflated = bytes.min(MAX_REMAINING_LENGTH);
+        self
+    }
+
+    // Deflate `publish`, if its payload reaches the threshold and deflating makes
+    // the publication smaller.
+    pub(crate) fn compress(&self, publish: &Publish) -> Option<Publish> {
+        if publish.payload().len() < self.threshold {
+            return None;
+        }
+
+        let payload = miniz_oxide::deflate::compress_to_vec(publish.payload(), self.level);
+        if PREFIX.len() + payload.len() >= publish.payload().len() {
+            return None;
+        }
+        copy(publish, &[PREFIX, publish.topic()].concat(), payload)
+    }
+
+    // Inflate `publish`, if it was compressed. Returns `None` for other publications.
+    pub(crate) fn decompress(&self, publish: &Publish) -> Result<Option<Publish>, InflateError> {
+        let Some(topic) = publish.topic().strip_prefix(PREFIX) else {
+            return Ok(None);
+        };
+        let payload = miniz_oxide::inflate::decompress_to_vec_with_limit(
+            publish.payload(),
+            self.max_inflated,
+        )
+        .map_err(|_| InflateError)?;
+        copy(publish, topic, payload).map(Some).ok_or(InflateError)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::deflate()
+    }
+}
+
+// The publication that offers compression, or accepts the offer.
+pub(crate) fn offer() -> Publish {
+    Publish::builder(NEGOTIATION_TOPIC, OFFER).build()
+}
+
+// Whether `publish` offers compression, or accepts the offer.
+pub(crate) fn is_offer(publish: &Publish) -> bool {
+    publish.topic() == NEGOTIATION_TOPIC && publish.payload() == OFFER
+}
+
+// Copy `publish` with another topic and payload. Returns `None` if the result is too large.
+fn copy(publish: &Publish, topic: &str, payload: alloc::vec::Vec<u8>) -> Option<Publish> {
+    let mut builder = Publish::builder(String::from(topic), payload)
+        .qos(publish.qos())
+        .retain(publish.retain())
+        .duplicate(publish.duplicate());
+    if let Some(packet_identifier) = publish.packet_identifier() {
+        builder = builder.packet_identifier(packet_identifier);
+    }
+    builder.try_build().ok()
+}
+
+// An error indicating that a compressed publication couldn't be inflated.
+#[derive(Debug)]
+pub(crate) struct InflateError;
+
+impl Display for InflateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "the payload isn't valid deflate data, or it's too large")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::QoS;
+
+    #[test]
+    fn test_round_trip() {
+        let compression = Compression::deflate().threshold(100);
+        let publish = Publish::builder("sensor/1", vec![b'a'; 99]).build();
+        assert_eq!(compression.compress(&publish), None);
+        assert_eq!(compression.decompress(&publish).unwrap(), None);
+
+        let publish = Publish::builder("sensor/1", vec![b'a'; 4096])
+            .qos(QoS::AtLeastOnceDelivery)
+            .packet_identifier(7)
+            .retain(true)
+            .build();
+        let compressed = compression.compress(&publish).unwrap();
+        assert_eq!(compressed.topic(), "$tjiftjaf/deflate/sensor/1");
+        assert!(compressed.payload().len() < 100);
+        assert_eq!(compressed.packet_identifier(), Some(7));
+        assert!(compressed.retain());
+
+        assert_eq!(compression.decompress(&compressed).unwrap(), Some(publish));
+
+        let corrupt = Publish::builder("$tjiftjaf/deflate/sensor/1", "not deflated").build();
+        assert!(compression.decompress(&corrupt).is_err());
+
+        // A payload that inflates past the limit isn't inflated.
+        assert!(compression
+            .max_inflated(4095)
+            .decompress(&compressed)
+            .is_err());
+    }
+
+    #[test]
+    fn test_incompressible() {
+        // Deflating a short, random payload makes it larger.
+        let payload: Vec<u8> = (0..64u32)
+            .map(|n| (n.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let publish = Publish::builder("sensor/1", payload).build();
+        assert_eq!(Compression::deflate().threshold(0).compress(&publish), None);
+    }
+}
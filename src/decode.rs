@@ -1,12 +1,18 @@
 // Decode fields
 //
-use super::PacketType;
-use std::fmt::Display;
+use super::{packet::HeaderFlags, PacketType};
+use alloc::{boxed::Box, format, string::String};
+use core::{fmt::Display, str::Utf8Error};
 
 #[derive(Debug)]
 pub struct InvalidPacketTypeError(pub u8);
 
-#[derive(Debug)]
+/// The reasons a frame fails to decode as a packet.
+///
+/// Errors in the variable header or payload of a packet are wrapped in
+/// [`DecodingError::Malformed`], which tells the type of the packet and the offset
+/// of the field that failed. [`Error::source()`](core::error::Error::source()) returns the cause.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DecodingError {
     /// The bytes are not enough to decode the packet.
     NotEnoughBytes {
@@ -20,6 +26,7 @@ pub enum DecodingError {
     /// The packet is not valid. Number 1 till and including 15 are valid packet numbers.
     InvalidPacketType(u8),
 
+    /// A field has a value that is not allowed.
     InvalidValue(String),
 
     // The field "remaining length" is not valid.
@@ -28,11 +35,64 @@ pub enum DecodingError {
     /// One or more flags in the header has an illegal value.
     /// PUBLISH, PUBREL, SUBSCRIBE and UNSUBSCRIBE can have flags set.
     /// For all other packets the flags must be all 0.
-    HeaderContainsInvalidFlags,
+    HeaderContainsInvalidFlags {
+        /// The type of the packet with the illegal flags.
+        packet_type: PacketType,
+        /// The lower 4 bits of the first byte of the fixed header.
+        flags: u8,
+    },
+
+    /// A string is not valid UTF-8. See [MQTT-1.5.3-1].
+    InvalidUtf8(Utf8Error),
+
+    /// The packet violates a normative statement of the MQTT 3.1.1 specification.
+    Violation {
+        /// The statement, like `MQTT-3.1.3-7`.
+        clause: &'static str,
+        /// What is wrong with the packet.
+        reason: String,
+    },
+
+    /// The packet is larger than the limit configured with
+    /// [`MqttBinding::set_max_packet_size()`](crate::MqttBinding::set_max_packet_size()).
+    PacketTooLarge {
+        /// The size of the packet, including its fixed header.
+        size: usize,
+        /// The largest size that is accepted.
+        limit: usize,
+    },
+
+    /// A field in the variable header or payload of a packet failed to decode.
+    Malformed {
+        /// The type of the packet.
+        packet_type: PacketType,
+        /// The offset of the field in the frame, counting from the start of the fixed header.
+        offset: usize,
+        /// Why the field failed to decode.
+        source: Box<DecodingError>,
+    },
+}
 
-    // TODO: For now a 'catch-all' type. When we approach a first stable
-    // release we should replace this variant with more explicit members.
-    Other,
+impl DecodingError {
+    // Attribute the error to the field at `offset` of a packet of `packet_type`.
+    // An error that is attributed already keeps its context.
+    pub(crate) fn at(self, packet_type: PacketType, offset: usize) -> Self {
+        match self {
+            Self::Malformed { .. } => self,
+            error => Self::Malformed {
+                packet_type,
+                offset,
+                source: Box::new(error),
+            },
+        }
+    }
+
+    pub(crate) fn violation(clause: &'static str, reason: impl Into<String>) -> Self {
+        Self::Violation {
+            clause,
+            reason: reason.into(),
+        }
+    }
 }
 
 impl From<InvalidPacketTypeError> for DecodingError {
@@ -41,8 +101,24 @@ impl From<InvalidPacketTypeError> for DecodingError {
     }
 }
 
+impl From<Utf8Error> for DecodingError {
+    fn from(value: Utf8Error) -> Self {
+        Self::InvalidUtf8(value)
+    }
+}
+
+impl core::error::Error for DecodingError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::InvalidUtf8(error) => Some(error),
+            Self::Malformed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
 impl Display for DecodingError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let msg = match self {
             Self::NotEnoughBytes { minimum, actual } => &format!(
                 "not enough bytes available, at minimum {minimum} bytes are expected but got {actual} bytes"
@@ -50,9 +126,20 @@ impl Display for DecodingError {
             Self::TooManyBytes => "too many bytes",
             Self::InvalidPacketType(value) => &format!("{value} is not a valid packet type"),
             Self::InvalidValue(reason) => reason,
-            Self::HeaderContainsInvalidFlags => "Header contains illegal flags",
+            Self::HeaderContainsInvalidFlags { packet_type, flags } => &format!(
+                "header of {packet_type:?} packet contains illegal flags {flags:#06b}"
+            ),
             Self::InvalidRemainingLength => "Field remaining length is not valid",
-            Self::Other => "Some other error",
+            Self::InvalidUtf8(_) => "string is not valid UTF-8",
+            Self::Violation { clause, reason } => &format!("{reason} [{clause}]"),
+            Self::PacketTooLarge { size, limit } => {
+                &format!("packet of {size} bytes exceeds the maximum packet size of {limit} bytes")
+            }
+            Self::Malformed {
+                packet_type,
+                offset,
+                ..
+            } => &format!("{packet_type:?} packet is malformed at byte {offset}"),
         };
         write!(f, "{msg}")
     }
@@ -66,6 +153,21 @@ pub fn packet_type(bytes: &[u8]) -> Result<PacketType, DecodingError> {
     PacketType::try_from(byte).map_err(|_| DecodingError::InvalidPacketType(*byte))
 }
 
+// Verify the flags encoded in the lower 4 bits of the first byte of the fixed header.
+//
+// PUBLISH is the only packet where these flags vary. The other packets
+// have reserved flags, see `HeaderFlags::reserved()`.
+pub fn flags(packet_type: PacketType, byte: u8) -> Result<(), DecodingError> {
+    let flags = HeaderFlags::from_byte(byte);
+    match HeaderFlags::reserved(packet_type) {
+        Some(reserved) if flags != reserved => Err(DecodingError::HeaderContainsInvalidFlags {
+            packet_type,
+            flags: flags.bits(),
+        }),
+        _ => Ok(()),
+    }
+}
+
 pub fn u16(bytes: &[u8]) -> Result<u16, DecodingError> {
     let msb = bytes.first().ok_or(DecodingError::NotEnoughBytes {
         minimum: 2,
@@ -101,9 +203,8 @@ pub fn packet_length(bytes: &[u8]) -> Result<u32, DecodingError> {
         }
 
         if index == 3 {
-            return Err(DecodingError::InvalidValue(
-                "The variable length field is at maximum 4 bytes long. But the fourth byte has the continuation bit set which indicates a fourth byte.".into(),
-            ));
+            // The field is at most 4 bytes long, but the fourth byte has the continuation bit set.
+            return Err(DecodingError::InvalidRemainingLength);
         }
 
         index += 1;
@@ -164,8 +265,7 @@ pub mod field {
     pub fn utf8(bytes: &[u8]) -> Result<(&str, usize), DecodingError> {
         let (bytes, offset) = crate::decode::field::bytes(bytes)?;
 
-        let value = std::str::from_utf8(bytes)
-            .map_err(|_| DecodingError::InvalidValue("Payload is not valid UTF-8".into()))?;
+        let value = core::str::from_utf8(bytes)?;
         Ok((value, offset))
     }
 
@@ -196,6 +296,55 @@ mod test {
     use super::*;
     use crate::encode;
 
+    // Verify the reserved flags of the fixed header for every packet type.
+    #[test]
+    fn test_flags() {
+        for packet_type in 1..=14 {
+            let packet_type = PacketType::try_from(packet_type << 4).unwrap();
+            let expected = match packet_type {
+                PacketType::Publish => continue,
+                PacketType::PubRel | PacketType::Subscribe | PacketType::Unsubscribe => 0b0010,
+                _ => 0b0000,
+            };
+
+            for value in 0..16 {
+                let result = flags(packet_type, (packet_type as u8) << 4 | value);
+                assert_eq!(
+                    result.is_ok(),
+                    value == expected,
+                    "{packet_type:?} {value:#06b}"
+                );
+            }
+        }
+    }
+
+    // Verify that `HeaderFlags` reads DUP, QoS and RETAIN of a PUBLISH, and agrees
+    // with `flags()` on the other packets.
+    #[test]
+    fn test_header_flags() {
+        for value in 0..16 {
+            let header = HeaderFlags::from_byte((PacketType::Publish as u8) << 4 | value);
+            let qos = (value >> 1) & 0b11;
+            assert_eq!(header.bits(), value);
+            assert_eq!(header.duplicate(), value & 0b1000 != 0);
+            assert_eq!(header.retain(), value & 0b0001 != 0);
+            assert_eq!(header.qos().map(|qos| qos as u8), (qos != 3).then_some(qos));
+            assert_eq!(header.is_valid_for(PacketType::Publish), qos != 3);
+
+            for packet_type in 1..=14 {
+                let packet_type = PacketType::try_from(packet_type << 4).unwrap();
+                if packet_type == PacketType::Publish {
+                    continue;
+                }
+                let byte = (packet_type as u8) << 4 | value;
+                assert_eq!(
+                    HeaderFlags::from_byte(byte).is_valid_for(packet_type),
+                    flags(packet_type, byte).is_ok()
+                );
+            }
+        }
+    }
+
     // Verify that the variable length is correctly encoded and decoded.
     // Depending on the size remaining length, this field takes up between 1 to 4 bytes.
     //
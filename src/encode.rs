@@ -1,28 +1,69 @@
-pub fn utf8(value: String) -> Vec<u8> {
-    let mut bytes = Vec::with_capacity(value.len() + 2);
+use crate::FieldTooLong;
+use alloc::vec::Vec;
 
-    // TODO: Check for maximum length of string.
-    bytes.extend_from_slice(&((value.len() as u16).to_be_bytes()));
-    bytes.append(&mut value.into_bytes());
+/// Allocate a buffer for a frame with the given first byte and the given
+/// remaining length. The buffer has exactly the capacity required to hold the
+/// full frame, so a builder never has to reallocate while writing the
+/// variable header and payload.
+pub fn frame(header: u8, length: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + remaining_length_size(length) + length);
+    bytes.push(header);
+    write_remaining_length(&mut bytes, length);
     bytes
 }
 
-// TODO: Consider taking `Vec<u8>` to make clear that
-// function clones value.
-pub fn bytes(value: &[u8]) -> Vec<u8> {
-    let mut bytes = Vec::with_capacity(value.len() + 2);
-    // TODO: Check for maximum length of string.
-    bytes.extend_from_slice(&((value.len() as u16).to_be_bytes()));
+/// Write a UTF-8 encoded string, prefixed with its length. `field` names the
+/// field in the error.
+pub fn write_utf8(
+    bytes: &mut Vec<u8>,
+    field: &'static str,
+    value: &str,
+) -> Result<(), FieldTooLong> {
+    write_bytes(bytes, field, value.as_bytes())
+}
+
+/// Write binary data, prefixed with its length. `field` names the field in the error.
+///
+/// [MQTT-1.5.3] The length is encoded in 2 bytes, so `value` must not exceed 65535 bytes.
+pub fn write_bytes(
+    bytes: &mut Vec<u8>,
+    field: &'static str,
+    value: &[u8],
+) -> Result<(), FieldTooLong> {
+    let Ok(length) = u16::try_from(value.len()) else {
+        return Err(FieldTooLong {
+            field,
+            length: value.len(),
+            limit: u16::MAX as usize,
+        });
+    };
+    bytes.extend_from_slice(&length.to_be_bytes());
     bytes.extend_from_slice(value);
-    bytes
+    Ok(())
 }
 
+#[cfg(test)]
 pub fn remaining_length(length: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(remaining_length_size(length));
+    write_remaining_length(&mut bytes, length);
+    bytes
+}
+
+/// The number of bytes required to encode `length` as remaining length.
+pub fn remaining_length_size(length: usize) -> usize {
+    match length {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+pub fn write_remaining_length(bytes: &mut Vec<u8>, length: usize) {
     // TODO: proper validation and error handling.
     assert!(length <= 268_435_455);
 
     let mut length = length;
-    let mut bytes = Vec::with_capacity(1);
 
     loop {
         let mut byte = (length % 128) as u8;
@@ -37,6 +78,50 @@ pub fn remaining_length(length: usize) -> Vec<u8> {
             break;
         }
     }
-    assert!(bytes.len() <= 4);
-    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_bytes_limit() {
+        let mut bytes = vec![];
+        write_utf8(&mut bytes, "topic", &"a".repeat(65_535)).unwrap();
+        assert_eq!(bytes[..2], [0xFF, 0xFF]);
+        assert_eq!(bytes.len(), 2 + 65_535);
+
+        let mut bytes = vec![];
+        let error = write_bytes(&mut bytes, "payload", &[0; 65_536]).unwrap_err();
+        assert_eq!(error.field(), "payload");
+        assert_eq!(error.length(), 65_536);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_remaining_length_size() {
+        for length in [
+            0,
+            127,
+            128,
+            16_383,
+            16_384,
+            2_097_151,
+            2_097_152,
+            268_435_455,
+        ] {
+            assert_eq!(
+                remaining_length_size(length),
+                remaining_length(length).len(),
+                "Wrong size for {length}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_frame() {
+        let bytes = frame(0b1100_0000, 200);
+        assert_eq!(bytes, vec![0b1100_0000, 200, 1]);
+        assert_eq!(bytes.capacity(), 203);
+    }
 }
@@ -0,0 +1,61 @@
+//! Inspect, modify or drop packets with an [`Interceptor`].
+//!
+//! Interceptors see every packet exchanged with the server, except the CONNECT.
+//! Use them to log traffic, to annotate publications, or to enforce a policy.
+//!
+//! ```
+//! use tjiftjaf::{intercept::Verdict, Connect, Interceptor, MqttBinding, Packet};
+//!
+//! // Drop outbound publications with a payload over 1 KiB.
+//! struct MaxPayload;
+//!
+//! impl Interceptor for MaxPayload {
+//!     fn on_outbound(&mut self, packet: &mut Packet) -> Verdict {
+//!         match packet {
+//!             Packet::Publish(publish) if publish.payload().len() > 1024 => Verdict::Drop,
+//!             _ => Verdict::Pass,
+//!         }
+//!     }
+//! }
+//!
+//! let mut binding = MqttBinding::from_connect(Connect::builder().build());
+//! binding.add_interceptor(MaxPayload);
+//! ```
+use crate::Packet;
+
+/// Hooks that run for every packet that passes through a binding.
+///
+/// See [`MqttBinding::add_interceptor()`](crate::MqttBinding::add_interceptor()).
+/// A hook may replace the packet it receives. Both hooks pass all packets by default.
+pub trait Interceptor {
+    /// Called for a packet received from the server, after the binding processed it,
+    /// but before it's returned to the application.
+    ///
+    /// The binding already acknowledged an inbound publication at this point.
+    /// So a dropped publication is not redelivered by the server.
+    fn on_inbound(&mut self, packet: &mut Packet) -> Verdict {
+        let _ = packet;
+        Verdict::Pass
+    }
+
+    /// Called for a packet queued by the application, before it's transmitted.
+    ///
+    /// The binding tracks the packet as it's returned by this hook. Only a PUBLISH,
+    /// SUBSCRIBE or UNSUBSCRIBE can be dropped, waiting for its acknowledgement fails with
+    /// [`HandleError::Dropped`](crate::HandleError::Dropped). Other packets are sent
+    /// regardless of the verdict.
+    fn on_outbound(&mut self, packet: &mut Packet) -> Verdict {
+        let _ = packet;
+        Verdict::Pass
+    }
+}
+
+/// Whether a packet continues after an [`Interceptor`] inspected it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let the packet through, possibly modified.
+    Pass,
+
+    /// Discard the packet.
+    Drop,
+}
@@ -1,25 +1,76 @@
 #![doc = include_str!("../README.md")]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) use crate::binding::Command;
+#[cfg(feature = "async")]
+pub(crate) use crate::binding::Lease;
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use crate::binding::{
+    ClientDisconnected, ConnectError, ConnectionStatus, DecodeErrorPolicy, MqttBinding,
+    PacketIdentifiersExhausted, Ping, QueueFull, Session, Snapshot, Statistics,
+};
 #[doc(inline)]
 pub use crate::decode::DecodingError;
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use crate::intercept::Interceptor;
 #[doc(inline)]
 pub use crate::packet::{
     connack::ConnAck, connect::Connect, disconnect::Disconnect, ping_req::PingReq,
-    ping_resp::PingResp, puback::PubAck, pubcomp::PubComp, publish::Publish, pubrec::PubRec,
-    pubrel::PubRel, suback::SubAck, subscribe::Subscribe, unsuback::UnsubAck,
-    unsubscribe::Unsubscribe, Frame, Packet, PacketType, ProtocolLevel, QoS,
+    ping_resp::PingResp, puback::PubAck, pubcomp::PubComp, publish::Publish,
+    publish::PublishTemplate, pubrec::PubRec, pubrel::PubRel, suback::SubAck, subscribe::Subscribe,
+    unsuback::UnsubAck, unsubscribe::Unsubscribe, Frame, Packet, PacketType, ProtocolLevel, QoS,
 };
-use log::{debug, error, trace};
-use std::{
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use crate::rewrite::TopicRewrite;
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use crate::store::SessionStore;
+use alloc::{string::String, vec::Vec};
+use core::{
     error::Error,
     fmt::Display,
-    time::{Duration, Instant, SystemTime},
+    sync::atomic::{AtomicU16, Ordering},
 };
 
+#[cfg(feature = "std")]
+mod binding;
 mod client;
+#[cfg(feature = "compression")]
+pub mod compression;
 pub mod decode;
 mod encode;
+#[cfg(feature = "std")]
+pub mod intercept;
 pub mod packet;
+#[cfg(feature = "std")]
+pub mod preflight;
+#[cfg(feature = "std")]
+pub mod probe;
+#[cfg(feature = "std")]
+pub mod quirks;
+#[cfg(feature = "std")]
+pub mod rewrite;
+pub mod secret;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(any(feature = "arbitrary", feature = "test-util"))]
+pub mod testing;
+#[cfg(feature = "serde")]
+mod timestamp;
+#[cfg(feature = "std")]
+pub mod timesync;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod topic;
 mod validate;
+#[cfg(feature = "std")]
+mod wire;
 
 #[cfg(feature = "blocking")]
 pub mod blocking;
@@ -27,13 +78,49 @@ pub mod blocking;
 #[cfg(feature = "async")]
 pub mod aio;
 
-pub fn packet_identifier() -> u16 {
-    let seconds = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => n.as_nanos(),
-        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-    };
+// The targets of the log records, one per subsystem. They allow applications to
+// filter the logs, e.g. with `RUST_LOG=tjiftjaf::server=debug`.
+mod target {
+    // Framing and decoding of packets.
+    #[cfg(feature = "std")]
+    pub(crate) const CODEC: &str = "tjiftjaf::codec";
+
+    // The sans-io state machine of the client: handshake, keep alive and acknowledgements.
+    #[cfg(feature = "std")]
+    pub(crate) const BINDING: &str = "tjiftjaf::binding";
+
+    // The packets exchanged with the server, optionally with a hex dump of their frames.
+    #[cfg(feature = "std")]
+    pub(crate) const WIRE: &str = "tjiftjaf::wire";
+
+    #[cfg(feature = "async")]
+    pub(crate) const AIO: &str = "tjiftjaf::aio";
+
+    #[cfg(all(feature = "async", feature = "experimental"))]
+    pub(crate) const SERVER: &str = "tjiftjaf::server";
 
-    seconds as u16
+    #[cfg(feature = "blocking")]
+    pub(crate) const BLOCKING: &str = "tjiftjaf::blocking";
+}
+
+// The next packet identifier returned by `packet_identifier()`.
+static NEXT_PACKET_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+/// Generate a packet identifier.
+///
+/// Identifiers are handed out in order, starting at 1. After 65535, they wrap around to 1,
+/// as 0 isn't a valid packet identifier. The builders of packets use this function by default.
+///
+/// The generator doesn't know which identifiers are in flight. [`MqttBinding`] replaces
+/// the identifier of a packet that collides with one that is still in use, see
+/// [`MqttBinding::allocate_packet_identifier()`].
+pub fn packet_identifier() -> u16 {
+    loop {
+        let packet_identifier = NEXT_PACKET_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+        if packet_identifier != 0 {
+            return packet_identifier;
+        }
+    }
 }
 
 pub fn connect(client_id: String, keep_alive_interval: u16) -> Packet {
@@ -71,6 +158,35 @@ pub fn unsubscribe(topic: &str) -> Unsubscribe {
     Unsubscribe::builder(topic).build()
 }
 
+/// Construct a [`Unsubscribe`] with the given topics.
+///
+/// It is analogous to:
+///
+/// ```
+/// use tjiftjaf::Unsubscribe;
+///
+/// Unsubscribe::builder("sensor/1/#").add_topic("sensor/2/#").build();
+/// ```
+///
+/// Returns `None` if `topics` is empty. An UNSUBSCRIBE must contain at least one topic.
+///
+/// ```
+/// use tjiftjaf::unsubscribe_many;
+///
+/// assert!(unsubscribe_many(&["sensor/1/#", "sensor/2/#"]).is_some());
+/// assert!(unsubscribe_many(&[]).is_none());
+/// ```
+pub fn unsubscribe_many(topics: &[&str]) -> Option<Unsubscribe> {
+    let (first, rest) = topics.split_first()?;
+    let unsubscribe = rest
+        .iter()
+        .fold(Unsubscribe::builder(*first), |builder, topic| {
+            builder.add_topic(*topic)
+        })
+        .build();
+    Some(unsubscribe)
+}
+
 /// Construct a [`Publish`] with the given topic and payload.
 ///
 /// The flags for QoS, retain and duplicate are all 0.
@@ -88,473 +204,372 @@ pub fn publish(topic: &str, payload: impl Into<Vec<u8>>) -> Publish {
     Publish::builder(topic, payload).build()
 }
 
-#[derive(Default, Debug)]
-enum State {
-    // The state machine is waiting for the start of a new packet.
-    #[default]
-    StartOfHeader,
+/// The largest remaining length of a packet, the number of bytes that follow
+/// the fixed header. See [MQTT-2.2.3].
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
 
-    // The state machine processed first half of a header and is waiting
-    // for the remainder of the header.
-    EndOfHeader {
-        partial_header: Vec<u8>,
-    },
+/// An error returned by the `wait_timeout()` methods of [`aio::DeliveryToken`] and
+/// [`blocking::DeliveryToken`].
+#[cfg(any(feature = "blocking", feature = "async"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitTimeoutError {
+    /// The server didn't acknowledge the publication in time. It might still do so later.
+    Timeout,
 
-    // The state machine processed the header and it knows the length
-    // of the entire packet. Now it waits for the remaining bytes
-    // to complete the packet.
-    RestOfPacket {
-        header: Vec<u8>,
-        // The number of bytes that are pending.
-        bytes_remaining: u32,
-    },
+    /// The client terminated before the server acknowledged the publication.
+    Disconnected,
 }
 
-pub struct MqttBinding {
-    connection_status: ConnectionStatus,
-    state: State,
-    transmits: Vec<Packet>,
-
-    statistics: Statistics,
-
-    last_io: Instant,
-    connect: Connect,
-}
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl Error for WaitTimeoutError {}
 
-impl MqttBinding {
-    /// Construct an new `MqttBinding`. The given `Connect` is
-    /// the first message emitted to the server.
-    pub fn from_connect(connect: Connect) -> Self {
-        Self {
-            connection_status: ConnectionStatus::default(),
-            state: State::default(),
-            transmits: vec![],
-            statistics: Statistics::default(),
-            last_io: Instant::now(),
-            connect,
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl Display for WaitTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "the publication wasn't acknowledged in time"),
+            Self::Disconnected => write!(f, "the client terminated"),
         }
     }
+}
 
-    pub fn handle_timeout(&mut self, now: Instant) {
-        if (now - self.last_io).as_secs() >= self.connect.keep_alive() as u64 {
-            // Always schedule a PINGREQ request, even if `self.keep_alive()` is 0.
-            // That is against the specification. However, when this value is 0 seconds,
-            // `MqttBinding.poll_timeout()` returns an value 30 years from now.
-            //
-            // So if keep_alive is 0 _and_ there is no IO for 30 years, then the binding
-            // violates the spec by emitting a PINGREQ.
-            self.transmits.push(Packet::PingReq(PingReq))
-        }
-    }
+/// An error returned by the handles of a client, like [`aio::ClientHandle`] and
+/// [`blocking::ClientHandle`].
+///
+/// Only [`HandleError::Backpressure`] is worth retrying with the same handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandleError {
+    /// The client refused the request, because its queue is full or all packet
+    /// identifiers are in use. Retrying later might succeed.
+    Backpressure,
+
+    /// The client terminated. The connection to the server broke, the server refused it,
+    /// or the application disconnected.
+    Disconnected,
 
-    pub fn poll_timeout(&mut self) -> Instant {
-        let mut interval = self.connect.keep_alive() as u64;
-        if interval == 0 {
-            // If keep_alive() interval is 0 seconds, the client is not supposed
-            // to emit PINGREQ requests. Therefore, binding does not have to be woken up
-            // X seconds after the last IO to schedule a PINGREQ.
-            //
-            // Unfortunately, there is not a way to obtain the maximum value of `Instant`.
-            // For example,  `Instant::MAX` does not exists. So we return an `Instant`
-            // roughly 30 years from now. It is inspired by Tokio's `Instant::far_future()`
-            // https://github.com/tokio-rs/tokio/blob/365269adaf6ec75743c0693f2378c3c6d04f806b/tokio/src/time/instant.rs#L57-L63
-            //
-            // See also https://internals.rust-lang.org/t/instant-systemtime-min-max/21375/16
-            interval = 86400 * 365 * 30
-        }
+    /// The client was dropped, or panicked, before it terminated.
+    ClientGone,
 
-        self.last_io
-            .checked_add(Duration::from_secs(interval))
-            .unwrap()
-    }
+    /// The server didn't acknowledge the request within the acknowledgement timeout.
+    /// It might still do so later.
+    Timeout,
 
-    /// Retrieve an input buffer. The event loop must fill the buffer and pass it to `Self::try_decode()`.
-    pub fn get_read_buffer(&mut self) -> Vec<u8> {
-        match self.state {
-            State::StartOfHeader => {
-                trace!("Waiting for start of header.");
-                vec![0; 2]
-            }
-            State::EndOfHeader { .. } => {
-                trace!("Waiting for end of the header.");
-                vec![0; 2]
+    /// The client dropped the packet instead of sending it, because an [`Interceptor`]
+    /// dropped it, its topics couldn't be rewritten, or it exceeds the maximum packet size.
+    /// See [`MqttBinding::add_topic_rewrite()`] and [`MqttBinding::set_max_packet_size()`].
+    Dropped,
+
+    /// The client isn't configured for the request. For example, `aio::ClientHandle::broadcast()`
+    /// requires `aio::DeliveryMode::Broadcast`.
+    Unsupported,
+}
+
+impl Error for HandleError {}
+
+impl Display for HandleError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HandleError::Backpressure => write!(f, "The `Client` is busy, try again later."),
+            HandleError::Disconnected => write!(f, "The `Client` terminated."),
+            HandleError::ClientGone => {
+                write!(f, "The `Client` was dropped before it terminated.")
             }
-            State::RestOfPacket {
-                bytes_remaining, ..
-            } => {
-                trace!("Waiting for remainder of the packet.");
-                vec![0; bytes_remaining as usize]
+            HandleError::Timeout => write!(f, "The server didn't acknowledge the request in time."),
+            HandleError::Dropped => write!(f, "The packet was dropped instead of sent."),
+            HandleError::Unsupported => {
+                write!(f, "The `Client` isn't configured for this request.")
             }
         }
     }
+}
 
-    /// Retrieve bytes that must be transmitted to the server.
-    ///
-    /// `Ok(None)` indicates no bytes are ready to be sent.
-    /// `Err()` indicates that the connection must be closed.
-    pub fn poll_transmits(&mut self, now: Instant) -> Result<Option<Vec<u8>>, ClientDisconnected> {
-        if self.connection_status == ConnectionStatus::Disconnected {
-            return Err(ClientDisconnected);
-        }
-
-        if self.connection_status == ConnectionStatus::NotConnected {
-            self.connection_status = ConnectionStatus::Connecting;
-
-            let packet: Packet = self.connect.clone().into();
-            debug!("<-- {packet:?}");
-            self.statistics.record_outbound_packet(&packet);
+// Records whether the event loop of a client terminated. Once the channels to the client
+// close, handles use it to tell `HandleError::Disconnected` from `HandleError::ClientGone`.
+#[cfg(any(feature = "blocking", feature = "async"))]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Termination(std::sync::Arc<std::sync::atomic::AtomicBool>);
 
-            self.last_io = now;
-            return Ok(Some(packet.into_bytes()));
-        }
-        if self.connection_status == ConnectionStatus::Connecting {
-            return Ok(None);
-        }
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl Termination {
+    // Must be called before the event loop drops its channels.
+    pub(crate) fn terminate(&self) {
+        self.0.store(true, Ordering::Release);
+    }
 
-        if let Some(packet) = self.transmits.pop() {
-            if let Packet::Disconnect(..) = &packet {
-                self.connection_status = ConnectionStatus::Disconnected;
-            };
-            self.last_io = now;
-            debug!("<-- {packet:?}");
-            self.statistics.record_outbound_packet(&packet);
+    // Whether the event loop terminated.
+    #[cfg(feature = "async")]
+    pub(crate) fn is_terminated(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
 
-            return Ok(Some(packet.into_bytes()));
+    // The error of a handle whose channel to the client closed.
+    pub(crate) fn error(&self) -> HandleError {
+        if self.0.load(Ordering::Acquire) {
+            return HandleError::Disconnected;
         }
-
-        Ok(None)
+        HandleError::ClientGone
     }
+}
 
-    /// Try parsing the bytes as a Packet.
-    pub fn try_decode(&mut self, mut buf: Vec<u8>, _now: Instant) -> Option<Packet> {
-        let (state, packet) = match &self.state {
-            State::StartOfHeader => {
-                // MQTT uses between 1 and 3 (including) bytes to encode the
-                // length of the packet.
-                let packet_length = match decode::packet_length(&buf[1..]) {
-                    Ok(packet_length) => packet_length,
-                    // `buf` doesn't contain enough bytes to decode the length.
-                    // At maximum, 2 more bytes are required to make the header complete.
-                    Err(decode::DecodingError::NotEnoughBytes { .. }) => {
-                        self.state = State::EndOfHeader {
-                            partial_header: buf,
-                        };
-                        return None;
-                    }
-                    Err(error) => {
-                        error!("Failed to decode packet length: {error:?}");
-                        return None;
-                    }
-                };
-
-                let bytes_remaining = packet_length - buf.len() as u32;
-                if bytes_remaining == 0 {
-                    match Packet::try_from(buf) {
-                        Ok(packet) => {
-                            debug!("--> {packet:?}");
-
-                            return Some(packet);
-                        }
-                        Err(error) => {
-                            error!("Failed to parse a 4 byte packet: {error:?}");
-                            return None;
-                        }
-                    };
-                }
-
-                (
-                    State::RestOfPacket {
-                        header: buf,
-                        bytes_remaining,
-                    },
-                    None,
-                )
-            }
-            State::EndOfHeader { ref partial_header } => {
-                let header = {
-                    // TODO: Remove to_owned()
-                    let mut partial_header = partial_header.to_owned();
-                    partial_header.append(&mut buf);
-                    partial_header
-                };
-
-                let packet_length = match decode::packet_length(&header[1..]) {
-                    Ok(packet_length) => packet_length,
-                    Err(error) => {
-                        error!("Failed to decode packet length: {error:?}");
-                        return None;
-                    }
-                };
-
-                let bytes_remaining = packet_length - header.len() as u32;
-                (
-                    State::RestOfPacket {
-                        // TODO: remove clone
-                        header: header.clone(),
-                        bytes_remaining,
-                    },
-                    None,
-                )
-            }
-
-            State::RestOfPacket {
-                header: ref prefix,
-                bytes_remaining: length,
-            } => {
-                if buf.len() < *length as usize {
-                    let remaining_length = length - buf.len() as u32;
-                    let partial_header: Vec<u8> = {
-                        // TODO: remove to_owned()
-                        let mut prefix = prefix.to_owned();
-                        prefix.append(&mut buf);
-                        prefix
-                    };
-
-                    self.state = State::RestOfPacket {
-                        header: partial_header,
-                        bytes_remaining: remaining_length,
-                    };
-                    return None;
-                }
-
-                let frame = {
-                    // TODO: remove to_owned()
-                    let mut prefix = prefix.to_owned();
-                    prefix.append(&mut buf);
-                    prefix
-                };
-
-                let packet = Packet::try_from(frame).unwrap();
-
-                if packet.packet_type() == PacketType::ConnAck {
-                    self.connection_status = ConnectionStatus::Connected;
-                }
-                self.statistics.record_inbound_packet(&packet);
-
-                (State::StartOfHeader, Some(packet))
-            }
-        };
+/// The outcome of a subscription that the server accepted. See [`Subscribe::check()`].
+///
+/// A server may grant a lower QoS than requested. Publications for such a topic are
+/// delivered with at most the granted QoS.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubscriptionResult {
+    // The topic filters, with the requested and the granted QoS.
+    topics: Vec<(String, QoS, QoS)>,
+}
 
-        self.state = state;
-        packet.as_ref().inspect(|ref packet| {
-            debug!("--> {packet:?}");
-        });
-        packet
+impl SubscriptionResult {
+    /// Returns the QoS the server granted for each topic filter, in the order of the [`Subscribe`].
+    pub fn granted(&self) -> impl Iterator<Item = (&str, QoS)> {
+        self.topics
+            .iter()
+            .map(|(topic, _, granted)| (topic.as_str(), *granted))
     }
 
-    /// Push a packet to the inner queue.
-    pub fn send(&mut self, packet: Packet) {
-        self.transmits.push(packet);
+    /// Returns the topic filters for which the server granted a lower QoS than requested.
+    pub fn downgraded(&self) -> impl Iterator<Item = &str> {
+        self.topics
+            .iter()
+            .filter(|(_, requested, granted)| (*granted as u8) < (*requested as u8))
+            .map(|(topic, _, _)| topic.as_str())
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
-enum ConnectionStatus {
-    #[default]
-    NotConnected,
-
-    Connecting,
-    Connected,
-
-    // The client has terminated the connection.
-    Disconnected,
+// Combine `subscriptions` into a single SUBSCRIBE. Returns `None` if there are none.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) fn subscribe_all(
+    subscriptions: impl IntoIterator<Item = (String, QoS)>,
+) -> Result<Option<Subscribe>, InvalidTopicFilter> {
+    let mut subscriptions = subscriptions.into_iter();
+    let Some((topic, qos)) = subscriptions.next() else {
+        return Ok(None);
+    };
+    let builder = subscriptions.fold(Subscribe::builder(topic, qos), |builder, (topic, qos)| {
+        builder.add_topic(topic, qos)
+    });
+    builder.try_build().map(Some)
 }
 
-/// An error indicating that the client terminated the connection with the server.
-#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
-pub struct ClientDisconnected;
+/// Type indicating that subscribing to a topic failed.
+#[derive(Debug)]
+pub enum SubscribeError {
+    /// The server responded with a [`SubAck`] containing
+    /// [`ReturnCode::Failure`](packet::suback::ReturnCode::Failure) for `topic`.
+    Rejected { topic: String },
 
-#[derive(Debug, Default)]
-struct Statistics {
-    pub bytes_read: usize,
-    pub bytes_sent: usize,
-    pub packets_read: usize,
-    pub packets_sent: usize,
-}
+    /// The client failed before the server responded.
+    Connection(HandleError),
 
-impl Statistics {
-    fn record_inbound_packet(&mut self, packet: &Packet) {
-        self.bytes_read += packet.length();
-        self.packets_read += 1;
-    }
+    /// The server didn't respond in time. It might still do so later.
+    Timeout,
 
-    fn record_outbound_packet(&mut self, packet: &Packet) {
-        self.bytes_sent += packet.length();
-        self.packets_sent += 1;
-    }
+    /// A topic filter violates the syntax of MQTT, so nothing was sent.
+    InvalidTopicFilter(InvalidTopicFilter),
 }
 
-/// Type indicating that the connection between a handle and the Client is broken.
-/// It's likely that the connection to the MQTT server broke.
-#[derive(Debug)]
-pub struct ConnectionError;
+impl Error for SubscribeError {}
 
-impl Error for ConnectionError {}
-
-impl Display for ConnectionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "The connection to the `Client` is broken. It probably happened because the connection to the MQTT server broke.")
+impl Display for SubscribeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SubscribeError::Rejected { topic } => {
+                write!(f, "The server rejected the subscription to '{topic}'.")
+            }
+            SubscribeError::Connection(error) => error.fmt(f),
+            SubscribeError::Timeout => {
+                write!(f, "The server didn't acknowledge the subscription in time.")
+            }
+            SubscribeError::InvalidTopicFilter(error) => error.fmt(f),
+        }
     }
 }
 
-#[cfg(any(feature = "blocking", feature = "async"))]
-impl From<async_channel::RecvError> for ConnectionError {
-    fn from(_: async_channel::RecvError) -> Self {
-        ConnectionError
+impl From<HandleError> for SubscribeError {
+    fn from(error: HandleError) -> Self {
+        SubscribeError::Connection(error)
     }
 }
 
-#[cfg(any(feature = "blocking", feature = "async"))]
-impl<T> From<async_channel::SendError<T>> for ConnectionError {
-    fn from(_: async_channel::SendError<T>) -> Self {
-        ConnectionError
+impl From<InvalidTopicFilter> for SubscribeError {
+    fn from(error: InvalidTopicFilter) -> Self {
+        SubscribeError::InvalidTopicFilter(error)
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::ConnAck;
-    use std::io::{Cursor, Read};
+/// An error indicating that a string or binary field exceeds 65535 bytes, or that
+/// the remaining length of a packet exceeds [`MAX_REMAINING_LENGTH`].
+///
+/// [MQTT-1.5.3] The length of these fields is encoded in 2 bytes. It's returned by the
+/// `try_build()` methods of the builders of [`Connect`] and [`Publish`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldTooLong {
+    field: &'static str,
+    length: usize,
+    limit: usize,
+}
 
-    fn as_str(bytes: &[u8]) -> &str {
-        std::str::from_utf8(bytes).expect("Failed to parse bytes as UTF-8.")
+impl FieldTooLong {
+    /// The name of the offending field, like `"topic"`. It's `"remaining length"` if
+    /// the packet as a whole is too large.
+    pub fn field(&self) -> &'static str {
+        self.field
     }
 
-    fn decode_message(packet: Packet) -> Packet {
-        let bytes = packet.into_bytes();
-        let mut binding = MqttBinding::from_connect(Connect::builder().build());
-        let mut offset = 0;
-
-        loop {
-            let buffer = binding.get_read_buffer();
-            let size = buffer.len();
-
-            if let Some(packet) =
-                binding.try_decode(bytes[offset..offset + size].to_vec(), Instant::now())
-            {
-                return packet;
-            }
-            offset += size;
-        }
+    /// The length of the offending field, in bytes.
+    pub fn length(&self) -> usize {
+        self.length
     }
 
-    #[test]
-    fn test_publish() {
-        let packet = publish("zigbee2mqtt/light/state", r#"{"state":"on"}"#);
-
-        let packet = decode_message(packet.into());
-
-        assert_eq!(packet.length(), 41);
-        assert_eq!(packet.packet_type(), PacketType::Publish);
-        // assert_eq!(packet.topic(), "$SYS/broker/uptime");
-        assert_eq!(as_str(packet.payload()), r#"{"state":"on"}"#);
-
-        let packet = publish("$SYS/broker/uptime", r#"388641 seconds"#);
-
-        let packet = decode_message(packet.into());
-        assert_eq!(packet.packet_type(), PacketType::Publish);
-        assert_eq!(packet.length(), 36);
-        // assert_eq!(packet.topic(), "$SYS/broker/uptime");
-        assert_eq!(as_str(packet.payload()), "388641 seconds");
+    /// The maximum length of the field, in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
 
-        let packet = publish(
-            "zigbee2mqtt/binary-switch",
-            r#"{"action":"off","battery":100,"linkquality":3,"voltage":1400}"#,
-        );
-        let packet = decode_message(packet.into());
-        assert_eq!(packet.packet_type(), PacketType::Publish);
-        // assert_eq!(packet.topic(), "zigbee2mqtt/binary-switch");
-        assert_eq!(
-            as_str(packet.payload()),
-            "{\"action\":\"off\",\"battery\":100,\"linkquality\":3,\"voltage\":1400}"
-        );
+impl Error for FieldTooLong {}
 
-        let packet = publish(
-            "zigbee2mqtt/thermo-hygrometer",
-            r#"{"battery":100,"comfort_humidity_max":60,"comfort_humidity_min":40,"comfort_temperature_max":27,"comfort_temperature_min":19,"humidity":47.2,"linkquality":105,"temperature":24,"temperature_units":"fahrenheit","update":{"installed_version":4105,"latest_version":8960,"state":"available"}}"#,
-        );
+impl Display for FieldTooLong {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "the {} is {} bytes long, but it must not exceed {} bytes",
+            self.field, self.length, self.limit
+        )
+    }
+}
 
-        let packet = decode_message(packet.into());
+/// Type indicating that a topic filter violates the syntax of MQTT.
+///
+/// It's returned by the `try_build()` methods of the builders of
+/// [`Subscribe`] and [`Unsubscribe`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidTopicFilter {
+    filter: String,
+    reason: &'static str,
+}
 
-        assert_eq!(packet.packet_type(), PacketType::Publish);
-        // assert_eq!(packet.topic(), "zigbee2mqtt/thermo-hygrometer");
-        pretty_assertions::assert_eq!(
-            as_str(packet.payload()),
-            "{\"battery\":100,\"comfort_humidity_max\":60,\"comfort_humidity_min\":40,\"comfort_temperature_max\":27,\"comfort_temperature_min\":19,\"humidity\":47.2,\"linkquality\":105,\"temperature\":24,\"temperature_units\":\"fahrenheit\",\"update\":{\"installed_version\":4105,\"latest_version\":8960,\"state\":\"available\"}}"
-        );
+impl InvalidTopicFilter {
+    /// The offending topic filter.
+    pub fn filter(&self) -> &str {
+        &self.filter
     }
+}
 
-    /// Verify that `MqttBinding.get_read_buffer()`
-    /// and `MqttBinding.try_decode()` correctly decode `Packet`s.
-    ///
-    /// This test iterates over a series of valid packets. Each packet
-    /// is deserialized as `Bytes` and fed to `MqttBinding`. The latter
-    /// should correctly decode the `Bytes` back into the `Packet` we started with.
-    #[test]
-    fn test_mqtt_binding_decoding_packets() {
-        let mut binding = MqttBinding::from_connect(Connect::builder().build());
+impl Error for InvalidTopicFilter {}
 
-        for test in valid_packets() {
-            let mut input = Cursor::new(test.clone().into_bytes());
+impl Display for InvalidTopicFilter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Invalid topic filter '{}': {}.",
+            self.filter, self.reason
+        )
+    }
+}
 
-            let mut iterations = 0;
-            // A mini-event loop that requests one or more read buffers
-            // to decode packets.
-            let packet = loop {
-                iterations += 1;
-                let mut buffer = binding.get_read_buffer();
-                let _ = input.read(&mut buffer).unwrap();
+/// An error indicating that a builder can't encode a packet.
+///
+/// The `try_build()` methods of the builders return a specific error, like [`FieldTooLong`]
+/// or [`InvalidTopicFilter`]. Both convert into `EncodingError`, so a function that builds
+/// different packets can propagate them with `?`.
+///
+/// ```
+/// use tjiftjaf::{Connect, EncodingError, Packet, QoS, Subscribe};
+///
+/// fn handshake(client_id: &str, filter: &str) -> Result<Vec<Packet>, EncodingError> {
+///     Ok(vec![
+///         Connect::builder().client_id(client_id).try_build()?.into(),
+///         Subscribe::builder(filter, QoS::AtMostOnceDelivery).try_build()?.into(),
+///     ])
+/// }
+///
+/// assert!(handshake("sensor", "sensor/#").is_ok());
+/// assert!(matches!(
+///     handshake("sensor", "sensor/#/1"),
+///     Err(EncodingError::InvalidTopicFilter(_))
+/// ));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EncodingError {
+    /// A field, or the packet as a whole, is too long.
+    FieldTooLong(FieldTooLong),
+
+    /// A topic filter violates the syntax of MQTT.
+    InvalidTopicFilter(InvalidTopicFilter),
+
+    /// The packet exceeds the size set with
+    /// [`publish::Builder::max_packet_size()`](packet::publish::Builder::max_packet_size()).
+    PacketTooLarge {
+        /// The size of the packet, in bytes.
+        size: usize,
+        /// The maximum size of the packet, in bytes.
+        limit: usize,
+    },
+}
 
-                if let Some(packet) = binding.try_decode(buffer, Instant::now()) {
-                    break packet;
-                }
-            };
+impl From<FieldTooLong> for EncodingError {
+    fn from(value: FieldTooLong) -> Self {
+        Self::FieldTooLong(value)
+    }
+}
 
-            assert!(iterations > 0);
-            assert!(iterations < 4);
+impl From<InvalidTopicFilter> for EncodingError {
+    fn from(value: InvalidTopicFilter) -> Self {
+        Self::InvalidTopicFilter(value)
+    }
+}
 
-            assert_eq!(test.into_bytes(), packet.into_bytes());
+impl Error for EncodingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::FieldTooLong(error) => Some(error),
+            Self::InvalidTopicFilter(error) => Some(error),
+            Self::PacketTooLarge { .. } => None,
         }
     }
+}
 
-    // A collection of valid `Packet`s.
-    fn valid_packets() -> Vec<Packet> {
-        vec![
-            PingReq.into(),
-            connect("test".to_string(), 300),
-            Connect::builder()
-                .username("admin")
-                .password("secret")
-                .build()
-                .into(),
-            ConnAck::builder().build().into(),
-        ]
+impl Display for EncodingError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FieldTooLong(error) => write!(f, "{error}"),
+            Self::InvalidTopicFilter(error) => write!(f, "{error}"),
+            Self::PacketTooLarge { size, limit } => write!(
+                f,
+                "the packet is {size} bytes long, but it must not exceed {limit} bytes"
+            ),
+        }
     }
+}
 
-    // Issue #53 tracks a bug where the MqttBinding enters a hot loop
-    // when the keep alive interval is 0.
-    //
-    // This test verifies the fix for that. First, it creates a binding with
-    // a keep alive interval of 5 seconds. `MqttBinding.poll_timeout()` returns
-    // an Instant that's about 5 seconds in the future.
-    //
-    // Then, the test is repeated with a keep alive interval of 0. Now, the Instant
-    // is 30 years in the future instead of 0 seconds.
-    #[test]
-    fn gh_53_test_fix_for_keep_alive_interval_of_0() {
-        let connect = Connect::builder().keep_alive(5).build();
-
-        let mut binding = MqttBinding::from_connect(connect);
-        let interval = binding.poll_timeout() - Instant::now();
-        assert_eq!(interval.as_secs_f32().round(), 5.0);
-
-        // Now, try again with a keep alive interval of 0 seconds.
-        let connect = Connect::builder().keep_alive(0).build();
+#[cfg(all(test, any(feature = "blocking", feature = "async")))]
+mod test {
+    use super::*;
 
-        let mut binding = MqttBinding::from_connect(connect);
-        let interval = binding.poll_timeout() - Instant::now();
+    // Verify that subscriptions are combined into one SUBSCRIBE, and that an invalid
+    // topic filter fails instead of panicking.
+    #[test]
+    fn test_subscribe_all() {
+        assert_eq!(subscribe_all([]), Ok(None));
+
+        let subscribe = subscribe_all([
+            ("sensor/1".to_string(), QoS::AtMostOnceDelivery),
+            ("sensor/2".to_string(), QoS::AtLeastOnceDelivery),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            subscribe.topics().collect::<Vec<_>>(),
+            [
+                ("sensor/1", QoS::AtMostOnceDelivery),
+                ("sensor/2", QoS::AtLeastOnceDelivery)
+            ]
+        );
 
-        assert_eq!(interval.as_secs_f32().round(), 946080000.0);
+        let error = subscribe_all([("sensor/#/1".to_string(), QoS::AtMostOnceDelivery)]);
+        assert_eq!(error.unwrap_err().filter(), "sensor/#/1");
     }
 }
@@ -1,5 +1,9 @@
 //! Providing [`Ack`], a type to compose messages like [`PubAck`], [`UnsubAck`] and more.  
-use crate::{decode::DecodingError, Frame, PacketType};
+use crate::{
+    decode::{self, DecodingError},
+    Frame, PacketType,
+};
+use alloc::vec::Vec;
 
 /// [`Ack`] is a type to compose messages like [`PubAck`], [`UnsubAck`] and a few others.  
 ///
@@ -12,8 +16,15 @@ pub(crate) struct Ack([u8; 4]);
 
 impl Ack {
     pub fn new(packet_type: PacketType, packet_identifier: u16) -> Self {
+        // [MQTT-3.6.1-1] Bits 3,2,1 and 0 of the fixed header in the PUBREL Control Packet
+        // are reserved and MUST be set to 0,0,1 and 0 respectively.
+        let flags = match packet_type {
+            PacketType::PubRel => 0b0010,
+            _ => 0b0000,
+        };
+
         Self([
-            (packet_type as u8) << 4,
+            (packet_type as u8) << 4 | flags,
             // The remaining length,
             2,
             // The high byte of the packet identifier
@@ -65,14 +76,11 @@ impl TryFrom<&[u8]> for Ack {
             });
         }
 
-        let packet_type = value[0];
-        _ = PacketType::try_from(packet_type)?;
+        let packet_type = PacketType::try_from(value[0])?;
+        decode::flags(packet_type, value[0])?;
 
-        let remaining_length = value[1];
-        if remaining_length != 2 {
-            return Err(DecodingError::InvalidValue(format!(
-                "The remaining length must be 2, but is {remaining_length} bytes."
-            )));
+        if value[1] != 2 {
+            return Err(DecodingError::InvalidRemainingLength);
         }
 
         if value.len() > 4 {
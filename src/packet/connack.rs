@@ -1,5 +1,9 @@
 //! Providing [`ConnAck`], a response from server to a `Connect`
-use crate::{decode::DecodingError, Frame, Packet};
+use crate::{
+    decode::{self, DecodingError},
+    Frame, Packet, PacketType,
+};
+use alloc::{format, vec::Vec};
 
 /// [Connack](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718033)
 #[derive(Clone, PartialEq, Eq)]
@@ -66,9 +70,10 @@ impl TryFrom<Vec<u8>> for ConnAck {
             5.. => return Err(DecodingError::TooManyBytes {}),
         };
 
-        if value[0] != 32 {
+        if value[0] >> 4 != PacketType::ConnAck as u8 {
             return Err(DecodingError::InvalidPacketType(value[0]));
         };
+        decode::flags(PacketType::ConnAck, value[0])?;
 
         if value[1] != 2 {
             return Err(DecodingError::InvalidRemainingLength);
@@ -77,14 +82,22 @@ impl TryFrom<Vec<u8>> for ConnAck {
         // Only the first bit of this field can be set. All other 7 bits
         // are not used.
         if value[2] > 1 {
-            return Err(DecodingError::Other);
+            return Err(DecodingError::InvalidValue(format!(
+                "the reserved connect acknowledge flags are set: {:#010b}",
+                value[2]
+            ))
+            .at(PacketType::ConnAck, 2));
         }
 
-        let return_code = ReturnCode::try_from(&value[3])?;
+        let return_code =
+            ReturnCode::try_from(&value[3]).map_err(|error| error.at(PacketType::ConnAck, 3))?;
 
-        //  [MQTT-3.2.2-4] If a server sends a CONNACK packet containing a non-zero return code it MUST set Session Present to 0 .
         if return_code != ReturnCode::ConnectionAccepted && value[2] != 0 {
-            return Err(DecodingError::Other);
+            return Err(DecodingError::violation(
+                "MQTT-3.2.2-4",
+                "session present is set with a non-zero return code",
+            )
+            .at(PacketType::ConnAck, 2));
         }
 
         Ok(Self {
@@ -94,8 +107,8 @@ impl TryFrom<Vec<u8>> for ConnAck {
     }
 }
 
-impl std::fmt::Debug for ConnAck {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for ConnAck {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("CONNACK")
             .field("length", &self.length())
             .field("session_present", &self.session_present())
@@ -108,6 +121,7 @@ impl std::fmt::Debug for ConnAck {
 /// to the server. If not, the return code provides a hint
 /// why the connection failed.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum ReturnCode {
     ConnectionAccepted = 0x0,
 
@@ -210,6 +224,20 @@ impl Default for ConnAckBuilder {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for ConnAck {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let return_code: ReturnCode = u.arbitrary()?;
+        let mut builder = ConnAck::builder().return_code(return_code);
+
+        // [MQTT-3.2.2-4] Only an accepted connection can have a session present.
+        if return_code == ReturnCode::ConnectionAccepted && u.arbitrary()? {
+            builder = builder.session_present();
+        }
+        Ok(builder.build())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::{packet::connack::ReturnCode, ConnAck};
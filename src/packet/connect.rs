@@ -2,10 +2,18 @@
 use super::UnverifiedFrame;
 use crate::{
     decode::{self, DecodingError},
-    encode, Frame, Packet, PacketType, ProtocolLevel, QoS,
+    encode,
+    secret::{self, SecretBytes, SecretString},
+    FieldTooLong, Frame, Packet, PacketType, ProtocolLevel, QoS,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
 };
 use core::fmt;
-use std::marker::PhantomData;
+use core::marker::PhantomData;
+use core::ops::Range;
 
 /// [Connect](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028) is the first packet a client sends, after a connection has been established.
 ///
@@ -38,6 +46,7 @@ use std::marker::PhantomData;
 #[derive(Clone, PartialEq, Eq)]
 pub struct Connect {
     inner: UnverifiedConnect,
+    fields: Fields,
 }
 
 impl Connect {
@@ -48,8 +57,8 @@ impl Connect {
         Builder::new()
     }
 
-    pub fn into_bytes(self) -> Vec<u8> {
-        self.inner.inner
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        core::mem::take(&mut self.inner.inner)
     }
 
     /// Retrieve the connection flags.
@@ -107,7 +116,8 @@ impl Connect {
     /// assert_eq!(packet.username(), Some("optimus"));
     /// ```
     pub fn username(&self) -> Option<&str> {
-        self.inner.username().unwrap()
+        self.field(&self.fields.username)
+            .map(|username| core::str::from_utf8(username).unwrap())
     }
 
     /// Retrieve the password, if configured.
@@ -125,7 +135,7 @@ impl Connect {
     /// assert_eq!(packet.password(), Some("prime".as_bytes()));
     /// ```
     pub fn password(&self) -> Option<&[u8]> {
-        self.inner.password().unwrap()
+        self.field(&self.fields.password)
     }
 
     /// Retrieve the `Will`, if configured.
@@ -148,7 +158,21 @@ impl Connect {
     /// assert_eq!(will.retain(), true);
     /// ```
     pub fn will(&self) -> Option<Will<'_>> {
-        self.inner.will().unwrap()
+        let topic = self.field(&self.fields.will_topic)?;
+        let message = self.field(&self.fields.will_message)?;
+        let flags = self.flags();
+
+        Some(Will {
+            topic: core::str::from_utf8(topic).unwrap(),
+            message,
+            retain: flags.will_retain(),
+            qos: flags.will_qos(),
+        })
+    }
+
+    // The bytes of an optional field of the payload, at a position found during verification.
+    fn field(&self, range: &Option<Range<usize>>) -> Option<&[u8]> {
+        range.clone().map(|range| &self.inner.inner[range])
     }
 }
 
@@ -172,7 +196,17 @@ impl TryFrom<Vec<u8>> for Connect {
 
 impl From<Connect> for Vec<u8> {
     fn from(value: Connect) -> Vec<u8> {
-        value.inner.inner
+        value.into_bytes()
+    }
+}
+
+// The encoded packet holds the credentials in the clear. Like the `Builder`, it
+// overwrites them when it's dropped.
+impl Drop for Connect {
+    fn drop(&mut self) {
+        if self.fields.username.is_some() || self.fields.password.is_some() {
+            secret::zeroize(&mut self.inner.inner);
+        }
     }
 }
 
@@ -182,13 +216,14 @@ impl From<Connect> for Packet {
     }
 }
 
-impl std::fmt::Debug for Connect {
+impl core::fmt::Debug for Connect {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("CONNECT")
             .field("length", &self.length())
             .field("client_id", &self.client_id())
             .field("keep_alive", &self.keep_alive())
-            .field("username", &self.username())
+            .field("username", &self.username().map(|_| "<redacted>"))
+            .field("password", &self.password().map(|_| "<redacted>"))
             .field("will", &self.will())
             .finish()
     }
@@ -215,74 +250,6 @@ impl UnverifiedConnect {
         Ok(Flags(variable_header[7]))
     }
 
-    fn will(&self) -> Result<Option<Will<'_>>, DecodingError> {
-        let connect_flags = self.connect_flags()?;
-
-        if !connect_flags.will_flag() {
-            // TODO:
-            // [MQTT-3.1.2-13] If the Will Flag is set to 0, then the Will QoS MUST be set to 0 (0x00)
-            // [MQTT-3.1.2-15] If the Will Flag is set to 0, then the Will Retain Flag MUST be set to 0
-            return Ok(None);
-        }
-
-        let payload = self.try_payload()?;
-
-        let (will_topic, _) = decode::field::variable_length_n(payload, 1)?;
-        let will_topic = std::str::from_utf8(will_topic)
-            .map_err(|_| DecodingError::InvalidValue("Payload is not valid UTF-8".into()))?;
-        let (will_message, _) = decode::field::variable_length_n(payload, 2)?;
-
-        Ok(Some(Will {
-            topic: will_topic,
-            message: will_message,
-            retain: connect_flags.will_retain(),
-            qos: connect_flags.will_qos(),
-        }))
-    }
-
-    pub fn username(&self) -> Result<Option<&str>, DecodingError> {
-        let connect_flags = self.connect_flags()?;
-        if !connect_flags.username() {
-            if connect_flags.password() {
-                todo!("Illegal! Pas")
-            }
-            return Ok(None);
-        };
-
-        let payload = self.try_payload()?;
-        let mut field_index = 1;
-
-        // The will requires 2 fields: one for the topic and one for the message.
-        if connect_flags.will_flag() {
-            field_index = 3
-        };
-
-        let (username, _) = decode::field::variable_length_n(payload, field_index)?;
-
-        let username = std::str::from_utf8(username)
-            .map_err(|_| DecodingError::InvalidValue("Payload is not valid UTF-8".into()))?;
-        Ok(Some(username))
-    }
-
-    pub fn password(&self) -> Result<Option<&[u8]>, DecodingError> {
-        let connect_flags = self.connect_flags()?;
-        if !connect_flags.password() {
-            return Ok(None);
-        };
-
-        let payload = self.try_payload()?;
-        let mut field_index = 2;
-
-        // The will requires 2 fields: one for the topic and one for the message.
-        if connect_flags.will_flag() {
-            field_index = 4
-        };
-
-        let (password, _) = decode::field::variable_length_n(payload, field_index)?;
-
-        Ok(Some(password))
-    }
-
     fn verify_header(&self) -> Result<(), DecodingError> {
         let header = self.try_header()?;
         let packet_type = decode::packet_type(header)?;
@@ -290,6 +257,7 @@ impl UnverifiedConnect {
             //  TODO return  correct packet type
             return Err(DecodingError::InvalidPacketType(5));
         }
+        decode::flags(packet_type, header[0])?;
 
         let packet_length = decode::packet_length(&header[1..header.len()])? as usize;
         if packet_length != self.length() {
@@ -301,49 +269,133 @@ impl UnverifiedConnect {
     }
 
     fn verify_variable_header(&self) -> Result<(), DecodingError> {
-        let header = self.try_variable_header()?;
-        let (protocol_name, offset) = decode::field::utf8(header)?;
-        // TODO: replace assert_eq with DecodingError
-        assert_eq!(protocol_name, "MQTT");
+        let offset = self.try_offset_variable_header()?;
+        let header = self.try_variable_header().map_err(self.malformed(offset))?;
+
+        let (protocol_name, length) =
+            decode::field::utf8(header).map_err(self.malformed(offset))?;
+        if protocol_name != "MQTT" {
+            return Err(DecodingError::violation(
+                "MQTT-3.1.2-1",
+                format!("{protocol_name:?} is not a valid protocol name"),
+            )
+            .at(PacketType::Connect, offset));
+        }
 
-        let protocol_level = header[offset];
-        assert_eq!(protocol_level, ProtocolLevel::_3_1_1 as u8);
+        let protocol_level = header[length];
+        if protocol_level != ProtocolLevel::_3_1_1 as u8 {
+            return Err(DecodingError::violation(
+                "MQTT-3.1.2-2",
+                format!("protocol level {protocol_level} is not supported"),
+            )
+            .at(PacketType::Connect, offset + length));
+        }
 
-        let connect_flags = header[offset + 1];
-        // Bit 0 must be 0, all other bits can be either 0 or 1.
-        assert!(connect_flags & 1 == 0);
+        let flags_offset = offset + length + 1;
+        let connect_flags = header[length + 1];
+        if connect_flags & 1 != 0 {
+            return Err(DecodingError::violation(
+                "MQTT-3.1.2-3",
+                "the reserved connect flag is set",
+            )
+            .at(PacketType::Connect, flags_offset));
+        }
+
+        let connect_flags = Flags(connect_flags);
+        if (connect_flags.0 & 24) >> 3 > 2 {
+            return Err(DecodingError::violation(
+                "MQTT-3.1.2-14",
+                "3 is not a valid value for the QoS of the will",
+            )
+            .at(PacketType::Connect, flags_offset));
+        }
+
+        if !connect_flags.will_flag() && connect_flags.will_qos() != QoS::AtMostOnceDelivery {
+            return Err(DecodingError::violation(
+                "MQTT-3.1.2-13",
+                "the QoS of the will is set without a will",
+            )
+            .at(PacketType::Connect, flags_offset));
+        }
+
+        if !connect_flags.will_flag() && connect_flags.will_retain() {
+            return Err(DecodingError::violation(
+                "MQTT-3.1.2-15",
+                "the retain flag of the will is set without a will",
+            )
+            .at(PacketType::Connect, flags_offset));
+        }
+
+        if !connect_flags.username() && connect_flags.password() {
+            return Err(DecodingError::violation(
+                "MQTT-3.1.2-22",
+                "the password flag is set without a username",
+            )
+            .at(PacketType::Connect, flags_offset));
+        }
 
         Ok(())
     }
 
-    fn verify_payload(&self) -> Result<(), DecodingError> {
+    // Walk the fields of the payload: the client id, and the will, username
+    // and password if their flags are set. Returns the positions of the latter.
+    fn verify_payload(&self) -> Result<Fields, DecodingError> {
+        let offset = self.try_offset_payload()?;
         let payload = self.try_payload()?;
-        // [MQTT-3.1.3-3] The Client Identifier (ClientId) MUST be present and MUST be the first field in the CONNECT packet payload.
-        // [[MQTT-3.1.3-4] The ClientId MUST be a UTF-8 encoded string as defined in Section 1.5.3.
-        let (client_id, _) = decode::field::utf8(payload)?;
-
         let connect_flags = self.connect_flags()?;
 
-        // [MQTT-3.1.3-7] If the Client supplies a zero-byte ClientId, the Client MUST also set CleanSession to 1 .
+        // [MQTT-3.1.3-3] The Client Identifier (ClientId) MUST be present and MUST be the first field in the CONNECT packet payload.
+        // [[MQTT-3.1.3-4] The ClientId MUST be a UTF-8 encoded string as defined in Section 1.5.3.
+        let (client_id, length) = decode::field::utf8(payload).map_err(self.malformed(offset))?;
         if client_id.is_empty() && !connect_flags.clean_session() {
-            // Raise DecodingError;
-            todo!()
+            return Err(DecodingError::violation(
+                "MQTT-3.1.3-7",
+                "an empty client identifier requires a clean session",
+            )
+            .at(PacketType::Connect, offset));
         }
 
-        // Try parsing fields related to will, username and password.
-        self.will()?;
-        self.username()?;
-        self.password()?;
+        // Decode the next field, either a string or binary data.
+        let mut position = length;
+        let mut field = |utf8: bool| -> Result<Option<Range<usize>>, DecodingError> {
+            let bytes = &payload[position..];
+            let length = if utf8 {
+                decode::field::utf8(bytes).map(|(_, length)| length)
+            } else {
+                decode::field::bytes(bytes).map(|(_, length)| length)
+            }
+            .map_err(self.malformed(offset + position))?;
 
-        Ok(())
+            // Skip the 2 bytes with the length of the field.
+            let range = offset + position + 2..offset + position + length;
+            position += length;
+            Ok(Some(range))
+        };
+
+        let mut fields = Fields::default();
+        if connect_flags.will_flag() {
+            fields.will_topic = field(true)?;
+            fields.will_message = field(false)?;
+        }
+        if connect_flags.username() {
+            fields.username = field(true)?;
+        }
+        if connect_flags.password() {
+            fields.password = field(false)?;
+        }
+
+        Ok(fields)
     }
 
     fn verify(self) -> Result<Connect, DecodingError> {
         self.verify_header()?;
         self.verify_variable_header()?;
-        self.verify_payload()?;
+        let fields = self.verify_payload()?;
 
-        Ok(Connect { inner: self })
+        Ok(Connect {
+            inner: self,
+            fields,
+        })
     }
 }
 
@@ -361,10 +413,20 @@ impl UnverifiedFrame for UnverifiedConnect {
     fn try_variable_header(&self) -> Result<&[u8], DecodingError> {
         // The variable header of a CONNECT packet has a fixed size of 10 bytes.
         let offset = self.try_offset_variable_header()?;
-        Ok(&self.as_bytes()[offset..offset + 10])
+        self.try_slice(offset, 10)
     }
 }
 
+// The positions of the optional fields in the bytes of a `Connect`, found while verifying
+// its payload. The ranges exclude the length of the fields.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Fields {
+    will_topic: Option<Range<usize>>,
+    will_message: Option<Range<usize>>,
+    username: Option<Range<usize>>,
+    password: Option<Range<usize>>,
+}
+
 #[derive(Clone, Default)]
 pub struct Flags(pub(crate) u8);
 
@@ -423,8 +485,8 @@ impl Flags {
     }
 }
 
-impl std::fmt::Debug for Flags {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Flags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Flags")
             .field("username", &self.username())
             .field("password", &self.password())
@@ -436,10 +498,11 @@ impl std::fmt::Debug for Flags {
     }
 }
 
+/// The will of a [`Connect`]. It borrows from the packet, see [`Will::into_owned()`]
+/// to store it.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Will<'a> {
     topic: &'a str,
-    // TODO: change to bytes
     message: &'a [u8],
 
     retain: bool,
@@ -447,6 +510,29 @@ pub struct Will<'a> {
 }
 
 impl<'a> Will<'a> {
+    /// Copy the will into a [`WillOwned`], that doesn't borrow from the [`Connect`].
+    ///
+    /// ```
+    /// use tjiftjaf::{packet::connect::WillOwned, Connect, QoS};
+    ///
+    /// let packet = Connect::builder().will("status", "offline").build();
+    /// let will = packet.will().unwrap().into_owned();
+    /// drop(packet);
+    ///
+    /// assert_eq!(
+    ///     will,
+    ///     WillOwned {
+    ///         topic: "status".into(),
+    ///         message: b"offline".to_vec(),
+    ///         qos: QoS::AtMostOnceDelivery,
+    ///         retain: false,
+    ///     }
+    /// );
+    /// ```
+    pub fn into_owned(self) -> WillOwned {
+        WillOwned::from(self)
+    }
+
     /// Retrieve the will topic.
     pub fn topic(&self) -> &str {
         self.topic
@@ -468,11 +554,46 @@ impl<'a> Will<'a> {
     }
 }
 
+/// A [`Will`] that owns its topic and message. Obtain it with [`Will::into_owned()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WillOwned {
+    /// The topic of the will.
+    pub topic: String,
+
+    /// The message of the will.
+    pub message: Vec<u8>,
+
+    /// The QoS of the will.
+    pub qos: QoS,
+
+    /// Whether the will is retained.
+    pub retain: bool,
+}
+
+impl From<Will<'_>> for WillOwned {
+    fn from(will: Will<'_>) -> Self {
+        Self {
+            topic: will.topic.to_string(),
+            message: will.message.to_vec(),
+            qos: will.qos,
+            retain: will.retain,
+        }
+    }
+}
+
 /// A marker to indicate that [`Builder`] does not include credentials.
 #[derive(Copy, Clone, Debug)]
 pub struct WithoutAuth;
 
 /// A marker to indicate that [`Builder`] includes credentials.
+///
+/// Only a `Builder` with a username accepts a password:
+///
+/// ```compile_fail
+/// use tjiftjaf::Connect;
+///
+/// let packet = Connect::builder().password("prime").build();
+/// ```
 #[derive(Copy, Clone, Debug)]
 pub struct WithAuth;
 
@@ -481,6 +602,22 @@ pub struct WithAuth;
 pub struct WithoutWill;
 
 /// A marker to indicate that [`Builder`] includes a configuration for a will.
+///
+/// Only a `Builder` with a will accepts the QoS of the will:
+///
+/// ```compile_fail
+/// use tjiftjaf::{Connect, QoS};
+///
+/// let packet = Connect::builder().will_qos(QoS::AtLeastOnceDelivery).build();
+/// ```
+///
+/// Or the retain flag of the will:
+///
+/// ```compile_fail
+/// use tjiftjaf::Connect;
+///
+/// let packet = Connect::builder().retain_will().build();
+/// ```
 #[derive(Copy, Clone, Debug)]
 pub struct WithWill;
 
@@ -506,8 +643,8 @@ pub struct Builder<A = WithoutAuth, W = WithoutWill> {
 
     will_topic: Option<String>,
     will_message: Option<Vec<u8>>,
-    username: Option<String>,
-    password: Option<Vec<u8>>,
+    username: Option<SecretString>,
+    password: Option<SecretBytes>,
     flags: Flags,
 
     _auth: PhantomData<A>,
@@ -583,7 +720,7 @@ impl<A, W> Builder<A, W> {
             keep_alive: self.keep_alive,
             will_topic: self.will_topic,
             will_message: self.will_message,
-            username: Some(username.to_string()),
+            username: Some(username.to_string().into()),
             password: self.password,
             flags: self.flags,
             _auth: auth,
@@ -659,56 +796,80 @@ impl<A, W> Builder<A, W> {
     }
 
     /// Build a `Connect`.
-    pub fn build(mut self) -> Connect {
-        let mut fixed_header = Vec::with_capacity(2);
-        fixed_header.push((PacketType::Connect as u8) << 4);
-
-        let mut variable_header = Vec::with_capacity(10);
-
-        variable_header.append(&mut encode::utf8("MQTT".into()));
-        // Version of the protocol.
-        variable_header.push(ProtocolLevel::_3_1_1 as u8);
+    ///
+    /// # Panics
+    ///
+    /// Panics if a field exceeds 65535 bytes. Use [`Builder::try_build()`] to handle that case.
+    pub fn build(self) -> Connect {
+        self.try_build().unwrap_or_else(|error| panic!("{error}"))
+    }
 
+    /// Like [`Builder::build()`], but fails if the client id, the will, the username
+    /// or the password exceeds 65535 bytes.
+    ///
+    /// ```
+    /// use tjiftjaf::Connect;
+    ///
+    /// assert!(Connect::builder().client_id("a".repeat(65_535)).try_build().is_ok());
+    /// assert!(Connect::builder().client_id("a".repeat(65_536)).try_build().is_err());
+    /// ```
+    pub fn try_build(mut self) -> Result<Connect, FieldTooLong> {
         // [MQTT-3.1.3-7] If the Client supplies a zero-byte ClientId, the Client MUST also set CleanSession to 1.
         if self.client_id.is_empty() {
             self.flags.set_clean_session();
         }
 
+        // The variable header is 10 bytes: protocol name, protocol level,
+        // connect flags and keep alive.
+        let mut length = 10 + 2 + self.client_id.len();
+        if let Some(will_topic) = &self.will_topic {
+            length += 2 + will_topic.len();
+        }
+        if let Some(will_message) = &self.will_message {
+            length += 2 + will_message.len();
+        }
+        if let Some(username) = &self.username {
+            length += 2 + username.expose().len();
+
+            if let Some(password) = &self.password {
+                length += 2 + password.expose().len();
+            }
+        }
+
+        let mut packet = encode::frame((PacketType::Connect as u8) << 4, length);
+
+        encode::write_utf8(&mut packet, "protocol name", "MQTT")?;
+        // Version of the protocol.
+        packet.push(ProtocolLevel::_3_1_1 as u8);
+
         // Connection flags
-        variable_header.push(self.flags.0);
+        packet.push(self.flags.0);
 
         // Keep Alive
-        variable_header.append(&mut self.keep_alive.to_be_bytes().to_vec());
+        packet.extend_from_slice(&self.keep_alive.to_be_bytes());
 
-        let mut payload = encode::utf8(self.client_id);
+        encode::write_utf8(&mut packet, "client id", &self.client_id)?;
         if let Some(will_topic) = self.will_topic {
-            payload.append(&mut encode::utf8(will_topic));
+            encode::write_utf8(&mut packet, "will topic", &will_topic)?;
         }
 
         if let Some(will_message) = self.will_message {
-            payload.append(&mut encode::bytes(&will_message));
+            encode::write_bytes(&mut packet, "will message", &will_message)?;
         }
 
-        if let Some(username) = self.username {
-            payload.append(&mut encode::utf8(username));
+        if let Some(username) = &self.username {
+            encode::write_utf8(&mut packet, "username", username.expose())?;
 
-            if let Some(password) = self.password {
-                payload.append(&mut encode::bytes(&password));
+            if let Some(password) = &self.password {
+                encode::write_bytes(&mut packet, "password", password.expose())?;
             }
         }
 
-        fixed_header.append(&mut encode::remaining_length(
-            variable_header.len() + payload.len(),
-        ));
-
-        fixed_header.append(&mut variable_header);
-        fixed_header.append(&mut payload);
-
-        UnverifiedConnect {
-            inner: fixed_header.to_vec()
+        Ok(UnverifiedConnect {
+            inner: packet
         }
         .verify()
-        .unwrap_or_else(|e| panic!("`Builder` failed to build `Connect`. This is a bug. Please report it to https://github.com/eastern-oak/tjiftjaf/issues. The error is '{e}'."))
+        .unwrap_or_else(|e| panic!("`Builder` failed to build `Connect`. This is a bug. Please report it to https://github.com/eastern-oak/tjiftjaf/issues. The error is '{e}'.")))
     }
 
     pub fn build_packet(self) -> Packet {
@@ -716,7 +877,7 @@ impl<A, W> Builder<A, W> {
     }
 }
 
-impl<WithAuth, W> Builder<WithAuth, W> {
+impl<W> Builder<WithAuth, W> {
     /// Configure the password.
     ///
     /// ```
@@ -733,12 +894,12 @@ impl<WithAuth, W> Builder<WithAuth, W> {
     /// ```
     pub fn password(mut self, password: impl Into<Vec<u8>>) -> Self {
         self.flags.set_password();
-        self.password = Some(password.into());
+        self.password = Some(password.into().into());
         self
     }
 }
 
-impl<A, WithWill> Builder<A, WithWill> {
+impl<A> Builder<A, WithWill> {
     /// Configure the `QoS` of the will message.
     /// ```
     /// use tjiftjaf::{QoS, Connect};
@@ -788,8 +949,8 @@ impl<A, WithWill> Builder<A, WithWill> {
     }
 }
 
-impl<A, W> std::fmt::Debug for Builder<A, W> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<A, W> core::fmt::Debug for Builder<A, W> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Builder")
             .field("client_id", &self.client_id)
             .field("keep_alive", &self.keep_alive)
@@ -842,6 +1003,31 @@ impl<'a> arbitrary::Arbitrary<'a> for Connect {
 mod test {
     use crate::Connect;
 
+    // Verify the boundary of the length of the fields: 65535 bytes are fine, 65536 aren't.
+    #[test]
+    fn test_field_length_limit() {
+        let connect = Connect::builder()
+            .client_id("a".repeat(65_535))
+            .will("status", vec![0; 65_535])
+            .try_build()
+            .unwrap();
+        let connect = Connect::try_from(connect.into_bytes()).unwrap();
+        assert_eq!(connect.client_id().len(), 65_535);
+        assert_eq!(connect.will().unwrap().message().len(), 65_535);
+
+        let error = Connect::builder()
+            .will("status", vec![0; 65_536])
+            .try_build()
+            .unwrap_err();
+        assert_eq!(error.field(), "will message");
+
+        let error = Connect::builder()
+            .username("a".repeat(65_536))
+            .try_build()
+            .unwrap_err();
+        assert_eq!(error.field(), "username");
+    }
+
     #[test]
     fn test_connect() {
         let packet = Connect::builder().build();
@@ -854,6 +1040,72 @@ mod test {
         let connect = Connect::try_from(packet.into_bytes()).unwrap();
         assert_eq!(connect.username(), Some("admin"));
         assert_eq!(connect.password(), None);
+
+        let packet = Connect::builder()
+            .client_id("sensor")
+            .will("status", "offline")
+            .username("admin")
+            .password("secret")
+            .build();
+
+        let connect = Connect::try_from(packet.into_bytes()).unwrap();
+        let will = connect.will().unwrap();
+        assert_eq!(will.topic(), "status");
+        assert_eq!(will.message(), b"offline");
+        assert_eq!(connect.username(), Some("admin"));
+        assert_eq!(connect.password(), Some(b"secret".as_slice()));
+    }
+
+    // Verify that errors in the payload tell the offset of the field that failed,
+    // and the statement of the specification that is violated.
+    #[test]
+    fn test_decoding_error_context() {
+        use crate::{decode::DecodingError, PacketType};
+        use core::error::Error;
+
+        let header = [0b0001_0000, 0, 0, 4, b'M', b'Q', b'T', b'T', 4];
+        let frame = |flags: u8, payload: &[u8]| {
+            let mut frame = header.to_vec();
+            frame[1] = 10 + payload.len() as u8;
+            frame.extend_from_slice(&[flags, 0, 60]);
+            frame.extend_from_slice(payload);
+            frame
+        };
+
+        let error = Connect::try_from(frame(0, &[0, 0])).unwrap_err();
+        let DecodingError::Malformed {
+            packet_type: PacketType::Connect,
+            offset: 12,
+            source,
+        } = &error
+        else {
+            panic!("Unexpected error {error:?}");
+        };
+        assert!(matches!(
+            **source,
+            DecodingError::Violation {
+                clause: "MQTT-3.1.3-7",
+                ..
+            }
+        ));
+        assert_eq!(error.to_string(), "Connect packet is malformed at byte 12");
+
+        // The username starts after the client id.
+        let error = Connect::try_from(frame(0b1000_0010, &[0, 1, b'a', 0, 1, 0xFF])).unwrap_err();
+        assert!(matches!(
+            error,
+            DecodingError::Malformed {
+                packet_type: PacketType::Connect,
+                offset: 15,
+                ..
+            }
+        ));
+        let source = error.source().unwrap();
+        assert!(matches!(
+            source.downcast_ref(),
+            Some(DecodingError::InvalidUtf8(_))
+        ));
+        assert!(source.source().unwrap().is::<core::str::Utf8Error>());
     }
 
     /// #61 tracks a bug where `connect::Builder.build()` encoded the length
@@ -868,4 +1120,30 @@ mod test {
         let packet = Connect::builder().will("topic", [0; 255]).build();
         assert!(Connect::try_from(packet.into_bytes()).is_ok());
     }
+
+    // Verify that neither the builder nor the packet print the credentials.
+    #[test]
+    fn test_debug_redacts_credentials() {
+        let builder = Connect::builder().username("optimus").password("prime");
+        let output = format!("{builder:?}");
+        assert!(!output.contains("optimus"));
+        assert!(!output.contains("prime"));
+
+        let output = format!("{:?}", builder.build());
+        assert!(!output.contains("optimus"));
+        assert!(!output.contains("prime"));
+    }
+
+    // Verify that the credentials survive `Connect::into_bytes()`, which moves the
+    // buffer out before the packet zeroizes it.
+    #[test]
+    fn test_into_bytes_keeps_credentials() {
+        let connect = Connect::builder()
+            .username("optimus")
+            .password("prime")
+            .build();
+        let connect = Connect::try_from(connect.into_bytes()).unwrap();
+        assert_eq!(connect.password(), Some(b"prime".as_slice()));
+        assert_eq!(Vec::from(connect.clone()), connect.into_bytes());
+    }
 }
@@ -1,5 +1,9 @@
 //! Providing [`Disconnect`]
-use crate::{decode::DecodingError, Frame, Packet, PacketType};
+use crate::{
+    decode::{self, DecodingError},
+    Frame, Packet, PacketType,
+};
+use alloc::vec::Vec;
 
 // A DISCONNECT packet consists of only a header of two bytes.
 // The first byte encodes the packet type, DISCONNECT in this case.
@@ -8,6 +12,7 @@ const DISCONNECT: [u8; 2] = [(PacketType::Disconnect as u8) << 4, 0];
 
 /// The Disconnect Packet is sent from a Client to the Server.
 #[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Disconnect;
 
 impl Frame for Disconnect {
@@ -47,7 +52,13 @@ impl TryFrom<&[u8]> for Disconnect {
             return Err(DecodingError::TooManyBytes);
         }
 
-        Err(DecodingError::Other)
+        let packet_type = decode::packet_type(value)?;
+        if packet_type != PacketType::Disconnect {
+            return Err(DecodingError::InvalidPacketType(packet_type as u8));
+        }
+        decode::flags(packet_type, value[0])?;
+
+        Err(DecodingError::InvalidRemainingLength)
     }
 }
 
@@ -63,8 +74,8 @@ impl From<Disconnect> for Packet {
     }
 }
 
-impl std::fmt::Debug for Disconnect {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Disconnect {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("DISCONNECT")
             .field("length", &self.length())
             .finish()
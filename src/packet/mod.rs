@@ -3,8 +3,9 @@ use crate::{
     decode, ConnAck, Connect, Disconnect, PingReq, PingResp, PubAck, PubComp, PubRec, PubRel,
     Publish, SubAck, Subscribe, UnsubAck, Unsubscribe,
 };
-use std::error::Error;
-use std::fmt::{self, Display};
+use alloc::vec::Vec;
+use core::error::Error;
+use core::fmt::{self, Display};
 
 mod ack;
 pub mod connack;
@@ -23,7 +24,8 @@ pub mod unsuback;
 pub mod unsubscribe;
 
 /// A model for each MQTT packet.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Packet {
     /// The first message sent by a client.
     Connect(Connect),
@@ -89,6 +91,71 @@ impl Packet {
         }
     }
 
+    // The packet identifier chosen by the sender of the packet. Only a PUBLISH with QoS 1 or 2,
+    // a SUBSCRIBE and an UNSUBSCRIBE carry one. Acknowledgements reuse the identifier
+    // of the packet they acknowledge.
+    #[cfg(feature = "std")]
+    pub(crate) fn allocated_packet_identifier(&self) -> Option<u16> {
+        match self {
+            Self::Publish(packet) => packet.packet_identifier(),
+            Self::Subscribe(packet) => Some(packet.packet_identifier()),
+            Self::Unsubscribe(packet) => Some(packet.packet_identifier()),
+            _ => None,
+        }
+    }
+
+    // Replace the packet identifier returned by `Packet::allocated_packet_identifier()`.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_packet_identifier(&mut self, packet_identifier: u16) {
+        match self {
+            Self::Publish(packet) => packet.set_packet_identifier(packet_identifier),
+            Self::Subscribe(packet) => packet.set_packet_identifier(packet_identifier),
+            Self::Unsubscribe(packet) => packet.set_packet_identifier(packet_identifier),
+            _ => {}
+        }
+    }
+
+    /// Retrieve the flags of the fixed header. See [`HeaderFlags`].
+    pub fn header_flags(&self) -> HeaderFlags {
+        match self {
+            Self::Connect(packet) => packet.header_flags(),
+            Self::ConnAck(packet) => packet.header_flags(),
+            Self::Disconnect(packet) => packet.header_flags(),
+            Self::Subscribe(packet) => packet.header_flags(),
+            Self::SubAck(packet) => packet.header_flags(),
+            Self::Publish(packet) => packet.header_flags(),
+            Self::PubAck(packet) => packet.header_flags(),
+            Self::PubComp(packet) => packet.header_flags(),
+            Self::PubRec(packet) => packet.header_flags(),
+            Self::PubRel(packet) => packet.header_flags(),
+            Self::PingReq(packet) => packet.header_flags(),
+            Self::PingResp(packet) => packet.header_flags(),
+            Self::UnsubAck(packet) => packet.header_flags(),
+            Self::Unsubscribe(packet) => packet.header_flags(),
+        }
+    }
+
+    // The frame of the packet.
+    #[cfg(feature = "std")]
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Connect(packet) => packet.as_bytes(),
+            Self::ConnAck(packet) => packet.as_bytes(),
+            Self::Disconnect(packet) => packet.as_bytes(),
+            Self::Subscribe(packet) => packet.as_bytes(),
+            Self::SubAck(packet) => packet.as_bytes(),
+            Self::Publish(packet) => packet.as_bytes(),
+            Self::PubAck(packet) => packet.as_bytes(),
+            Self::PubComp(packet) => packet.as_bytes(),
+            Self::PubRec(packet) => packet.as_bytes(),
+            Self::PubRel(packet) => packet.as_bytes(),
+            Self::PingReq(packet) => packet.as_bytes(),
+            Self::PingResp(packet) => packet.as_bytes(),
+            Self::UnsubAck(packet) => packet.as_bytes(),
+            Self::Unsubscribe(packet) => packet.as_bytes(),
+        }
+    }
+
     /// Serialize the packet into bytes.
     pub fn into_bytes(self) -> Vec<u8> {
         match self {
@@ -151,7 +218,7 @@ impl Packet {
     }
 }
 
-impl std::fmt::Debug for Packet {
+impl core::fmt::Debug for Packet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Connect(packet) => packet.fmt(f),
@@ -204,7 +271,8 @@ impl TryFrom<Vec<u8>> for Packet {
 }
 
 /// Every packet type of MQTT 3.1.1.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum PacketType {
     /// The first message sent by a client.
     Connect = 1,
@@ -312,14 +380,15 @@ pub trait Frame {
     fn header(&self) -> &[u8] {
         let inner = self.as_bytes();
 
-        if inner[1] & 128 == 0 {
-            return &self.as_bytes()[0..2];
-        } else if inner[2] & 128 == 0 {
-            return &inner[0..3];
-        } else if inner[3] & 128 == 0 {
-            return &inner[0..4];
-        };
-        &inner[0..5]
+        // The field "remaining length" ends with the first byte that doesn't have
+        // the continuation bit set. It's at most 4 bytes long.
+        let end = inner
+            .iter()
+            .skip(1)
+            .take(4)
+            .position(|byte| byte & 128 == 0)
+            .map_or(5, |n| n + 2);
+        &inner[..end.min(inner.len())]
     }
 
     /// Return the index where the variable header starts.
@@ -362,6 +431,96 @@ pub trait Frame {
 
         PacketType::try_from(&self.as_bytes()[0]).expect("Failed to decode packet type")
     }
+
+    /// Return the flags of the fixed header: the lower 4 bits of the first byte.
+    ///
+    /// Not to be confused with [`Connect::flags()`], the connect flags of the variable header.
+    fn fixed_header_flags(&self) -> u8 {
+        self.as_bytes()[0] & 0b1111
+    }
+
+    /// Like [`Frame::fixed_header_flags()`], but typed. See [`HeaderFlags`].
+    fn header_flags(&self) -> HeaderFlags {
+        HeaderFlags::from_byte(self.as_bytes()[0])
+    }
+}
+
+/// The flags of the fixed header of a packet: the lower 4 bits of its first byte.
+///
+/// Only the flags of a [`Publish`] carry information: DUP, QoS and RETAIN. The other
+/// packets have reserved flags with a fixed value, see [`HeaderFlags::reserved()`].
+///
+/// ```
+/// use tjiftjaf::{packet::HeaderFlags, Frame, PacketType, Publish, QoS};
+///
+/// let publish = Publish::builder("sensor/1", "26.1")
+///     .qos(QoS::AtLeastOnceDelivery)
+///     .retain(true)
+///     .build();
+/// let flags = publish.header_flags();
+/// assert_eq!(flags.bits(), 0b0011);
+/// assert_eq!(flags.qos(), Some(QoS::AtLeastOnceDelivery));
+/// assert!(flags.retain());
+/// assert!(!flags.duplicate());
+///
+/// // Inspect the first byte of a raw frame.
+/// let flags = HeaderFlags::from_byte(0b1000_0000);
+/// assert!(!flags.is_valid_for(PacketType::Subscribe));
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HeaderFlags(u8);
+
+impl HeaderFlags {
+    /// Take the flags from the first byte of a frame.
+    pub fn from_byte(byte: u8) -> Self {
+        Self(byte & 0b1111)
+    }
+
+    /// The flags as the lower 4 bits of a byte.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// The DUP flag of a [`Publish`].
+    pub fn duplicate(self) -> bool {
+        self.0 & 0b1000 != 0
+    }
+
+    /// The QoS of a [`Publish`]. Returns `None` if both QoS bits are set, which is invalid.
+    pub fn qos(self) -> Option<QoS> {
+        QoS::try_from((self.0 >> 1) & 0b11).ok()
+    }
+
+    /// The RETAIN flag of a [`Publish`].
+    pub fn retain(self) -> bool {
+        self.0 & 0b0001 != 0
+    }
+
+    /// The flags that a packet of `packet_type` must have. Returns `None` for
+    /// [`PacketType::Publish`], which has variable flags.
+    ///
+    /// The flags of [`PubRel`], [`Subscribe`] and [`Unsubscribe`] are 0b0010, those
+    /// of the other packets are 0.
+    ///
+    /// See <https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718022>.
+    pub fn reserved(packet_type: PacketType) -> Option<Self> {
+        match packet_type {
+            PacketType::Publish => None,
+            PacketType::PubRel | PacketType::Subscribe | PacketType::Unsubscribe => {
+                Some(Self(0b0010))
+            }
+            _ => Some(Self(0b0000)),
+        }
+    }
+
+    /// Whether a packet of `packet_type` may have these flags. A [`Publish`] may
+    /// have any flags, except a QoS with both bits set.
+    pub fn is_valid_for(self, packet_type: PacketType) -> bool {
+        match Self::reserved(packet_type) {
+            Some(reserved) => self == reserved,
+            None => self.qos().is_some(),
+        }
+    }
 }
 
 /// The revision of the MQTT protocol.
@@ -374,6 +533,7 @@ pub enum ProtocolLevel {
 /// The delivery guarantee for packets [`Subscribe`] and [`Publish`].
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum QoS {
     /// The message is not guaranteed to be delivered.
@@ -418,15 +578,19 @@ impl Display for InvalidQoS {
     }
 }
 
-pub fn min_bytes_required(payload: &[u8]) -> u32 {
+/// The number of bytes that must be appended to `payload` to complete the packet.
+///
+/// While the fixed header is incomplete, that is a lower bound. Fails if the fixed header is invalid.
+pub fn min_bytes_required(payload: &[u8]) -> Result<u32, DecodingError> {
     if payload.len() < 2 {
-        return 2 - payload.len() as u32;
+        return Ok(2 - payload.len() as u32);
     }
 
     match decode::packet_length(&payload[1..]) {
-        Ok(length) => length - payload.len() as u32,
-        Err(DecodingError::NotEnoughBytes { .. }) => 127 - payload.len() as u32,
-        Err(error) => panic!("{error:?}"),
+        Ok(length) => Ok(length.saturating_sub(payload.len() as u32)),
+        // The field "remaining length" continues in the next byte.
+        Err(DecodingError::NotEnoughBytes { .. }) => Ok(1),
+        Err(error) => Err(error),
     }
 }
 
@@ -454,13 +618,12 @@ pub trait UnverifiedFrame {
         // * 1 byte for encoding the packet type
         for n in 1..5 {
             let byte = inner.get(n).ok_or(DecodingError::NotEnoughBytes {
-                minimum: 1,
-                actual: 0,
+                minimum: n + 1,
+                actual: inner.len(),
             })?;
 
             if byte & 128 == 0 {
-                // TODO: Make lookup infallible.
-                return Ok(&self.as_bytes()[0..n + 1]);
+                return Ok(&inner[0..n + 1]);
             }
         }
 
@@ -484,9 +647,26 @@ pub trait UnverifiedFrame {
     // The slice might be empty for packets without payload.
     fn try_payload(&self) -> Result<&[u8], DecodingError> {
         let offset = self.try_offset_payload()?;
-        let size = self.length() - offset;
+        self.try_slice(offset, self.length().saturating_sub(offset))
+    }
+
+    // Return the `size` bytes that start at `offset`.
+    // Fails if the frame ends before that.
+    fn try_slice(&self, offset: usize, size: usize) -> Result<&[u8], DecodingError> {
+        let inner = self.as_bytes();
+        inner
+            .get(offset..offset + size)
+            .ok_or(DecodingError::NotEnoughBytes {
+                minimum: offset + size,
+                actual: inner.len(),
+            })
+    }
 
-        // TODO: Make lookup infallible.
-        Ok(&self.as_bytes()[offset..offset + size])
+    // Attribute an error to the field at `offset` of the frame.
+    fn malformed(&self, offset: usize) -> impl FnOnce(DecodingError) -> DecodingError + '_ {
+        move |error| match decode::packet_type(self.as_bytes()) {
+            Ok(packet_type) => error.at(packet_type, offset),
+            Err(_) => error,
+        }
     }
 }
@@ -1,5 +1,9 @@
 //! Providing [`PingReq`]
-use crate::{decode::DecodingError, Frame, Packet};
+use crate::{
+    decode::{self, DecodingError},
+    Frame, Packet, PacketType,
+};
+use alloc::vec::Vec;
 
 // A PINGREQ packet consists of only a header of two bytes.
 // The first byte encodes the packet type, PINGREQ in this case.
@@ -11,6 +15,7 @@ const PINGREQ: [u8; 2] = [12 << 4, 0];
 /// * Request that the Server responds to confirm that it is alive.
 /// * Exercise the network to indicate that the Network Connection is active.
 #[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct PingReq;
 
 impl Frame for PingReq {
@@ -50,7 +55,13 @@ impl TryFrom<&[u8]> for PingReq {
             return Err(DecodingError::TooManyBytes);
         }
 
-        Err(DecodingError::Other)
+        let packet_type = decode::packet_type(value)?;
+        if packet_type != PacketType::PingReq {
+            return Err(DecodingError::InvalidPacketType(packet_type as u8));
+        }
+        decode::flags(packet_type, value[0])?;
+
+        Err(DecodingError::InvalidRemainingLength)
     }
 }
 
@@ -66,8 +77,8 @@ impl From<PingReq> for Packet {
     }
 }
 
-impl std::fmt::Debug for PingReq {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for PingReq {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PINGREQ")
             .field("length", &self.length())
             .finish()
@@ -1,5 +1,9 @@
 //! Providing [`PingResp`]
-use crate::{decode::DecodingError, Frame};
+use crate::{
+    decode::{self, DecodingError},
+    Frame, Packet, PacketType,
+};
+use alloc::vec::Vec;
 
 // A PINGRESP packet consists of only a header of two bytes.
 // The first byte encodes the packet type, PINGRESP in this case.
@@ -8,6 +12,7 @@ const PINGRESP: [u8; 2] = [13 << 4, 0];
 
 /// A PINGRESP Packet is sent by the Server to the Client in response to a PINGREQ Packet. It indicates that the Server is alive.
 #[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct PingResp;
 
 impl Frame for PingResp {
@@ -47,7 +52,13 @@ impl TryFrom<&[u8]> for PingResp {
             return Err(DecodingError::TooManyBytes);
         }
 
-        Err(DecodingError::Other)
+        let packet_type = decode::packet_type(value)?;
+        if packet_type != PacketType::PingResp {
+            return Err(DecodingError::InvalidPacketType(packet_type as u8));
+        }
+        decode::flags(packet_type, value[0])?;
+
+        Err(DecodingError::InvalidRemainingLength)
     }
 }
 
@@ -57,8 +68,14 @@ impl From<PingResp> for Vec<u8> {
     }
 }
 
-impl std::fmt::Debug for PingResp {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl From<PingResp> for Packet {
+    fn from(value: PingResp) -> Packet {
+        Packet::PingResp(value)
+    }
+}
+
+impl core::fmt::Debug for PingResp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PINGRESP")
             .field("length", &self.length())
             .finish()
@@ -68,7 +85,7 @@ impl std::fmt::Debug for PingResp {
 #[cfg(test)]
 mod test {
     use super::PingResp;
-    use crate::Frame;
+    use crate::{Frame, Packet, PacketType};
 
     #[test]
     fn test_encode_and_decode() {
@@ -80,6 +97,13 @@ mod test {
         assert!(PingResp::try_from(&[15 << 4, 0][..]).is_err());
     }
 
+    #[test]
+    fn test_into_packet() {
+        let packet = Packet::from(PingResp);
+        assert_eq!(packet.packet_type(), PacketType::PingResp);
+        assert_eq!(packet.into_bytes(), Vec::from(PingResp));
+    }
+
     #[test]
     fn test_variable_header() {
         // The PingResp message doesn't have a variable header.
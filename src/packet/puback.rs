@@ -1,5 +1,6 @@
 //! Providing [`PubAck`], to acknowledge a [`crate::Publish`].
 use crate::{decode::DecodingError, packet::ack::Ack, Frame, Packet, PacketType};
+use alloc::vec::Vec;
 
 /// A [`PubAck`] packet is the response to a [`crate::Publish`] packet with
 /// [`crate::QoS::AtLeastOnceDelivery`].
@@ -61,8 +62,8 @@ impl From<PubAck> for Packet {
     }
 }
 
-impl std::fmt::Debug for PubAck {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for PubAck {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PUBACK")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -70,6 +71,13 @@ impl std::fmt::Debug for PubAck {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PubAck {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::PubAck;
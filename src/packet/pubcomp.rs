@@ -1,5 +1,6 @@
 //! Providing [`PubComp`], a message that acknowledges a [`crate::PubRel`].
 use crate::{decode::DecodingError, packet::ack::Ack, Frame, Packet, PacketType};
+use alloc::vec::Vec;
 
 /// [`PubComp`] is the response to a [`crate::PubRel`] packet with [`crate::QoS::ExactlyOnceDelivery`].
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -59,8 +60,8 @@ impl From<PubComp> for Packet {
     }
 }
 
-impl std::fmt::Debug for PubComp {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for PubComp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PUBCOMP")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -68,6 +69,13 @@ impl std::fmt::Debug for PubComp {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PubComp {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::PubComp;
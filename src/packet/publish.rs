@@ -3,8 +3,10 @@ use crate::{
     decode::{self, DecodingError},
     encode,
     packet::UnverifiedFrame,
-    packet_identifier, ConnectionError, Frame, Packet, PacketType, QoS,
+    packet_identifier, EncodingError, FieldTooLong, Frame, Packet, PacketType, QoS,
+    MAX_REMAINING_LENGTH,
 };
+use alloc::{string::String, vec::Vec};
 
 /// [Publish](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718037) is used by both clients and servers
 /// to emit a message for a specific topic.
@@ -63,6 +65,44 @@ impl Publish {
         self.inner.payload().unwrap()
     }
 
+    /// Take the payload of this message. Requires the feature `bytes`.
+    ///
+    /// Unlike copying [`Publish::payload()`], this doesn't allocate: the returned
+    /// [`Bytes`](bytes::Bytes) refers to the frame of the publication.
+    ///
+    /// ```
+    /// use tjiftjaf::Publish;
+    ///
+    /// let packet = Publish::builder("sensor/1", "26.1").build();
+    /// assert_eq!(packet.into_payload(), "26.1");
+    /// ```
+    #[cfg(feature = "bytes")]
+    pub fn into_payload(self) -> bytes::Bytes {
+        self.into_parts().1
+    }
+
+    /// Take the topic and the payload of this message. Requires the feature `bytes`.
+    ///
+    /// Only the topic is copied. The payload refers to the frame of the publication,
+    /// like with [`Publish::into_payload()`].
+    ///
+    /// ```
+    /// use tjiftjaf::Publish;
+    ///
+    /// let packet = Publish::builder("sensor/1", "26.1").build();
+    /// let (topic, payload) = packet.into_parts();
+    /// assert_eq!(topic, "sensor/1");
+    /// assert_eq!(payload, "26.1");
+    /// ```
+    #[cfg(feature = "bytes")]
+    pub fn into_parts(self) -> (String, bytes::Bytes) {
+        let topic = String::from(self.topic());
+
+        let offset = self.offset_payload();
+        let payload = bytes::Bytes::from(self.inner.inner).slice(offset..);
+        (topic, payload)
+    }
+
     /// Get the QoS level of this message.
     pub fn qos(&self) -> QoS {
         self.inner.qos().unwrap()
@@ -82,6 +122,16 @@ impl Publish {
     pub fn packet_identifier(&self) -> Option<u16> {
         self.inner.packet_identifier().unwrap()
     }
+
+    // Replace the packet identifier. Publications with QoS 0 don't have one.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_packet_identifier(&mut self, packet_identifier: u16) {
+        if self.qos() == QoS::AtMostOnceDelivery {
+            return;
+        }
+        let offset = self.offset_variable_header() + 2 + self.topic().len();
+        self.inner.inner[offset..offset + 2].copy_from_slice(&packet_identifier.to_be_bytes());
+    }
 }
 
 impl Frame for Publish {
@@ -94,8 +144,8 @@ impl Frame for Publish {
     }
 }
 
-impl std::fmt::Debug for Publish {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Publish {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PUBLISH")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -146,7 +196,8 @@ impl UnverifiedPublish {
     fn qos(&self) -> Result<QoS, DecodingError> {
         let header = self.try_header()?;
         let flags = header[0] >> 1 & 0b11;
-        QoS::try_from(flags).map_err(|_| DecodingError::InvalidValue("Invalid QoS value".into()))
+        QoS::try_from(flags)
+            .map_err(|_| DecodingError::violation("MQTT-3.3.1-4", "both bits of the QoS are set"))
     }
 
     fn retain(&self) -> Result<bool, DecodingError> {
@@ -163,13 +214,9 @@ impl UnverifiedPublish {
         let variable_header = self.try_variable_header()?;
         let (_, offset) = decode::field::utf8(variable_header)?;
 
+        // Only publications with a QoS of 1 or 2 have a packet identifier.
         if self.qos()? == QoS::AtMostOnceDelivery {
-            if variable_header.len() == offset {
-                return Ok(None);
-            }
-
-            // A packet identifier is not  allowed with QoS 0
-            return Err(DecodingError::Other);
+            return Ok(None);
         }
 
         let identifier = decode::u16(&variable_header[offset..])?;
@@ -187,13 +234,17 @@ impl UnverifiedPublish {
         if packet_length != self.length() {
             return Err(DecodingError::TooManyBytes);
         }
+        self.qos()?;
 
         Ok(())
     }
 
     fn verify_variable_header(&self) -> Result<(), DecodingError> {
-        self.topic()?;
-        self.packet_identifier()?;
+        let offset = self.try_offset_variable_header()?;
+        let topic = self.try_slice(offset, self.length() - offset)?;
+        let (_, length) = decode::field::utf8(topic).map_err(self.malformed(offset))?;
+        self.packet_identifier()
+            .map_err(self.malformed(offset + length))?;
 
         Ok(())
     }
@@ -216,14 +267,14 @@ impl UnverifiedFrame for UnverifiedPublish {
         let mut len = 0;
 
         // Calculate variable header length (topic + optional packet identifier)
-        let (_, topic_len) = decode::field::utf8(&self.inner[offset..])?;
+        let (_, topic_len) = decode::field::utf8(self.try_slice(offset, self.length() - offset)?)?;
         len += topic_len;
 
         if self.qos()? != QoS::AtMostOnceDelivery {
             len += 2; // Packet identifier length
         }
 
-        Ok(&self.as_bytes()[offset..offset + len])
+        self.try_slice(offset, len)
     }
 }
 
@@ -236,6 +287,7 @@ pub struct Builder {
     retain: bool,
     duplicate: bool,
     packet_identifier: Option<u16>,
+    max_packet_size: Option<usize>,
 }
 
 impl Builder {
@@ -247,6 +299,7 @@ impl Builder {
             retain: false,
             duplicate: false,
             packet_identifier: None,
+            max_packet_size: None,
         }
     }
 
@@ -274,55 +327,72 @@ impl Builder {
         self
     }
 
-    /// Build the `Publish` packet.
-    pub fn build(mut self) -> Publish {
-        // The 4 least significant bits configure
-        // * Retain
-        // * QoS
-        // * Duplicate
-        //
-        //      3 | 2 1 |   0
-        //   +----+-----+-------+
-        //    DUP | QoS | RETAIN
-        let mut flags = 0b0000;
-        if self.retain {
-            flags |= 0b0001;
-        }
-        flags |= (self.qos as u8) << 1;
-        if self.duplicate {
-            flags |= 0b1000;
-        }
+    /// Limit the size of the packet, including its fixed header, to `size` bytes.
+    /// Brokers commonly refuse larger packets.
+    ///
+    /// [`Builder::try_build()`] fails for a larger packet.
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = Some(size);
+        self
+    }
 
-        let mut fixed_header = Vec::new();
-        fixed_header.push((PacketType::Publish as u8) << 4 | flags);
+    /// Build the `Publish` packet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the topic exceeds 65535 bytes, or if the packet is too large.
+    /// Use [`Builder::try_build()`] to handle these cases.
+    pub fn build(self) -> Publish {
+        self.try_build().unwrap_or_else(|error| panic!("{error}"))
+    }
 
-        let mut variable_header = Vec::new();
-        variable_header.append(&mut encode::utf8(self.topic).to_vec());
+    /// Like [`Builder::build()`], but fails if the topic exceeds 65535 bytes, if the remaining
+    /// length exceeds [`MAX_REMAINING_LENGTH`], or if the packet exceeds the size set with
+    /// [`Builder::max_packet_size()`].
+    ///
+    /// ```
+    /// use tjiftjaf::{EncodingError, Publish};
+    ///
+    /// assert!(Publish::builder("a".repeat(65_535), "").try_build().is_ok());
+    /// assert!(Publish::builder("a".repeat(65_536), "").try_build().is_err());
+    ///
+    /// let error = Publish::builder("sensor/1", vec![0; 1024])
+    ///     .max_packet_size(1024)
+    ///     .try_build()
+    ///     .unwrap_err();
+    /// assert_eq!(error, EncodingError::PacketTooLarge { size: 1037, limit: 1024 });
+    /// ```
+    pub fn try_build(self) -> Result<Publish, EncodingError> {
+        let flags = flags(self.qos, self.retain, self.duplicate);
 
         // The Packet Identifier field is only present in PUBLISH Packets where the QoS level is 1 or 2. Section 2.3.1 provides more information about Packet Identifiers.
-        if self.qos != QoS::AtMostOnceDelivery {
-            variable_header.append(
-                &mut self
-                    .packet_identifier
-                    .unwrap_or_else(packet_identifier)
-                    .to_be_bytes()
-                    .to_vec(),
-            );
+        let packet_identifier = (self.qos != QoS::AtMostOnceDelivery)
+            .then(|| self.packet_identifier.unwrap_or_else(packet_identifier));
+
+        let length = 2 + self.topic.len() + packet_identifier.map_or(0, |_| 2) + self.payload.len();
+        if length > MAX_REMAINING_LENGTH {
+            return Err(FieldTooLong {
+                field: "remaining length",
+                length,
+                limit: MAX_REMAINING_LENGTH,
+            }
+            .into());
         }
 
-        let mut payload = Vec::new();
-        payload.append(&mut self.payload);
-
-        let remaining_length = encode::remaining_length(variable_header.len() + payload.len());
-        fixed_header.append(&mut remaining_length.to_vec());
-        fixed_header.append(&mut variable_header);
-        fixed_header.append(&mut payload);
+        // [MQTT-2.2.3] The fixed header is 1 byte, followed by the remaining length.
+        let size = 1 + encode::remaining_length_size(length) + length;
+        if let Some(limit) = self.max_packet_size.filter(|limit| size > *limit) {
+            return Err(EncodingError::PacketTooLarge { size, limit });
+        }
 
-        UnverifiedPublish {
-            inner: fixed_header,
+        let mut packet = encode::frame((PacketType::Publish as u8) << 4 | flags, length);
+        encode::write_utf8(&mut packet, "topic", &self.topic)?;
+        if let Some(packet_identifier) = packet_identifier {
+            packet.extend_from_slice(&packet_identifier.to_be_bytes());
         }
-        .verify()
-        .unwrap()
+        packet.extend_from_slice(&self.payload);
+
+        Ok(UnverifiedPublish { inner: packet }.verify().unwrap())
     }
 
     /// Build a `Packet::Publish`.
@@ -331,6 +401,143 @@ impl Builder {
     }
 }
 
+// The flags of the fixed header. The 4 least significant bits configure
+// * Retain
+// * QoS
+// * Duplicate
+//
+//      3 | 2 1 |   0
+//   +----+-----+-------+
+//    DUP | QoS | RETAIN
+fn flags(qos: QoS, retain: bool, duplicate: bool) -> u8 {
+    let mut flags = 0b0000;
+    if retain {
+        flags |= 0b0001;
+    }
+    flags |= (qos as u8) << 1;
+    if duplicate {
+        flags |= 0b1000;
+    }
+    flags
+}
+
+/// Publish many payloads to the same topic, without encoding the topic every time.
+///
+/// The template encodes the topic once. [`PublishTemplate::with_payload()`] then only
+/// writes the fixed header, the packet identifier and the payload. MQTT 3.1.1 has no
+/// topic aliases, so the topic is still transmitted with every publication.
+///
+/// ```
+/// use tjiftjaf::{PublishTemplate, QoS};
+///
+/// let template = PublishTemplate::new("building/3/floor/2/room/14/temperature")
+///     .unwrap()
+///     .qos(QoS::AtLeastOnceDelivery);
+/// for reading in ["21.3", "21.4", "21.2"] {
+///     let publish = template.with_payload(reading);
+///     assert_eq!(publish.topic(), "building/3/floor/2/room/14/temperature");
+///     assert!(publish.packet_identifier().is_some());
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct PublishTemplate {
+    // The topic, prefixed with its length.
+    topic: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+    max_packet_size: Option<usize>,
+}
+
+impl PublishTemplate {
+    /// Create a template for publications to `topic`, with QoS 0. Fails if the topic
+    /// exceeds 65535 bytes.
+    pub fn new(topic: &str) -> Result<Self, FieldTooLong> {
+        // Encoding the topic verifies its length, so publications built
+        // from the template don't need to.
+        let mut encoded = Vec::with_capacity(2 + topic.len());
+        encode::write_utf8(&mut encoded, "topic", topic)?;
+        Ok(Self {
+            topic: encoded,
+            qos: QoS::AtMostOnceDelivery,
+            retain: false,
+            max_packet_size: None,
+        })
+    }
+
+    /// Set the QoS level of the publications.
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Set whether the publications should be retained.
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Limit the size of the publications. See [`Builder::max_packet_size()`].
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = Some(size);
+        self
+    }
+
+    /// The topic of the publications.
+    pub fn topic(&self) -> &str {
+        // The topic was a `&str` when the template was created.
+        core::str::from_utf8(&self.topic[2..]).unwrap()
+    }
+
+    /// Build a [`Publish`] with `payload`. Publications with QoS 1 or 2 receive
+    /// a new packet identifier.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the packet is too large. Use [`PublishTemplate::try_with_payload()`]
+    /// to handle that case.
+    pub fn with_payload(&self, payload: impl AsRef<[u8]>) -> Publish {
+        self.try_with_payload(payload)
+            .unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    /// Like [`PublishTemplate::with_payload()`], but fails if the remaining length exceeds
+    /// [`MAX_REMAINING_LENGTH`], or if the packet exceeds the size set with
+    /// [`PublishTemplate::max_packet_size()`].
+    pub fn try_with_payload(&self, payload: impl AsRef<[u8]>) -> Result<Publish, EncodingError> {
+        let payload = payload.as_ref();
+        let packet_identifier = (self.qos != QoS::AtMostOnceDelivery).then(packet_identifier);
+
+        let length = self.topic.len() + packet_identifier.map_or(0, |_| 2) + payload.len();
+        if length > MAX_REMAINING_LENGTH {
+            return Err(FieldTooLong {
+                field: "remaining length",
+                length,
+                limit: MAX_REMAINING_LENGTH,
+            }
+            .into());
+        }
+
+        let size = 1 + encode::remaining_length_size(length) + length;
+        if let Some(limit) = self.max_packet_size.filter(|limit| size > *limit) {
+            return Err(EncodingError::PacketTooLarge { size, limit });
+        }
+
+        let flags = flags(self.qos, self.retain, false);
+        let mut packet = encode::frame((PacketType::Publish as u8) << 4 | flags, length);
+        packet.extend_from_slice(&self.topic);
+        if let Some(packet_identifier) = packet_identifier {
+            packet.extend_from_slice(&packet_identifier.to_be_bytes());
+        }
+        packet.extend_from_slice(payload);
+
+        // The topic was verified by `PublishTemplate::new()`, the rest is valid
+        // by construction.
+        Ok(Publish {
+            inner: UnverifiedPublish { inner: packet },
+        })
+    }
+}
+
 #[cfg(feature = "async")]
 impl crate::aio::Emit for Publish {
     /// Publish `payload` to the given `topic`.
@@ -350,7 +557,7 @@ impl crate::aio::Emit for Publish {
     ///     .unwrap();
     /// # });
     /// ```
-    async fn emit(self, handler: &crate::aio::ClientHandle) -> Result<(), ConnectionError> {
+    async fn emit(self, handler: &crate::aio::ClientHandle) -> Result<(), crate::HandleError> {
         handler.send(self.into()).await?;
         Ok(())
     }
@@ -372,16 +579,103 @@ impl crate::blocking::Emit for Publish {
     ///     .emit(&handle)
     ///     .unwrap();
     ///```
-    fn emit(self, handler: &crate::blocking::ClientHandle) -> Result<(), ConnectionError> {
+    fn emit(self, handler: &crate::blocking::ClientHandle) -> Result<(), crate::HandleError> {
         handler.send(self.into())?;
         Ok(())
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Publish {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let qos: QoS = u.arbitrary()?;
+        let mut builder = Publish::builder(String::arbitrary(u)?, Vec::<u8>::arbitrary(u)?)
+            .qos(qos)
+            .retain(u.arbitrary()?)
+            .packet_identifier(u.arbitrary()?);
+
+        // [MQTT-3.3.1-2] The DUP flag must be 0 for publications with QoS 0.
+        if qos != QoS::AtMostOnceDelivery {
+            builder = builder.duplicate(u.arbitrary()?);
+        }
+        builder
+            .try_build()
+            .map_err(|_| arbitrary::Error::IncorrectFormat)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // Verify that a template builds the same publications as a `Builder`.
+    #[test]
+    fn test_publish_template() {
+        for qos in [
+            QoS::AtMostOnceDelivery,
+            QoS::AtLeastOnceDelivery,
+            QoS::ExactlyOnceDelivery,
+        ] {
+            let template = PublishTemplate::new("sensor/1")
+                .unwrap()
+                .qos(qos)
+                .retain(true);
+            assert_eq!(template.topic(), "sensor/1");
+            for payload in [vec![], vec![1; 200], vec![2; 20_000]] {
+                let publish = template.with_payload(&payload);
+                let mut builder = Builder::new("sensor/1", payload).qos(qos).retain(true);
+                if let Some(packet_identifier) = publish.packet_identifier() {
+                    builder = builder.packet_identifier(packet_identifier);
+                }
+                assert_eq!(publish, builder.build());
+            }
+        }
+
+        let template = PublishTemplate::new("sensor/1")
+            .unwrap()
+            .max_packet_size(1024);
+        assert!(template.try_with_payload([0; 1011]).is_ok());
+        let error = template.try_with_payload([0; 1012]).unwrap_err();
+        assert_eq!(
+            error,
+            EncodingError::PacketTooLarge {
+                size: 1025,
+                limit: 1024
+            }
+        );
+
+        assert!(PublishTemplate::new(&"a".repeat(65_536)).is_err());
+    }
+
+    // Verify the boundary of the length of the topic: 65535 bytes are fine, 65536 aren't.
+    #[test]
+    fn test_topic_length_limit() {
+        let topic = "a".repeat(65_535);
+        let publish = Publish::builder(topic.clone(), "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .packet_identifier(1)
+            .try_build()
+            .unwrap();
+        let publish = Publish::try_from(publish.into_bytes()).unwrap();
+        assert_eq!(publish.topic(), topic);
+        assert_eq!(publish.payload(), b"26.1");
+
+        let error = Publish::builder("a".repeat(65_536), "26.1")
+            .try_build()
+            .unwrap_err();
+        let EncodingError::FieldTooLong(error) = error else {
+            panic!("expected FieldTooLong, got {error:?}");
+        };
+        assert_eq!(error.field(), "topic");
+        assert_eq!(error.length(), 65_536);
+    }
+
+    #[test]
+    #[should_panic(expected = "the topic is 65536 bytes long")]
+    fn test_build_panics_on_long_topic() {
+        Publish::builder("a".repeat(65_536), "26.1").build();
+    }
+
     #[test]
     fn test_publish_basic() {
         let packet = Publish::builder("test/topic", "Hello MQTT!")
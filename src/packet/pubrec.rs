@@ -1,5 +1,6 @@
 //! Providing [`PubRec`], to acknowledge a [`crate::Publish`].
 use crate::{decode::DecodingError, packet::ack::Ack, Frame, Packet, PacketType};
+use alloc::vec::Vec;
 
 /// A [`PubRec`] packet is the response to a [`crate::Publish`] packet with [`crate::QoS::ExactlyOnceDelivery`].
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -59,8 +60,8 @@ impl From<PubRec> for Packet {
     }
 }
 
-impl std::fmt::Debug for PubRec {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for PubRec {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PUBREC")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -68,6 +69,13 @@ impl std::fmt::Debug for PubRec {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PubRec {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::PubRec;
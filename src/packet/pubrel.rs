@@ -1,5 +1,6 @@
 //! Providing [`PubRel`], to acknowledge a [`crate::PubRec`].
 use crate::{decode::DecodingError, packet::ack::Ack, Frame, Packet, PacketType};
+use alloc::vec::Vec;
 
 /// A [`PubRel`] packet is the response to a [`crate::PubRec`].
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -59,8 +60,8 @@ impl From<PubRel> for Packet {
     }
 }
 
-impl std::fmt::Debug for PubRel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for PubRel {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PUBREL")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -68,9 +69,17 @@ impl std::fmt::Debug for PubRel {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PubRel {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::PubRel;
+    use crate::{decode::DecodingError, Frame, PacketType};
 
     #[test]
     #[allow(clippy::useless_conversion)]
@@ -81,4 +90,20 @@ mod test {
 
         assert_eq!(puback.packet_identifier(), 1568);
     }
+
+    // [MQTT-3.6.1-1] The flags of the fixed header of PUBREL must be 0b0010.
+    #[test]
+    fn test_flags() {
+        let pubrel = PubRel::new(1568);
+        assert_eq!(pubrel.as_bytes()[0], 0b0110_0010);
+
+        let frame = vec![0b0110_0000, 2, 6, 32];
+        assert!(matches!(
+            PubRel::try_from(frame),
+            Err(DecodingError::HeaderContainsInvalidFlags {
+                packet_type: PacketType::PubRel,
+                flags: 0b0000
+            })
+        ));
+    }
 }
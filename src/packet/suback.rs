@@ -5,6 +5,7 @@ use crate::{
     packet::UnverifiedFrame,
     Frame, Packet, PacketType, QoS,
 };
+use alloc::{format, vec, vec::Vec};
 
 /// [SubAck](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718068) is emitted by a broker to confirm a [`crate::Subscribe`] request.
 ///
@@ -104,8 +105,8 @@ impl From<SubAck> for Packet {
     }
 }
 
-impl std::fmt::Debug for SubAck {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for SubAck {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SUBACK")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -135,9 +136,7 @@ impl UnverifiedSubAck {
 
         // The lowest 4 bits of the header include flags.
         // For SUBACK, none of these flags is set.
-        if header[0] & 0b1111 != 0 {
-            return Err(DecodingError::HeaderContainsInvalidFlags);
-        }
+        decode::flags(packet_type, header[0])?;
 
         // TODO: limit payload length to 255.
         let packet_length = decode::packet_length(&header[1..header.len()])? as usize;
@@ -150,17 +149,25 @@ impl UnverifiedSubAck {
     }
 
     fn try_return_codes(&self) -> Result<Vec<ReturnCode>, DecodingError> {
+        let offset = self.try_offset_payload()?;
         self.try_payload()?
             .iter()
-            .map(ReturnCode::try_from)
-            .collect::<Result<Vec<ReturnCode>, InvalidReturnCode>>()
-            .map_err(|error| {
-                DecodingError::InvalidValue(format!("{} is not a valid ReturnCode", error.0))
+            .enumerate()
+            .map(|(index, return_code)| {
+                ReturnCode::try_from(return_code).map_err(|error: InvalidReturnCode| {
+                    DecodingError::violation(
+                        "MQTT-3.9.3-2",
+                        format!("{} is not a valid return code", error.0),
+                    )
+                    .at(PacketType::SubAck, offset + index)
+                })
             })
+            .collect()
     }
 
     fn verify_variable_header(&self) -> Result<(), DecodingError> {
-        self.try_variable_header()?;
+        let offset = self.try_offset_variable_header()?;
+        self.try_variable_header().map_err(self.malformed(offset))?;
         Ok(())
     }
 
@@ -188,7 +195,7 @@ impl UnverifiedFrame for UnverifiedSubAck {
     fn try_variable_header(&self) -> Result<&[u8], DecodingError> {
         // The variable header of a SUBACK packet has a fixed size of 2 bytes.
         let offset = self.try_offset_variable_header()?;
-        Ok(&self.as_bytes()[offset..offset + 2])
+        self.try_slice(offset, 2)
     }
 }
 
@@ -211,22 +218,14 @@ impl Builder {
     }
 
     pub fn build(self) -> SubAck {
-        let mut variable_header = self.packet_identifier.to_be_bytes().to_vec();
+        let packet_type: u8 = PacketType::SubAck.into();
+        let mut packet = encode::frame(packet_type << 4, 2 + self.return_codes.len());
+        packet.extend_from_slice(&self.packet_identifier.to_be_bytes());
 
-        let mut payload: Vec<u8> = Vec::with_capacity(self.return_codes.len());
         for code in self.return_codes {
-            payload.push(code.into())
+            packet.push(code.into())
         }
 
-        let mut packet: Vec<u8> = Vec::new();
-        let packet_type: u8 = PacketType::SubAck.into();
-        packet.push(packet_type << 4);
-
-        let remaining_length = encode::remaining_length(variable_header.len() + payload.len());
-        packet.append(&mut remaining_length.to_vec());
-        packet.append(&mut variable_header);
-        packet.append(&mut payload);
-
         UnverifiedSubAck { inner: packet }.verify().unwrap()
     }
 
@@ -236,6 +235,7 @@ impl Builder {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum ReturnCode {
     QoS(QoS),
     Failure,
@@ -283,6 +283,25 @@ impl TryFrom<u8> for ReturnCode {
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct InvalidReturnCode(u8);
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for SubAck {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        use core::ops::ControlFlow;
+
+        let mut builder = SubAck::builder(u.arbitrary()?, ReturnCode::arbitrary(u)?);
+        let mut return_codes: Vec<ReturnCode> = vec![];
+        u.arbitrary_loop(Some(0), Some(254), |u| {
+            return_codes.push(u.arbitrary()?);
+            Ok(ControlFlow::Continue(()))
+        })?;
+
+        for return_code in return_codes {
+            builder = builder.add_return_code(return_code);
+        }
+        Ok(builder.build())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
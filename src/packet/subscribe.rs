@@ -2,8 +2,15 @@
 use crate::{
     decode::{self, DecodingError},
     encode,
-    packet::UnverifiedFrame,
-    packet_identifier, ConnectionError, Frame, Packet, PacketType, QoS,
+    packet::{suback::ReturnCode, UnverifiedFrame},
+    packet_identifier, validate, Frame, InvalidTopicFilter, Packet, PacketType, QoS, SubAck,
+    SubscribeError, SubscriptionResult,
+};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
 };
 
 /// [Subscribe](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718063) allows a client to express interest in one or more topics.
@@ -53,6 +60,13 @@ impl Subscribe {
         self.inner.try_packet_identifier().unwrap()
     }
 
+    // Replace the packet identifier.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_packet_identifier(&mut self, packet_identifier: u16) {
+        let offset = self.offset_variable_header();
+        self.inner.inner[offset..offset + 2].copy_from_slice(&packet_identifier.to_be_bytes());
+    }
+
     /// Returns an iterator over the topics.
     ///
     /// # Example
@@ -74,6 +88,69 @@ impl Subscribe {
             offset: 0,
         }
     }
+
+    /// Returns an iterator over the topics that `suback` rejected.
+    ///
+    /// The return codes of a [`SubAck`] are in the same order as the topics of the `Subscribe`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tjiftjaf::{packet::suback::ReturnCode, QoS, SubAck, Subscribe};
+    ///
+    /// let subscribe = Subscribe::builder("topic-1", QoS::AtMostOnceDelivery)
+    ///     .add_topic("topic-2", QoS::AtMostOnceDelivery)
+    ///     .build();
+    /// let suback = SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery)
+    ///     .add_return_code(ReturnCode::Failure)
+    ///     .build();
+    ///
+    /// let mut rejected = subscribe.rejected_topics(&suback);
+    /// assert_eq!(rejected.next(), Some("topic-2"));
+    /// assert_eq!(rejected.next(), None);
+    /// ```
+    pub fn rejected_topics<'a>(&'a self, suback: &SubAck) -> impl Iterator<Item = &'a str> {
+        self.topics()
+            .zip(suback.return_codes())
+            .filter(|(_, code)| *code == ReturnCode::Failure)
+            .map(|((topic, _), _)| topic)
+    }
+
+    /// Verify that `suback` accepted all topics, and obtain the QoS granted per topic.
+    ///
+    /// Returns [`SubscribeError::Rejected`] for the first topic that the server rejected.
+    ///
+    /// ```
+    /// use tjiftjaf::{QoS, SubAck, Subscribe};
+    ///
+    /// let subscribe = Subscribe::builder("topic-1", QoS::AtMostOnceDelivery)
+    ///     .add_topic("topic-2", QoS::ExactlyOnceDelivery)
+    ///     .build();
+    /// let suback = SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery)
+    ///     .add_return_code(QoS::AtLeastOnceDelivery)
+    ///     .build();
+    ///
+    /// let result = subscribe.check(&suback).unwrap();
+    /// assert_eq!(result.granted().collect::<Vec<_>>(), [
+    ///     ("topic-1", QoS::AtMostOnceDelivery),
+    ///     ("topic-2", QoS::AtLeastOnceDelivery),
+    /// ]);
+    /// assert_eq!(result.downgraded().collect::<Vec<_>>(), ["topic-2"]);
+    /// ```
+    pub fn check(&self, suback: &SubAck) -> Result<SubscriptionResult, SubscribeError> {
+        let mut topics = vec![];
+        for ((topic, requested), code) in self.topics().zip(suback.return_codes()) {
+            match code {
+                ReturnCode::QoS(granted) => topics.push((topic.to_string(), requested, granted)),
+                ReturnCode::Failure => {
+                    return Err(SubscribeError::Rejected {
+                        topic: topic.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(SubscriptionResult { topics })
+    }
 }
 
 #[cfg(feature = "async")]
@@ -99,7 +176,7 @@ impl crate::aio::Emit for Subscribe {
     /// }
     /// # });
     /// ```
-    async fn emit(self, handler: &crate::aio::ClientHandle) -> Result<(), ConnectionError> {
+    async fn emit(self, handler: &crate::aio::ClientHandle) -> Result<(), crate::HandleError> {
         handler.send(self.into()).await?;
         Ok(())
     }
@@ -127,7 +204,7 @@ impl crate::blocking::Emit for Subscribe {
     ///   );
     /// }
     /// ```
-    fn emit(self, handler: &crate::blocking::ClientHandle) -> Result<(), ConnectionError> {
+    fn emit(self, handler: &crate::blocking::ClientHandle) -> Result<(), crate::HandleError> {
         handler.send(self.into())?;
         Ok(())
     }
@@ -164,8 +241,8 @@ impl From<Subscribe> for Packet {
     }
 }
 
-impl std::fmt::Debug for Subscribe {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Subscribe {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let mut list = vec![];
         // let topics = self.topics();
         for (topic, _) in self.topics() {
@@ -220,6 +297,10 @@ impl UnverifiedSubscribe {
             return Err(DecodingError::InvalidPacketType(5));
         }
 
+        // [MQTT-3.8.1-1] Bits 3,2,1 and 0 of the fixed header of the SUBSCRIBE Control Packet
+        // are reserved and MUST be set to 0,0,1 and 0 respectively.
+        decode::flags(packet_type, header[0])?;
+
         let packet_length = decode::packet_length(&header[1..header.len()])? as usize;
         if packet_length != self.length() {
             // TODO: Return  correct error
@@ -231,18 +312,28 @@ impl UnverifiedSubscribe {
 
     // TODO: figure out if returning `Topics` is better.
     fn try_topics(&self) -> Result<Vec<(String, QoS)>, DecodingError> {
+        let start = self.try_offset_payload()?;
         let payload = self.try_payload()?;
         let mut offset = 0;
         let mut topics = vec![];
 
         loop {
-            let (topic, length) = decode::field::utf8(&payload[offset..])?;
+            let (topic, length) =
+                decode::field::utf8(&payload[offset..]).map_err(self.malformed(start + offset))?;
             offset += length;
-            let qos = QoS::try_from(payload[offset]).map_err(|_| {
-                DecodingError::InvalidValue(format!(
-                    "{} is not a valid value for QoS",
-                    payload[offset]
-                ))
+            let qos = payload
+                .get(offset)
+                .ok_or(DecodingError::NotEnoughBytes {
+                    minimum: offset + 1,
+                    actual: payload.len(),
+                })
+                .map_err(self.malformed(start + offset))?;
+            let qos = QoS::try_from(qos).map_err(|_| {
+                DecodingError::violation(
+                    "MQTT-3.8.3-4",
+                    format!("{qos} is not a valid value for QoS"),
+                )
+                .at(PacketType::Subscribe, start + offset)
             })?;
             offset += 1;
             topics.push((topic.to_string(), qos));
@@ -255,7 +346,8 @@ impl UnverifiedSubscribe {
     }
 
     fn verify_variable_header(&self) -> Result<(), DecodingError> {
-        self.try_variable_header()?;
+        let offset = self.try_offset_variable_header()?;
+        self.try_variable_header().map_err(self.malformed(offset))?;
         Ok(())
     }
 
@@ -283,7 +375,7 @@ impl UnverifiedFrame for UnverifiedSubscribe {
     fn try_variable_header(&self) -> Result<&[u8], DecodingError> {
         // The variable header of a SUBSCRIBE packet has a fixed size of 2 bytes.
         let offset = self.try_offset_variable_header()?;
-        Ok(&self.as_bytes()[offset..offset + 2])
+        self.try_slice(offset, 2)
     }
 }
 
@@ -296,7 +388,7 @@ pub struct Builder {
 
 #[cfg(feature = "arbitrary")]
 fn arbitrary_topics(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Vec<(String, QoS)>> {
-    use std::ops::ControlFlow;
+    use core::ops::ControlFlow;
     let mut topics: Vec<(String, QoS)> = vec![];
     // A `Subscribe` packet can not have more than 255 subscriptions.
     u.arbitrary_loop(Some(1), Some(255), |u| {
@@ -323,32 +415,65 @@ impl Builder {
         self
     }
 
-    pub fn build(self) -> Subscribe {
-        let mut variable_header: Vec<u8> = self.packet_identifier.to_be_bytes().to_vec();
+    /// Set the packet identifier. By default, [`packet_identifier()`](crate::packet_identifier()) generates one.
+    pub fn packet_identifier(mut self, id: u16) -> Self {
+        self.packet_identifier = id;
+        self
+    }
 
-        let mut payload = Vec::new();
-        for (topic, qos) in self.topics {
-            payload.append(&mut encode::utf8(topic).to_vec());
-            payload.push(qos as u8);
-        }
+    /// Build the `Subscribe` packet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a topic filter exceeds 65535 bytes. Use [`Builder::try_build()`] to handle that case.
+    pub fn build(self) -> Subscribe {
+        let length = 2 + self
+            .topics
+            .iter()
+            .map(|(topic, _)| 2 + topic.len() + 1)
+            .sum::<usize>();
 
-        let mut packet = Vec::new();
         let packet_type: u8 = PacketType::Subscribe.into();
-        packet.push((packet_type << 4) + 2);
+        let mut packet = encode::frame((packet_type << 4) + 2, length);
+        packet.extend_from_slice(&self.packet_identifier.to_be_bytes());
 
-        let remaining_length = encode::remaining_length(variable_header.len() + payload.len());
-        packet.append(&mut remaining_length.to_vec());
-        packet.append(&mut variable_header);
-        packet.append(&mut payload);
+        for (topic, qos) in self.topics {
+            // A topic filter that exceeds 65535 bytes can't be encoded.
+            encode::write_utf8(&mut packet, "topic filter", &topic)
+                .unwrap_or_else(|error| panic!("{error}"));
+            packet.push(qos as u8);
+        }
 
         UnverifiedSubscribe { inner: packet }.verify().unwrap()
     }
 
+    /// Like [`Builder::build()`], but first verify the syntax of the topic filters.
+    ///
+    /// ```
+    /// use tjiftjaf::{QoS, Subscribe};
+    ///
+    /// assert!(Subscribe::builder("sensor/+/temperature", QoS::AtMostOnceDelivery).try_build().is_ok());
+    /// assert!(Subscribe::builder("sensor/temperature+", QoS::AtMostOnceDelivery).try_build().is_err());
+    /// ```
+    pub fn try_build(self) -> Result<Subscribe, InvalidTopicFilter> {
+        for (topic, _) in &self.topics {
+            validate::topic_filter(topic)?;
+        }
+        Ok(self.build())
+    }
+
     pub fn build_packet(self) -> Packet {
         Packet::Subscribe(self.build())
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Subscribe {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Builder::arbitrary(u)?.build())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -364,6 +489,27 @@ mod test {
         let _: Subscribe = frame.into_bytes().try_into().unwrap();
     }
 
+    // [MQTT-3.8.1-1] The flags of the fixed header of SUBSCRIBE must be 0b0010.
+    // Verify that any other value is rejected.
+    #[test]
+    fn test_subscribe_with_invalid_flags() {
+        let mut frame = Subscribe::builder("topic-1", QoS::AtMostOnceDelivery)
+            .build()
+            .into_bytes();
+        assert_eq!(frame[0] & 0b1111, 0b0010);
+
+        for flags in [0b0000, 0b0001, 0b0011, 0b1010] {
+            frame[0] = (PacketType::Subscribe as u8) << 4 | flags;
+            assert!(matches!(
+                Subscribe::try_from(frame.clone()),
+                Err(DecodingError::HeaderContainsInvalidFlags {
+                    packet_type: PacketType::Subscribe,
+                    ..
+                })
+            ));
+        }
+    }
+
     // Issue #40 tracks a bug when the `Builder` panics
     // trying to create a `Subscribe` with a lot of topics.
     //
@@ -378,6 +524,26 @@ mod test {
         builder.build();
     }
 
+    // Verify the boundary of the length of a topic filter: 65535 bytes are fine, 65536 aren't.
+    #[test]
+    fn test_topic_filter_length_limit() {
+        let filter = "a".repeat(65_535);
+        let subscribe = Subscribe::builder(filter.clone(), QoS::AtMostOnceDelivery)
+            .try_build()
+            .unwrap();
+        let subscribe = Subscribe::try_from(subscribe.into_bytes()).unwrap();
+        assert_eq!(
+            subscribe.topics().next(),
+            Some((filter.as_str(), QoS::AtMostOnceDelivery))
+        );
+
+        assert!(
+            Subscribe::builder("a".repeat(65_536), QoS::AtMostOnceDelivery)
+                .try_build()
+                .is_err()
+        );
+    }
+
     // Issue #45 tracks a bug when the `Subscribe.topics()` panics
     // if the message includes a lot of topics.
     //
@@ -1,5 +1,6 @@
 //! Providing [`UnsubAck`], to acknowledge a [`crate::Unsubscribe`].
 use crate::{decode::DecodingError, packet::ack::Ack, Frame, Packet, PacketType};
+use alloc::vec::Vec;
 
 /// A [`UnsubAck`] packet is the response to a [`crate::Unsubscribe`].
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -59,8 +60,8 @@ impl From<UnsubAck> for Packet {
     }
 }
 
-impl std::fmt::Debug for UnsubAck {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for UnsubAck {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("UNSUBACK")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -68,6 +69,13 @@ impl std::fmt::Debug for UnsubAck {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for UnsubAck {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::UnsubAck;
@@ -3,7 +3,12 @@ use crate::{
     decode::{self, DecodingError},
     encode,
     packet::UnverifiedFrame,
-    packet_identifier, ConnectionError, Frame, Packet, PacketType,
+    packet_identifier, validate, Frame, InvalidTopicFilter, Packet, PacketType,
+};
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
 };
 
 /// [Unsubscribe](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718072) allows a client unsubscribe from one or more topics.
@@ -54,6 +59,13 @@ impl Unsubscribe {
         self.inner.try_packet_identifier().unwrap()
     }
 
+    // Replace the packet identifier.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_packet_identifier(&mut self, packet_identifier: u16) {
+        let offset = self.offset_variable_header();
+        self.inner.inner[offset..offset + 2].copy_from_slice(&packet_identifier.to_be_bytes());
+    }
+
     /// Returns an iterator over the topics.
     ///
     /// # Example
@@ -90,7 +102,7 @@ impl Frame for Unsubscribe {
 
 #[cfg(feature = "async")]
 impl crate::aio::Emit for Unsubscribe {
-    async fn emit(self, handler: &crate::aio::ClientHandle) -> Result<(), ConnectionError> {
+    async fn emit(self, handler: &crate::aio::ClientHandle) -> Result<(), crate::HandleError> {
         handler.send(self.into()).await?;
         Ok(())
     }
@@ -111,7 +123,7 @@ impl crate::blocking::Emit for Unsubscribe {
     ///    .emit(&handle)
     ///    .unwrap();
     /// ```
-    fn emit(self, handler: &crate::blocking::ClientHandle) -> Result<(), ConnectionError> {
+    fn emit(self, handler: &crate::blocking::ClientHandle) -> Result<(), crate::HandleError> {
         handler.send(self.into())?;
         Ok(())
     }
@@ -137,8 +149,8 @@ impl From<Unsubscribe> for Packet {
     }
 }
 
-impl std::fmt::Debug for Unsubscribe {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for Unsubscribe {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("UNSUBSCRIBE")
             .field("length", &self.length())
             .field("packet_identifier", &self.packet_identifier())
@@ -187,6 +199,10 @@ impl UnverifiedUnsubscribe {
             return Err(DecodingError::InvalidPacketType(10));
         }
 
+        // [MQTT-3.10.1-1] Bits 3,2,1 and 0 of the fixed header of the UNSUBSCRIBE Control Packet
+        // are reserved and MUST be set to 0,0,1 and 0 respectively.
+        decode::flags(packet_type, header[0])?;
+
         let packet_length = decode::packet_length(&header[1..header.len()])? as usize;
         if packet_length != self.length() {
             // TODO: Return  correct error
@@ -198,12 +214,14 @@ impl UnverifiedUnsubscribe {
 
     // TODO: figure out if returning `Topics` is better.
     fn try_topics(&self) -> Result<Vec<String>, DecodingError> {
+        let start = self.try_offset_payload()?;
         let payload = self.try_payload()?;
         let mut offset = 0;
         let mut topics = vec![];
 
         loop {
-            let (topic, length) = decode::field::utf8(&payload[offset..])?;
+            let (topic, length) =
+                decode::field::utf8(&payload[offset..]).map_err(self.malformed(start + offset))?;
             offset += length;
             topics.push(topic.to_string());
 
@@ -215,7 +233,8 @@ impl UnverifiedUnsubscribe {
     }
 
     fn verify_variable_header(&self) -> Result<(), DecodingError> {
-        self.try_variable_header()?;
+        let offset = self.try_offset_variable_header()?;
+        self.try_variable_header().map_err(self.malformed(offset))?;
         Ok(())
     }
 
@@ -243,7 +262,7 @@ impl UnverifiedFrame for UnverifiedUnsubscribe {
     fn try_variable_header(&self) -> Result<&[u8], DecodingError> {
         // The variable header of a UNSUBSCRIBE packet has a fixed size of 2 bytes.
         let offset = self.try_offset_variable_header()?;
-        Ok(&self.as_bytes()[offset..offset + 2])
+        self.try_slice(offset, 2)
     }
 }
 
@@ -267,31 +286,76 @@ impl Builder {
         self
     }
 
-    pub fn build(self) -> Unsubscribe {
-        let mut variable_header = self.packet_identifier.to_be_bytes().to_vec();
+    /// Set the packet identifier. By default, [`packet_identifier()`](crate::packet_identifier()) generates one.
+    pub fn packet_identifier(mut self, id: u16) -> Self {
+        self.packet_identifier = id;
+        self
+    }
 
-        let mut payload = Vec::new();
-        for topic in self.topics {
-            payload.append(&mut encode::utf8(topic).to_vec());
-        }
+    /// Build the `Unsubscribe` packet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a topic filter exceeds 65535 bytes. Use [`Builder::try_build()`] to handle that case.
+    pub fn build(self) -> Unsubscribe {
+        let length = 2 + self
+            .topics
+            .iter()
+            .map(|topic| 2 + topic.len())
+            .sum::<usize>();
 
-        let mut packet = Vec::new();
         let packet_type: u8 = PacketType::Unsubscribe.into();
-        packet.push((packet_type << 4) + 2);
+        let mut packet = encode::frame((packet_type << 4) + 2, length);
+        packet.extend_from_slice(&self.packet_identifier.to_be_bytes());
 
-        let remaining_length = encode::remaining_length(variable_header.len() + payload.len());
-        packet.append(&mut remaining_length.to_vec());
-        packet.append(&mut variable_header);
-        packet.append(&mut payload);
+        for topic in self.topics {
+            // A topic filter that exceeds 65535 bytes can't be encoded.
+            encode::write_utf8(&mut packet, "topic filter", &topic)
+                .unwrap_or_else(|error| panic!("{error}"));
+        }
 
         UnverifiedUnsubscribe { inner: packet }.verify().unwrap()
     }
 
+    /// Like [`Builder::build()`], but first verify the syntax of the topic filters.
+    ///
+    /// ```
+    /// use tjiftjaf::Unsubscribe;
+    ///
+    /// assert!(Unsubscribe::builder("sensor/+/temperature").try_build().is_ok());
+    /// assert!(Unsubscribe::builder("sensor/#/temperature").try_build().is_err());
+    /// ```
+    pub fn try_build(self) -> Result<Unsubscribe, InvalidTopicFilter> {
+        for topic in &self.topics {
+            validate::topic_filter(topic)?;
+        }
+        Ok(self.build())
+    }
+
     pub fn build_packet(self) -> Packet {
         Packet::Unsubscribe(self.build())
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Unsubscribe {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        use core::ops::ControlFlow;
+
+        let mut builder = Unsubscribe::builder(String::arbitrary(u)?);
+        let mut topics: Vec<String> = vec![];
+        u.arbitrary_loop(Some(0), Some(254), |u| {
+            topics.push(u.arbitrary()?);
+            Ok(ControlFlow::Continue(()))
+        })?;
+
+        for topic in topics {
+            builder = builder.add_topic(topic);
+        }
+        Ok(builder.packet_identifier(u.arbitrary()?).build())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -305,4 +369,21 @@ mod test {
         let frame = Unsubscribe::builder("topic-1").add_topic("topic-2").build();
         let _: Unsubscribe = frame.into_bytes().try_into().unwrap();
     }
+
+    // [MQTT-3.10.1-1] The flags of the fixed header of UNSUBSCRIBE must be 0b0010.
+    // Verify that any other value is rejected.
+    #[test]
+    fn test_unsubscribe_with_invalid_flags() {
+        let mut frame = Unsubscribe::builder("topic-1").build().into_bytes();
+        assert_eq!(frame[0] & 0b1111, 0b0010);
+
+        frame[0] = (PacketType::Unsubscribe as u8) << 4;
+        assert!(matches!(
+            Unsubscribe::try_from(frame),
+            Err(DecodingError::HeaderContainsInvalidFlags {
+                packet_type: PacketType::Unsubscribe,
+                flags: 0b0000
+            })
+        ));
+    }
 }
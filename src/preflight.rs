@@ -0,0 +1,294 @@
+//! Check a [`Connect`] against the known restrictions of a broker, before connecting.
+//!
+//! Managed brokers often reject or silently adjust a CONNECT that is valid according
+//! to the MQTT specification. [`validate()`] flags these incompatibilities up front,
+//! so they don't surface as an unexplained refused or dropped connection.
+//!
+//! ```
+//! use tjiftjaf::{preflight::{self, BrokerProfile, Severity}, Connect, QoS};
+//!
+//! let connect = Connect::builder()
+//!     .client_id("sensor-1")
+//!     .keep_alive(10)
+//!     .clean_session()
+//!     .will("sensor-1/status", "offline")
+//!     .will_qos(QoS::ExactlyOnceDelivery)
+//!     .build();
+//!
+//! let issues = preflight::validate(&connect, BrokerProfile::AwsIot);
+//! assert_eq!(issues.len(), 2);
+//! assert!(issues.iter().all(|issue| issue.severity() == Severity::Error));
+//! ```
+use crate::{Connect, QoS};
+use std::fmt::{self, Display};
+
+/// A broker, or family of brokers, with known restrictions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BrokerProfile {
+    /// [AWS IoT Core](https://docs.aws.amazon.com/iot/latest/developerguide/mqtt.html).
+    AwsIot,
+
+    /// [Azure IoT Hub](https://learn.microsoft.com/en-us/azure/iot/iot-mqtt-connect-to-iot-hub).
+    AzureIotHub,
+
+    /// [HiveMQ Cloud](https://docs.hivemq.com/hivemq-cloud/).
+    HiveMqCloud,
+
+    /// [Mosquitto](https://mosquitto.org/man/mosquitto-conf-5.html) with its default configuration.
+    Mosquitto,
+}
+
+/// How severe an [`Issue`] is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The broker refuses the connection, or closes it.
+    Error,
+
+    /// The broker accepts the connection, but behaves differently than requested.
+    Warning,
+}
+
+/// An incompatibility between a [`Connect`] and a [`BrokerProfile`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issue {
+    severity: Severity,
+    message: String,
+}
+
+impl Issue {
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+
+    /// How severe the issue is.
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// A description of the issue.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// Check `connect` against the restrictions of `profile`.
+///
+/// Returns an empty `Vec` if no issues are found. That doesn't guarantee the broker
+/// accepts the connection: credentials and authorization policies can't be checked offline.
+pub fn validate(connect: &Connect, profile: BrokerProfile) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    // [MQTT-3.1.3-11] The Will Topic MUST be a UTF-8 encoded string. Like the topic of
+    // any PUBLISH, it must not be empty or contain wildcards.
+    if let Some(will) = connect.will() {
+        if will.topic().is_empty() {
+            issues.push(Issue::error("the will topic is empty"));
+        }
+        if will.topic().contains(['+', '#']) {
+            issues.push(Issue::error(format!(
+                "the will topic '{}' contains a wildcard",
+                will.topic()
+            )));
+        }
+    }
+
+    match profile {
+        BrokerProfile::AwsIot => aws_iot(connect, &mut issues),
+        BrokerProfile::AzureIotHub => azure_iot_hub(connect, &mut issues),
+        BrokerProfile::HiveMqCloud => hivemq_cloud(connect, &mut issues),
+        // The default configuration of Mosquitto accepts everything the MQTT 3.1.1
+        // specification allows.
+        BrokerProfile::Mosquitto => {}
+    }
+    issues
+}
+
+fn aws_iot(connect: &Connect, issues: &mut Vec<Issue>) {
+    if connect.client_id().is_empty() {
+        issues.push(Issue::error("AWS IoT requires a client id"));
+    }
+    if connect.client_id().len() > 128 {
+        issues.push(Issue::error("AWS IoT limits the client id to 128 bytes"));
+    }
+
+    match connect.keep_alive() {
+        0 => issues.push(Issue::warning(
+            "AWS IoT doesn't support disabling the keep alive, it uses 1200 seconds instead",
+        )),
+        1..30 => issues.push(Issue::error(
+            "AWS IoT requires a keep alive interval of at least 30 seconds",
+        )),
+        1201.. => issues.push(Issue::warning(
+            "AWS IoT limits the keep alive interval to 1200 seconds",
+        )),
+        _ => {}
+    }
+
+    if !connect.flags().clean_session() {
+        issues.push(Issue::warning(
+            "AWS IoT discards persistent sessions after 1 hour by default",
+        ));
+    }
+
+    let Some(will) = connect.will() else {
+        return;
+    };
+    if will.qos() == QoS::ExactlyOnceDelivery {
+        issues.push(Issue::error("AWS IoT doesn't support QoS 2"));
+    }
+    if will.topic().len() > 256 {
+        issues.push(Issue::error("AWS IoT limits topics to 256 bytes"));
+    }
+    if will.topic().matches('/').count() > 7 {
+        issues.push(Issue::error("AWS IoT limits topics to 8 levels"));
+    }
+    if will.topic().starts_with("$aws/") {
+        issues.push(Issue::error(
+            "AWS IoT reserves topics starting with '$aws/'",
+        ));
+    }
+}
+
+fn azure_iot_hub(connect: &Connect, issues: &mut Vec<Issue>) {
+    // The client id is the id of the device.
+    let device_id = connect.client_id();
+    if device_id.is_empty() {
+        issues.push(Issue::error(
+            "Azure IoT Hub requires the device id as client id",
+        ));
+    }
+
+    // The username must be '{iot hub hostname}/{device id}/?api-version={version}'.
+    match connect.username() {
+        None => issues.push(Issue::error(
+            "Azure IoT Hub requires a username of the form '{hostname}/{device id}/?api-version={version}'",
+        )),
+        Some(username) if !username.contains(&format!("/{device_id}/")) => {
+            issues.push(Issue::error(format!(
+                "the username '{username}' doesn't contain the device id '{device_id}'"
+            )))
+        }
+        Some(_) => {}
+    }
+
+    if connect.keep_alive() == 0 || connect.keep_alive() > 1177 {
+        issues.push(Issue::warning(
+            "Azure IoT Hub limits the keep alive interval to 1177 seconds",
+        ));
+    }
+
+    let Some(will) = connect.will() else {
+        return;
+    };
+    if will.qos() == QoS::ExactlyOnceDelivery {
+        issues.push(Issue::error("Azure IoT Hub doesn't support QoS 2"));
+    }
+    if will.retain() {
+        issues.push(Issue::warning(
+            "Azure IoT Hub doesn't support retained messages",
+        ));
+    }
+    if !will
+        .topic()
+        .starts_with(&format!("devices/{device_id}/messages/events/"))
+    {
+        issues.push(Issue::error(format!(
+            "Azure IoT Hub only accepts a will topic starting with 'devices/{device_id}/messages/events/'"
+        )));
+    }
+}
+
+fn hivemq_cloud(connect: &Connect, issues: &mut Vec<Issue>) {
+    if connect.username().is_none() || connect.password().is_none() {
+        issues.push(Issue::error(
+            "HiveMQ Cloud requires a username and password",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_will_topic() {
+        let connect = Connect::builder().will("sensor/+", "offline").build();
+        let issues = validate(&connect, BrokerProfile::Mosquitto);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity(), Severity::Error);
+
+        let connect = Connect::builder().will("sensor/1", "offline").build();
+        assert!(validate(&connect, BrokerProfile::Mosquitto).is_empty());
+    }
+
+    #[test]
+    fn test_aws_iot() {
+        let connect = Connect::builder()
+            .client_id("sensor-1")
+            .keep_alive(60)
+            .clean_session()
+            .build();
+        assert!(validate(&connect, BrokerProfile::AwsIot).is_empty());
+
+        let connect = Connect::builder().keep_alive(60).build();
+        let issues = validate(&connect, BrokerProfile::AwsIot);
+        assert_eq!(issues, vec![Issue::error("AWS IoT requires a client id")]);
+
+        let connect = Connect::builder()
+            .client_id("sensor-1")
+            .keep_alive(60)
+            .build();
+        let issues = validate(&connect, BrokerProfile::AwsIot);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn test_azure_iot_hub() {
+        let connect = Connect::builder()
+            .client_id("sensor-1")
+            .keep_alive(60)
+            .username("hub.azure-devices.net/sensor-1/?api-version=2021-04-12")
+            .password("SharedAccessSignature sr=...")
+            .build();
+        assert!(validate(&connect, BrokerProfile::AzureIotHub).is_empty());
+
+        let connect = Connect::builder()
+            .client_id("sensor-1")
+            .keep_alive(60)
+            .username("hub.azure-devices.net/sensor-2/?api-version=2021-04-12")
+            .build();
+        assert_eq!(validate(&connect, BrokerProfile::AzureIotHub).len(), 1);
+    }
+
+    #[test]
+    fn test_hivemq_cloud() {
+        let connect = Connect::builder().username("optimus").build();
+        assert_eq!(validate(&connect, BrokerProfile::HiveMqCloud).len(), 1);
+
+        let connect = Connect::builder()
+            .username("optimus")
+            .password("prime")
+            .build();
+        assert!(validate(&connect, BrokerProfile::HiveMqCloud).is_empty());
+    }
+}
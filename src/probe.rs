@@ -0,0 +1,162 @@
+//! Check if a broker is alive, without disturbing the session of a client.
+//!
+//! [`check()`] opens a fresh connection and performs a minimal CONNECT/CONNACK/DISCONNECT
+//! exchange. That makes it usable for health checks and deployment scripts.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use tjiftjaf::{packet::connack::ReturnCode, probe};
+//!
+//! let report = probe::check("localhost:1883", Duration::from_secs(5)).unwrap();
+//! assert_eq!(report.return_code, ReturnCode::ConnectionAccepted);
+//! println!("The broker responded in {:?}", report.latency);
+//! ```
+use crate::{packet::connack::ReturnCode, ConnAck, Connect, Disconnect, Packet};
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+/// The outcome of a [`check()`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// The return code of the [`ConnAck`].
+    pub return_code: ReturnCode,
+
+    /// The time between emitting the [`Connect`] and receiving the [`ConnAck`].
+    pub latency: Duration,
+}
+
+/// Connect to the broker at `addr` and report how it responded.
+///
+/// `timeout` bounds the whole exchange. If the broker doesn't respond in time,
+/// an error of kind [`ErrorKind::TimedOut`] is returned. A broker that refuses
+/// the connection is still alive, so that's not an error. Inspect [`Report::return_code`] instead.
+pub fn check(addr: impl ToSocketAddrs, timeout: Duration) -> Result<Report, Error> {
+    let deadline = Instant::now() + timeout;
+    let mut stream = connect(addr, timeout)?;
+
+    // The local port makes the client id unique on this host. Two probes with
+    // the same client id would kick each other off the broker.
+    let connect = Connect::builder()
+        .client_id(format!("tjiftjaf-probe-{}", stream.local_addr()?.port()))
+        .build();
+    let start = Instant::now();
+    stream.set_write_timeout(Some(remaining(deadline)?))?;
+    stream.write_all(&Packet::from(connect).into_bytes())?;
+
+    // A CONNACK is always 4 bytes long.
+    let mut buffer = vec![0; 4];
+    stream.set_read_timeout(Some(remaining(deadline)?))?;
+    stream.read_exact(&mut buffer).map_err(|error| {
+        // Depending on the platform, a read that timed out returns `WouldBlock`.
+        if error.kind() == ErrorKind::WouldBlock {
+            return Error::from(ErrorKind::TimedOut);
+        }
+        error
+    })?;
+    let latency = start.elapsed();
+
+    let connack =
+        ConnAck::try_from(buffer).map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+    let return_code = connack.return_code();
+
+    // The server closes the connection if it refused it.
+    if return_code == ReturnCode::ConnectionAccepted {
+        stream.write_all(&Packet::from(Disconnect).into_bytes())?;
+    }
+
+    Ok(Report {
+        return_code,
+        latency,
+    })
+}
+
+// Connect to the first address of `addr` that accepts the connection.
+fn connect(addr: impl ToSocketAddrs, timeout: Duration) -> Result<TcpStream, Error> {
+    let mut last_error = Error::new(ErrorKind::InvalidInput, "no address to connect to");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+fn remaining(deadline: Instant) -> Result<Duration, Error> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(remaining) if !remaining.is_zero() => Ok(remaining),
+        _ => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    // Spawn a server that answers the first CONNECT with `response`, if any.
+    // The thread returns the packets it received.
+    fn serve(response: Option<ConnAck>) -> (u16, thread::JoinHandle<Vec<Packet>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut packets = vec![];
+            loop {
+                // The packets in this test are smaller than 128 bytes. So the
+                // fixed header is always 2 bytes.
+                let mut frame = vec![0; 2];
+                if stream.read_exact(&mut frame).is_err() {
+                    return packets;
+                }
+                frame.resize(2 + frame[1] as usize, 0);
+                stream.read_exact(&mut frame[2..]).unwrap();
+                packets.push(Packet::try_from(frame).unwrap());
+
+                match &response {
+                    Some(connack) if packets.len() == 1 => {
+                        stream.write_all(&Vec::from(connack.clone())).unwrap()
+                    }
+                    Some(_) => {}
+                    None => thread::sleep(Duration::from_millis(500)),
+                }
+            }
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_check() {
+        let (port, server) = serve(Some(ConnAck::builder().build()));
+        let report = check(("127.0.0.1", port), Duration::from_secs(5)).unwrap();
+        assert_eq!(report.return_code, ReturnCode::ConnectionAccepted);
+
+        let packets = server.join().unwrap();
+        let [Packet::Connect(_), Packet::Disconnect(_)] = packets.as_slice() else {
+            panic!("Expected CONNECT and DISCONNECT, got {packets:?}");
+        };
+    }
+
+    #[test]
+    fn test_check_refused() {
+        let connack = ConnAck::builder()
+            .return_code(ReturnCode::ConnectionRefusedNotAuthorized)
+            .build();
+        let (port, _server) = serve(Some(connack));
+        let report = check(("127.0.0.1", port), Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            report.return_code,
+            ReturnCode::ConnectionRefusedNotAuthorized
+        );
+    }
+
+    #[test]
+    fn test_check_timeout() {
+        let (port, _server) = serve(None);
+        let error = check(("127.0.0.1", port), Duration::from_millis(100)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,77 @@
+//! Interoperate with brokers that violate the specification in known ways.
+//!
+//! By default, a [`MqttBinding`](crate::MqttBinding) rejects packets that violate
+//! MQTT 3.1.1. [`Quirks`] relax specific validations, instead of all of them.
+//!
+//! ```
+//! use tjiftjaf::{quirks::Quirks, Connect, MqttBinding};
+//!
+//! let mut binding = MqttBinding::from_connect(Connect::builder().build());
+//! binding.set_quirks(Quirks::RESERVED_HEADER_FLAGS | Quirks::RESERVED_CONNACK_FLAGS);
+//! ```
+//!
+//! Packets that arrive before the CONNACK, like a SUBACK that overtakes it,
+//! are accepted regardless of the quirks.
+use crate::{packet::HeaderFlags, PacketType};
+use core::ops::{BitOr, BitOrAssign};
+
+/// A set of deviations from the specification that a binding tolerates.
+///
+/// Combine them with `|`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks(u8);
+
+impl Quirks {
+    /// Tolerate no deviations. That's the default.
+    pub const NONE: Quirks = Quirks(0);
+
+    /// Ignore the reserved flags in the fixed header of inbound packets. For example,
+    /// a PUBACK with flags `0b0010`. See [MQTT-2.2.2-2].
+    pub const RESERVED_HEADER_FLAGS: Quirks = Quirks(1);
+
+    /// Ignore the reserved bits of the connect acknowledge flags of a CONNACK.
+    /// See [MQTT-3.2.2].
+    pub const RESERVED_CONNACK_FLAGS: Quirks = Quirks(2);
+
+    /// Whether all quirks of `other` are part of this set.
+    pub fn contains(self, other: Quirks) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // Clear the reserved bits of an inbound frame that these quirks tolerate,
+    // so the frame decodes.
+    pub(crate) fn apply(self, frame: &mut [u8]) {
+        let Some(packet_type) = frame
+            .first()
+            .and_then(|byte| PacketType::try_from(byte).ok())
+        else {
+            return;
+        };
+
+        if self.contains(Quirks::RESERVED_HEADER_FLAGS) {
+            let flags =
+                HeaderFlags::reserved(packet_type).map_or(frame[0] & 0b1111, HeaderFlags::bits);
+            frame[0] = frame[0] & 0b1111_0000 | flags;
+        }
+
+        if self.contains(Quirks::RESERVED_CONNACK_FLAGS) && packet_type == PacketType::ConnAck {
+            if let Some(flags) = frame.get_mut(2) {
+                *flags &= 1;
+            }
+        }
+    }
+}
+
+impl BitOr for Quirks {
+    type Output = Quirks;
+
+    fn bitor(self, rhs: Quirks) -> Quirks {
+        Quirks(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Quirks {
+    fn bitor_assign(&mut self, rhs: Quirks) {
+        self.0 |= rhs.0;
+    }
+}
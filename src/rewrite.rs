@@ -0,0 +1,350 @@
+//! Rewrite topics between the application and the server.
+use crate::{EncodingError, InvalidTopicFilter, Packet, Publish, Subscribe, Unsubscribe};
+
+/// Rewrite the topics of packets, by prefix or by regular expression.
+///
+/// Use it to move an application to a different topic hierarchy, for example when migrating to a
+/// new broker, without changing every call site. Outbound topics and topic filters are rewritten
+/// before they're sent to the server. The topics of inbound publications are rewritten the other
+/// way around. So the application keeps using the old topics.
+///
+/// ```
+/// use tjiftjaf::TopicRewrite;
+///
+/// let rewrite = TopicRewrite::prefix("old/", "new/");
+/// assert_eq!(rewrite.outbound("old/sensor/1"), Some("new/sensor/1".to_string()));
+/// assert_eq!(rewrite.inbound("new/sensor/1"), Some("old/sensor/1".to_string()));
+/// assert_eq!(rewrite.outbound("other/sensor/1"), None);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicRewrite {
+    outbound: Replace,
+    inbound: Option<Replace>,
+}
+
+impl TopicRewrite {
+    /// Create a rule that replaces the prefix `from` with `to`.
+    pub fn prefix(from: impl Into<String>, to: impl Into<String>) -> Self {
+        let (from, to) = (from.into(), to.into());
+        Self {
+            outbound: Replace::Prefix {
+                from: from.clone(),
+                to: to.clone(),
+            },
+            inbound: Some(Replace::Prefix { from: to, to: from }),
+        }
+    }
+
+    /// Create a rule that replaces the first match of the regular expression `pattern` with
+    /// `replacement`. The replacement refers to capture groups with `$1` or `$name`, see
+    /// [`regex::Regex::replace()`].
+    ///
+    /// A regular expression can't be reversed. So this rule only rewrites outbound topics.
+    /// Use [`TopicRewrite::with_inbound_regex()`] to rewrite inbound topics as well.
+    ///
+    /// ```
+    /// use tjiftjaf::TopicRewrite;
+    ///
+    /// let rewrite = TopicRewrite::regex("^old/([^/]+)/(.*)$", "new/$2/$1")
+    ///     .unwrap()
+    ///     .with_inbound_regex("^new/([^/]+)/(.*)$", "old/$2/$1")
+    ///     .unwrap();
+    /// assert_eq!(rewrite.outbound("old/sensor/1"), Some("new/1/sensor".to_string()));
+    /// assert_eq!(rewrite.inbound("new/1/sensor"), Some("old/sensor/1".to_string()));
+    /// ```
+    #[cfg(feature = "regex")]
+    pub fn regex(pattern: &str, replacement: impl Into<String>) -> Result<Self, regex::Error> {
+        Ok(Self {
+            outbound: Replace::regex(pattern, replacement)?,
+            inbound: None,
+        })
+    }
+
+    /// Rewrite inbound topics with the regular expression `pattern`, instead of the inverse of
+    /// this rule. See [`TopicRewrite::regex()`].
+    #[cfg(feature = "regex")]
+    pub fn with_inbound_regex(
+        mut self,
+        pattern: &str,
+        replacement: impl Into<String>,
+    ) -> Result<Self, regex::Error> {
+        self.inbound = Some(Replace::regex(pattern, replacement)?);
+        Ok(self)
+    }
+
+    /// Rewrite a topic, or topic filter, sent by the application.
+    /// Returns `None` if the rule doesn't apply.
+    pub fn outbound(&self, topic: &str) -> Option<String> {
+        self.outbound.apply(topic)
+    }
+
+    /// Rewrite a topic received from the server.
+    /// Returns `None` if the rule doesn't apply.
+    pub fn inbound(&self, topic: &str) -> Option<String> {
+        self.inbound.as_ref()?.apply(topic)
+    }
+}
+
+// A rewrite of a topic in one direction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Replace {
+    Prefix {
+        from: String,
+        to: String,
+    },
+    #[cfg(feature = "regex")]
+    Regex {
+        pattern: Pattern,
+        replacement: String,
+    },
+}
+
+impl Replace {
+    #[cfg(feature = "regex")]
+    fn regex(pattern: &str, replacement: impl Into<String>) -> Result<Self, regex::Error> {
+        Ok(Self::Regex {
+            pattern: Pattern(regex::Regex::new(pattern)?),
+            replacement: replacement.into(),
+        })
+    }
+
+    fn apply(&self, topic: &str) -> Option<String> {
+        match self {
+            Self::Prefix { from, to } => topic
+                .strip_prefix(from.as_str())
+                .map(|remainder| format!("{to}{remainder}")),
+            #[cfg(feature = "regex")]
+            Self::Regex {
+                pattern,
+                replacement,
+            } => pattern
+                .0
+                .is_match(topic)
+                .then(|| pattern.0.replace(topic, replacement.as_str()).into_owned()),
+        }
+    }
+}
+
+// A `regex::Regex` doesn't implement `PartialEq`. Two patterns are equal if their source is.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+struct Pattern(regex::Regex);
+
+#[cfg(feature = "regex")]
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Eq for Pattern {}
+
+// Apply the first matching rule. Returns `None` if no rule applies.
+fn rewrite(
+    rules: &[TopicRewrite],
+    topic: &str,
+    f: impl Fn(&TopicRewrite, &str) -> Option<String>,
+) -> Option<String> {
+    rules.iter().find_map(|rule| f(rule, topic))
+}
+
+// Rewrite the topics of a packet that is about to be sent to the server.
+// Returns `None` if no rule applies. Fails if the rewritten packet can't be encoded,
+// for example because a topic became too long.
+pub(crate) fn outbound(
+    rules: &[TopicRewrite],
+    packet: &Packet,
+) -> Result<Option<Packet>, EncodingError> {
+    if rules.is_empty() {
+        return Ok(None);
+    }
+
+    let packet = match packet {
+        Packet::Publish(publish) => match rewrite(rules, publish.topic(), TopicRewrite::outbound) {
+            Some(topic) => with_topic(publish, topic)?.into(),
+            None => return Ok(None),
+        },
+        Packet::Subscribe(subscribe) => {
+            let mut topics = subscribe.topics().map(|(topic, qos)| {
+                let topic = rewrite(rules, topic, TopicRewrite::outbound)
+                    .unwrap_or_else(|| topic.to_string());
+                (topic, qos)
+            });
+
+            let (topic, qos) = topics.next().ok_or_else(|| {
+                without_topics("a SUBSCRIBE must contain at least one topic filter")
+            })?;
+            let mut builder =
+                Subscribe::builder(topic, qos).packet_identifier(subscribe.packet_identifier());
+            for (topic, qos) in topics {
+                builder = builder.add_topic(topic, qos);
+            }
+            builder.try_build()?.into()
+        }
+        Packet::Unsubscribe(unsubscribe) => {
+            let mut topics = unsubscribe.topics().map(|topic| {
+                rewrite(rules, topic, TopicRewrite::outbound).unwrap_or_else(|| topic.to_string())
+            });
+
+            let topic = topics.next().ok_or_else(|| {
+                without_topics("an UNSUBSCRIBE must contain at least one topic filter")
+            })?;
+            let mut builder =
+                Unsubscribe::builder(topic).packet_identifier(unsubscribe.packet_identifier());
+            for topic in topics {
+                builder = builder.add_topic(topic);
+            }
+            builder.try_build()?.into()
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(packet))
+}
+
+// Rewrite the topic of a publication received from the server.
+// Returns `None` if no rule applies.
+pub(crate) fn inbound(
+    rules: &[TopicRewrite],
+    packet: &Packet,
+) -> Result<Option<Packet>, EncodingError> {
+    let Packet::Publish(publish) = packet else {
+        return Ok(None);
+    };
+
+    match rewrite(rules, publish.topic(), TopicRewrite::inbound) {
+        Some(topic) => Ok(Some(with_topic(publish, topic)?.into())),
+        None => Ok(None),
+    }
+}
+
+// [MQTT-3.8.3-3] and [MQTT-3.10.3-2] A SUBSCRIBE and an UNSUBSCRIBE contain at least one
+// topic filter. The decoder rejects packets without, but fail instead of panicking anyway.
+fn without_topics(reason: &'static str) -> InvalidTopicFilter {
+    InvalidTopicFilter {
+        filter: String::new(),
+        reason,
+    }
+}
+
+// Copy `publish`, but with a different topic.
+fn with_topic(publish: &Publish, topic: String) -> Result<Publish, EncodingError> {
+    let mut builder = Publish::builder(topic, publish.payload())
+        .qos(publish.qos())
+        .retain(publish.retain())
+        .duplicate(publish.duplicate());
+    if let Some(packet_identifier) = publish.packet_identifier() {
+        builder = builder.packet_identifier(packet_identifier);
+    }
+    builder.try_build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{QoS, Unsubscribe};
+
+    #[test]
+    fn test_outbound() {
+        let rules = [
+            TopicRewrite::prefix("old/", "new/"),
+            TopicRewrite::prefix("old/sensor/", "unreachable/"),
+        ];
+
+        let publish = Publish::builder("old/sensor/1", "21.3")
+            .qos(QoS::AtLeastOnceDelivery)
+            .packet_identifier(1)
+            .build();
+        let Packet::Publish(rewritten) = outbound(&rules, &publish.into()).unwrap().unwrap() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(rewritten.topic(), "new/sensor/1");
+        assert_eq!(rewritten.payload(), b"21.3");
+        assert_eq!(rewritten.packet_identifier(), Some(1));
+
+        let subscribe = Subscribe::builder("old/#", QoS::AtMostOnceDelivery)
+            .add_topic("other/#", QoS::AtLeastOnceDelivery)
+            .packet_identifier(2)
+            .build();
+        let Packet::Subscribe(rewritten) = outbound(&rules, &subscribe.into()).unwrap().unwrap()
+        else {
+            panic!("Expected a SUBSCRIBE");
+        };
+        assert_eq!(rewritten.packet_identifier(), 2);
+        assert_eq!(
+            rewritten.topics().collect::<Vec<_>>(),
+            vec![
+                ("new/#", QoS::AtMostOnceDelivery),
+                ("other/#", QoS::AtLeastOnceDelivery)
+            ]
+        );
+
+        let unsubscribe = Unsubscribe::builder("old/#").packet_identifier(3).build();
+        let Packet::Unsubscribe(rewritten) =
+            outbound(&rules, &unsubscribe.into()).unwrap().unwrap()
+        else {
+            panic!("Expected an UNSUBSCRIBE");
+        };
+        assert_eq!(rewritten.packet_identifier(), 3);
+        assert_eq!(rewritten.topics().collect::<Vec<_>>(), vec!["new/#"]);
+    }
+
+    #[test]
+    fn test_inbound() {
+        let rules = [TopicRewrite::prefix("old/", "new/")];
+
+        let Some(Packet::Publish(rewritten)) =
+            inbound(&rules, &crate::publish("new/1", "a").into()).unwrap()
+        else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(rewritten.topic(), "old/1");
+
+        let rewritten = inbound(&rules, &crate::publish("other/1", "a").into()).unwrap();
+        assert!(rewritten.is_none());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex() {
+        let rules = [TopicRewrite::regex("^old/(.*)$", "new/$1")
+            .unwrap()
+            .with_inbound_regex("^new/(.*)$", "old/$1")
+            .unwrap()];
+
+        let Some(Packet::Publish(rewritten)) =
+            outbound(&rules, &crate::publish("old/sensor/1", "a").into()).unwrap()
+        else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(rewritten.topic(), "new/sensor/1");
+
+        let Some(Packet::Publish(rewritten)) =
+            inbound(&rules, &crate::publish("new/sensor/1", "a").into()).unwrap()
+        else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(rewritten.topic(), "old/sensor/1");
+
+        let rewritten = outbound(&rules, &crate::publish("older/sensor/1", "a").into()).unwrap();
+        assert!(rewritten.is_none());
+
+        // Without an inbound regex, inbound topics are left alone.
+        let rule = TopicRewrite::regex("^old/(.*)$", "new/$1").unwrap();
+        assert_eq!(rule.inbound("new/sensor/1"), None);
+
+        assert!(TopicRewrite::regex("(", "new/").is_err());
+    }
+
+    // Verify that a rewrite that makes a topic too long fails, instead of panicking.
+    #[test]
+    fn test_rewrite_too_long() {
+        let rules = [TopicRewrite::prefix("old/", "a".repeat(65_535))];
+
+        let error = outbound(&rules, &crate::publish("old/1", "a").into()).unwrap_err();
+        let EncodingError::FieldTooLong(error) = error else {
+            panic!("Expected a `FieldTooLong`");
+        };
+        assert_eq!(error.field(), "topic");
+    }
+}
@@ -0,0 +1,131 @@
+//! Wrappers for credentials that shouldn't leak into logs or linger in memory.
+//!
+//! [`SecretString`] and [`SecretBytes`] redact their contents in `Debug` and
+//! overwrite their memory with zeroes when they're dropped. The
+//! [`connect::Builder`](crate::packet::connect::Builder) stores the username
+//! and password with them. A [`Connect`](crate::Connect) with credentials
+//! zeroizes its encoded bytes when it's dropped, too.
+//!
+//! ```
+//! use tjiftjaf::secret::SecretString;
+//!
+//! let password = SecretString::from("prime");
+//! assert_eq!(format!("{password:?}"), "SecretString(<redacted>)");
+//! assert_eq!(password.expose(), "prime");
+//! ```
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+/// A `String` that is redacted in `Debug` output and zeroized on drop.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Access the secret.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(<redacted>)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // SAFETY: zeroes are valid UTF-8.
+        zeroize(unsafe { self.0.as_mut_vec() });
+    }
+}
+
+/// Bytes that are redacted in `Debug` output and zeroized on drop.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    /// Access the secret.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes(<redacted>)")
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+// Overwrite the full capacity of `buffer` with zeroes.
+// The volatile writes prevent the compiler from optimizing them away,
+// even though the memory is freed right after.
+pub(crate) fn zeroize(buffer: &mut Vec<u8>) {
+    buffer.clear();
+    for byte in buffer.spare_capacity_mut() {
+        // SAFETY: `byte` is a valid, aligned pointer into the allocation of `buffer`.
+        unsafe { core::ptr::write_volatile(byte.as_mut_ptr(), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_zeroize() {
+        let mut buffer = b"prime".to_vec();
+        zeroize(&mut buffer);
+        assert!(buffer.is_empty());
+
+        // SAFETY: the capacity of `buffer` is at least 5 and all bytes were initialized.
+        let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr(), 5) };
+        assert_eq!(bytes, [0; 5]);
+    }
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let username = SecretString::from("optimus");
+        let password = SecretBytes::from(b"prime".as_slice());
+
+        assert!(!format!("{username:?}").contains("optimus"));
+        assert!(!format!("{password:?}").contains("prime"));
+    }
+}
@@ -0,0 +1,454 @@
+//! Persist a [`Session`] with a [`SessionStore`].
+//!
+//! A client that connects with the clean session flag set to 0 must retransmit
+//! unacknowledged publications when it reconnects. A store keeps the session
+//! around, even if the application crashes or restarts.
+//!
+//! ```no_run
+//! use tjiftjaf::{store::FileStore, Connect, MqttBinding, SessionStore};
+//!
+//! let mut store = FileStore::new("/var/lib/sensor/session.mqtt");
+//! let mut binding = match store.load().unwrap() {
+//!     Some(session) => MqttBinding::from_session(session),
+//!     None => MqttBinding::from_connect(Connect::builder().client_id("sensor").build()),
+//! };
+//! binding.set_session_store(store);
+//! ```
+use crate::{Connect, Packet, PubRel, Session};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Storage for a [`Session`].
+///
+/// See [`MqttBinding::set_session_store()`](crate::MqttBinding::set_session_store()).
+pub trait SessionStore {
+    /// Save `session`, replacing the previous session.
+    fn save(&mut self, session: &Session) -> Result<(), Error>;
+
+    /// Load the session saved last. Returns `None` if no session was saved.
+    fn load(&mut self) -> Result<Option<Session>, Error>;
+}
+
+/// A [`SessionStore`] that keeps the session in memory.
+///
+/// It survives reconnects, but not a restart of the application.
+/// Clones share the same session. So keep a clone to load the session
+/// after the binding is gone.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn save(&mut self, session: &Session) -> Result<(), Error> {
+        *self
+            .session
+            .lock()
+            .map_err(|_| Error::other("lock poisoned"))? = Some(session.clone());
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Option<Session>, Error> {
+        Ok(self
+            .session
+            .lock()
+            .map_err(|_| Error::other("lock poisoned"))?
+            .clone())
+    }
+}
+
+/// A [`SessionStore`] that writes the session to a file.
+///
+/// The session is encoded with [`Session::into_bytes()`]. It's first written to a temporary
+/// file next to `path`, which then replaces `path`. So a crash while saving doesn't corrupt it.
+///
+/// With the feature `async`, the file is written on the thread pool of
+/// [blocking](https://docs.rs/blocking), so saving doesn't block the executor. Sessions
+/// saved in quick succession are coalesced, and [`SessionStore::load()`] returns the
+/// session saved last, even if it's not written yet. A write that fails is reported
+/// by the next call to [`SessionStore::save()`]. Clones share the writes that are pending.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    #[cfg(feature = "async")]
+    pending: Arc<Mutex<Pending>>,
+}
+
+// The session that waits to be written by the thread pool.
+#[cfg(feature = "async")]
+#[derive(Debug, Default)]
+struct Pending {
+    // The encoded session saved last, until it's written.
+    bytes: Option<Arc<Vec<u8>>>,
+
+    // Whether a task of the thread pool is writing.
+    writing: bool,
+
+    // The error of the last write that failed.
+    error: Option<Error>,
+}
+
+impl FileStore {
+    /// Store the session at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            #[cfg(feature = "async")]
+            pending: Arc::default(),
+        }
+    }
+
+    // Wait until the thread pool wrote the session saved last.
+    #[cfg(all(test, feature = "async"))]
+    fn wait_written(&self) {
+        while self.pending.lock().unwrap().writing {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+}
+
+// Write `bytes` to `path`, via a temporary file that's synced before it replaces `path`.
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+// Write the pending session until no newer one was saved in the meantime.
+#[cfg(feature = "async")]
+fn write_pending(path: &Path, pending: &Mutex<Pending>) {
+    let lock = || {
+        pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    };
+    loop {
+        let Some(bytes) = lock().bytes.clone() else {
+            lock().writing = false;
+            return;
+        };
+        let result = write_file(path, &bytes);
+
+        let mut pending = lock();
+        if let Err(error) = result {
+            pending.error = Some(error);
+        }
+        if pending
+            .bytes
+            .as_ref()
+            .is_some_and(|latest| Arc::ptr_eq(latest, &bytes))
+        {
+            pending.bytes = None;
+            pending.writing = false;
+            return;
+        }
+    }
+}
+
+impl SessionStore for FileStore {
+    #[cfg(not(feature = "async"))]
+    fn save(&mut self, session: &Session) -> Result<(), Error> {
+        write_file(&self.path, &session.clone().into_bytes())
+    }
+
+    #[cfg(feature = "async")]
+    fn save(&mut self, session: &Session) -> Result<(), Error> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| Error::other("lock poisoned"))?;
+        pending.bytes = Some(Arc::new(session.clone().into_bytes()));
+        if !pending.writing {
+            pending.writing = true;
+            let (path, shared) = (self.path.clone(), self.pending.clone());
+            blocking::unblock(move || write_pending(&path, &shared)).detach();
+        }
+        pending.error.take().map_or(Ok(()), Err)
+    }
+
+    fn load(&mut self) -> Result<Option<Session>, Error> {
+        // A session that's not written yet is newer than the file.
+        #[cfg(feature = "async")]
+        if let Some(bytes) = self
+            .pending
+            .lock()
+            .map_err(|_| Error::other("lock poisoned"))?
+            .bytes
+            .clone()
+        {
+            return Session::try_from(bytes.to_vec())
+                .map(Some)
+                .map_err(|error| Error::new(ErrorKind::InvalidData, error));
+        }
+
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        Session::try_from(bytes)
+            .map(Some)
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))
+    }
+}
+
+/// A [`SessionStore`] that uses the persistence directory layout of the
+/// [Eclipse Paho C](https://github.com/eclipse-paho/paho.mqtt.c) client.
+///
+/// Use it to migrate a durable client from Paho to tjiftjaf without losing the publications
+/// that are in flight. Point it to the directory of the client, which Paho names
+/// `{client id}-{host}-{port}`. Every message is stored in a separate file, named after
+/// its packet identifier and containing the encoded packet:
+///
+/// * `s-{id}.msg`: an outbound publication with QoS 1 or 2.
+/// * `sc-{id}.msg`: the [`PubRel`] of an outbound QoS 2 publication that the broker received.
+/// * `r-{id}.msg`: an inbound QoS 2 publication that is not yet released. It's read, but never
+///   written, because tjiftjaf only keeps the packet identifier of these publications.
+///   It's removed once the publication is released.
+///
+/// Paho doesn't store the [`Connect`] or the subscriptions. So the `Connect` is passed to
+/// [`PahoStore::new()`] and a loaded [`Session`] has no subscriptions. Publications with
+/// QoS 0 don't have a packet identifier and are not stored.
+///
+/// ```no_run
+/// use tjiftjaf::{store::PahoStore, Connect, MqttBinding, SessionStore};
+///
+/// let connect = Connect::builder().client_id("sensor").build();
+/// let mut store = PahoStore::new("/var/lib/sensor/sensor-broker-1883", connect.clone());
+/// let mut binding = match store.load().unwrap() {
+///     Some(session) => MqttBinding::from_session(session),
+///     None => MqttBinding::from_connect(connect),
+/// };
+/// binding.set_session_store(store);
+/// ```
+#[derive(Clone, Debug)]
+pub struct PahoStore {
+    directory: PathBuf,
+    connect: Connect,
+}
+
+impl PahoStore {
+    /// Store the session in `directory`. `connect` is used to resume a loaded session.
+    pub fn new(directory: impl Into<PathBuf>, connect: Connect) -> Self {
+        Self {
+            directory: directory.into(),
+            connect,
+        }
+    }
+
+    // Write `bytes` to the file `name`, via a temporary file.
+    fn write(&self, name: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        write_file(&self.directory.join(name), &bytes)
+    }
+}
+
+// Split the file name of a message, like `sc-12.msg`, in its prefix and packet identifier.
+fn parse_message_name(path: &Path) -> Option<(&str, u16)> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".msg")?;
+    let (prefix, packet_identifier) = name.split_once('-')?;
+    Some((prefix, packet_identifier.parse().ok()?))
+}
+
+impl SessionStore for PahoStore {
+    fn save(&mut self, session: &Session) -> Result<(), Error> {
+        fs::create_dir_all(&self.directory)?;
+
+        let mut names = BTreeSet::new();
+        for publish in &session.publications {
+            let Some(packet_identifier) = publish.packet_identifier() else {
+                continue;
+            };
+            let name = format!("s-{packet_identifier}.msg");
+            self.write(&name, Packet::from(publish.clone()).into_bytes())?;
+            names.insert(name);
+        }
+        for packet_identifier in &session.released {
+            let name = format!("sc-{packet_identifier}.msg");
+            self.write(
+                &name,
+                Packet::from(PubRel::new(*packet_identifier)).into_bytes(),
+            )?;
+            names.insert(name);
+        }
+
+        // Remove the messages that are no longer part of the session.
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            let Some((prefix, packet_identifier)) = parse_message_name(&path) else {
+                continue;
+            };
+            let name = path.file_name().and_then(|name| name.to_str());
+            let obsolete = match prefix {
+                "s" | "sc" => !name.is_some_and(|name| names.contains(name)),
+                "r" => !session.received.contains(&packet_identifier),
+                _ => false,
+            };
+            if obsolete {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Option<Session>, Error> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let mut publications = vec![];
+        let mut released = vec![];
+        let mut received = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            match parse_message_name(&path) {
+                Some(("s", _)) => {
+                    let packet = Packet::try_from(fs::read(&path)?)
+                        .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+                    let Packet::Publish(publish) = packet else {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("{} doesn't contain a PUBLISH", path.display()),
+                        ));
+                    };
+                    publications.push(publish);
+                }
+                Some(("sc", packet_identifier)) => released.push(packet_identifier),
+                Some(("r", packet_identifier)) => received.push(packet_identifier),
+                _ => {}
+            }
+        }
+
+        if publications.is_empty() && released.is_empty() && received.is_empty() {
+            return Ok(None);
+        }
+
+        // Retransmit the publications in the order of their packet identifiers.
+        publications.sort_by_key(|publish| publish.packet_identifier());
+        released.sort();
+        received.sort();
+        Ok(Some(Session {
+            connect: self.connect.clone(),
+            subscriptions: vec![],
+            publications,
+            released,
+            received,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ConnAck, Connect, MqttBinding, Packet, PubAck, Publish, QoS};
+    use std::time::Instant;
+
+    // Feed `packet` to `binding`, like an event loop does.
+    fn feed(binding: &mut MqttBinding, packet: Packet) {
+        let mut bytes = packet.into_bytes();
+        while !bytes.is_empty() {
+            let length = binding.get_read_buffer().len().min(bytes.len());
+            let buffer: Vec<u8> = bytes.drain(..length).collect();
+            binding.try_decode(buffer, Instant::now());
+        }
+    }
+
+    // Queue a publication with QoS 1 and verify that the store captures it,
+    // until the server acknowledges it.
+    fn verify(mut store: impl SessionStore + Clone + Send + 'static) {
+        assert!(store.load().unwrap().is_none());
+
+        let mut binding = MqttBinding::from_connect(Connect::builder().client_id("store").build());
+        binding.set_session_store(store.clone());
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(&mut binding, ConnAck::builder().build().into());
+
+        binding.send(
+            Publish::builder("sensor/1", "26.1")
+                .qos(QoS::AtLeastOnceDelivery)
+                .packet_identifier(1)
+                .build_packet(),
+        );
+        let session = store.load().unwrap().unwrap();
+        assert_eq!(session.connect().client_id(), "store");
+        assert_eq!(session.publications().len(), 1);
+
+        // The publication survives a restart.
+        binding.poll_transmits(Instant::now()).unwrap();
+        let resumed = MqttBinding::from_session(store.load().unwrap().unwrap());
+        assert_eq!(resumed.session().publications().len(), 1);
+
+        feed(&mut binding, PubAck::new(1).into());
+        assert!(store
+            .load()
+            .unwrap()
+            .is_none_or(|session| session.publications().is_empty()));
+    }
+
+    #[test]
+    fn test_memory_store() {
+        verify(MemoryStore::default());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("tjiftjaf-{}.session", std::process::id()));
+        let store = FileStore::new(&path);
+        verify(store.clone());
+        #[cfg(feature = "async")]
+        store.wait_written();
+
+        let session = FileStore::new(&path).load().unwrap().unwrap();
+        assert!(session.publications().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_paho_store() {
+        let directory = std::env::temp_dir().join(format!("tjiftjaf-{}-paho", std::process::id()));
+        let connect = Connect::builder().client_id("store").build();
+        verify(PahoStore::new(&directory, connect.clone()));
+
+        // A directory written by Paho, with a publication that still waits for its
+        // PUBACK, and an inbound QoS 2 publication that is not yet released.
+        let publish = Publish::builder("sensor/2", "26.2")
+            .qos(QoS::AtLeastOnceDelivery)
+            .packet_identifier(7)
+            .build();
+        fs::write(
+            directory.join("s-7.msg"),
+            Packet::from(publish.clone()).into_bytes(),
+        )
+        .unwrap();
+        fs::write(directory.join("r-9.msg"), []).unwrap();
+
+        let mut store = PahoStore::new(&directory, connect);
+        let session = store.load().unwrap().unwrap();
+        assert_eq!(session.publications(), &[publish]);
+        assert_eq!(session.received(), &[9]);
+
+        // Once the publication is released, its message is removed.
+        let mut binding = MqttBinding::from_session(session);
+        binding.poll_transmits(Instant::now()).unwrap();
+        feed(
+            &mut binding,
+            ConnAck::builder().session_present().build().into(),
+        );
+        feed(&mut binding, PubRel::new(9).into());
+        store.save(&binding.session()).unwrap();
+        assert!(directory.join("s-7.msg").exists());
+        assert!(!directory.join("r-9.msg").exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
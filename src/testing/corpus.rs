@@ -0,0 +1,189 @@
+// A corpus of packets and frames for property tests and fuzzing.
+use crate::{
+    packet::{connack, suback},
+    ConnAck, Connect, Disconnect, Packet, PingReq, PingResp, PubAck, PubComp, PubRec, PubRel,
+    Publish, QoS, SubAck, Subscribe, UnsubAck, Unsubscribe,
+};
+
+const QOS: [QoS; 3] = [
+    QoS::AtMostOnceDelivery,
+    QoS::AtLeastOnceDelivery,
+    QoS::ExactlyOnceDelivery,
+];
+
+/// Valid packets of every [`PacketType`](crate::PacketType), covering every combination
+/// of flags.
+pub fn valid_packets() -> Vec<Packet> {
+    let mut packets: Vec<Packet> = vec![
+        Connect::builder().build().into(),
+        Connect::builder()
+            .client_id("tjiftjaf")
+            .keep_alive(60)
+            .clean_session()
+            .build()
+            .into(),
+        Connect::builder().username("optimus").build().into(),
+        Connect::builder()
+            .username("optimus")
+            .password("prime")
+            .build()
+            .into(),
+        ConnAck::builder().build().into(),
+        ConnAck::builder().session_present().build().into(),
+        ConnAck::builder()
+            .return_code(connack::ReturnCode::ConnectionRefusedNotAuthorized)
+            .build()
+            .into(),
+        PubAck::new(1).into(),
+        PubRec::new(2).into(),
+        PubRel::new(3).into(),
+        PubComp::new(u16::MAX).into(),
+        SubAck::builder(5, QoS::AtLeastOnceDelivery)
+            .add_return_code(suback::ReturnCode::Failure)
+            .build()
+            .into(),
+        Unsubscribe::builder("sensor/#")
+            .add_topic("$SYS/broker/uptime")
+            .build()
+            .into(),
+        UnsubAck::new(6).into(),
+        PingReq.into(),
+        PingResp.into(),
+        Disconnect.into(),
+    ];
+
+    for qos in QOS {
+        packets.push(
+            Connect::builder()
+                .client_id("tjiftjaf")
+                .will("status", "offline")
+                .will_qos(qos)
+                .retain_will()
+                .build()
+                .into(),
+        );
+        packets.push(
+            Subscribe::builder("sensor/+/temperature", qos)
+                .add_topic("sensor/#", qos)
+                .build()
+                .into(),
+        );
+
+        for (retain, duplicate) in [(false, false), (true, false), (false, true), (true, true)] {
+            // The duplicate flag must be 0 for publications with QoS 0.
+            if qos == QoS::AtMostOnceDelivery && duplicate {
+                continue;
+            }
+
+            packets.push(
+                Publish::builder("sensor/1/temperature", "26.1")
+                    .qos(qos)
+                    .retain(retain)
+                    .duplicate(duplicate)
+                    .packet_identifier(7)
+                    .build()
+                    .into(),
+            );
+        }
+    }
+
+    // A publication with an empty payload, and one whose remaining length takes 2 bytes.
+    packets.push(Publish::builder("sensor/1", "").build().into());
+    packets.push(Publish::builder("sensor/1", vec![0; 256]).build().into());
+    packets
+}
+
+/// Frames that fail to decode as a [`Packet`].
+pub fn invalid_frames() -> Vec<Vec<u8>> {
+    vec![
+        // Packet type 0 is reserved.
+        vec![0b0000_0000, 0],
+        // Packet type 15 is reserved.
+        vec![0b1111_0000, 0],
+        // A CONNACK with the reserved flags set.
+        vec![0b0010_0001, 2, 0, 0],
+        // A PUBREL must set its reserved flags to 0b0010.
+        vec![0b0110_0000, 2, 0, 1],
+        // A SUBACK with an illegal return code.
+        vec![0b1001_0000, 3, 0, 1, 5],
+        // A remaining length that is encoded in more than 4 bytes.
+        vec![0b0011_0000, 0xFF, 0xFF, 0xFF, 0xFF],
+        // A CONNECT without a variable header.
+        vec![0b0001_0000, 0],
+        // A CONNECT with a password, but without a username.
+        connect_frame(0b0100_0010, &[0, 1, b'a', 0, 1, b'p']),
+        // A CONNECT with an empty client id, that doesn't request a clean session.
+        connect_frame(0, &[0, 0]),
+        // A CONNECT with a will QoS of 3.
+        connect_frame(0b0001_1110, &[0, 1, b'a']),
+        // A PUBLISH with QoS 1, but without a packet identifier.
+        vec![0b0011_0010, 2, 0, 0],
+        // A SUBACK, SUBSCRIBE and UNSUBSCRIBE without a packet identifier.
+        vec![0b1001_0000, 0],
+        vec![0b1000_0010, 0],
+        vec![0b1010_0010, 0],
+        // A SUBSCRIBE with a topic, but without a QoS.
+        vec![0b1000_0010, 5, 0, 1, 0, 1, b'a'],
+    ]
+}
+
+// A CONNECT frame of MQTT 3.1.1 with the given `flags`, followed by `payload`.
+fn connect_frame(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0b0001_0000, 10 + payload.len() as u8, 0, 4];
+    frame.extend_from_slice(b"MQTT");
+    frame.extend_from_slice(&[4, flags, 0, 60]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_valid_packets() {
+        for packet in valid_packets() {
+            let bytes = packet.into_bytes();
+            assert_eq!(Packet::try_from(bytes.clone()).unwrap().into_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        for frame in invalid_frames() {
+            assert!(Packet::try_from(frame.clone()).is_err(), "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_packets_round_trip() {
+        use arbitrary::{Arbitrary, Unstructured};
+        use std::collections::HashSet;
+
+        // A deterministic xorshift generator, so failures are reproducible.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut bytes = vec![0u8; 4096];
+        let mut packet_types = HashSet::new();
+
+        for _ in 0..2000 {
+            for byte in bytes.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *byte = state as u8;
+            }
+
+            let mut u = Unstructured::new(&bytes);
+            let Ok(packet) = Packet::arbitrary(&mut u) else {
+                continue;
+            };
+            packet_types.insert(packet.packet_type() as u8);
+
+            let decoded = Packet::try_from(packet.clone().into_bytes())
+                .unwrap_or_else(|error| panic!("{packet:?}: {error:?}"));
+            assert_eq!(decoded, packet);
+        }
+
+        assert_eq!(packet_types.len(), 14);
+    }
+}
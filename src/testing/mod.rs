@@ -0,0 +1,57 @@
+//! Helpers to test code that speaks MQTT.
+//!
+//! With the feature `arbitrary`, the module offers a corpus of packets and frames for
+//! property tests and fuzzing. Downstream crates can use the corpus as seeds for their
+//! own fuzz targets, or to test code that handles [`Packet`](crate::Packet)s.
+//!
+//! ```
+//! # #[cfg(feature = "arbitrary")] {
+//! use tjiftjaf::{testing, Packet};
+//!
+//! for packet in testing::valid_packets() {
+//!     let bytes = packet.into_bytes();
+//!     assert_eq!(Packet::try_from(bytes.clone()).unwrap().into_bytes(), bytes);
+//! }
+//!
+//! for frame in testing::invalid_frames() {
+//!     assert!(Packet::try_from(frame).is_err());
+//! }
+//! # }
+//! ```
+//!
+//! With the feature `test-util`, the module offers a [`Wiretap`]. It sits between
+//! a client and a broker and records the packets they exchange in a [`Transcription`].
+//! Use it to assert how fast a broker deployment acknowledges packets.
+//!
+//! ```no_run
+//! # #[cfg(feature = "test-util")] {
+//! # use async_net::TcpStream;
+//! # use std::time::Duration;
+//! use tjiftjaf::{
+//!     aio::{Client, Emit},
+//!     subscribe, testing::Wiretap, Connect, PacketType,
+//! };
+//! # smol::block_on(async {
+//! let (wiretap, mut transcription) = Wiretap::bind("127.0.0.1:1883".parse().unwrap()).unwrap();
+//! let address = wiretap.local_addr().unwrap();
+//! smol::spawn(wiretap.run()).detach();
+//!
+//! let stream = TcpStream::connect(address).await.unwrap();
+//! let (handle, task) = Client::new(Connect::builder().build(), stream).spawn();
+//! smol::spawn(task).detach();
+//! subscribe("sensor/#").emit(&handle).await.unwrap();
+//!
+//! transcription.assert_ack_within(PacketType::ConnAck, Duration::from_millis(50)).await;
+//! transcription.assert_ack_within(PacketType::SubAck, Duration::from_millis(50)).await;
+//! # });
+//! # }
+//! ```
+#[cfg(feature = "arbitrary")]
+mod corpus;
+#[cfg(feature = "test-util")]
+mod wiretap;
+
+#[cfg(feature = "arbitrary")]
+pub use corpus::{invalid_frames, valid_packets};
+#[cfg(feature = "test-util")]
+pub use wiretap::{Line, Peer, Transcription, Wiretap};
@@ -0,0 +1,222 @@
+// A proxy that records the packets a client and a broker exchange.
+use crate::{packet, Packet, PacketType};
+use async_channel::{Receiver, Sender};
+use async_io::{Async, Timer};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    io::{Error, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+/// A proxy between a client and a broker, that records every packet passing through it
+/// in a [`Transcription`].
+///
+/// The wiretap listens on a random port of the loopback interface. Point the client at
+/// [`Wiretap::local_addr()`], and run the wiretap with [`Wiretap::run()`]. See the
+/// [module](super) for an example.
+pub struct Wiretap {
+    listener: Async<TcpListener>,
+    broker: SocketAddr,
+    sender: Sender<Line>,
+}
+
+impl Wiretap {
+    /// Create a wiretap for the broker at `broker`. The returned [`Transcription`]
+    /// receives the packets once the wiretap runs.
+    pub fn bind(broker: SocketAddr) -> std::io::Result<(Self, Transcription)> {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0))?;
+        let (sender, receiver) = async_channel::unbounded();
+        let wiretap = Self {
+            listener,
+            broker,
+            sender,
+        };
+        let transcription = Transcription {
+            receiver,
+            history: vec![],
+        };
+        Ok((wiretap, transcription))
+    }
+
+    /// The address the client must connect to.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.get_ref().local_addr()
+    }
+
+    /// Accept one client, connect to the broker and forward the packets between the two.
+    /// Returns once either of them closed the connection.
+    pub async fn run(self) -> std::io::Result<()> {
+        let (client, _) = self.listener.accept().await?;
+        let broker = Async::<TcpStream>::connect(self.broker).await?;
+
+        let upstream = forward(&client, &broker, Peer::Client, &self.sender);
+        let downstream = forward(&broker, &client, Peer::Broker, &self.sender);
+        futures_lite::future::or(upstream, downstream).await
+    }
+}
+
+// Forward the packets that `peer` writes to `from`, to `to`. Returns once `from` is closed.
+async fn forward(
+    mut from: &Async<TcpStream>,
+    mut to: &Async<TcpStream>,
+    peer: Peer,
+    sender: &Sender<Line>,
+) -> std::io::Result<()> {
+    let mut frame = vec![];
+    loop {
+        let required = packet::min_bytes_required(&frame)
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+        if required > 0 {
+            let start = frame.len();
+            frame.resize(start + required as usize, 0);
+            match from.read_exact(&mut frame[start..]).await {
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof && start == 0 => {
+                    return Ok(())
+                }
+                result => result?,
+            }
+            continue;
+        }
+
+        let at = Instant::now();
+        to.write_all(&frame).await?;
+        let packet = Packet::try_from(core::mem::take(&mut frame))
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+        // The `Transcription` might be dropped already.
+        _ = sender.try_send(Line { peer, packet, at });
+    }
+}
+
+/// Who sent a packet that passed the [`Wiretap`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peer {
+    /// The client sent the packet to the broker.
+    Client,
+
+    /// The broker sent the packet to the client.
+    Broker,
+}
+
+/// A packet that passed the [`Wiretap`].
+#[derive(Clone, Debug)]
+pub struct Line {
+    /// The sender of the packet.
+    pub peer: Peer,
+
+    /// The packet.
+    pub packet: Packet,
+
+    /// The moment the wiretap received the last byte of the packet.
+    pub at: Instant,
+}
+
+/// The packets that passed a [`Wiretap`], in order of arrival.
+///
+/// Search it with [`Transcription::find()`] and [`Transcription::find_with()`]. Both
+/// consume the packets up to the match, so the next search continues after it.
+pub struct Transcription {
+    receiver: Receiver<Line>,
+
+    // All packets received so far.
+    history: Vec<Line>,
+}
+
+impl Transcription {
+    /// Wait for the next packet of type `packet_type`.
+    pub async fn find(&mut self, packet_type: PacketType) -> Line {
+        self.find_with(|line| line.packet.packet_type() == packet_type)
+            .await
+    }
+
+    /// Wait for the next packet that matches `predicate`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the wiretap stopped before such a packet arrived.
+    pub async fn find_with(&mut self, mut predicate: impl FnMut(&Line) -> bool) -> Line {
+        loop {
+            let line = self
+                .receiver
+                .recv()
+                .await
+                .expect("The wiretap stopped before the packet arrived.");
+            self.history.push(line.clone());
+            if predicate(&line) {
+                return line;
+            }
+        }
+    }
+
+    /// The packets consumed by the searches so far.
+    pub fn history(&self) -> &[Line] {
+        &self.history
+    }
+
+    /// Wait for the next acknowledgement of type `ack` from the broker, and assert that it
+    /// arrived within `within` of the packet it acknowledges. Returns the latency.
+    ///
+    /// `ack` is one of [`PacketType::ConnAck`], [`PacketType::SubAck`],
+    /// [`PacketType::UnsubAck`], [`PacketType::PubAck`], [`PacketType::PubRec`],
+    /// [`PacketType::PubComp`] or [`PacketType::PingResp`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ack` is not an acknowledgement, if no acknowledgement arrives within
+    /// `within`, or if it was too slow.
+    pub async fn assert_ack_within(&mut self, ack: PacketType, within: Duration) -> Duration {
+        let request_type = match ack {
+            PacketType::ConnAck => PacketType::Connect,
+            PacketType::SubAck => PacketType::Subscribe,
+            PacketType::UnsubAck => PacketType::Unsubscribe,
+            PacketType::PubAck | PacketType::PubRec => PacketType::Publish,
+            PacketType::PubComp => PacketType::PubRel,
+            PacketType::PingResp => PacketType::PingReq,
+            other => panic!("{other:?} is not an acknowledgement."),
+        };
+
+        let found =
+            self.find_with(|line| line.peer == Peer::Broker && line.packet.packet_type() == ack);
+        let timeout = async {
+            Timer::after(within).await;
+            panic!("No {ack:?} arrived within {within:?}.");
+        };
+        let acknowledgement = futures_lite::future::or(found, timeout).await;
+
+        let identifier = packet_identifier(&acknowledgement.packet);
+        let request = self
+            .history
+            .iter()
+            .rev()
+            .find(|line| {
+                line.peer == Peer::Client
+                    && line.packet.packet_type() == request_type
+                    && packet_identifier(&line.packet) == identifier
+            })
+            .unwrap_or_else(|| panic!("The {ack:?} doesn't acknowledge any {request_type:?}."));
+
+        let latency = acknowledgement.at.saturating_duration_since(request.at);
+        assert!(
+            latency <= within,
+            "The {ack:?} arrived after {latency:?}, expected it within {within:?}."
+        );
+        latency
+    }
+}
+
+// The packet identifier of `packet`, if it has one.
+fn packet_identifier(packet: &Packet) -> Option<u16> {
+    match packet {
+        Packet::Publish(publish) => publish.packet_identifier(),
+        Packet::PubAck(packet) => Some(packet.packet_identifier()),
+        Packet::PubRec(packet) => Some(packet.packet_identifier()),
+        Packet::PubRel(packet) => Some(packet.packet_identifier()),
+        Packet::PubComp(packet) => Some(packet.packet_identifier()),
+        Packet::Subscribe(packet) => Some(packet.packet_identifier()),
+        Packet::SubAck(packet) => Some(packet.packet_identifier()),
+        Packet::Unsubscribe(packet) => Some(packet.packet_identifier()),
+        Packet::UnsubAck(packet) => Some(packet.packet_identifier()),
+        _ => None,
+    }
+}
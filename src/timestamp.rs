@@ -0,0 +1,28 @@
+// Serialize `Instant`s as wall-clock time, with the feature `serde`.
+//
+// An `Instant` has no meaning outside of the process that measured it, so it's
+// converted to a `SystemTime` by its distance to the current moment.
+use serde::{Serialize, Serializer};
+use std::time::{Instant, SystemTime};
+
+pub(crate) fn serialize<S: Serializer>(
+    instant: &Instant,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    system_time(*instant).serialize(serializer)
+}
+
+pub(crate) fn serialize_option<S: Serializer>(
+    instant: &Option<Instant>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    instant.map(system_time).serialize(serializer)
+}
+
+fn system_time(instant: Instant) -> SystemTime {
+    let (now, wall_clock) = (Instant::now(), SystemTime::now());
+    match instant.checked_duration_since(now) {
+        Some(ahead) => wall_clock + ahead,
+        None => wall_clock - now.duration_since(instant),
+    }
+}
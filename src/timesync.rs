@@ -0,0 +1,305 @@
+//! Estimate the offset between the local clock and the clock of a broker.
+//!
+//! Constrained devices often lack NTP, but they do talk to a broker. Many brokers, or a
+//! service next to them, periodically publish the current time on a topic, in the style of
+//! the `$SYS/broker/...` topics of Mosquitto. An [`Estimator`] turns these publications into an
+//! [`Offset`], which corrects the local clock.
+//!
+//! ```
+//! use std::time::{Duration, SystemTime, UNIX_EPOCH};
+//! use tjiftjaf::timesync::{Estimator, Offset};
+//!
+//! let mut estimator = Estimator::new();
+//! let received_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+//! estimator.observe(b"1700000042", received_at);
+//! assert_eq!(estimator.offset(), Some(Offset::Ahead(Duration::from_secs(42))));
+//! ```
+//!
+//! With the async client, [`track()`] keeps an estimator up to date:
+//!
+//! ```no_run
+//! # use async_net::TcpStream;
+//! # use tjiftjaf::{aio::Client, timesync, Connect};
+//! # smol::block_on(async {
+//! # let stream = TcpStream::connect("localhost:1883").await.unwrap();
+//! # let (handle, task) = Client::new(Connect::builder().build(), stream).spawn();
+//! let (clock, synchronize) = timesync::track(&handle, "$SYS/broker/time").await.unwrap();
+//! smol::spawn(synchronize).detach();
+//!
+//! if let Some(now) = clock.now() {
+//!     println!("The broker thinks it's {now:?}");
+//! }
+//! # });
+//! ```
+//!
+//! # Accuracy
+//!
+//! A publication arrives some time after the broker stamped it. So every observation
+//! underestimates how far the broker is ahead, by the transit time of the publication.
+//! The estimator keeps the largest of the most recent observations, which is the one with
+//! the shortest transit time. Besides the transit time, the resolution of the timestamps
+//! bounds the accuracy: a broker that publishes whole seconds truncates up to a second.
+//!
+//! On a local network, expect an offset that is accurate within the resolution of the
+//! timestamps plus a few milliseconds. The estimate doesn't account for drift of either
+//! clock, so it's only as fresh as the publications on the topic.
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "async")]
+use crate::{aio::ClientHandle, SubscribeError};
+#[cfg(feature = "async")]
+use futures::StreamExt;
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+// The default number of observations an `Estimator` considers.
+const DEFAULT_WINDOW: usize = 8;
+
+/// The offset of the clock of the broker, relative to the local clock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Offset {
+    /// The broker is ahead of the local clock.
+    Ahead(Duration),
+
+    /// The broker is behind the local clock.
+    Behind(Duration),
+}
+
+impl Offset {
+    /// Correct the local time `local` to the time of the broker.
+    pub fn apply(&self, local: SystemTime) -> SystemTime {
+        match self {
+            Offset::Ahead(offset) => local + *offset,
+            Offset::Behind(offset) => local - *offset,
+        }
+    }
+
+    // The offset of an observation: the broker stamped `remote`, it was received at `local`.
+    fn between(remote: SystemTime, local: SystemTime) -> Self {
+        match remote.duration_since(local) {
+            Ok(offset) => Offset::Ahead(offset),
+            Err(error) => Offset::Behind(error.duration()),
+        }
+    }
+
+    // Order offsets from most behind to most ahead.
+    fn key(&self) -> (bool, Duration) {
+        match self {
+            Offset::Ahead(offset) => (true, *offset),
+            Offset::Behind(offset) => (false, Duration::MAX - *offset),
+        }
+    }
+}
+
+/// Estimates the [`Offset`] of a broker clock from publications of its time.
+///
+/// See the [module documentation](crate::timesync) for the accuracy of the estimate.
+#[derive(Clone, Debug)]
+pub struct Estimator {
+    // The offsets of the most recent observations, oldest first.
+    observations: VecDeque<Offset>,
+    window: usize,
+    parse: fn(&[u8]) -> Option<SystemTime>,
+}
+
+impl Default for Estimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Estimator {
+    /// Create an `Estimator` for payloads in the format of [`parse_unix_timestamp()`].
+    pub fn new() -> Self {
+        Self::with_parser(parse_unix_timestamp)
+    }
+
+    /// Create an `Estimator` that extracts the time from payloads with `parse`.
+    pub fn with_parser(parse: fn(&[u8]) -> Option<SystemTime>) -> Self {
+        Self {
+            observations: VecDeque::new(),
+            window: DEFAULT_WINDOW,
+            parse,
+        }
+    }
+
+    /// Configure how many of the most recent observations are considered. The default is 8.
+    ///
+    /// A larger window is more likely to contain an observation with a short transit time,
+    /// but it adapts slower to a clock that jumps.
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.max(1);
+        while self.observations.len() > self.window {
+            self.observations.pop_front();
+        }
+    }
+
+    /// Record the `payload` of a publication with the time of the broker, that was
+    /// received at `received_at`.
+    ///
+    /// Returns `false` if the payload couldn't be parsed. It's ignored then.
+    pub fn observe(&mut self, payload: &[u8], received_at: SystemTime) -> bool {
+        let Some(remote) = (self.parse)(payload) else {
+            return false;
+        };
+
+        if self.observations.len() == self.window {
+            self.observations.pop_front();
+        }
+        self.observations
+            .push_back(Offset::between(remote, received_at));
+        true
+    }
+
+    /// The estimated offset, or `None` if nothing was observed yet.
+    pub fn offset(&self) -> Option<Offset> {
+        self.observations
+            .iter()
+            .max_by_key(|offset| offset.key())
+            .copied()
+    }
+
+    /// The current time according to the broker, or `None` if nothing was observed yet.
+    pub fn now(&self) -> Option<SystemTime> {
+        self.offset().map(|offset| offset.apply(SystemTime::now()))
+    }
+}
+
+/// Parse a payload that starts with the number of seconds since the Unix epoch, like
+/// `1700000000` or `1700000000.250`.
+///
+/// Text after the number, like a unit, is ignored.
+pub fn parse_unix_timestamp(payload: &[u8]) -> Option<SystemTime> {
+    let payload = std::str::from_utf8(payload).ok()?.trim_start();
+    let end = payload
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(payload.len());
+    let seconds: f64 = payload[..end].parse().ok()?;
+    let since_epoch = Duration::try_from_secs_f64(seconds).ok()?;
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+/// A clock corrected with the offset of the broker. It's returned by [`track()`].
+///
+/// Clones share the same estimate.
+#[cfg(feature = "async")]
+#[derive(Clone, Debug)]
+pub struct Clock {
+    estimator: Arc<Mutex<Estimator>>,
+}
+
+#[cfg(feature = "async")]
+impl Clock {
+    /// The estimated offset, or `None` if no publication was observed yet.
+    pub fn offset(&self) -> Option<Offset> {
+        self.estimator.lock().unwrap().offset()
+    }
+
+    /// The current time according to the broker, or `None` if no publication was observed yet.
+    pub fn now(&self) -> Option<SystemTime> {
+        self.estimator.lock().unwrap().now()
+    }
+}
+
+/// Subscribe to `topic` and keep a [`Clock`] synchronized with the publications on it.
+///
+/// The payloads must be in the format of [`parse_unix_timestamp()`]. Use [`track_with()`]
+/// for other formats. The returned future updates the clock until the [`Client`](crate::aio::Client)
+/// terminates. Spawn it on the executor of the application.
+#[cfg(feature = "async")]
+pub async fn track(
+    handle: &ClientHandle,
+    topic: &str,
+) -> Result<(Clock, impl Future<Output = ()> + Send + 'static), SubscribeError> {
+    track_with(handle, topic, Estimator::new()).await
+}
+
+/// Like [`track()`], but update the given `estimator`.
+#[cfg(feature = "async")]
+pub async fn track_with(
+    handle: &ClientHandle,
+    topic: &str,
+    estimator: Estimator,
+) -> Result<(Clock, impl Future<Output = ()> + Send + 'static), SubscribeError> {
+    let mut subscription = handle.subscribe_stream(topic).await?;
+    let clock = Clock {
+        estimator: Arc::new(Mutex::new(estimator)),
+    };
+
+    let estimator = clock.estimator.clone();
+    let synchronize = async move {
+        while let Some(publish) = subscription.next().await {
+            let received_at = SystemTime::now();
+            estimator
+                .lock()
+                .unwrap()
+                .observe(publish.payload(), received_at);
+        }
+    };
+    Ok((clock, synchronize))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_parse_unix_timestamp() {
+        assert_eq!(parse_unix_timestamp(b"1700000000"), Some(at(1_700_000_000)));
+        assert_eq!(parse_unix_timestamp(b" 12 seconds"), Some(at(12)));
+        assert_eq!(
+            parse_unix_timestamp(b"1.5"),
+            Some(UNIX_EPOCH + Duration::from_millis(1500))
+        );
+        assert_eq!(parse_unix_timestamp(b"seconds"), None);
+        assert_eq!(parse_unix_timestamp(&[0xFF]), None);
+    }
+
+    // Verify that the estimator picks the observation with the shortest transit time,
+    // and forgets observations that are out of the window.
+    #[test]
+    fn test_estimator() {
+        let mut estimator = Estimator::new();
+        assert_eq!(estimator.offset(), None);
+        assert!(!estimator.observe(b"now", at(100)));
+
+        estimator.set_window(2);
+        assert!(estimator.observe(b"90", at(100)));
+        assert!(estimator.observe(b"95", at(100)));
+        assert_eq!(
+            estimator.offset(),
+            Some(Offset::Behind(Duration::from_secs(5)))
+        );
+
+        assert!(estimator.observe(b"93", at(100)));
+        assert_eq!(
+            estimator.offset(),
+            Some(Offset::Behind(Duration::from_secs(5)))
+        );
+        assert!(estimator.observe(b"91", at(100)));
+        assert_eq!(
+            estimator.offset(),
+            Some(Offset::Behind(Duration::from_secs(7)))
+        );
+
+        assert!(estimator.observe(b"102", at(100)));
+        assert_eq!(
+            estimator.offset(),
+            Some(Offset::Ahead(Duration::from_secs(2)))
+        );
+        assert_eq!(
+            Offset::Ahead(Duration::from_secs(2)).apply(at(100)),
+            at(102)
+        );
+    }
+}
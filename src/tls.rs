@@ -0,0 +1,262 @@
+//! Encrypt the connection to the broker with TLS.
+//!
+//! Requires the feature `tls`. The TLS implementation is [rustls](https://docs.rs/rustls),
+//! which is re-exported as [`tls::rustls`](crate::tls::rustls). Use it to create a [`ClientConfig`],
+//! then connect with [`blocking::Client::connect_tls()`](crate::blocking::Client::connect_tls())
+//! or [`aio::Client::connect_tls()`](crate::aio::Client::connect_tls()).
+//!
+//! ```no_run
+//! # #[cfg(feature = "blocking")]
+//! # {
+//! use std::sync::Arc;
+//! use tjiftjaf::{blocking::Client, tls::rustls, Connect};
+//!
+//! let mut roots = rustls::RootCertStore::empty();
+//! // Add the certificate of the certificate authority that signed the broker's certificate.
+//! # let certificate = rustls::pki_types::CertificateDer::from(vec![]);
+//! roots.add(certificate).unwrap();
+//!
+//! let config = rustls::ClientConfig::builder()
+//!     .with_root_certificates(roots)
+//!     .with_no_client_auth();
+//!
+//! let connect = Connect::builder().client_id("tjiftjaf").build();
+//! let client = Client::connect_tls("broker.example.com:8883", connect, Arc::new(config)).unwrap();
+//! # }
+//! ```
+//!
+//! # Session resumption
+//!
+//! A `ClientConfig` remembers the session tickets of the brokers it connected to. Reconnect
+//! with the same `Arc<ClientConfig>`, and the handshake resumes the earlier session. That saves
+//! a round trip and the verification of the certificate chain. Configure the cache with
+//! [`ClientConfig::resumption`](rustls::ClientConfig::resumption), or disable it with
+//! [`Resumption::disabled()`](rustls::client::Resumption::disabled()).
+//!
+//! The [`Statistics`](crate::Statistics) of a client record whether the handshake resumed,
+//! how long it took, and how long the TCP connect took before it. A slow connect points at
+//! the network, a slow handshake at the broker.
+pub use rustls;
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, HandshakeKind};
+#[cfg(any(feature = "blocking", feature = "async"))]
+use std::{
+    io::{Error, ErrorKind},
+    sync::Arc,
+};
+
+#[cfg(feature = "async")]
+pub use self::aio::TlsStream;
+
+// Create a TLS connection for the server at `addr`, formatted as `host:port`.
+// The host is used to verify the certificate of the server.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) fn client_connection(
+    addr: &str,
+    config: Arc<ClientConfig>,
+) -> Result<ClientConnection, Error> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+
+    ClientConnection::new(config, server_name).map_err(Error::other)
+}
+
+// Whether the handshake of `connection` resumed an earlier session.
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub(crate) fn resumed(connection: &ClientConnection) -> bool {
+    connection.handshake_kind() == Some(HandshakeKind::Resumed)
+}
+
+#[cfg(feature = "async")]
+mod aio {
+    use futures::{AsyncRead, AsyncWrite};
+    use rustls::ClientConnection;
+    use std::{
+        io::{Error, ErrorKind, Read, Write},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// A socket encrypted with TLS. It's returned by
+    /// [`aio::Client::connect_tls()`](crate::aio::Client::connect_tls()).
+    pub struct TlsStream<S> {
+        io: S,
+        connection: ClientConnection,
+
+        // Whether a close_notify alert is queued.
+        closing: bool,
+    }
+
+    impl<S> TlsStream<S> {
+        pub(crate) fn new(io: S, connection: ClientConnection) -> Self {
+            Self {
+                io,
+                connection,
+                closing: false,
+            }
+        }
+    }
+
+    // Expose an asynchronous socket as a synchronous one to rustls.
+    // `Poll::Pending` becomes an error of kind `WouldBlock`.
+    struct SyncAdapter<'a, 'b, S> {
+        io: &'a mut S,
+        cx: &'a mut Context<'b>,
+    }
+
+    impl<S: AsyncRead + Unpin> Read for SyncAdapter<'_, '_, S> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            match Pin::new(&mut *self.io).poll_read(self.cx, buf) {
+                Poll::Ready(result) => result,
+                Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> Write for SyncAdapter<'_, '_, S> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+                Poll::Ready(result) => result,
+                Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            match Pin::new(&mut *self.io).poll_flush(self.cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+        // Write encrypted data to the socket, until rustls has nothing left to write.
+        fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            let mut io = SyncAdapter {
+                io: &mut self.io,
+                cx,
+            };
+
+            while self.connection.wants_write() {
+                match self.connection.write_tls(&mut io) {
+                    Ok(_) => {}
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => return Poll::Pending,
+                    Err(error) => return Poll::Ready(Err(error)),
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        // Complete the handshake, and transmit the last message of it.
+        pub(crate) async fn handshake(&mut self) -> Result<(), Error> {
+            futures::future::poll_fn(|cx| self.poll_handshake(cx)).await
+        }
+
+        fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            while self.connection.is_handshaking() {
+                futures::ready!(self.poll_write_tls(cx))?;
+                if !self.connection.wants_read() {
+                    continue;
+                }
+
+                let mut io = SyncAdapter {
+                    io: &mut self.io,
+                    cx,
+                };
+                match self.connection.read_tls(&mut io) {
+                    Ok(0) => return Poll::Ready(Err(ErrorKind::UnexpectedEof.into())),
+                    Ok(_) => {
+                        self.connection
+                            .process_new_packets()
+                            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+                    }
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => return Poll::Pending,
+                    Err(error) => return Poll::Ready(Err(error)),
+                }
+            }
+            self.poll_write_tls(cx)
+        }
+
+        pub(crate) fn connection(&self) -> &ClientConnection {
+            &self.connection
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize, Error>> {
+            let this = self.get_mut();
+            loop {
+                match this.connection.reader().read(buf) {
+                    Ok(n) => return Poll::Ready(Ok(n)),
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                    Err(error) => return Poll::Ready(Err(error)),
+                }
+
+                // Processing inbound data, like handshake messages, might require a response.
+                if let Poll::Ready(Err(error)) = this.poll_write_tls(cx) {
+                    return Poll::Ready(Err(error));
+                }
+
+                let mut io = SyncAdapter {
+                    io: &mut this.io,
+                    cx,
+                };
+                match this.connection.read_tls(&mut io) {
+                    Ok(0) => return Poll::Ready(Ok(0)),
+                    Ok(_) => {
+                        this.connection
+                            .process_new_packets()
+                            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+                    }
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => return Poll::Pending,
+                    Err(error) => return Poll::Ready(Err(error)),
+                }
+            }
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, Error>> {
+            let this = self.get_mut();
+            let mut n = this.connection.writer().write(buf)?;
+            if n == 0 && !buf.is_empty() {
+                // The buffer of rustls is full. Make room by transmitting it first.
+                futures::ready!(this.poll_write_tls(cx))?;
+                n = this.connection.writer().write(buf)?;
+            }
+
+            // The data is buffered by rustls. `poll_flush()` makes sure it's transmitted.
+            if let Poll::Ready(Err(error)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(error));
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            let this = self.get_mut();
+            futures::ready!(this.poll_write_tls(cx))?;
+            Pin::new(&mut this.io).poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            let this = self.get_mut();
+            if !this.closing {
+                this.connection.send_close_notify();
+                this.closing = true;
+            }
+            futures::ready!(this.poll_write_tls(cx))?;
+            Pin::new(&mut this.io).poll_close(cx)
+        }
+    }
+}
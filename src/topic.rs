@@ -0,0 +1,77 @@
+// Verify if a topic match a subscription. The subscription may
+// include wildcards like `#` and `+`.
+pub(crate) fn does_topic_match_subscription(subscription: &str, topic: &str) -> bool {
+    // If no wild cards are used, check for exact match
+    if !subscription.contains('#') && !subscription.contains('+') {
+        return subscription == topic;
+    }
+
+    if let Some((prefix, _)) = subscription.split_once('#') {
+        return topic.starts_with(prefix);
+    }
+
+    let mut topic_segments = topic.split('/');
+
+    for filter in subscription.split('/') {
+        // The topic and a subscription using `+` must have the same
+        // number of segments. If the topic has less segments, it is no match.
+        let Some(segment) = topic_segments.next() else {
+            return false;
+        };
+
+        if filter == "+" {
+            continue;
+        }
+
+        if filter != segment {
+            return false;
+        }
+    }
+
+    // The topic and a subscription using `+` must have the same
+    // number of segments. If the topic has more segments, it is no match.
+    if topic_segments.next().is_some() {
+        return false;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::does_topic_match_subscription;
+
+    #[test]
+    fn test_does_topic_match_subscription() {
+        assert!(does_topic_match_subscription(
+            "sensors/3/value",
+            "sensors/3/value"
+        ));
+
+        assert!(does_topic_match_subscription(
+            "sensors/+/value",
+            "sensors/3/value"
+        ));
+
+        assert!(does_topic_match_subscription(
+            "sensors/+/+",
+            "sensors/3/value"
+        ));
+
+        assert!(does_topic_match_subscription(
+            "sensors/#",
+            "sensors/3/value"
+        ));
+
+        // These topics don't match
+        assert!(!does_topic_match_subscription(
+            "sensors/3/value",
+            "sensors/1/value"
+        ));
+
+        assert!(!does_topic_match_subscription(
+            "sensors/+/value",
+            "sensors/1/name"
+        ));
+    }
+}
@@ -1 +1,73 @@
+use crate::InvalidTopicFilter;
+use alloc::string::ToString;
 
+// Verify the syntax of a topic filter, as used by SUBSCRIBE and UNSUBSCRIBE.
+pub(crate) fn topic_filter(filter: &str) -> Result<(), InvalidTopicFilter> {
+    let invalid = |reason| {
+        Err(InvalidTopicFilter {
+            filter: filter.to_string(),
+            reason,
+        })
+    };
+
+    // [MQTT-4.7.3-1] All Topic Names and Topic Filters MUST be at least one character long.
+    if filter.is_empty() {
+        return invalid("a topic filter must not be empty");
+    }
+    // [MQTT-1.5.3-1] The length of a UTF-8 encoded string is encoded in 2 bytes.
+    if filter.len() > u16::MAX as usize {
+        return invalid("a topic filter must not exceed 65535 bytes");
+    }
+    // [MQTT-4.7.3-2] Topic Names and Topic Filters MUST NOT include the null character.
+    if filter.contains('\0') {
+        return invalid("a topic filter must not contain the null character");
+    }
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        // [MQTT-4.7.1-2] The multi-level wildcard character MUST be specified either on its
+        // own or following a topic level separator. In either case it MUST be the last
+        // character specified in the Topic Filter.
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return invalid("'#' must be the last level of a topic filter");
+        }
+        // [MQTT-4.7.1-3] The single-level wildcard can be used at any level in the Topic
+        // Filter, including first and last levels. Where it is used it MUST occupy an
+        // entire level of the filter.
+        if level.contains('+') && level != "+" {
+            return invalid("'+' must occupy an entire level of a topic filter");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::topic_filter;
+
+    #[test]
+    fn test_topic_filter() {
+        for filter in [
+            "sensor",
+            "sensor/#",
+            "#",
+            "+",
+            "sensor/+/temperature",
+            "/",
+            "+/+",
+        ] {
+            assert!(topic_filter(filter).is_ok(), "{filter}");
+        }
+
+        for filter in [
+            "",
+            "sensor#",
+            "sensor/#/1",
+            "sensor+",
+            "sensor/+1",
+            "sensor/\0",
+        ] {
+            assert!(topic_filter(filter).is_err(), "{filter:?}");
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Log the packets exchanged with the server, to debug the interoperability with brokers.
+//
+// Every packet is logged at level debug with its type, packet identifier, topic and size.
+// At level trace, a hex dump of the frame follows, without the payload of a CONNECT as that
+// holds the credentials. With the feature `tracing`, the packets
+// are emitted as events of `tracing`, with those as fields, instead of as records of `log`.
+use crate::{target, Frame, Packet};
+use core::fmt::{self, Display};
+
+// Whether a packet was received from or sent to the server.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Direction::Inbound => "-->",
+            Direction::Outbound => "<--",
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+// Log `packet`, that passed the wire in `direction`.
+pub(crate) fn log(direction: Direction, packet: &Packet) {
+    #[cfg(feature = "tracing")]
+    {
+        let topic = match packet {
+            Packet::Publish(publish) => Some(publish.topic()),
+            _ => None,
+        };
+        tracing::debug!(
+            target: target::WIRE,
+            direction = direction.as_str(),
+            packet_type = ?packet.packet_type(),
+            packet_identifier = packet_identifier(packet),
+            topic,
+            size = packet.length(),
+            "{} {packet:?}",
+            direction.arrow()
+        );
+        tracing::trace!(
+            target: target::WIRE,
+            direction = direction.as_str(),
+            frame = %HexDump(frame(packet)),
+        );
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    {
+        log::debug!(target: target::WIRE, "{} {packet:?}", direction.arrow());
+        log::trace!(target: target::WIRE, "{} {}", direction.arrow(), HexDump(frame(packet)));
+    }
+}
+
+// The packet identifier of `packet`, including the one an acknowledgement refers to.
+#[cfg(feature = "tracing")]
+fn packet_identifier(packet: &Packet) -> Option<u16> {
+    match packet {
+        Packet::PubAck(packet) => Some(packet.packet_identifier()),
+        Packet::PubRec(packet) => Some(packet.packet_identifier()),
+        Packet::PubRel(packet) => Some(packet.packet_identifier()),
+        Packet::PubComp(packet) => Some(packet.packet_identifier()),
+        Packet::SubAck(packet) => Some(packet.packet_identifier()),
+        Packet::UnsubAck(packet) => Some(packet.packet_identifier()),
+        _ => packet.allocated_packet_identifier(),
+    }
+}
+
+// The bytes of `packet` to dump. The payload of a CONNECT, with the client id, will and
+// credentials, is left out.
+fn frame(packet: &Packet) -> &[u8] {
+    match packet {
+        Packet::Connect(connect) => &connect.as_bytes()[..connect.offset_payload()],
+        _ => packet.as_bytes(),
+    }
+}
+
+// Formats bytes as pairs of hex digits, separated by spaces, like `40 02 01 02`.
+pub(crate) struct HexDump<'a>(pub(crate) &'a [u8]);
+
+impl Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{publish, Connect, PubAck};
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(HexDump(&[]).to_string(), "");
+
+        let packet = Packet::from(PubAck::new(258));
+        assert_eq!(HexDump(packet.as_bytes()).to_string(), "40 02 01 02");
+
+        let packet = Packet::from(publish("a", "hi"));
+        assert_eq!(
+            HexDump(packet.as_bytes()).to_string(),
+            "30 05 00 01 61 68 69"
+        );
+    }
+
+    #[test]
+    fn test_frame_leaves_out_credentials() {
+        let packet = Packet::from(
+            Connect::builder()
+                .username("optimus")
+                .password("prime")
+                .build(),
+        );
+        let dump = HexDump(frame(&packet)).to_string();
+        assert_eq!(dump.split(' ').count(), 12);
+        assert!(!dump.contains(&HexDump(b"prime").to_string()));
+
+        let packet = Packet::from(publish("a", "hi"));
+        assert_eq!(frame(&packet), packet.as_bytes());
+    }
+}
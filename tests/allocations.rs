@@ -0,0 +1,123 @@
+//! Verify that builders allocate the buffer of a frame once, with exactly the
+//! capacity required. The buffer must never grow while the frame is written.
+//!
+//! This lives in a separate test binary, because it replaces the global allocator.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use tjiftjaf::{packet_identifier, Connect, Publish, QoS, SubAck, Subscribe, Unsubscribe};
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static REALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        REALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[derive(Debug, PartialEq)]
+struct Count {
+    allocations: usize,
+    reallocations: usize,
+}
+
+// Count the (re)allocations done by `f` on the current thread.
+fn count<T>(f: impl FnOnce() -> T) -> (T, Count) {
+    let allocations = ALLOCATIONS.with(Cell::get);
+    let reallocations = REALLOCATIONS.with(Cell::get);
+    let value = f();
+    let count = Count {
+        allocations: ALLOCATIONS.with(Cell::get) - allocations,
+        reallocations: REALLOCATIONS.with(Cell::get) - reallocations,
+    };
+    (value, count)
+}
+
+#[test]
+fn test_publish() {
+    // A payload larger than 127 bytes requires a remaining length of 2 bytes.
+    let builder = Publish::builder("sensor/temperature", vec![1; 300])
+        .qos(QoS::AtLeastOnceDelivery)
+        .packet_identifier(packet_identifier());
+    let (publish, count) = count(|| builder.build());
+    assert_eq!(
+        count,
+        Count {
+            allocations: 1,
+            reallocations: 0
+        }
+    );
+
+    let bytes = publish.into_bytes();
+    assert_eq!(bytes.len(), bytes.capacity());
+}
+
+#[test]
+fn test_connect() {
+    let builder = Connect::builder()
+        .client_id("sensor-1")
+        .username("user")
+        .password("password");
+    let (connect, count) = count(|| builder.build());
+    assert_eq!(
+        count,
+        Count {
+            allocations: 1,
+            reallocations: 0
+        }
+    );
+
+    let bytes = connect.into_bytes();
+    assert_eq!(bytes.len(), bytes.capacity());
+}
+
+// Verifying the frames below allocates, so only check that the
+// buffer of the frame doesn't grow.
+#[test]
+fn test_subscribe() {
+    let builder = Subscribe::builder("sensor/1", QoS::AtMostOnceDelivery)
+        .add_topic("sensor/2", QoS::AtLeastOnceDelivery);
+    let (subscribe, count) = count(|| builder.build());
+    assert_eq!(count.reallocations, 0);
+
+    let bytes = subscribe.into_bytes();
+    assert_eq!(bytes.len(), bytes.capacity());
+}
+
+#[test]
+fn test_unsubscribe() {
+    let builder = Unsubscribe::builder("sensor/1").add_topic("sensor/2");
+    let (unsubscribe, count) = count(|| builder.build());
+    assert_eq!(count.reallocations, 0);
+
+    let bytes = unsubscribe.into_bytes();
+    assert_eq!(bytes.len(), bytes.capacity());
+}
+
+#[test]
+fn test_suback() {
+    let builder =
+        SubAck::builder(1, QoS::AtMostOnceDelivery).add_return_code(QoS::AtLeastOnceDelivery);
+    let (suback, count) = count(|| builder.build());
+    assert_eq!(count.reallocations, 0);
+
+    let bytes = suback.into_bytes();
+    assert_eq!(bytes.len(), bytes.capacity());
+}
@@ -0,0 +1,600 @@
+//! Conformance checks of the experimental `Server` against MQTT 3.1.1.
+//!
+//! Every check starts a fresh server, drives it over a raw TCP connection and verifies
+//! one normative statement of the specification. Checks of behavior the server doesn't
+//! implement yet are listed in `PENDING`. The test fails when any other check fails, but
+//! also when a pending check passes, so the list shrinks as the server matures.
+//!
+//! Run it with `cargo test --features experimental --test conformance -- --nocapture`
+//! to see the report.
+#![cfg(feature = "experimental")]
+use async_net::{TcpListener, TcpStream};
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use smol::Timer;
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+use tjiftjaf::{
+    aio::server::Server,
+    packet::{connack::ReturnCode, suback},
+    ConnAck, Connect, Disconnect, Packet, PingReq, PubRec, PubRel, Publish, QoS, Subscribe,
+    Unsubscribe,
+};
+
+// The statements of the checks that are known to fail.
+const PENDING: &[&str] = &[
+    "MQTT-3.2.2-2",
+    "MQTT-3.1.2-2",
+    "MQTT-3.1.3-8",
+    "MQTT-3.8.4-6",
+    "MQTT-4.3.2-2",
+    "MQTT-4.3.3-2",
+];
+
+type Outcome = Result<(), String>;
+
+// A check of the normative statement `statement`. `run` receives the port of the server.
+struct Check {
+    statement: &'static str,
+    description: &'static str,
+    run: fn(u16) -> Pin<Box<dyn Future<Output = Outcome>>>,
+}
+
+fn checks() -> Vec<Check> {
+    vec![
+        Check {
+            statement: "MQTT-3.2.2-1",
+            description: "a clean session is accepted without a session present",
+            run: |port| Box::pin(clean_session_accepted(port)),
+        },
+        Check {
+            statement: "MQTT-3.2.2-2",
+            description: "a resumed session is accepted with a session present",
+            run: |port| Box::pin(session_present(port)),
+        },
+        Check {
+            statement: "MQTT-3.1.2-2",
+            description: "an unsupported protocol level is refused with return code 1",
+            run: |port| Box::pin(unacceptable_protocol_level(port)),
+        },
+        Check {
+            statement: "MQTT-3.1.3-8",
+            description: "an empty client id without clean session is refused with return code 2",
+            run: |port| Box::pin(identifier_rejected(port)),
+        },
+        Check {
+            statement: "MQTT-3.1.2-8",
+            description: "the will is published when the connection closes",
+            run: |port| Box::pin(will_published(port)),
+        },
+        Check {
+            statement: "MQTT-3.1.2-10",
+            description: "the will is discarded after a DISCONNECT",
+            run: |port| Box::pin(will_discarded(port)),
+        },
+        Check {
+            statement: "MQTT-3.1.2-24",
+            description: "a client is disconnected after 1.5 times its keep alive",
+            run: |port| Box::pin(keep_alive_timeout(port)),
+        },
+        Check {
+            statement: "MQTT-3.12.4-1",
+            description: "a PINGREQ is answered with a PINGRESP",
+            run: |port| Box::pin(pingresp(port)),
+        },
+        Check {
+            statement: "MQTT-3.8.4-5",
+            description: "a SUBACK holds a return code per topic filter",
+            run: |port| Box::pin(suback_return_codes(port)),
+        },
+        Check {
+            statement: "MQTT-3.3.1-8",
+            description:
+                "a new subscription receives the retained publication with the retain flag",
+            run: |port| Box::pin(retained_delivered(port)),
+        },
+        Check {
+            statement: "MQTT-3.3.1-5",
+            description: "a retained publication replaces the previous one",
+            run: |port| Box::pin(retained_replaced(port)),
+        },
+        Check {
+            statement: "MQTT-3.3.1-10",
+            description: "a retained publication with an empty payload removes it",
+            run: |port| Box::pin(retained_removed(port)),
+        },
+        Check {
+            statement: "MQTT-3.3.1-9",
+            description: "a publication for an established subscription has no retain flag",
+            run: |port| Box::pin(retain_flag_cleared(port)),
+        },
+        Check {
+            statement: "MQTT-4.6.0-6",
+            description: "publications on a topic are delivered in order, after the retained one",
+            run: |port| Box::pin(ordered_delivery(port)),
+        },
+        Check {
+            statement: "MQTT-3.8.4-6",
+            description: "a publication is delivered with at most the granted QoS",
+            run: |port| Box::pin(qos_downgrade(port)),
+        },
+        Check {
+            statement: "MQTT-4.3.2-2",
+            description: "a publication with QoS 1 is acknowledged with a PUBACK",
+            run: |port| Box::pin(qos_1_acknowledged(port)),
+        },
+        Check {
+            statement: "MQTT-4.3.3-1",
+            description: "a PUBREC for a publication with QoS 2 is answered with a PUBREL",
+            run: |port| Box::pin(qos_2_released(port)),
+        },
+        Check {
+            statement: "MQTT-4.3.3-2",
+            description: "a publication with QoS 2 is acknowledged with a PUBREC and a PUBCOMP",
+            run: |port| Box::pin(qos_2_acknowledged(port)),
+        },
+        Check {
+            statement: "MQTT-3.10.4-4",
+            description: "an UNSUBSCRIBE is answered with an UNSUBACK",
+            run: |port| Box::pin(unsuback(port)),
+        },
+        Check {
+            statement: "MQTT-3.10.4-2",
+            description: "an unsubscribed topic filter receives no new publications",
+            run: |port| Box::pin(unsubscribed(port)),
+        },
+    ]
+}
+
+#[test]
+fn conformance() {
+    let mut unexpected = vec![];
+    for check in checks() {
+        let outcome = smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let _server = smol::spawn(Server::new(listener).run());
+
+            future::or((check.run)(port), async {
+                Timer::after(Duration::from_secs(3)).await;
+                Err(String::from("the check timed out"))
+            })
+            .await
+        });
+
+        let pending = PENDING.contains(&check.statement);
+        let verdict = match (&outcome, pending) {
+            (Ok(()), false) => "pass",
+            (Err(_), true) => "pending",
+            (Ok(()), true) => "PASSES, remove it from PENDING",
+            (Err(_), false) => "FAIL",
+        };
+        println!("[{}] {}: {verdict}", check.statement, check.description);
+        if let Err(error) = &outcome {
+            println!("    {error}");
+        }
+        if outcome.is_ok() == pending {
+            unexpected.push(check.statement);
+        }
+    }
+    assert!(unexpected.is_empty(), "Unexpected outcomes: {unexpected:?}");
+}
+
+async fn clean_session_accepted(port: u16) -> Outcome {
+    let connect = Connect::builder()
+        .client_id("clean")
+        .clean_session()
+        .build();
+    let connack = handshake(port, &Packet::from(connect).into_bytes()).await?;
+    expect_eq(connack.return_code(), ReturnCode::ConnectionAccepted)?;
+    expect_eq(connack.session_present(), false)
+}
+
+async fn session_present(port: u16) -> Outcome {
+    let mut stream = connect(port, Connect::builder().client_id("resumed").build()).await?;
+    subscribe(&mut stream, "sensor/#", QoS::AtMostOnceDelivery).await?;
+    send(&mut stream, Disconnect.into()).await?;
+    drop(stream);
+
+    let connect = Connect::builder().client_id("resumed").build();
+    let connack = handshake(port, &Packet::from(connect).into_bytes()).await?;
+    expect_eq(connack.session_present(), true)
+}
+
+async fn unacceptable_protocol_level(port: u16) -> Outcome {
+    // A CONNECT of MQTT 5, with client id "a". The properties are left out,
+    // the server must refuse the connection after reading the protocol level.
+    let frame = [
+        0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 5, 0b10, 0, 60, 0, 1, b'a',
+    ];
+    let connack = handshake(port, &frame).await?;
+    expect_eq(
+        connack.return_code(),
+        ReturnCode::ConnectionRefusedUnacceptableProtocolVersion,
+    )
+}
+
+async fn identifier_rejected(port: u16) -> Outcome {
+    // A CONNECT with an empty client id and without the clean session flag.
+    let frame = [0x10, 12, 0, 4, b'M', b'Q', b'T', b'T', 4, 0, 0, 60, 0, 0];
+    let connack = handshake(port, &frame).await?;
+    expect_eq(
+        connack.return_code(),
+        ReturnCode::ConnectionRefusedIdentifierRejected,
+    )
+}
+
+async fn will_published(port: u16) -> Outcome {
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "status/#", QoS::AtMostOnceDelivery).await?;
+
+    let connect_with_will = Connect::builder()
+        .client_id("dropped")
+        .will("status/dropped", "offline")
+        .build();
+    drop(connect(port, connect_with_will).await?);
+
+    let publish = read_publish(&mut subscriber).await?;
+    expect_eq(publish.topic(), "status/dropped")?;
+    expect_eq(publish.payload(), b"offline".as_slice())
+}
+
+async fn will_discarded(port: u16) -> Outcome {
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "status/#", QoS::AtMostOnceDelivery).await?;
+
+    let connect_with_will = Connect::builder()
+        .client_id("graceful")
+        .will("status/graceful", "offline")
+        .build();
+    let mut stream = connect(port, connect_with_will).await?;
+    send(&mut stream, Disconnect.into()).await?;
+    drop(stream);
+
+    expect_silence(&mut subscriber).await
+}
+
+async fn keep_alive_timeout(port: u16) -> Outcome {
+    let connect = Connect::builder().client_id("silent").keep_alive(1).build();
+    let mut stream = self::connect(port, connect).await?;
+    let start = Instant::now();
+
+    match read_packet(&mut stream).await {
+        Err(error) if error == CLOSED => {}
+        Err(error) => return Err(error),
+        Ok(packet) => return Err(format!("expected the connection to close, got {packet:?}")),
+    }
+    let elapsed = start.elapsed();
+    if elapsed < Duration::from_secs(1) {
+        return Err(format!("the connection closed after {elapsed:?}"));
+    }
+    Ok(())
+}
+
+async fn pingresp(port: u16) -> Outcome {
+    let mut stream = connect(port, Connect::builder().client_id("ping").build()).await?;
+    ping(&mut stream).await
+}
+
+async fn suback_return_codes(port: u16) -> Outcome {
+    let mut stream = connect(port, Connect::builder().client_id("sub").build()).await?;
+    let subscribe = Subscribe::builder("sensor/1", QoS::AtMostOnceDelivery)
+        .add_topic("sensor/2", QoS::AtLeastOnceDelivery)
+        .packet_identifier(7)
+        .build();
+    send(&mut stream, subscribe.into()).await?;
+
+    let Packet::SubAck(suback) = read_packet(&mut stream).await? else {
+        return Err(String::from("expected a SUBACK"));
+    };
+    expect_eq(suback.packet_identifier(), 7)?;
+    expect_eq(suback.return_codes().len(), 2)
+}
+
+async fn retained_delivered(port: u16) -> Outcome {
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    let retained = Publish::builder("sensor/1", "26.1").retain(true).build();
+    send(&mut publisher, retained.into()).await?;
+    ping(&mut publisher).await?;
+
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::AtMostOnceDelivery).await?;
+    let publish = read_publish(&mut subscriber).await?;
+    expect_eq(publish.payload(), b"26.1".as_slice())?;
+    expect_eq(publish.retain(), true)
+}
+
+async fn retained_replaced(port: u16) -> Outcome {
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    for payload in ["26.1", "26.2"] {
+        let retained = Publish::builder("sensor/1", payload).retain(true).build();
+        send(&mut publisher, retained.into()).await?;
+    }
+    ping(&mut publisher).await?;
+
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::AtMostOnceDelivery).await?;
+    let publish = read_publish(&mut subscriber).await?;
+    expect_eq(publish.payload(), b"26.2".as_slice())?;
+    expect_silence(&mut subscriber).await
+}
+
+async fn retained_removed(port: u16) -> Outcome {
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    for payload in ["26.1", ""] {
+        let retained = Publish::builder("sensor/1", payload).retain(true).build();
+        send(&mut publisher, retained.into()).await?;
+    }
+    ping(&mut publisher).await?;
+
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::AtMostOnceDelivery).await?;
+    expect_silence(&mut subscriber).await
+}
+
+async fn retain_flag_cleared(port: u16) -> Outcome {
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::AtMostOnceDelivery).await?;
+
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    let retained = Publish::builder("sensor/1", "26.1").retain(true).build();
+    send(&mut publisher, retained.into()).await?;
+
+    let publish = read_publish(&mut subscriber).await?;
+    expect_eq(publish.retain(), false)
+}
+
+async fn ordered_delivery(port: u16) -> Outcome {
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    let retained = Publish::builder("sensor/1", "0").retain(true).build();
+    send(&mut publisher, retained.into()).await?;
+    ping(&mut publisher).await?;
+
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::AtMostOnceDelivery).await?;
+    for payload in ["1", "2", "3"] {
+        send(
+            &mut publisher,
+            Publish::builder("sensor/1", payload).build().into(),
+        )
+        .await?;
+    }
+
+    for expected in ["0", "1", "2", "3"] {
+        let publish = read_publish(&mut subscriber).await?;
+        expect_eq(publish.payload(), expected.as_bytes())?;
+    }
+    Ok(())
+}
+
+async fn qos_downgrade(port: u16) -> Outcome {
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::AtMostOnceDelivery).await?;
+
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    let publish = Publish::builder("sensor/1", "26.1")
+        .qos(QoS::AtLeastOnceDelivery)
+        .packet_identifier(1)
+        .build();
+    send(&mut publisher, publish.into()).await?;
+
+    let publish = read_publish(&mut subscriber).await?;
+    expect_eq(publish.qos(), QoS::AtMostOnceDelivery)
+}
+
+async fn qos_1_acknowledged(port: u16) -> Outcome {
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    let publish = Publish::builder("sensor/1", "26.1")
+        .qos(QoS::AtLeastOnceDelivery)
+        .packet_identifier(9)
+        .build();
+    send(&mut publisher, publish.into()).await?;
+
+    match read_packet(&mut publisher).await? {
+        Packet::PubAck(puback) => expect_eq(puback.packet_identifier(), 9),
+        packet => Err(format!("expected a PUBACK, got {packet:?}")),
+    }
+}
+
+async fn qos_2_released(port: u16) -> Outcome {
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::ExactlyOnceDelivery).await?;
+
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    let publish = Publish::builder("sensor/1", "26.1")
+        .qos(QoS::ExactlyOnceDelivery)
+        .packet_identifier(5)
+        .build();
+    send(&mut publisher, publish.into()).await?;
+
+    let publish = read_publish(&mut subscriber).await?;
+    expect_eq(publish.qos(), QoS::ExactlyOnceDelivery)?;
+    let packet_identifier = publish
+        .packet_identifier()
+        .ok_or("expected a packet identifier")?;
+    send(&mut subscriber, PubRec::new(packet_identifier).into()).await?;
+    match read_packet(&mut subscriber).await? {
+        Packet::PubRel(pubrel) => expect_eq(pubrel.packet_identifier(), packet_identifier),
+        packet => Err(format!("expected a PUBREL, got {packet:?}")),
+    }
+}
+
+async fn qos_2_acknowledged(port: u16) -> Outcome {
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    let publish = Publish::builder("sensor/1", "26.1")
+        .qos(QoS::ExactlyOnceDelivery)
+        .packet_identifier(9)
+        .build();
+    send(&mut publisher, publish.into()).await?;
+
+    match read_packet(&mut publisher).await? {
+        Packet::PubRec(pubrec) => expect_eq(pubrec.packet_identifier(), 9)?,
+        packet => return Err(format!("expected a PUBREC, got {packet:?}")),
+    }
+    send(&mut publisher, PubRel::new(9).into()).await?;
+    match read_packet(&mut publisher).await? {
+        Packet::PubComp(pubcomp) => expect_eq(pubcomp.packet_identifier(), 9),
+        packet => Err(format!("expected a PUBCOMP, got {packet:?}")),
+    }
+}
+
+async fn unsuback(port: u16) -> Outcome {
+    let mut stream = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut stream, "sensor/#", QoS::AtMostOnceDelivery).await?;
+    let unsubscribe = Unsubscribe::builder("sensor/#")
+        .packet_identifier(7)
+        .build();
+    send(&mut stream, unsubscribe.into()).await?;
+
+    match read_packet(&mut stream).await? {
+        Packet::UnsubAck(unsuback) => expect_eq(unsuback.packet_identifier(), 7),
+        packet => Err(format!("expected an UNSUBACK, got {packet:?}")),
+    }
+}
+
+async fn unsubscribed(port: u16) -> Outcome {
+    let mut subscriber = connect(port, Connect::builder().client_id("sub").build()).await?;
+    subscribe(&mut subscriber, "sensor/#", QoS::AtMostOnceDelivery).await?;
+    let unsubscribe = Unsubscribe::builder("sensor/#")
+        .packet_identifier(2)
+        .build();
+    send(&mut subscriber, unsubscribe.into()).await?;
+    match read_packet(&mut subscriber).await? {
+        Packet::UnsubAck(_) => {}
+        packet => return Err(format!("expected an UNSUBACK, got {packet:?}")),
+    }
+
+    let mut publisher = connect(port, Connect::builder().client_id("pub").build()).await?;
+    send(
+        &mut publisher,
+        Publish::builder("sensor/1", "26.1").build().into(),
+    )
+    .await?;
+    ping(&mut publisher).await?;
+    expect_silence(&mut subscriber).await
+}
+
+// The error of `read_packet()` when the server closed the connection.
+const CLOSED: &str = "the server closed the connection";
+
+// Open a connection, send `frame` and return the CONNACK.
+async fn handshake(port: u16, frame: &[u8]) -> Result<ConnAck, String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))
+        .await
+        .map_err(|error| error.to_string())?;
+    stream
+        .write_all(frame)
+        .await
+        .map_err(|error| error.to_string())?;
+    match read_packet(&mut stream).await? {
+        Packet::ConnAck(connack) => Ok(connack),
+        packet => Err(format!("expected a CONNACK, got {packet:?}")),
+    }
+}
+
+// Open a connection that the server accepted.
+async fn connect(port: u16, connect: Connect) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))
+        .await
+        .map_err(|error| error.to_string())?;
+    send(&mut stream, connect.into()).await?;
+    match read_packet(&mut stream).await? {
+        Packet::ConnAck(connack) if connack.return_code() == ReturnCode::ConnectionAccepted => {
+            Ok(stream)
+        }
+        packet => Err(format!("expected an accepting CONNACK, got {packet:?}")),
+    }
+}
+
+async fn send(stream: &mut TcpStream, packet: Packet) -> Outcome {
+    stream
+        .write_all(&packet.into_bytes())
+        .await
+        .map_err(|error| error.to_string())
+}
+
+// Subscribe to `filter` and wait for the SUBACK.
+async fn subscribe(stream: &mut TcpStream, filter: &str, qos: QoS) -> Outcome {
+    let subscribe = Subscribe::builder(filter, qos).packet_identifier(1).build();
+    send(stream, subscribe.into()).await?;
+    match read_packet(stream).await? {
+        Packet::SubAck(suback) if suback.return_codes() == [suback::ReturnCode::QoS(qos)] => Ok(()),
+        packet => Err(format!(
+            "expected a SUBACK granting {qos:?}, got {packet:?}"
+        )),
+    }
+}
+
+// Send a PINGREQ and wait for the PINGRESP. The server processed every packet
+// sent before on this connection, once it answers.
+async fn ping(stream: &mut TcpStream) -> Outcome {
+    send(stream, PingReq.into()).await?;
+    match read_packet(stream).await? {
+        Packet::PingResp(_) => Ok(()),
+        packet => Err(format!("expected a PINGRESP, got {packet:?}")),
+    }
+}
+
+async fn read_publish(stream: &mut TcpStream) -> Result<Publish, String> {
+    match read_packet(stream).await? {
+        Packet::Publish(publish) => Ok(publish),
+        packet => Err(format!("expected a PUBLISH, got {packet:?}")),
+    }
+}
+
+// Verify that the server sends nothing for a while.
+async fn expect_silence(stream: &mut TcpStream) -> Outcome {
+    let packet = future::or(async { Some(read_packet(stream).await) }, async {
+        Timer::after(Duration::from_millis(200)).await;
+        None
+    })
+    .await;
+    match packet {
+        None => Ok(()),
+        Some(packet) => Err(format!("expected no packet, got {packet:?}")),
+    }
+}
+
+async fn read_packet(stream: &mut TcpStream) -> Result<Packet, String> {
+    let mut frame = vec![0; 1];
+    read_exact(stream, &mut frame).await?;
+
+    // The remaining length is encoded in at most 4 bytes.
+    let mut remaining_length = 0;
+    for shift in (0..4).map(|n| 7 * n) {
+        let mut byte = [0];
+        read_exact(stream, &mut byte).await?;
+        frame.push(byte[0]);
+        remaining_length |= usize::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let header = frame.len();
+    frame.resize(header + remaining_length, 0);
+    read_exact(stream, &mut frame[header..]).await?;
+    Packet::try_from(frame).map_err(|error| format!("failed to decode a packet: {error:?}"))
+}
+
+async fn read_exact(stream: &mut TcpStream, buffer: &mut [u8]) -> Outcome {
+    stream
+        .read_exact(buffer)
+        .await
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset => {
+                String::from(CLOSED)
+            }
+            _ => error.to_string(),
+        })
+}
+
+fn expect_eq<T: PartialEq + std::fmt::Debug>(actual: T, expected: T) -> Outcome {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("expected {expected:?}, got {actual:?}"))
+    }
+}
@@ -1,3 +1,5 @@
 pub mod broker;
+#[cfg(feature = "tls")]
+pub mod tls;
 #[cfg(feature = "async")]
 pub mod wiretap;
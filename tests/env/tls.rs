@@ -0,0 +1,104 @@
+// A minimal MQTT server behind TLS. It accepts a single connection.
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+use tjiftjaf::{
+    tls::rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    },
+    ConnAck, Packet,
+};
+
+// A self-signed certificate for `localhost` and 127.0.0.1.
+const CERTIFICATE: &[u8] = include_bytes!("tls/cert.pem");
+const KEY: &[u8] = include_bytes!("tls/key.pem");
+
+/// A `ClientConfig` that trusts the certificate of the server.
+pub fn client_config() -> Arc<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_slice(CERTIFICATE).unwrap())
+        .unwrap();
+
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Start the server. The thread returns the packets it received,
+/// when the client disconnects.
+pub fn server() -> (u16, JoinHandle<Vec<Packet>>) {
+    serve(1)
+}
+
+/// Like `server()`, but accept `connections` connections, one after another.
+/// The thread returns the packets of all connections.
+pub fn serve(connections: usize) -> (u16, JoinHandle<Vec<Packet>>) {
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from_pem_slice(CERTIFICATE).unwrap()],
+            PrivateKeyDer::from_pem_slice(KEY).unwrap(),
+        )
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let config = Arc::new(config);
+    let handle = thread::spawn(move || {
+        let mut packets = vec![];
+        for _ in 0..connections {
+            let (socket, _) = listener.accept().unwrap();
+            let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+            let mut stream = rustls::StreamOwned::new(connection, socket);
+            accept(&mut stream, &mut packets);
+        }
+        packets
+    });
+    (port, handle)
+}
+
+// Answer the packets of a client until it disconnects.
+fn accept(
+    stream: &mut rustls::StreamOwned<rustls::ServerConnection, TcpStream>,
+    packets: &mut Vec<Packet>,
+) {
+    loop {
+        // Read the fixed header byte by byte, until the remaining length is complete.
+        let mut frame = vec![0; 2];
+        stream.read_exact(&mut frame).unwrap();
+        let (mut remaining_length, mut shift) = (0, 0);
+        while let Some(&byte) = frame.last() {
+            remaining_length |= (byte as usize & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            frame.push(byte[0]);
+        }
+        let header = frame.len();
+        frame.resize(header + remaining_length, 0);
+        stream.read_exact(&mut frame[header..]).unwrap();
+
+        let packet = Packet::try_from(frame).unwrap();
+        let disconnect = matches!(packet, Packet::Disconnect(_));
+        if matches!(packet, Packet::Connect(_)) {
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+        }
+        packets.push(packet);
+
+        if disconnect {
+            return;
+        }
+    }
+}
@@ -37,7 +37,10 @@ pub async fn wiretapped_client(port: u16) -> (Client<TcpStream>, Transcription)
         loop {
             let future_1 = async {
                 loop {
-                    let bytes_required = client_parser.bytes_required() as usize;
+                    let bytes_required = client_parser
+                        .bytes_required()
+                        .unwrap_or_else(|error| panic!("Wiretap failed to parse packet: {error:?}"))
+                        as usize;
                     if bytes_required == 0 {
                         break;
                     }
@@ -55,7 +58,10 @@ pub async fn wiretapped_client(port: u16) -> (Client<TcpStream>, Transcription)
             };
             let future_2 = async {
                 loop {
-                    let bytes_required = broker_parser.bytes_required() as usize;
+                    let bytes_required = broker_parser
+                        .bytes_required()
+                        .unwrap_or_else(|error| panic!("Wiretap failed to parse packet: {error:?}"))
+                        as usize;
                     if bytes_required == 0 {
                         break;
                     }
@@ -254,7 +260,7 @@ impl Parser {
         self.inner.append(&mut data.to_vec());
     }
 
-    pub fn bytes_required(&self) -> u32 {
+    pub fn bytes_required(&self) -> Result<u32, DecodingError> {
         packet::min_bytes_required(&self.inner)
     }
 
@@ -32,6 +32,20 @@ mod aio {
         Client::new(connect, stream)
     }
 
+    // Open a connection to the server at `port`, send `connect` and wait for the CONNACK.
+    #[cfg(feature = "experimental")]
+    async fn handshake(port: u16, connect: Connect) -> TcpStream {
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        stream
+            .write_all(&Packet::from(connect).into_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(read_packet(&mut stream).await, Packet::ConnAck(_)));
+        stream
+    }
+
     // Connect a client to a broker.
     // Then, subscribe to a topic and publish to that same topic.
     // Verify that the client receives published message.
@@ -62,7 +76,8 @@ mod aio {
         assert_eq!(publish.payload(), b"test_subscribe_and_publish");
 
         // TODO GH-118: When uncommented, this line causes the test to become
-        // flaky.
+        // flaky. `ClientHandle::pings()` shows when the client sent its PINGREQs
+        // and whether they were answered.
         // let packet = history.find(PacketType::PinResp).await;
 
         handle.disconnect().await.unwrap();
@@ -209,55 +224,3232 @@ mod aio {
         assert_eq!(&publication.topic(), &"test/client_and_server");
         assert_eq!(&publication.payload(), b"test_subscribe_and_publish");
     }
-}
 
-#[cfg(feature = "blocking")]
-mod blocking {
-    use pretty_assertions::assert_eq;
-    use std::time::Duration;
-    use tjiftjaf::{
-        blocking::{self, Emit},
-        publish, subscribe, Connect,
-    };
+    // Verify that the server delivers publications for a shared subscription
+    // to the members of the group in turns.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_shared_subscription() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).run());
 
-    const TOPIC: &str = "topic";
+        let mut members = vec![];
+        for _ in 0..2 {
+            let (handle, task) = create_client(port).await.spawn();
+            smol::spawn(task).detach();
+            subscribe("$share/workers/jobs/#")
+                .emit(&handle)
+                .await
+                .unwrap();
 
-    fn create_blocking_client(port: u16) -> blocking::Client {
-        let stream = std::net::TcpStream::connect(format!("127.0.0.1:{}", port))
-            .expect("Failed to open TCP connection to broker.");
+            // Wait until the server processed the subscription.
+            while handle.statistics().await.unwrap().packets_read < 2 {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            members.push(handle);
+        }
 
-        let connect = Connect::builder().client_id("test").keep_alive(5).build();
-        blocking::Client::new(connect, stream)
+        let (publisher, task) = create_client(port).await.spawn();
+        smol::spawn(task).detach();
+        for job in 0..4 {
+            publish(&format!("jobs/{job}"), "work")
+                .emit(&publisher)
+                .await
+                .unwrap();
+        }
+
+        for handle in &mut members {
+            for _ in 0..2 {
+                let publication = handle.subscriptions().await.unwrap();
+                assert!(publication.topic().starts_with("jobs/"));
+            }
+        }
+
+        // Each member received half of the publications.
+        Timer::after(Duration::from_millis(100)).await;
+        for handle in &members {
+            assert_eq!(handle.statistics().await.unwrap().packets_read, 4);
+        }
     }
 
-    // Connect a client to a broker.
-    // Then, subscribe to a topic and publish to that same topic.
-    // Verify that the client receives published message.
-    #[test]
-    fn test_subscribe_and_publish_with_blocking_client() {
-        use crate::env::broker::Broker;
+    // Verify that the server delivers retained publications to new subscribers,
+    // and that a retained publication with an empty payload removes it.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_retained_publications() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).run());
 
-        let broker = Broker::new();
-        let (mut handle_a, task) = create_blocking_client(broker.port).spawn().unwrap();
+        // Connect a client that subscribes, and wait until the server processed it.
+        async fn subscriber(port: u16) -> tjiftjaf::aio::ClientHandle {
+            let (handle, task) = create_client(port).await.spawn();
+            smol::spawn(task).detach();
+            subscribe("status/#").emit(&handle).await.unwrap();
+            while handle.statistics().await.unwrap().packets_read < 2 {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            handle
+        }
 
-        subscribe(TOPIC).emit(&handle_a).unwrap();
+        // Once the publisher receives its own publication, the server processed it.
+        let mut publisher = subscriber(port).await;
+        Publish::builder("status/1", "online")
+            .retain(true)
+            .build()
+            .emit(&publisher)
+            .await
+            .unwrap();
+        publisher.subscriptions().await.unwrap();
 
-        // Until GH-71 is implemented, we need to introduce an artificial
-        // sleep.
-        //
-        // https://github.com/eastern-oak/tjiftjaf/issues/71
-        std::thread::sleep(Duration::from_secs(1));
+        let mut handle = subscriber(port).await;
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "status/1");
+        assert_eq!(publication.payload(), b"online");
+        assert!(publication.retain());
 
-        publish(TOPIC, "test_subscribe_and_publish")
-            .emit(&handle_a)
+        // Established subscriptions receive the publication with the retain flag set to 0.
+        Publish::builder("status/1", "")
+            .retain(true)
+            .build()
+            .emit(&publisher)
+            .await
             .unwrap();
+        let publication = handle.subscriptions().await.unwrap();
+        assert!(publication.payload().is_empty());
+        assert!(!publication.retain());
 
-        let publish = handle_a.publication().unwrap();
+        let handle = subscriber(port).await;
+        Timer::after(Duration::from_millis(100)).await;
+        assert_eq!(handle.statistics().await.unwrap().packets_read, 2);
+    }
 
-        assert_eq!(publish.topic(), TOPIC);
-        assert_eq!(publish.payload(), b"test_subscribe_and_publish");
+    // Verify that a client and the server that agreed to compress publications exchange
+    // deflated payloads, while other subscribers receive the original publication.
+    #[cfg(all(feature = "experimental", feature = "compression"))]
+    #[apply(test!)]
+    async fn test_server_compression() {
+        use tjiftjaf::compression::{self, Compression};
 
-        handle_a.disconnect().unwrap();
-        assert!(task.join().is_ok());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let compression = Compression::deflate().threshold(100);
+        let _server_handle = smol::spawn(Server::new(listener).compression(compression).run());
+
+        // Subscribe a connection without a client, and wait for the SUBACK.
+        async fn subscriber(port: u16, client_id: &str, offer: bool) -> TcpStream {
+            let mut stream = handshake(port, Connect::builder().client_id(client_id).build()).await;
+            if offer {
+                let offer = publish(compression::NEGOTIATION_TOPIC, "deflate");
+                let bytes = Packet::from(offer.clone()).into_bytes();
+                stream.write_all(&bytes).await.unwrap();
+                assert_eq!(read_packet(&mut stream).await, offer.into());
+            }
+            let bytes = subscribe("data/#").into_bytes();
+            stream.write_all(&bytes).await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::SubAck(_)));
+            stream
+        }
+        let mut deflating = subscriber(port, "deflating", true).await;
+        let mut plain = subscriber(port, "plain", false).await;
+
+        let client = create_client(port).await.compression(compression);
+        let (mut handle, task) = client.spawn();
+        let _task = smol::spawn(task);
+        subscribe("data/#").emit(&handle).await.unwrap();
+
+        let payload = vec![b'a'; 100];
+        publish("data/1", payload.clone())
+            .emit(&handle)
+            .await
+            .unwrap();
+
+        let Packet::Publish(deflated) = read_packet(&mut deflating).await else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(deflated.topic(), "$tjiftjaf/deflate/data/1");
+        assert!(deflated.payload().len() < 20);
+
+        let Packet::Publish(publication) = read_packet(&mut plain).await else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publication.topic(), "data/1");
+        assert_eq!(publication.payload(), payload);
+
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "data/1");
+        assert_eq!(publication.payload(), payload);
+    }
+
+    // Verify that the server refuses the connections its `Authenticator` rejects,
+    // and accepts the others.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_authenticator() {
+        use tjiftjaf::{packet::connack::ReturnCode, ConnectError};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let authenticator =
+            |client_id: &str, username: Option<&str>, password: Option<&[u8]>| match (
+                client_id, username, password,
+            ) {
+                ("intruder", _, _) => ReturnCode::ConnectionRefusedNotAuthorized,
+                (_, Some("sensor"), Some(b"secret")) => ReturnCode::ConnectionAccepted,
+                _ => ReturnCode::ConnectionRefusedBadUsernameOrPassword,
+            };
+        let _server_handle = smol::spawn(Server::new(listener).authenticator(authenticator).run());
+
+        async fn connect(port: u16, client_id: &str, password: &str) -> Result<(), ConnectError> {
+            let connect = Connect::builder()
+                .client_id(client_id)
+                .username("sensor")
+                .password(password)
+                .build();
+            let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+                .await
+                .unwrap();
+            let (handle, task) = Client::new(connect, stream).spawn();
+            let task = smol::spawn(task);
+            match handle.subscribe(subscribe("sensor/#")).await {
+                Ok(_) => Ok(()),
+                Err(_) => {
+                    let error = task.await.unwrap_err();
+                    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+                    Err(*error.get_ref().unwrap().downcast_ref().unwrap())
+                }
+            }
+        }
+
+        assert_eq!(connect(port, "sensor-1", "secret").await, Ok(()));
+        assert_eq!(
+            connect(port, "sensor-2", "guess").await,
+            Err(ConnectError::Refused(
+                ReturnCode::ConnectionRefusedBadUsernameOrPassword
+            ))
+        );
+        assert_eq!(
+            connect(port, "intruder", "secret").await,
+            Err(ConnectError::Refused(
+                ReturnCode::ConnectionRefusedNotAuthorized
+            ))
+        );
+    }
+
+    // Verify that the server drops the publications and refuses the subscriptions its
+    // `Authorizer` doesn't authorize.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_authorizer() {
+        use tjiftjaf::{aio::server::Authorizer, packet::suback::ReturnCode, QoS};
+
+        struct Sensors;
+
+        impl Authorizer for Sensors {
+            fn can_publish(&self, client_id: &str, topic: &str) -> bool {
+                client_id.starts_with("sensor") && topic.starts_with("public/")
+            }
+
+            fn can_subscribe(&self, _client_id: &str, filter: &str) -> bool {
+                filter.starts_with("public/")
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).authorizer(Sensors).run());
+
+        let mut subscriber = handshake(port, Connect::builder().client_id("display").build()).await;
+        let bytes = Subscribe::builder("public/#", QoS::AtMostOnceDelivery)
+            .add_topic("private/#", QoS::AtMostOnceDelivery)
+            .build_packet()
+            .into_bytes();
+        subscriber.write_all(&bytes).await.unwrap();
+        let Packet::SubAck(suback) = read_packet(&mut subscriber).await else {
+            panic!("Expected a SUBACK");
+        };
+        assert_eq!(
+            suback.return_codes(),
+            vec![
+                ReturnCode::QoS(QoS::AtMostOnceDelivery),
+                ReturnCode::Failure
+            ]
+        );
+
+        let mut intruder = handshake(port, Connect::builder().client_id("intruder").build()).await;
+        let bytes = Packet::from(publish("public/1", "intruder")).into_bytes();
+        intruder.write_all(&bytes).await.unwrap();
+
+        let mut sensor = handshake(port, Connect::builder().client_id("sensor-1").build()).await;
+        for topic in ["private/1", "public/1"] {
+            let bytes = Packet::from(publish(topic, "sensor")).into_bytes();
+            sensor.write_all(&bytes).await.unwrap();
+        }
+
+        let Packet::Publish(publication) = read_packet(&mut subscriber).await else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publication.topic(), "public/1");
+        assert_eq!(publication.payload(), b"sensor");
+    }
+
+    // Verify that the server enforces its `RetainedLimits`.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_retained_limits() {
+        use tjiftjaf::aio::server::RetainedLimits;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let limits = RetainedLimits::new()
+            .max_messages(1)
+            .max_payload_size(2)
+            .expiry(Duration::from_millis(300));
+        let _server_handle = smol::spawn(Server::new(listener).retained_limits(limits).run());
+
+        // The publisher subscribes as well. Once it receives its own publications,
+        // the server processed them.
+        let (mut publisher, task) = create_client(port).await.spawn();
+        smol::spawn(task).detach();
+        subscribe("a/#").emit(&publisher).await.unwrap();
+        while publisher.statistics().await.unwrap().packets_read < 2 {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        // The first is too large, the third exceeds the number of retained publications.
+        for (topic, payload) in [("a/1", "off"), ("a/2", "on"), ("a/3", "on")] {
+            Publish::builder(topic, payload)
+                .retain(true)
+                .build()
+                .emit(&publisher)
+                .await
+                .unwrap();
+        }
+        for _ in 0..3 {
+            publisher.subscriptions().await.unwrap();
+        }
+
+        // Connect a client that subscribes, and count the packets it reads.
+        async fn packets_read(port: u16) -> usize {
+            let (handle, task) = create_client(port).await.spawn();
+            smol::spawn(task).detach();
+            subscribe("a/#").emit(&handle).await.unwrap();
+            Timer::after(Duration::from_millis(100)).await;
+            handle.statistics().await.unwrap().packets_read
+        }
+
+        // CONNACK, SUBACK and the publication on 'a/2'.
+        assert_eq!(packets_read(port).await, 3);
+
+        Timer::after(Duration::from_millis(300)).await;
+        assert_eq!(packets_read(port).await, 2);
+    }
+
+    // Verify that the server publishes the will of a client that disconnects
+    // without a DISCONNECT packet, honoring the QoS and retain flag of the will.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_will() {
+        use tjiftjaf::{Disconnect, QoS};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).run());
+
+        let (mut handle, task) = create_client(port).await.spawn();
+        smol::spawn(task).detach();
+        subscribe("status/#").emit(&handle).await.unwrap();
+        while handle.statistics().await.unwrap().packets_read < 2 {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        // A client that disconnects gracefully doesn't trigger its will.
+        let mut stream = handshake(
+            port,
+            Connect::builder()
+                .client_id("graceful")
+                .will("status/graceful", "offline")
+                .build(),
+        )
+        .await;
+        stream
+            .write_all(&Packet::from(Disconnect).into_bytes())
+            .await
+            .unwrap();
+        drop(stream);
+
+        // A client whose socket closes does.
+        let stream = handshake(
+            port,
+            Connect::builder()
+                .client_id("dropped")
+                .will("status/dropped", "offline")
+                .will_qos(QoS::AtLeastOnceDelivery)
+                .retain_will()
+                .build(),
+        )
+        .await;
+        drop(stream);
+
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "status/dropped");
+        assert_eq!(publication.payload(), b"offline");
+        assert_eq!(publication.qos(), QoS::AtLeastOnceDelivery);
+
+        // So does a client that exceeds its keep alive interval.
+        let _stream = handshake(
+            port,
+            Connect::builder()
+                .client_id("silent")
+                .keep_alive(1)
+                .will("status/silent", "offline")
+                .build(),
+        )
+        .await;
+
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "status/silent");
+
+        // The will of `dropped` was retained.
+        let (mut handle, task) = create_client(port).await.spawn();
+        smol::spawn(task).detach();
+        subscribe("status/dropped").emit(&handle).await.unwrap();
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.payload(), b"offline");
+        assert!(publication.retain());
+    }
+
+    // Verify that the server completes the handshake of a will with QoS 2 with the
+    // subscriber, and acknowledges the UNSUBSCRIBE of the subscriber afterwards.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_qos_2_will_and_unsubscribe() {
+        use tjiftjaf::{PingReq, PubComp, PubRec, QoS, Unsubscribe};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).run());
+
+        let mut subscriber = handshake(port, Connect::builder().client_id("sub").build()).await;
+        let subscribe = Subscribe::builder("status/#", QoS::ExactlyOnceDelivery)
+            .packet_identifier(1)
+            .build();
+        subscriber.write_all(subscribe.as_bytes()).await.unwrap();
+        assert!(matches!(
+            read_packet(&mut subscriber).await,
+            Packet::SubAck(_)
+        ));
+
+        let stream = handshake(
+            port,
+            Connect::builder()
+                .client_id("dropped")
+                .will("status/dropped", "offline")
+                .will_qos(QoS::ExactlyOnceDelivery)
+                .build(),
+        )
+        .await;
+        drop(stream);
+
+        let Packet::Publish(publish) = read_packet(&mut subscriber).await else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publish.qos(), QoS::ExactlyOnceDelivery);
+        let packet_identifier = publish.packet_identifier().unwrap();
+        subscriber
+            .write_all(&Packet::from(PubRec::new(packet_identifier)).into_bytes())
+            .await
+            .unwrap();
+        let Packet::PubRel(pubrel) = read_packet(&mut subscriber).await else {
+            panic!("Expected a PUBREL");
+        };
+        assert_eq!(pubrel.packet_identifier(), packet_identifier);
+        subscriber
+            .write_all(&Packet::from(PubComp::new(packet_identifier)).into_bytes())
+            .await
+            .unwrap();
+
+        let unsubscribe = Unsubscribe::builder("status/#")
+            .packet_identifier(2)
+            .build();
+        subscriber.write_all(unsubscribe.as_bytes()).await.unwrap();
+        let Packet::UnsubAck(unsuback) = read_packet(&mut subscriber).await else {
+            panic!("Expected an UNSUBACK");
+        };
+        assert_eq!(unsuback.packet_identifier(), 2);
+
+        // The connection is still open.
+        subscriber
+            .write_all(&Packet::from(PingReq).into_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_packet(&mut subscriber).await,
+            Packet::PingResp(_)
+        ));
+    }
+
+    // Verify that a connection with the client id of a connected client takes over:
+    // the server closes the old connection, which publishes its will, and the new
+    // connection continues its subscriptions.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_client_takeover() {
+        use std::sync::{Arc, Mutex};
+        use tjiftjaf::aio::server::Event;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let events = Arc::new(Mutex::new(vec![]));
+        let hook = {
+            let events = events.clone();
+            move |event: &Event| events.lock().unwrap().push(event.clone())
+        };
+        let _server_handle = smol::spawn(Server::new(listener).hook(hook).run());
+
+        let (mut observer, task) = create_client(port).await.spawn();
+        smol::spawn(task).detach();
+        subscribe("status/#").emit(&observer).await.unwrap();
+        while observer.statistics().await.unwrap().packets_read < 2 {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        let mut old = handshake(
+            port,
+            Connect::builder()
+                .client_id("sensor")
+                .will("status/sensor", "offline")
+                .build(),
+        )
+        .await;
+        old.write_all(&Packet::from(subscribe("sensor/#")).into_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(read_packet(&mut old).await, Packet::SubAck(_)));
+
+        let mut new = handshake(port, Connect::builder().client_id("sensor").build()).await;
+        let mut buffer = [0; 1];
+        assert_eq!(old.read(&mut buffer).await.unwrap(), 0);
+        let will = observer.subscriptions().await.unwrap();
+        assert_eq!(will.topic(), "status/sensor");
+
+        publish("sensor/1", "26.1").emit(&observer).await.unwrap();
+        let Packet::Publish(publication) = read_packet(&mut new).await else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publication.topic(), "sensor/1");
+
+        let events: Vec<Event> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.client_id() == "sensor")
+            .cloned()
+            .collect();
+        let client_id = String::from("sensor");
+        assert_eq!(
+            events,
+            [
+                Event::Connected {
+                    client_id: client_id.clone()
+                },
+                Event::Subscribed {
+                    client_id: client_id.clone(),
+                    filters: vec!["sensor/#".into()]
+                },
+                Event::Disconnected {
+                    client_id: client_id.clone(),
+                    graceful: false
+                },
+                Event::Connected { client_id },
+            ]
+        );
+    }
+
+    // Verify that the hook of the server observes connections, subscriptions
+    // and disconnections.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_hook() {
+        use std::sync::{Arc, Mutex};
+        use tjiftjaf::aio::server::Event;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let events = Arc::new(Mutex::new(vec![]));
+        let hook = {
+            let events = events.clone();
+            move |event: &Event| events.lock().unwrap().push(event.clone())
+        };
+        let _server_handle = smol::spawn(Server::new(listener).hook(hook).run());
+
+        let (handle, task) = create_client(port).await.spawn();
+        let task = smol::spawn(task);
+        Subscribe::builder("sensor/#", tjiftjaf::QoS::AtMostOnceDelivery)
+            .add_topic("status", tjiftjaf::QoS::AtMostOnceDelivery)
+            .build()
+            .emit(&handle)
+            .await
+            .unwrap();
+        while handle.statistics().await.unwrap().packets_read < 2 {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        handle.disconnect().await.unwrap();
+        task.await.unwrap();
+
+        while events.lock().unwrap().len() < 3 {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        let events = events.lock().unwrap();
+        let client_id = events[0].client_id().to_owned();
+        assert_eq!(
+            *events,
+            [
+                Event::Connected {
+                    client_id: client_id.clone()
+                },
+                Event::Subscribed {
+                    client_id: client_id.clone(),
+                    filters: vec!["sensor/#".into(), "status".into()]
+                },
+                Event::Disconnected {
+                    client_id,
+                    graceful: true
+                },
+            ]
+        );
+    }
+
+    // Verify that the server counts publications per topic, aggregates them within its
+    // limits, and reports the busiest topics on a $SYS topic.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_topic_metrics() {
+        use tjiftjaf::aio::server::{TopicMetrics, TopicStats};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let metrics = TopicMetrics::new()
+            .depth(2)
+            .max_topics(2)
+            .report(Duration::from_millis(50), 2);
+        let _server_handle =
+            smol::spawn(Server::new(listener).topic_metrics(metrics.clone()).run());
+
+        let (mut handle, task) = create_client(port).await.spawn();
+        smol::spawn(task).detach();
+        subscribe("$SYS/broker/topics/busiest")
+            .emit(&handle)
+            .await
+            .unwrap();
+        while handle.statistics().await.unwrap().packets_read < 2 {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        for (topic, payload) in [
+            ("sensor/1/temperature", "21"),
+            ("sensor/1/humidity", "80%"),
+            ("status", "online"),
+            ("sensor/1/temperature", "22"),
+            ("other/1", "1"),
+        ] {
+            publish(topic, payload).emit(&handle).await.unwrap();
+        }
+
+        let expected = r#"[{"topic":"sensor/1/#","messages":3,"bytes":7},{"topic":"status","messages":1,"bytes":6}]"#;
+        loop {
+            let report = handle.subscriptions().await.unwrap();
+            assert_eq!(report.topic(), "$SYS/broker/topics/busiest");
+            if report.payload() == expected.as_bytes() {
+                break;
+            }
+        }
+
+        let stats = |topic: &str, messages, bytes| TopicStats {
+            topic: topic.into(),
+            messages,
+            bytes,
+        };
+        assert_eq!(
+            metrics.top(5),
+            [
+                stats("sensor/1/#", 3, 7),
+                stats("status", 1, 6),
+                stats("#", 1, 1)
+            ]
+        );
+
+        metrics.clear();
+        assert_eq!(metrics.top(5), []);
+    }
+
+    // Verify that the server drops connections that don't send a CONNECT in time,
+    // or that announce a CONNECT that's too large. Other clients can still connect.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_handshake_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Server::new(listener)
+            .handshake_timeout(Duration::from_millis(100))
+            .max_connect_size(64);
+        let _server_handle = smol::spawn(server.run());
+
+        // Wait until the server closes `stream`.
+        async fn closed(mut stream: TcpStream) {
+            let read = async { matches!(stream.read(&mut [0; 8]).await, Ok(0) | Err(_)) };
+            let timeout = async {
+                Timer::after(Duration::from_secs(5)).await;
+                false
+            };
+            assert!(futures_lite::future::race(read, timeout).await);
+        }
+
+        let silent = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        closed(silent).await;
+
+        // The fixed header of a CONNECT of 127 bytes.
+        let mut oversized = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        oversized.write_all(&[0x10, 0x7f]).await.unwrap();
+        closed(oversized).await;
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _handle = smol::spawn(task);
+        while handle.debug_snapshot().await.unwrap().connection_status
+            != tjiftjaf::ConnectionStatus::Connected
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+    }
+
+    // Verify that the server throttles or disconnects clients that exceed their rate limit.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_rate_limit() {
+        use std::time::Instant;
+        use tjiftjaf::{
+            aio::server::{RateLimit, RateLimitPolicy},
+            PingReq,
+        };
+
+        // Connect to the server at `port` and send 5 publications, followed by a PINGREQ.
+        async fn flood(port: u16) -> TcpStream {
+            let mut stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+                .await
+                .unwrap();
+            let connect = Connect::builder().client_id("flood").build();
+            stream
+                .write_all(&Packet::from(connect).into_bytes())
+                .await
+                .unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::ConnAck(_)));
+
+            for _ in 0..5 {
+                stream
+                    .write_all(&publish("sensor/1", "26.1").into_bytes())
+                    .await
+                    .unwrap();
+            }
+            stream
+                .write_all(&Packet::from(PingReq).into_bytes())
+                .await
+                .unwrap();
+            stream
+        }
+
+        // With 4 messages per second, the burst of 5 messages exceeds the
+        // limit by 1. The server reads the PINGREQ after ~250 ms.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let limit = RateLimit::new().messages_per_second(4);
+        let _server = smol::spawn(Server::new(listener).rate_limit(limit).run());
+
+        let start = Instant::now();
+        let mut stream = flood(port).await;
+        assert!(matches!(
+            read_packet(&mut stream).await,
+            Packet::PingResp(_)
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let limit = RateLimit::new()
+            .messages_per_second(4)
+            .policy(RateLimitPolicy::Disconnect);
+        let _server = smol::spawn(Server::new(listener).rate_limit(limit).run());
+
+        let mut stream = flood(port).await;
+        assert!(matches!(stream.read(&mut [0; 8]).await, Ok(0) | Err(_)));
+    }
+
+    // Verify that the fan-out applies its overflow policy to subscribers with a full queue,
+    // and reports subscribers whose queue is closed.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_server_fan_out() {
+        use tjiftjaf::aio::server::{FanOut, OverflowPolicy};
+
+        let packet: Packet = publish("sensor/1", "26.1").into();
+        let (full, _full) = async_channel::bounded(1);
+        full.try_send(packet.clone()).unwrap();
+        let (open, open_receiver) = async_channel::bounded(1);
+        let (closed, _) = async_channel::bounded::<Packet>(1);
+
+        let fan_out = FanOut::new().overflow_policy(OverflowPolicy::Drop);
+        let failed = fan_out.deliver(&packet, &[&full, &open, &closed]).await;
+        assert_eq!(failed, vec![2]);
+        assert_eq!(full.len(), 1);
+        assert!(open_receiver.try_recv().is_ok());
+
+        let fan_out = FanOut::new().overflow_policy(OverflowPolicy::Disconnect);
+        let failed = fan_out.deliver(&packet, &[&full, &open, &closed]).await;
+        assert_eq!(failed, vec![0, 2]);
+        assert!(open_receiver.try_recv().is_ok());
+
+        let (wide, wide_receiver) = async_channel::bounded(2);
+        let fan_out = FanOut::new().workers(2);
+        let failed = fan_out.deliver(&packet, &[&wide, &closed, &wide]).await;
+        assert_eq!(failed, vec![1]);
+        assert_eq!(wide_receiver.len(), 2);
+    }
+
+    // Same as `test_client_and_server()`, but the clients use a dedicated writer.
+    // Also verify that the client terminates cleanly.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_client_and_server_with_dedicated_writer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).run());
+
+        let (mut handle_1, task) = create_client(port).await.dedicated_writer().spawn();
+        let _handle = smol::spawn(task);
+        let (handle_2, task_2) = create_client(port).await.dedicated_writer().spawn();
+        let task_2 = smol::spawn(task_2);
+
+        subscribe("test/#").emit(&handle_1).await.unwrap();
+
+        // Wait until the server processed the subscription.
+        while handle_1
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_read
+            < 2
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        for n in 0..20 {
+            publish("test/dedicated_writer", format!("{n}"))
+                .emit(&handle_2)
+                .await
+                .unwrap();
+        }
+
+        // Wait until all publications are handed to the writer.
+        while handle_2
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_sent
+            < 21
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        handle_2.disconnect().await.unwrap();
+        assert!(task_2.await.is_ok());
+
+        for _ in 0..20 {
+            let publication = handle_1.subscriptions().await.unwrap();
+            assert_eq!(publication.topic(), "test/dedicated_writer");
+        }
+    }
+
+    // Publish over a socket that stops accepting writes after the CONNECT. Verify that
+    // a client with a dedicated writer stops accepting publications, instead of queueing
+    // them without bound.
+    #[apply(test!)]
+    async fn test_dedicated_writer_backpressure() {
+        use futures_lite::{AsyncRead, AsyncWrite};
+        use std::{
+            pin::Pin,
+            task::{Context, Poll},
+        };
+
+        // Reading yields a CONNACK and then blocks forever. Only the first write completes.
+        struct Socket {
+            inbound: Vec<u8>,
+            written: bool,
+        }
+
+        impl AsyncRead for Socket {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<std::io::Result<usize>> {
+                if self.inbound.is_empty() {
+                    return Poll::Pending;
+                }
+                let length = buf.len().min(self.inbound.len());
+                buf[..length].copy_from_slice(&self.inbound[..length]);
+                self.inbound.drain(..length);
+                Poll::Ready(Ok(length))
+            }
+        }
+
+        impl AsyncWrite for Socket {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                if self.written {
+                    return Poll::Pending;
+                }
+                self.written = true;
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let socket = Socket {
+            inbound: Packet::from(ConnAck::builder().build()).into_bytes(),
+            written: false,
+        };
+        let client = Client::new(Connect::builder().build(), socket).dedicated_writer();
+        let (handle, task) = client.spawn();
+        let _task = smol::spawn(task);
+
+        let mut accepted = 0;
+        for _ in 0..5000 {
+            let emit = async { Some(publish(TOPIC, "26.1").emit(&handle).await) };
+            let timeout = async {
+                Timer::after(Duration::from_millis(500)).await;
+                None
+            };
+            match futures_lite::future::or(emit, timeout).await {
+                Some(result) => result.unwrap(),
+                None => break,
+            }
+            accepted += 1;
+        }
+        assert!(
+            accepted < 5000,
+            "The client accepted {accepted} publications"
+        );
+    }
+
+    // Verify that `ClientHandle::raw_packets()` yields packets
+    // other than PUBLISH, while `ClientHandle::subscriptions()`
+    // still yields the publications.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_raw_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let _server_handle = smol::spawn(Server::new(listener).run());
+
+        let (mut handle, task) = create_client(local_addr.port()).await.spawn();
+
+        // Register the stream before the task runs, so it receives the CONNACK.
+        let mut packets = handle.raw_packets().await.unwrap();
+        let _handle = smol::spawn(task);
+
+        subscribe(TOPIC).emit(&handle).await.unwrap();
+
+        let packet = packets.next().await.unwrap();
+        assert_eq!(packet.packet_type(), PacketType::ConnAck);
+
+        let packet = packets.next().await.unwrap();
+        assert_eq!(packet.packet_type(), PacketType::SubAck);
+
+        publish(TOPIC, "test_raw_packets")
+            .emit(&handle)
+            .await
+            .unwrap();
+
+        let packet = packets.next().await.unwrap();
+        assert_eq!(packet.packet_type(), PacketType::Publish);
+
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.payload(), b"test_raw_packets");
+    }
+
+    // Suspend a client and resume it on a new connection.
+    // Verify that the subscriptions of the client survive.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_suspend_and_resume() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).run());
+
+        let (handle_1, task) = create_client(port).await.spawn();
+        let task = smol::spawn(task);
+        let (handle_2, task_2) = create_client(port).await.spawn();
+        let _handle = smol::spawn(task_2);
+
+        // Wait until the connection is established.
+        while handle_1.debug_snapshot().await.unwrap().connection_status
+            != tjiftjaf::ConnectionStatus::Connected
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        subscribe("test/#").emit(&handle_1).await.unwrap();
+        let session = handle_1.suspend().await.unwrap();
+        assert!(task.await.is_ok());
+        assert_eq!(session.subscriptions().len(), 1);
+
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let (mut handle_1, task) = Client::resume(session, stream).spawn();
+        let _handle = smol::spawn(task);
+
+        // Wait until the server processed the subscription.
+        while handle_1
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_read
+            < 2
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        publish("test/suspend_and_resume", "resumed")
+            .emit(&handle_2)
+            .await
+            .unwrap();
+
+        let publication = handle_1.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "test/suspend_and_resume");
+    }
+
+    // Read a packet smaller than 128 bytes from `stream`.
+    async fn read_packet(stream: &mut TcpStream) -> Packet {
+        let mut frame = vec![0; 2];
+        stream.read_exact(&mut frame).await.unwrap();
+        frame.resize(2 + frame[1] as usize, 0);
+        stream.read_exact(&mut frame[2..]).await.unwrap();
+        Packet::try_from(frame).unwrap()
+    }
+
+    // Connect to a server at an IPv6 literal and publish a message.
+    #[apply(test!)]
+    async fn test_connect_ipv6() {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            read_packet(&mut stream).await
+        });
+
+        let connect = Connect::builder().build();
+        let client = Client::connect_tcp(format!("[::1]:{port}"), connect)
+            .await
+            .unwrap();
+        let (handle, task) = client.spawn();
+        let _task = smol::spawn(task);
+
+        publish("sensor/1", "26.1").emit(&handle).await.unwrap();
+        let Packet::Publish(publish) = server.await else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publish.topic(), "sensor/1");
+    }
+
+    // Verify that the task of a client that the server refuses, or doesn't answer,
+    // fails with a `ConnectError`.
+    #[apply(test!)]
+    async fn test_connect_error() {
+        use tjiftjaf::{packet::connack::ReturnCode, ConnectError};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut refused, _) = listener.accept().await.unwrap();
+            assert!(matches!(
+                read_packet(&mut refused).await,
+                Packet::Connect(_)
+            ));
+            let connack = ConnAck::builder()
+                .return_code(ReturnCode::ConnectionRefusedNotAuthorized)
+                .build();
+            refused
+                .write_all(&Packet::from(connack).into_bytes())
+                .await
+                .unwrap();
+
+            // Accept the second connection, but never answer it.
+            let (silent, _) = listener.accept().await.unwrap();
+            future::pending::<()>().await;
+            drop((refused, silent));
+        });
+
+        let connect_error = |error: std::io::Error| {
+            *error
+                .get_ref()
+                .and_then(|error| error.downcast_ref::<ConnectError>())
+                .unwrap()
+        };
+
+        let (_handle, task) = create_client(port).await.spawn();
+        let error = task.await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            connect_error(error),
+            ConnectError::Refused(ReturnCode::ConnectionRefusedNotAuthorized)
+        );
+
+        let client = create_client(port)
+            .await
+            .connect_timeout(Duration::from_millis(100));
+        let (_handle, task) = client.spawn();
+        let error = task.await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(connect_error(error), ConnectError::Timeout);
+    }
+
+    // Verify that the client acknowledges publications, even if the
+    // application doesn't consume them.
+    #[apply(test!)]
+    async fn test_acknowledge_while_application_lags() {
+        use tjiftjaf::QoS;
+        const PUBLICATIONS: u16 = 150;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            for packet_identifier in 1..=PUBLICATIONS {
+                let publish = Publish::builder("sensor/1", "26.1")
+                    .qos(QoS::AtLeastOnceDelivery)
+                    .packet_identifier(packet_identifier)
+                    .build();
+                stream.write_all(publish.as_bytes()).await.unwrap();
+            }
+
+            for packet_identifier in 1..=PUBLICATIONS {
+                let Packet::PubAck(puback) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBACK");
+                };
+                assert_eq!(puback.packet_identifier(), packet_identifier);
+            }
+        });
+
+        let (_handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            panic!("Not all publications were acknowledged.");
+        };
+        futures_lite::future::race(server, timeout).await;
+    }
+
+    // Publish more QoS 1 messages than the client queues for its handle, while the
+    // application never drains the handle. Verify that all of them are acknowledged.
+    #[apply(test!)]
+    async fn test_publish_while_application_lags() {
+        use tjiftjaf::{PubAck, QoS};
+        const PUBLICATIONS: usize = 300;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            loop {
+                let Packet::Publish(publish) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBLISH");
+                };
+                let puback = PubAck::new(publish.packet_identifier().unwrap());
+                stream
+                    .write_all(&Packet::from(puback).into_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let publishing = async {
+            let mut tokens = vec![];
+            for n in 0..PUBLICATIONS {
+                let publish = Publish::builder("sensor/1", n.to_string())
+                    .qos(QoS::AtLeastOnceDelivery)
+                    .build();
+                tokens.push(handle.publish(publish).await.unwrap());
+            }
+            for token in tokens {
+                token.await.unwrap();
+            }
+        };
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            panic!("Not all publications were acknowledged.");
+        };
+        futures_lite::future::race(publishing, timeout).await;
+    }
+
+    // Verify that a QoS 2 publication that the server retransmits before it receives
+    // the PUBREC is acknowledged twice, but delivered to the application once.
+    #[apply(test!)]
+    async fn test_qos_2_retransmission_delivered_once() {
+        use tjiftjaf::{PubRel, QoS};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            for duplicate in [false, true] {
+                let publication = Publish::builder("sensor/1", "26.1")
+                    .qos(QoS::ExactlyOnceDelivery)
+                    .packet_identifier(7)
+                    .duplicate(duplicate)
+                    .build();
+                stream.write_all(publication.as_bytes()).await.unwrap();
+            }
+            for _ in 0..2 {
+                let Packet::PubRec(pubrec) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBREC");
+                };
+                assert_eq!(pubrec.packet_identifier(), 7);
+            }
+
+            stream
+                .write_all(&Packet::from(PubRel::new(7)).into_bytes())
+                .await
+                .unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::PubComp(_)));
+            stream
+                .write_all(publish("sensor/2", "26.2").as_bytes())
+                .await
+                .unwrap();
+            Timer::after(Duration::from_secs(5)).await;
+        });
+
+        let (mut handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "sensor/1");
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "sensor/2");
+    }
+
+    // Verify that with `DeliveryMode::Broadcast` every broadcast `Subscription` receives
+    // every publication.
+    #[apply(test!)]
+    async fn test_broadcast_delivery() {
+        use tjiftjaf::aio::DeliveryMode;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            // The client subscribes after it registered both broadcasts.
+            assert!(matches!(
+                read_packet(&mut stream).await,
+                Packet::Subscribe(_)
+            ));
+            for topic in ["sensor/1", "alarm/1"] {
+                stream
+                    .write_all(publish(topic, "26.1").as_bytes())
+                    .await
+                    .unwrap();
+            }
+            Timer::after(Duration::from_secs(5)).await;
+        });
+
+        let client = create_client(port)
+            .await
+            .delivery_mode(DeliveryMode::Broadcast);
+        let (handle, task) = client.spawn();
+        let _task = smol::spawn(task);
+
+        let mut logger = handle.broadcast().await.unwrap();
+        let mut dashboard = handle.broadcast().await.unwrap();
+        subscribe("#").emit(&handle).await.unwrap();
+
+        for subscription in [&mut logger, &mut dashboard] {
+            assert_eq!(subscription.next().await.unwrap().topic(), "sensor/1");
+            assert_eq!(subscription.next().await.unwrap().topic(), "alarm/1");
+        }
+    }
+
+    // Verify that `ClientHandle::broadcast()` fails without `DeliveryMode::Broadcast`,
+    // instead of returning a `Subscription` that never yields.
+    #[apply(test!)]
+    async fn test_broadcast_requires_broadcast_delivery() {
+        use tjiftjaf::HandleError;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (handle, _task) = create_client(port).await.spawn();
+        assert!(matches!(
+            handle.broadcast().await,
+            Err(HandleError::Unsupported)
+        ));
+    }
+
+    // Limit the number of buffered publications. Verify that the client withholds the
+    // acknowledgements of publications beyond the limit, but keeps reading other packets.
+    // Once the application consumes publications, the remaining ones are acknowledged.
+    #[apply(test!)]
+    async fn test_max_buffered_publications() {
+        use tjiftjaf::{unsubscribe, QoS, UnsubAck};
+        // The channel to the handle holds 100 publications. The client buffers 10 more.
+        const PUBLICATIONS: u16 = 150;
+        const ACKNOWLEDGED: u16 = 110;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (throttled, throttling) = async_channel::bounded(1);
+        let server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            for packet_identifier in 1..=PUBLICATIONS {
+                let publish = Publish::builder("sensor/1", "26.1")
+                    .qos(QoS::AtLeastOnceDelivery)
+                    .packet_identifier(packet_identifier)
+                    .build();
+                stream.write_all(publish.as_bytes()).await.unwrap();
+            }
+            for packet_identifier in 1..=ACKNOWLEDGED {
+                let Packet::PubAck(puback) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBACK");
+                };
+                assert_eq!(puback.packet_identifier(), packet_identifier);
+            }
+            throttled.send(()).await.unwrap();
+
+            // The client must read the UNSUBACK, while it withholds the other PUBACKs.
+            let Packet::Unsubscribe(unsubscribe) = read_packet(&mut stream).await else {
+                panic!("Expected an UNSUBSCRIBE");
+            };
+            let unsuback = UnsubAck::new(unsubscribe.packet_identifier());
+            stream
+                .write_all(&Packet::from(unsuback).into_bytes())
+                .await
+                .unwrap();
+
+            for packet_identifier in ACKNOWLEDGED + 1..=PUBLICATIONS {
+                let Packet::PubAck(puback) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBACK");
+                };
+                assert_eq!(puback.packet_identifier(), packet_identifier);
+            }
+        });
+
+        let (mut handle, task) = create_client(port)
+            .await
+            .max_buffered_publications(10)
+            .spawn();
+        let _task = smol::spawn(task);
+
+        let client = async {
+            throttling.recv().await.unwrap();
+            handle.unsubscribe(unsubscribe("sensor/2")).await.unwrap();
+            for _ in 0..PUBLICATIONS {
+                handle.subscriptions().await.unwrap();
+            }
+            server.await;
+        };
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            panic!("Not all publications were acknowledged.");
+        };
+        futures_lite::future::race(client, timeout).await;
+    }
+
+    // Verify that `ClientHandle::unsubscribe()` returns once the server sent an UNSUBACK,
+    // and that it ends the streams of the topic filter.
+    #[apply(test!)]
+    async fn test_unsubscribe() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use tjiftjaf::{unsubscribe_many, QoS, SubAck, UnsubAck};
+
+        let acknowledged = Arc::new(AtomicBool::new(false));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn({
+            let acknowledged = Arc::clone(&acknowledged);
+            async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+                stream
+                    .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                    .await
+                    .unwrap();
+                let Packet::Subscribe(subscribe) = read_packet(&mut stream).await else {
+                    panic!("Expected a SUBSCRIBE");
+                };
+                let suback =
+                    SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery).build();
+                stream
+                    .write_all(&Packet::from(suback).into_bytes())
+                    .await
+                    .unwrap();
+
+                let Packet::Unsubscribe(unsubscribe) = read_packet(&mut stream).await else {
+                    panic!("Expected an UNSUBSCRIBE");
+                };
+                assert_eq!(
+                    unsubscribe.topics().collect::<Vec<_>>(),
+                    ["sensor/1/#", "sensor/2/#"]
+                );
+
+                Timer::after(Duration::from_millis(100)).await;
+                acknowledged.store(true, Ordering::SeqCst);
+                let unsuback = UnsubAck::new(unsubscribe.packet_identifier());
+                stream
+                    .write_all(&Packet::from(unsuback).into_bytes())
+                    .await
+                    .unwrap();
+                future::pending::<()>().await;
+            }
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+        let mut subscription = handle.subscribe_stream("sensor/1/#").await.unwrap();
+
+        handle
+            .unsubscribe(unsubscribe_many(&["sensor/1/#", "sensor/2/#"]).unwrap())
+            .await
+            .unwrap();
+        assert!(acknowledged.load(Ordering::SeqCst));
+        assert!(subscription.next().await.is_none());
+    }
+
+    // Verify that subscribing reports the QoS granted by the broker, and fails
+    // when the broker rejects a topic.
+    #[apply(test!)]
+    async fn test_subscribe_reports_granted_qos() {
+        use tjiftjaf::{packet::suback::ReturnCode, QoS, SubAck, SubscribeError};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            let Packet::Subscribe(subscribe) = read_packet(&mut stream).await else {
+                panic!("Expected a SUBSCRIBE");
+            };
+            let suback = SubAck::builder(subscribe.packet_identifier(), QoS::AtLeastOnceDelivery)
+                .add_return_code(QoS::AtLeastOnceDelivery);
+            stream
+                .write_all(&Packet::from(suback.build()).into_bytes())
+                .await
+                .unwrap();
+
+            for _ in 0..2 {
+                let Packet::Subscribe(subscribe) = read_packet(&mut stream).await else {
+                    panic!("Expected a SUBSCRIBE");
+                };
+                let suback = SubAck::builder(subscribe.packet_identifier(), ReturnCode::Failure);
+                stream
+                    .write_all(&Packet::from(suback.build()).into_bytes())
+                    .await
+                    .unwrap();
+            }
+            future::pending::<()>().await;
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let topics = Subscribe::builder("sensor/1", QoS::AtLeastOnceDelivery)
+            .add_topic("sensor/2", QoS::ExactlyOnceDelivery)
+            .build();
+        let result = handle.subscribe(topics).await.unwrap();
+        assert_eq!(
+            result.granted().collect::<Vec<_>>(),
+            [
+                ("sensor/1", QoS::AtLeastOnceDelivery),
+                ("sensor/2", QoS::AtLeastOnceDelivery)
+            ]
+        );
+        assert_eq!(result.downgraded().collect::<Vec<_>>(), ["sensor/2"]);
+
+        let Err(SubscribeError::Rejected { topic }) = handle.subscribe(subscribe("sensor/3")).await
+        else {
+            panic!("Expected the subscription to be rejected");
+        };
+        assert_eq!(topic, "sensor/3");
+
+        // A `Subscription` isn't returned either if the broker rejects it.
+        let Err(SubscribeError::Rejected { topic }) = handle.subscribe_stream("sensor/4").await
+        else {
+            panic!("Expected the subscription to be rejected");
+        };
+        assert_eq!(topic, "sensor/4");
+    }
+
+    // Verify that the client keeps a leased subscription while the application waits on it,
+    // and unsubscribes once the application stops polling it.
+    #[apply(test!)]
+    async fn test_subscribe_stream_with_lease() {
+        use std::time::Instant;
+        use tjiftjaf::{QoS, SubAck};
+        const LEASE: Duration = Duration::from_millis(200);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            let Packet::Subscribe(subscribe) = read_packet(&mut stream).await else {
+                panic!("Expected a SUBSCRIBE");
+            };
+            let suback =
+                SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery).build();
+            stream
+                .write_all(&Packet::from(suback).into_bytes())
+                .await
+                .unwrap();
+
+            // The application waits for a publication longer than the lease.
+            Timer::after(2 * LEASE).await;
+            stream
+                .write_all(&publish("sensor/1", "26.1").into_bytes())
+                .await
+                .unwrap();
+            let published = Instant::now();
+
+            let Packet::Unsubscribe(unsubscribe) = read_packet(&mut stream).await else {
+                panic!("Expected an UNSUBSCRIBE");
+            };
+            assert!(published.elapsed() >= LEASE);
+            assert_eq!(unsubscribe.topics().collect::<Vec<_>>(), ["sensor/#"]);
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+        let mut subscription = handle
+            .subscribe_stream_with_lease("sensor/#", LEASE)
+            .await
+            .unwrap();
+        assert_eq!(subscription.next().await.unwrap().topic(), "sensor/1");
+
+        // Stop polling the subscription, without dropping it.
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            panic!("The client didn't unsubscribe.");
+        };
+        futures_lite::future::race(server, timeout).await;
+        drop(subscription);
+    }
+
+    // Export the subscriptions of one client and import them into another. Verify that
+    // the second client then receives the publications of these topics.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_import_subscriptions() {
+        use tjiftjaf::QoS;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server_handle = smol::spawn(Server::new(listener).run());
+
+        let (blue, task) = create_client(port).await.spawn();
+        let _blue_task = smol::spawn(task);
+        let (mut green, task) = create_client(port).await.spawn();
+        let _green_task = smol::spawn(task);
+
+        let result = green.import_subscriptions(vec![]).await.unwrap();
+        assert_eq!(result.granted().count(), 0);
+
+        let subscribe = Subscribe::builder("sensor/#", QoS::AtLeastOnceDelivery)
+            .add_topic("status", QoS::AtMostOnceDelivery)
+            .build();
+        blue.subscribe(subscribe).await.unwrap();
+        let subscriptions = blue.subscriptions_snapshot().await.unwrap();
+        assert_eq!(
+            subscriptions,
+            vec![
+                ("sensor/#".to_string(), QoS::AtLeastOnceDelivery),
+                ("status".to_string(), QoS::AtMostOnceDelivery)
+            ]
+        );
+
+        let result = green.import_subscriptions(subscriptions).await.unwrap();
+        assert_eq!(result.granted().count(), 2);
+        assert_eq!(
+            green.subscriptions_snapshot().await.unwrap(),
+            blue.subscriptions_snapshot().await.unwrap()
+        );
+
+        publish("status", "online").emit(&blue).await.unwrap();
+        let publication = green.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "status");
+    }
+
+    // Verify that rotating credentials subscribes the new connection to the topics
+    // of the old connection, before the old connection is disconnected.
+    #[apply(test!)]
+    async fn test_rotate_credentials() {
+        use tjiftjaf::{QoS, SubAck};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = smol::spawn(async move {
+            let (mut old, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut old).await, Packet::Connect(_)));
+            old.write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            let Packet::Subscribe(subscribe) = read_packet(&mut old).await else {
+                panic!("Expected a SUBSCRIBE");
+            };
+            let suback =
+                SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery).build();
+            old.write_all(&Packet::from(suback).into_bytes())
+                .await
+                .unwrap();
+
+            let (mut new, _) = listener.accept().await.unwrap();
+            let Packet::Connect(connect) = read_packet(&mut new).await else {
+                panic!("Expected a CONNECT");
+            };
+            assert_eq!(connect.username(), Some("rotated"));
+            new.write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            let Packet::Subscribe(subscribe) = read_packet(&mut new).await else {
+                panic!("Expected a SUBSCRIBE");
+            };
+            assert_eq!(
+                subscribe
+                    .topics()
+                    .map(|(topic, _)| topic)
+                    .collect::<Vec<_>>(),
+                ["sensor/#"]
+            );
+            let suback =
+                SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery).build();
+            new.write_all(&Packet::from(suback).into_bytes())
+                .await
+                .unwrap();
+
+            assert!(matches!(read_packet(&mut old).await, Packet::Disconnect(_)));
+            new.write_all(&Packet::from(Publish::builder("sensor/1", "21.3").build()).into_bytes())
+                .await
+                .unwrap();
+            let Packet::Publish(publish) = read_packet(&mut new).await else {
+                panic!("Expected a PUBLISH");
+            };
+            assert_eq!(publish.topic(), "command/1");
+        });
+
+        let (mut handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+        subscribe("sensor/#").emit(&handle).await.unwrap();
+
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let connect = Connect::builder()
+            .client_id("rotated")
+            .username("rotated")
+            .password("secret")
+            .build();
+        let (new_handle, new_task) = Client::new(connect, stream).spawn();
+        let _new_task = smol::spawn(new_task);
+
+        handle.rotate_credentials(new_handle).await.unwrap();
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.topic(), "sensor/1");
+        publish("command/1", "on").emit(&handle).await.unwrap();
+        server.await;
+    }
+
+    // Verify that `ClientHandle::subscribe_stream()` yields only the publications
+    // matching its topic filter. Other publications are yielded by `subscriptions()`.
+    #[apply(test!)]
+    async fn test_subscribe_stream() {
+        use tjiftjaf::{QoS, SubAck};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            for _ in 0..2 {
+                let Packet::Subscribe(subscribe) = read_packet(&mut stream).await else {
+                    panic!("Expected a SUBSCRIBE");
+                };
+                let suback =
+                    SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery).build();
+                stream
+                    .write_all(&Packet::from(suback).into_bytes())
+                    .await
+                    .unwrap();
+            }
+
+            for topic in ["sensor/1/temperature", "sensor/1/humidity", "other"] {
+                stream
+                    .write_all(&publish(topic, "26.1").into_bytes())
+                    .await
+                    .unwrap();
+            }
+            let () = future::pending().await;
+        });
+
+        let (mut handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let mut temperatures = handle
+            .subscribe_stream("sensor/+/temperature")
+            .await
+            .unwrap();
+        let mut humidities = handle.subscribe_stream("sensor/#").await.unwrap();
+
+        let publish = temperatures.next().await.unwrap();
+        assert_eq!(publish.topic(), "sensor/1/temperature");
+
+        let publish = humidities.next().await.unwrap();
+        assert_eq!(publish.topic(), "sensor/1/temperature");
+        let publish = humidities.next().await.unwrap();
+        assert_eq!(publish.topic(), "sensor/1/humidity");
+
+        let publish = handle.subscriptions().await.unwrap();
+        assert_eq!(publish.topic(), "other");
+    }
+
+    // Verify that `ClientHandle::subscribe_stream_with_replay()` first yields the recent
+    // publications that match, within the capacity of the replay buffer.
+    #[apply(test!)]
+    async fn test_subscribe_stream_with_replay() {
+        use tjiftjaf::{QoS, SubAck};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            for (topic, payload) in [
+                ("config/a", "1"),
+                ("config/b", "2"),
+                ("config/a", "3"),
+                ("other", "4"),
+            ] {
+                stream
+                    .write_all(&publish(topic, payload).into_bytes())
+                    .await
+                    .unwrap();
+            }
+
+            let Packet::Subscribe(subscribe) = read_packet(&mut stream).await else {
+                panic!("Expected a SUBSCRIBE");
+            };
+            let suback =
+                SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery).build();
+            stream
+                .write_all(&Packet::from(suback).into_bytes())
+                .await
+                .unwrap();
+            stream
+                .write_all(&publish("config/c", "5").into_bytes())
+                .await
+                .unwrap();
+            let () = future::pending().await;
+        });
+
+        let (mut handle, task) = create_client(port).await.replay_buffer(3).spawn();
+        let _task = smol::spawn(task);
+        for _ in 0..4 {
+            handle.subscriptions().await.unwrap();
+        }
+
+        // The buffer doesn't hold the first publication anymore.
+        // Asking for more publications than can be replayed doesn't overflow.
+        let mut config = handle
+            .subscribe_stream_with_replay("config/#", usize::MAX)
+            .await
+            .unwrap();
+        for (topic, payload) in [("config/b", "2"), ("config/a", "3"), ("config/c", "5")] {
+            let publish = config.next().await.unwrap();
+            assert_eq!(publish.topic(), topic);
+            assert_eq!(publish.payload(), payload.as_bytes());
+        }
+    }
+
+    // Verify that the future returned by `PublishService::call()` resolves
+    // only after the server acknowledged the publication.
+    #[apply(test!)]
+    async fn test_publish_service() {
+        use tjiftjaf::{PubAck, QoS};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (acknowledging, acknowledged) = async_channel::bounded(1);
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            let Packet::Publish(publish) = read_packet(&mut stream).await else {
+                panic!("Expected a PUBLISH");
+            };
+
+            Timer::after(Duration::from_millis(100)).await;
+            acknowledging.send(()).await.unwrap();
+            let puback = PubAck::new(publish.packet_identifier().unwrap());
+            stream
+                .write_all(&Packet::from(puback).into_bytes())
+                .await
+                .unwrap();
+            let () = future::pending().await;
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let mut service = handle.publish_service();
+        futures::future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .unwrap();
+        let publish = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .build();
+        service.call(publish).await.unwrap();
+        assert!(acknowledged.try_recv().is_ok());
+    }
+
+    // Fill the queue of a client that doesn't run yet. Verify that `PublishService::poll_ready()`
+    // waits without waking itself, and that it's woken once the client made room.
+    #[apply(test!)]
+    async fn test_publish_service_waits_for_capacity() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::task::{Context, Wake, Waker};
+
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            let () = future::pending().await;
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let mut service = handle.publish_service();
+        let mut calls = vec![];
+        for _ in 0..100 {
+            calls.push(smol::spawn(service.call(publish("sensor/1", "26.1"))));
+        }
+
+        // Let the calls fill the queue.
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        while service
+            .poll_ready(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            smol::future::yield_now().await;
+        }
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        let _task = smol::spawn(task);
+        while !flag.0.load(Ordering::SeqCst) {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        assert!(service
+            .poll_ready(&mut Context::from_waker(&waker))
+            .is_ready());
+        for call in calls {
+            call.await.unwrap();
+        }
+    }
+
+    // Exchange a burst of publications with a server, while the budget of the client
+    // only allows one packet per turn. Verify that every publication is written and
+    // received, although the event loop yields all the time.
+    #[apply(test!)]
+    async fn test_budget() {
+        use tjiftjaf::aio::Budget;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            let mut burst = vec![];
+            for n in 0..10 {
+                let Packet::Publish(publish) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBLISH");
+                };
+                assert_eq!(publish.topic(), format!("sensor/{n}"));
+                burst.extend(Packet::from(publish).into_bytes());
+            }
+            stream.write_all(&burst).await.unwrap();
+            let () = future::pending().await;
+        });
+
+        let client = create_client(port)
+            .await
+            .budget(Budget::default().max_decoded(1).max_written(1));
+        let (mut handle, task) = client.spawn();
+        let _task = smol::spawn(task);
+
+        let batch = (0..10).map(|n| publish(&format!("sensor/{n}"), "26.1"));
+        handle.publish_batch(batch).await.unwrap();
+
+        for n in 0..10 {
+            let publication = handle.subscriptions().await.unwrap();
+            assert_eq!(publication.topic(), format!("sensor/{n}"));
+        }
+    }
+
+    // Publish a batch to a server that acknowledges only the first publication with QoS 1.
+    // Verify that the publications arrive in order, and that the batch token waits
+    // for all acknowledgements.
+    #[apply(test!)]
+    async fn test_publish_batch() {
+        use tjiftjaf::{PubAck, QoS, WaitTimeoutError};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (acknowledge, acknowledging) = async_channel::bounded(1);
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+
+            let mut packet_identifiers = vec![];
+            for topic in ["sensor/0", "sensor/1", "sensor/2"] {
+                let Packet::Publish(publish) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBLISH");
+                };
+                assert_eq!(publish.topic(), topic);
+                packet_identifiers.extend(publish.packet_identifier());
+            }
+
+            for packet_identifier in packet_identifiers {
+                acknowledging.recv().await.unwrap();
+                stream
+                    .write_all(&Packet::from(PubAck::new(packet_identifier)).into_bytes())
+                    .await
+                    .unwrap();
+            }
+            let () = future::pending().await;
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let batch = (0..3).map(|n| {
+            let qos = if n == 0 {
+                QoS::AtMostOnceDelivery
+            } else {
+                QoS::AtLeastOnceDelivery
+            };
+            Publish::builder(format!("sensor/{n}"), "26.1")
+                .qos(qos)
+                .build()
+        });
+        let mut token = handle.publish_batch(batch).await.unwrap();
+        assert_eq!(token.packet_identifiers().len(), 3);
+        assert_eq!(token.packet_identifiers()[0], None);
+
+        acknowledge.send(()).await.unwrap();
+        assert_eq!(
+            token.wait_timeout(Duration::from_millis(100)).await,
+            Err(WaitTimeoutError::Timeout)
+        );
+
+        acknowledge.send(()).await.unwrap();
+        token.wait_timeout(Duration::from_secs(5)).await.unwrap();
+        token.await.unwrap();
+    }
+
+    // Publish with `ClientHandle::publish()` to a server that delays its PUBACK.
+    // Verify that the delivery token times out first, and resolves after the PUBACK.
+    #[apply(test!)]
+    async fn test_delivery_token() {
+        use tjiftjaf::{PubAck, QoS, WaitTimeoutError};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (acknowledge, acknowledging) = async_channel::bounded(1);
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            let Packet::Publish(publish) = read_packet(&mut stream).await else {
+                panic!("Expected a PUBLISH");
+            };
+
+            acknowledging.recv().await.unwrap();
+            let puback = PubAck::new(publish.packet_identifier().unwrap());
+            stream
+                .write_all(&Packet::from(puback).into_bytes())
+                .await
+                .unwrap();
+            let () = future::pending().await;
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let _task = smol::spawn(task);
+
+        let publish = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .build();
+        let mut token = handle.publish(publish).await.unwrap();
+        assert!(token.packet_identifier().is_some());
+        assert_eq!(
+            token.wait_timeout(Duration::from_millis(100)).await,
+            Err(WaitTimeoutError::Timeout)
+        );
+
+        acknowledge.send(()).await.unwrap();
+        token.wait_timeout(Duration::from_secs(5)).await.unwrap();
+        token.await.unwrap();
+
+        // Publications with QoS 0 are acknowledged immediately.
+        let token = handle
+            .publish(Publish::builder("sensor/1", "26.2").build())
+            .await
+            .unwrap();
+        assert_eq!(token.packet_identifier(), None);
+        token.await.unwrap();
+    }
+
+    // Let a server close the connection while a publication awaits its PUBACK.
+    // Verify that the handle reports that the client terminated, while the handle of a client
+    // that never ran reports that the client is gone.
+    #[apply(test!)]
+    async fn test_handle_error() {
+        use tjiftjaf::{HandleError, QoS};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Publish(_)));
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let task = smol::spawn(task);
+
+        let publication = Publish::builder("sensor/1", "26.1")
+            .qos(QoS::AtLeastOnceDelivery)
+            .build();
+        let token = handle.publish(publication).await.unwrap();
+        assert_eq!(token.await, Err(HandleError::Disconnected));
+        assert!(task.await.is_err());
+        assert_eq!(
+            handle.debug_snapshot().await.unwrap_err(),
+            HandleError::Disconnected
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (handle, task) = create_client(port).await.spawn();
+        drop(task);
+        assert_eq!(
+            publish("sensor/1", "26.1").emit(&handle).await,
+            Err(HandleError::ClientGone)
+        );
+    }
+
+    // Verify that a `WeakClientHandle` upgrades while the client runs, but doesn't keep
+    // the client running once its handle is dropped, nor upgrades after the client terminated.
+    #[apply(test!)]
+    async fn test_weak_client_handle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _server = smol::spawn(async move {
+            let mut streams = vec![];
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+                stream
+                    .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                    .await
+                    .unwrap();
+                streams.push(stream);
+            }
+            // Close the second connection, keep the first open.
+            streams.pop();
+            future::pending::<()>().await;
+        });
+
+        let (handle, task) = create_client(port).await.spawn();
+        let task = smol::spawn(task);
+        let weak = handle.downgrade();
+        let upgraded = weak.upgrade().unwrap();
+        publish("sensor/1", "26.1").emit(&upgraded).await.unwrap();
+        drop(upgraded);
+
+        drop(handle);
+        assert!(task.await.is_err());
+        assert!(weak.upgrade().is_none());
+
+        let (handle, task) = create_client(port).await.spawn();
+        let weak = handle.downgrade();
+        assert!(task.await.is_err());
+        assert!(weak.upgrade().is_none());
+    }
+
+    // Connect to a server over TLS and publish a message.
+    #[cfg(feature = "tls")]
+    #[apply(test!)]
+    async fn test_tls() {
+        let (port, server) = crate::env::tls::server();
+        let connect = Connect::builder().client_id("tls").build();
+        let client = Client::connect_tls(
+            &format!("localhost:{port}"),
+            connect,
+            crate::env::tls::client_config(),
+        )
+        .await
+        .unwrap();
+        let (handle, task) = client.spawn();
+        let task = smol::spawn(task);
+
+        while handle.debug_snapshot().await.unwrap().connection_status
+            != tjiftjaf::ConnectionStatus::Connected
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        publish("sensor/1", "26.1").emit(&handle).await.unwrap();
+        let statistics = handle.statistics().await.unwrap();
+        assert!(statistics.connect_duration.is_some());
+        assert!(statistics.tls_handshake_duration.is_some());
+        assert!(!statistics.tls_resumed);
+
+        while handle
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_sent
+            < 2
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        handle.disconnect().await.unwrap();
+        task.await.unwrap();
+
+        let packets = server.join().unwrap();
+        let [Packet::Connect(_), Packet::Publish(publish), Packet::Disconnect(_)] =
+            packets.as_slice()
+        else {
+            panic!("Unexpected packets: {packets:?}");
+        };
+        assert_eq!(publish.topic(), "sensor/1");
+    }
+
+    // Publish a message over TLS that's larger than the buffers of rustls and of the
+    // socket, so writing has to wait until the server reads.
+    #[cfg(feature = "tls")]
+    #[apply(test!)]
+    async fn test_tls_large_publish() {
+        let (port, server) = crate::env::tls::server();
+        let connect = Connect::builder().client_id("tls").build();
+        let client = Client::connect_tls(
+            &format!("localhost:{port}"),
+            connect,
+            crate::env::tls::client_config(),
+        )
+        .await
+        .unwrap();
+        let (handle, task) = client.spawn();
+        let task = smol::spawn(task);
+
+        let payload = vec![b'x'; 16 * 1024 * 1024];
+        publish("sensor/1", payload.clone())
+            .emit(&handle)
+            .await
+            .unwrap();
+        while handle
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_sent
+            < 2
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        handle.disconnect().await.unwrap();
+        task.await.unwrap();
+
+        let packets = server.join().unwrap();
+        let [Packet::Connect(_), Packet::Publish(publish), Packet::Disconnect(_)] =
+            packets.as_slice()
+        else {
+            panic!("Unexpected packets: {packets:?}");
+        };
+        assert_eq!(publish.payload(), payload);
+    }
+
+    // Suspend a client in the middle of a QoS 2 handshake in both directions, encode the
+    // session and resume it on a new connection. Verify that the client completes both
+    // handshakes without retransmitting its publication or delivering the server's twice.
+    #[apply(test!)]
+    async fn test_resume_qos_2_handshake() {
+        use tjiftjaf::{PubComp, PubRec, PubRel, QoS, Session};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let inbound = Publish::builder("sensor/2", "26.2")
+            .qos(QoS::ExactlyOnceDelivery)
+            .packet_identifier(2)
+            .build();
+
+        let (done, finished) = async_channel::bounded(1);
+        let server = smol::spawn(async move {
+            // The first connection ends after the server received the outbound publication
+            // and the client received the inbound publication.
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Publish(_)));
+            stream
+                .write_all(&Packet::from(PubRec::new(1)).into_bytes())
+                .await
+                .unwrap();
+            stream.write_all(inbound.as_bytes()).await.unwrap();
+            done.send(()).await.unwrap();
+
+            // After resuming, the client releases its publication and acknowledges
+            // the retransmission of the inbound publication.
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_packet(&mut stream).await, Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .await
+                .unwrap();
+            let Packet::PubRel(pubrel) = read_packet(&mut stream).await else {
+                panic!("Expected a PUBREL");
+            };
+            assert_eq!(pubrel.packet_identifier(), 1);
+
+            let retransmission = Publish::builder("sensor/2", "26.2")
+                .qos(QoS::ExactlyOnceDelivery)
+                .packet_identifier(2)
+                .duplicate(true)
+                .build();
+            stream.write_all(retransmission.as_bytes()).await.unwrap();
+            let Packet::PubRec(pubrec) = read_packet(&mut stream).await else {
+                panic!("Expected a PUBREC");
+            };
+            assert_eq!(pubrec.packet_identifier(), 2);
+
+            stream
+                .write_all(&Packet::from(PubRel::new(2)).into_bytes())
+                .await
+                .unwrap();
+            stream
+                .write_all(&Packet::from(PubComp::new(1)).into_bytes())
+                .await
+                .unwrap();
+            let Packet::PubComp(pubcomp) = read_packet(&mut stream).await else {
+                panic!("Expected a PUBCOMP");
+            };
+            assert_eq!(pubcomp.packet_identifier(), 2);
+            done.send(()).await.unwrap();
+            let () = future::pending().await;
+        });
+
+        let (mut handle, task) = create_client(port).await.spawn();
+        let task = smol::spawn(task);
+        Publish::builder("sensor/1", "26.1")
+            .qos(QoS::ExactlyOnceDelivery)
+            .packet_identifier(1)
+            .build()
+            .emit(&handle)
+            .await
+            .unwrap();
+
+        finished.recv().await.unwrap();
+        let publication = handle.subscriptions().await.unwrap();
+        assert_eq!(publication.packet_identifier(), Some(2));
+
+        // Wait until the client processed the PUBREC of the server.
+        while handle
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_read
+            < 3
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        // Simulate a restart of the process by storing the session as bytes.
+        let bytes = handle.suspend().await.unwrap().into_bytes();
+        assert!(task.await.is_ok());
+        let session = Session::try_from(bytes).unwrap();
+        assert_eq!(session.released(), &[1]);
+        assert_eq!(session.received(), &[2]);
+
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let (mut handle, task) = Client::resume(session, stream).spawn();
+        let _task = smol::spawn(task);
+
+        finished.recv().await.unwrap();
+        while !handle.debug_snapshot().await.unwrap().inflight.is_empty() {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        assert!(futures_lite::future::poll_once(handle.subscriptions())
+            .await
+            .is_none());
+        drop(server);
+    }
+
+    // Verify that a client with the timer of tokio emits keep alives
+    // when it runs on a tokio runtime.
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_tokio_timer() {
+        use std::io::{Read, Write};
+        use tjiftjaf::aio::sleep::Tokio;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frame = [0; 2];
+            stream.read_exact(&mut frame).unwrap();
+            stream.read_exact(&mut vec![0; frame[1] as usize]).unwrap();
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+
+            stream.read_exact(&mut frame).unwrap();
+            assert_eq!(
+                Packet::try_from(frame.to_vec()).unwrap().packet_type(),
+                PacketType::PingReq
+            );
+            stream
+                .write_all(&Packet::from(tjiftjaf::PingResp).into_bytes())
+                .unwrap();
+            stream
+        });
+
+        let stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap()
+            .compat();
+        let connect = Connect::builder().keep_alive(1).build();
+        let (handle, task) = Client::new(connect, stream).timer::<Tokio>().spawn();
+
+        let answered = async {
+            loop {
+                let pings = handle.pings().await.unwrap();
+                if pings.first().is_some_and(|ping| ping.answered.is_some()) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::select! {
+            result = task => panic!("The client stopped: {result:?}"),
+            _ = answered => {}
+        }
+        drop(server.join().unwrap());
+    }
+
+    // Connect a client of `aio::tokio` to a server, and publish a message.
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_tokio_client() {
+        use std::io::{Read, Write};
+        use tjiftjaf::aio::tokio;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Read a packet whose remaining length is less than 128 bytes.
+        fn read_packet(stream: &mut std::net::TcpStream) -> Packet {
+            let mut frame = vec![0; 2];
+            stream.read_exact(&mut frame).unwrap();
+            frame.resize(2 + frame[1] as usize, 0);
+            stream.read_exact(&mut frame[2..]).unwrap();
+            Packet::try_from(frame).unwrap()
+        }
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(matches!(read_packet(&mut stream), Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+
+            let Packet::Publish(publish) = read_packet(&mut stream) else {
+                panic!("Expected a PUBLISH");
+            };
+            assert_eq!(publish.topic(), "sensor/1");
+            stream
+        });
+
+        let connect = Connect::builder().build();
+        let client = tokio::Client::connect(format!("127.0.0.1:{port}"), connect)
+            .await
+            .unwrap();
+        let (handle, task) = client.spawn();
+
+        let published = async {
+            publish("sensor/1", "26.1").emit(&handle).await.unwrap();
+            ::tokio::task::spawn_blocking(move || server.join().unwrap())
+                .await
+                .unwrap()
+        };
+        let stream = ::tokio::select! {
+            result = task => panic!("The client stopped: {result:?}"),
+            stream = published => stream,
+        };
+        drop(stream);
+    }
+
+    // Same as `test_client_and_server()`, but every connection
+    // is handled in a separate task.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_client_and_server_with_spawner() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let server = Server::new(listener).spawner(|future| smol::spawn(future).detach());
+        let _server_handle = smol::spawn(server.run());
+
+        let (mut handle_1, task) = create_client(local_addr.port()).await.spawn();
+        let _handle = smol::spawn(task);
+        let (handle_2, task) = create_client(local_addr.port()).await.spawn();
+        let _handle = smol::spawn(task);
+
+        Subscribe::builder("test/#", tjiftjaf::QoS::AtLeastOnceDelivery)
+            .build()
+            .emit(&handle_1)
+            .await
+            .unwrap();
+
+        // Wait until the server processed the subscription.
+        while handle_1
+            .debug_snapshot()
+            .await
+            .unwrap()
+            .statistics
+            .packets_read
+            < 2
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        publish("test/client_and_server", "test_subscribe_and_publish")
+            .emit(&handle_2)
+            .await
+            .unwrap();
+
+        let publication = handle_1.subscriptions().await.unwrap();
+        assert_eq!(&publication.topic(), &"test/client_and_server");
+        assert_eq!(&publication.payload(), b"test_subscribe_and_publish");
+    }
+
+    // Drop a server that routes messages in a task of its own. Verify that the routing
+    // task stops as well, so the connections that are still open are closed.
+    #[cfg(feature = "experimental")]
+    #[apply(test!)]
+    async fn test_drop_server_with_spawner() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let server = Server::new(listener).spawner(|future| smol::spawn(future).detach());
+        let server = smol::spawn(server.run());
+
+        let (handle, task) = create_client(local_addr.port()).await.spawn();
+        let task = smol::spawn(task);
+        while handle.debug_snapshot().await.unwrap().connection_status
+            != tjiftjaf::ConnectionStatus::Connected
+        {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        drop(server);
+        _ = publish("sensor/1", "26.1").emit(&handle).await;
+        let stopped = async {
+            _ = task.await;
+            true
+        };
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            false
+        };
+        assert!(futures_lite::future::race(stopped, timeout).await);
+    }
+
+    // Send packets through a `LossyTransport`. Verify that they're delayed in both
+    // directions and reordered, and that dropped packets never arrive.
+    #[cfg(feature = "test-util")]
+    #[apply(test!)]
+    async fn test_lossy_transport() {
+        use std::time::Instant;
+        use tjiftjaf::{aio::lossy::LossyTransport, PingResp};
+        const LATENCY: Duration = Duration::from_millis(100);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let start = Instant::now();
+        let server = smol::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for topic in ["second", "first"] {
+                let Packet::Publish(publish) = read_packet(&mut stream).await else {
+                    panic!("Expected a PUBLISH");
+                };
+                assert_eq!(publish.topic(), topic);
+            }
+            assert!(start.elapsed() >= LATENCY);
+            stream
+                .write_all(&Packet::from(PingResp).into_bytes())
+                .await
+                .unwrap();
+
+            // Every packet on the second connection is dropped.
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut bytes = vec![];
+            stream.read_to_end(&mut bytes).await.unwrap();
+            assert!(bytes.is_empty());
+        });
+
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let mut transport = LossyTransport::new(stream)
+            .latency(LATENCY)
+            .reorder_rate(1.0);
+        let first = Publish::builder("first", "").build().into_bytes();
+        let second = Publish::builder("second", "").build().into_bytes();
+        transport
+            .write_all(&[first, second].concat())
+            .await
+            .unwrap();
+
+        let mut frame = [0; 2];
+        transport.read_exact(&mut frame).await.unwrap();
+        assert_eq!(
+            Packet::try_from(frame.to_vec()).unwrap().packet_type(),
+            PacketType::PingResp
+        );
+        assert!(start.elapsed() >= 2 * LATENCY);
+
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let mut transport = LossyTransport::new(stream).drop_rate(1.0);
+        transport
+            .write_all(&Packet::from(Connect::builder().build()).into_bytes())
+            .await
+            .unwrap();
+        transport.close().await.unwrap();
+        server.await;
+    }
+
+    // Put a `Wiretap` between a client and the server, and assert how fast the server
+    // acknowledges the CONNECT, SUBSCRIBE and UNSUBSCRIBE.
+    #[cfg(all(feature = "test-util", feature = "experimental"))]
+    #[apply(test!)]
+    async fn test_wiretap_ack_latencies() {
+        use tjiftjaf::{testing::Wiretap, unsubscribe};
+        const WITHIN: Duration = Duration::from_secs(1);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = listener.local_addr().unwrap();
+        let _server_handle = smol::spawn(Server::new(listener).run());
+
+        let (wiretap, mut transcription) = Wiretap::bind(broker).unwrap();
+        let port = wiretap.local_addr().unwrap().port();
+        let _wiretap_handle = smol::spawn(wiretap.run());
+
+        let (handle, task) = create_client(port).await.spawn();
+        smol::spawn(task).detach();
+        subscribe(TOPIC).emit(&handle).await.unwrap();
+        unsubscribe(TOPIC).emit(&handle).await.unwrap();
+
+        for ack in [
+            PacketType::ConnAck,
+            PacketType::SubAck,
+            PacketType::UnsubAck,
+        ] {
+            assert!(transcription.assert_ack_within(ack, WITHIN).await <= WITHIN);
+        }
+        assert!(transcription
+            .history()
+            .iter()
+            .any(|line| line.packet.packet_type() == PacketType::Unsubscribe));
+    }
+}
+
+#[cfg(feature = "blocking")]
+mod blocking {
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use tjiftjaf::{
+        blocking::{self, Emit},
+        publish, subscribe, Connect,
+    };
+
+    const TOPIC: &str = "topic";
+
+    fn create_blocking_client(port: u16) -> blocking::Client {
+        let stream = std::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+            .expect("Failed to open TCP connection to broker.");
+
+        let connect = Connect::builder().client_id("test").keep_alive(5).build();
+        blocking::Client::new(connect, stream)
+    }
+
+    // Read a packet whose remaining length is less than 128 bytes.
+    fn read_packet(stream: &mut std::net::TcpStream) -> tjiftjaf::Packet {
+        use std::io::Read;
+
+        let mut buffer = vec![0; 2];
+        stream.read_exact(&mut buffer).unwrap();
+        buffer.resize(2 + buffer[1] as usize, 0);
+        stream.read_exact(&mut buffer[2..]).unwrap();
+        tjiftjaf::Packet::try_from(buffer).unwrap()
+    }
+
+    // Connect to a server at an IPv6 address given as host and port, and publish a message.
+    #[test]
+    fn test_connect_ipv6() {
+        use std::io::Write;
+        use std::net::Ipv6Addr;
+        use tjiftjaf::{ConnAck, Packet};
+
+        let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(matches!(read_packet(&mut stream), Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+            read_packet(&mut stream)
+        });
+
+        let connect = Connect::builder().build();
+        let client = blocking::Client::connect((Ipv6Addr::LOCALHOST, port), connect).unwrap();
+        let (handle, _task) = client.spawn().unwrap();
+
+        publish("sensor/1", "26.1").emit(&handle).unwrap();
+        let Packet::Publish(publish) = server.join().unwrap() else {
+            panic!("Expected a PUBLISH");
+        };
+        assert_eq!(publish.topic(), "sensor/1");
+    }
+
+    // Connect a client to a broker.
+    // Then, subscribe to a topic and publish to that same topic.
+    // Verify that the client receives published message.
+    #[test]
+    fn test_subscribe_and_publish_with_blocking_client() {
+        use crate::env::broker::Broker;
+
+        let broker = Broker::new();
+        let (mut handle_a, task) = create_blocking_client(broker.port).spawn().unwrap();
+
+        handle_a.subscribe(subscribe(TOPIC)).unwrap();
+
+        publish(TOPIC, "test_subscribe_and_publish")
+            .emit(&handle_a)
+            .unwrap();
+
+        let publish = handle_a.publication().unwrap();
+
+        assert_eq!(publish.topic(), TOPIC);
+        assert_eq!(publish.payload(), b"test_subscribe_and_publish");
+
+        handle_a.disconnect().unwrap();
+        assert!(task.join().is_ok());
+    }
+
+    // Publish to a server that delays its PUBACK. Verify that the delivery token times out
+    // first, and resolves after the PUBACK.
+    #[test]
+    fn test_delivery_token_with_blocking_client() {
+        use std::io::Write;
+        use tjiftjaf::{ConnAck, Packet, PubAck, Publish, QoS, WaitTimeoutError};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (acknowledge, acknowledging) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(matches!(read_packet(&mut stream), Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+            let Packet::Publish(publish) = read_packet(&mut stream) else {
+                panic!("Expected a PUBLISH");
+            };
+
+            acknowledging.recv().unwrap();
+            let puback = PubAck::new(publish.packet_identifier().unwrap());
+            stream
+                .write_all(&Packet::from(puback).into_bytes())
+                .unwrap();
+            assert!(matches!(read_packet(&mut stream), Packet::Disconnect(_)));
+        });
+
+        let (handle, task) = create_blocking_client(port).spawn().unwrap();
+        let publish = Publish::builder(TOPIC, "test_delivery_token")
+            .qos(QoS::AtLeastOnceDelivery)
+            .build();
+        let mut token = handle.publish(publish).unwrap();
+        assert!(token.packet_identifier().is_some());
+        assert_eq!(
+            token.wait_timeout(Duration::from_millis(100)),
+            Err(WaitTimeoutError::Timeout)
+        );
+
+        acknowledge.send(()).unwrap();
+        token.wait_timeout(Duration::from_secs(5)).unwrap();
+        token.wait().unwrap();
+
+        handle.disconnect().unwrap();
+        assert!(task.join().is_ok());
+    }
+
+    // Subscribe at a server that delays its SUBACK, and rejects the second subscription.
+    // Verify that `subscribe_timeout()` times out, and that `subscribe()` reports the rejection.
+    #[test]
+    fn test_subscribe_with_blocking_client() {
+        use std::io::Write;
+        use tjiftjaf::{packet::suback::ReturnCode, ConnAck, Packet, QoS, SubAck, SubscribeError};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(matches!(read_packet(&mut stream), Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+
+            let Packet::Subscribe(subscribe) = read_packet(&mut stream) else {
+                panic!("Expected a SUBSCRIBE");
+            };
+            std::thread::sleep(Duration::from_millis(200));
+            let suback = SubAck::builder(subscribe.packet_identifier(), QoS::AtMostOnceDelivery);
+            stream
+                .write_all(&Packet::from(suback.build()).into_bytes())
+                .unwrap();
+
+            let Packet::Subscribe(subscribe) = read_packet(&mut stream) else {
+                panic!("Expected a SUBSCRIBE");
+            };
+            let suback = SubAck::builder(subscribe.packet_identifier(), ReturnCode::Failure);
+            stream
+                .write_all(&Packet::from(suback.build()).into_bytes())
+                .unwrap();
+            assert!(matches!(read_packet(&mut stream), Packet::Disconnect(_)));
+        });
+
+        let (handle, task) = create_blocking_client(port).spawn().unwrap();
+        assert!(matches!(
+            handle.subscribe_timeout(subscribe("sensor/1"), Duration::from_millis(50)),
+            Err(SubscribeError::Timeout)
+        ));
+        let Err(SubscribeError::Rejected { topic }) = handle.subscribe(subscribe("sensor/2"))
+        else {
+            panic!("Expected the subscription to be rejected");
+        };
+        assert_eq!(topic, "sensor/2");
+
+        handle.disconnect().unwrap();
+        assert!(task.join().is_ok());
+    }
+
+    // Connect to a server over a Unix domain socket. Verify that a publication arrives.
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_with_blocking_client() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::{UnixListener, UnixStream};
+        use tjiftjaf::{ConnAck, Packet};
+
+        let path = std::env::temp_dir().join(format!("tjiftjaf-{}.sock", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frame = [0; 2];
+            stream.read_exact(&mut frame).unwrap();
+            stream.read_exact(&mut vec![0; frame[1] as usize]).unwrap();
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+            stream
+                .write_all(&publish(TOPIC, "over a unix socket").into_bytes())
+                .unwrap();
+            stream
+        });
+
+        let socket = UnixStream::connect(&path).unwrap();
+        let connect = Connect::builder().keep_alive(5).build();
+        let (mut handle, task) = blocking::Client::new_unix(connect, socket).spawn().unwrap();
+        let publication = handle.publication().unwrap();
+        assert_eq!(publication.topic(), TOPIC);
+        assert_eq!(publication.payload(), b"over a unix socket");
+
+        handle.disconnect().unwrap();
+        assert!(task.join().unwrap().is_ok());
+        drop(server.join().unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Let a server trickle a publication byte by byte, and read a large publication only
+    // after a delay. Verify that the client reassembles the publication from partial reads,
+    // and writes the large publication although the socket isn't always writable.
+    #[test]
+    fn test_partial_io_with_blocking_client() {
+        use std::io::{Read, Write};
+        use tjiftjaf::{ConnAck, Packet};
+
+        let large = publish("sensor/1", vec![b'a'; 4 * 1024 * 1024]);
+        let expected = large.clone().into_bytes();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut frame = [0; 2];
+            stream.read_exact(&mut frame).unwrap();
+            stream.read_exact(&mut vec![0; frame[1] as usize]).unwrap();
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+
+            for byte in publish(TOPIC, "trickled").into_bytes() {
+                stream.write_all(&[byte]).unwrap();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+
+            std::thread::sleep(Duration::from_millis(200));
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).unwrap();
+            (stream, received == expected)
+        });
+
+        let (mut handle, _task) = create_blocking_client(port).spawn().unwrap();
+        let publication = handle.publication().unwrap();
+        assert_eq!(publication.topic(), TOPIC);
+        assert_eq!(publication.payload(), b"trickled");
+
+        large.emit(&handle).unwrap();
+        let (_stream, received) = server.join().unwrap();
+        assert!(received);
+    }
+
+    // Let a server send more publications than the client queues for a lagging handle.
+    // Verify that the client resumes reading once the handle catches up.
+    #[test]
+    fn test_lagging_handle_with_blocking_client() {
+        use std::io::{Read, Write};
+        use tjiftjaf::{ConnAck, Packet};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frame = [0; 2];
+            stream.read_exact(&mut frame).unwrap();
+            stream.read_exact(&mut vec![0; frame[1] as usize]).unwrap();
+            let mut burst = Packet::from(ConnAck::builder().build()).into_bytes();
+            for n in 0..500 {
+                burst.extend(publish(TOPIC, n.to_string()).into_bytes());
+            }
+            stream.write_all(&burst).unwrap();
+            stream
+        });
+
+        let (mut handle, _task) = create_blocking_client(port).spawn().unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        for n in 0..500 {
+            let publication = handle.publication().unwrap();
+            assert_eq!(publication.payload(), n.to_string().as_bytes());
+        }
+        drop(server.join().unwrap());
+    }
+
+    // Publish more QoS 1 messages than the client queues for its handle, while the
+    // application never drains the handle. Verify that all of them are acknowledged.
+    #[test]
+    fn test_publish_while_handle_lags_with_blocking_client() {
+        use std::io::Write;
+        use tjiftjaf::{ConnAck, Packet, PubAck, Publish, QoS};
+        const PUBLICATIONS: usize = 300;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(matches!(read_packet(&mut stream), Packet::Connect(_)));
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+
+            while let Packet::Publish(publish) = read_packet(&mut stream) {
+                let puback = PubAck::new(publish.packet_identifier().unwrap());
+                stream
+                    .write_all(&Packet::from(puback).into_bytes())
+                    .unwrap();
+            }
+        });
+
+        let (handle, _task) = create_blocking_client(port).spawn().unwrap();
+        let mut tokens = vec![];
+        for n in 0..PUBLICATIONS {
+            let publish = Publish::builder(TOPIC, n.to_string())
+                .qos(QoS::AtLeastOnceDelivery)
+                .build();
+            tokens.push(handle.publish(publish).unwrap());
+        }
+        for mut token in tokens {
+            token.wait_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    // Shut down a blocking client, once gracefully and once without waiting.
+    // Verify that the thread terminates in both cases.
+    #[test]
+    fn test_shutdown_blocking_client() {
+        use std::io::{Read, Write};
+        use tjiftjaf::{ConnAck, Packet};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frame = [0; 2];
+            stream.read_exact(&mut frame).unwrap();
+            stream.read_exact(&mut vec![0; frame[1] as usize]).unwrap();
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+
+            // The second client might be shut down before it sends the CONNECT.
+            let (second, _) = listener.accept().unwrap();
+            (stream, second)
+        });
+
+        let (handle, task) = create_blocking_client(port).spawn().unwrap();
+        handle.shutdown(Duration::from_secs(5)).unwrap();
+        assert!(task.join().unwrap().is_ok());
+
+        // Without time to exit gracefully, the socket is shut down.
+        let (handle, task) = create_blocking_client(port).spawn().unwrap();
+        handle.shutdown(Duration::ZERO).unwrap();
+        let _ = task.join().unwrap();
+        drop(server.join().unwrap());
+    }
+
+    // Let a server close the connection after the handshake.
+    // Verify that the handle reports that the client terminated.
+    #[test]
+    fn test_handle_error_with_blocking_client() {
+        use std::io::{Read, Write};
+        use tjiftjaf::{ConnAck, HandleError, Packet};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = vec![0; 2];
+            stream.read_exact(&mut buffer).unwrap();
+            buffer.resize(2 + buffer[1] as usize, 0);
+            stream.read_exact(&mut buffer[2..]).unwrap();
+            stream
+                .write_all(&Packet::from(ConnAck::builder().build()).into_bytes())
+                .unwrap();
+        });
+
+        let (handle, task) = create_blocking_client(port).spawn().unwrap();
+        assert!(task.join().unwrap().is_err());
+        assert_eq!(
+            handle.debug_snapshot().unwrap_err(),
+            HandleError::Disconnected
+        );
+        assert_eq!(
+            publish(TOPIC, "test_handle_error").emit(&handle),
+            Err(HandleError::Disconnected)
+        );
+    }
+
+    // Subscribe to 2 topic filters with a `SimpleClient`.
+    // Verify that every publication is routed to the receivers of matching filters.
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn test_simple_client() {
+        use tjiftjaf::{aio::server::Server, blocking::simple::SimpleClient};
+
+        let listener = smol::block_on(async_net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || smol::block_on(Server::new(listener).run()));
+
+        let stream = std::net::TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+        let connect = Connect::builder().client_id("simple").build();
+        let client = SimpleClient::connect(connect, stream).unwrap();
+
+        // `subscribe()` returns after the server acknowledged the subscription.
+        let all = client.subscribe("sensor/#").unwrap();
+        let temperatures = client.subscribe("sensor/+/temperature").unwrap();
+
+        client.clone().publish("sensor/1/humidity", "63").unwrap();
+        client.publish("sensor/1/temperature", "21.3").unwrap();
+
+        let mut topics: Vec<String> = (0..2)
+            .map(|_| all.recv_timeout(Duration::from_secs(1)).unwrap())
+            .map(|publish| publish.topic().to_string())
+            .collect();
+        topics.sort();
+        assert_eq!(topics, vec!["sensor/1/humidity", "sensor/1/temperature"]);
+
+        let publish = temperatures.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(publish.topic(), "sensor/1/temperature");
+        assert_eq!(publish.payload(), b"21.3");
+
+        client.close().unwrap();
+        assert!(all.recv().is_err());
+        assert!(client.close().is_ok());
+    }
+
+    // Connect to a server over TLS and publish a message.
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls() {
+        let (port, server) = crate::env::tls::server();
+        let connect = Connect::builder().client_id("tls").build();
+        let client = blocking::Client::connect_tls(
+            &format!("localhost:{port}"),
+            connect,
+            crate::env::tls::client_config(),
+        )
+        .unwrap();
+        let (handle, task) = client.spawn().unwrap();
+
+        while handle.debug_snapshot().unwrap().connection_status
+            != tjiftjaf::ConnectionStatus::Connected
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        publish("sensor/1", "26.1").emit(&handle).unwrap();
+        while handle.debug_snapshot().unwrap().statistics.packets_sent < 2 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        handle.disconnect().unwrap();
+        task.join().unwrap().unwrap();
+
+        let packets = server.join().unwrap();
+        let [tjiftjaf::Packet::Connect(_), tjiftjaf::Packet::Publish(publish), tjiftjaf::Packet::Disconnect(_)] =
+            packets.as_slice()
+        else {
+            panic!("Unexpected packets: {packets:?}");
+        };
+        assert_eq!(publish.topic(), "sensor/1");
+    }
+
+    // Reconnect over TLS with the same `ClientConfig`. Verify that the second handshake
+    // resumes the session of the first, and that both are recorded in the statistics.
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_resumption() {
+        let (port, server) = crate::env::tls::serve(2);
+        let config = crate::env::tls::client_config();
+
+        let mut resumed = vec![];
+        for _ in 0..2 {
+            let connect = Connect::builder().client_id("tls").build();
+            let client = blocking::Client::connect_tls(
+                &format!("localhost:{port}"),
+                connect,
+                config.clone(),
+            )
+            .unwrap();
+            let (handle, task) = client.spawn().unwrap();
+
+            // The session ticket arrives after the handshake, before the CONNACK.
+            while handle.debug_snapshot().unwrap().connection_status
+                != tjiftjaf::ConnectionStatus::Connected
+            {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            let statistics = handle.statistics().unwrap();
+            assert!(statistics.connect_duration.is_some());
+            assert!(statistics.tls_handshake_duration.is_some());
+            resumed.push(statistics.tls_resumed);
+
+            handle.disconnect().unwrap();
+            task.join().unwrap().unwrap();
+        }
+        assert_eq!(resumed, [false, true]);
+        assert_eq!(server.join().unwrap().len(), 4);
+    }
+
+    // Verify that `SimpleClient::subscribe()` fails if the server rejects the subscription.
+    #[test]
+    fn test_simple_client_subscription_rejected() {
+        use std::io::{Read, Write};
+        use tjiftjaf::{
+            blocking::simple::SimpleClient, packet::suback::ReturnCode, ConnAck, Packet, SubAck,
+            SubscribeError,
+        };
+
+        // A server that accepts the connection, but rejects every subscription.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            loop {
+                // The packets in this test are smaller than 128 bytes. So the
+                // fixed header is always 2 bytes.
+                let mut frame = vec![0; 2];
+                if stream.read_exact(&mut frame).is_err() {
+                    return;
+                }
+                frame.resize(2 + frame[1] as usize, 0);
+                stream.read_exact(&mut frame[2..]).unwrap();
+
+                let response: Packet = match Packet::try_from(frame).unwrap() {
+                    Packet::Connect(_) => ConnAck::builder().build().into(),
+                    Packet::Subscribe(subscribe) => {
+                        SubAck::builder(subscribe.packet_identifier(), ReturnCode::Failure)
+                            .build()
+                            .into()
+                    }
+                    _ => return,
+                };
+                stream.write_all(&response.into_bytes()).unwrap();
+            }
+        });
+
+        let stream = std::net::TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+        let connect = Connect::builder().client_id("simple").build();
+        let client = SimpleClient::connect(connect, stream).unwrap();
+
+        let Err(SubscribeError::Rejected { topic }) = client.subscribe("sensor/#") else {
+            panic!("Expected the subscription to be rejected.");
+        };
+        assert_eq!(topic, "sensor/#");
+
+        // The rejected filter is not part of the session.
+        let snapshot = client.debug_snapshot().unwrap();
+        assert_eq!(snapshot.subscriptions, vec![]);
+
+        client.close().unwrap();
     }
 }